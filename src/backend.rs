@@ -0,0 +1,228 @@
+// Operator backends the model runs on. Each method has the semantics of the operators.rs function of the
+// same name, so a BLAS or GPU backend only has to match those to be a drop-in replacement for Cpu
+use crate::operators::{self as OP, Activation, RopeScaling};
+use crate::quant::{Int8Tensor, QuantBlock, QuantTensor};
+use crate::tensor::Tensor;
+
+pub trait Ops: Send + Sync {
+    fn gather(&self, y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<f32>);
+
+    fn rms_norm(&self, y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon: f32);
+
+    // C = beta * C + alpha * A @ B^T
+    fn matmul_transb(&self, c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<f32>, alpha: f32);
+
+    // The same with per-row int8 weights, A is quantized on the fly so the inner loop stays in integers
+    fn matmul_transb_int8(&self, c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Int8Tensor, alpha: f32);
+
+    // The same with block-quantized weights (Q8_0, Q4_0, Q4_K), dequantized on the fly
+    fn matmul_transb_quant<B: QuantBlock>(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &QuantTensor<B>,
+        alpha: f32,
+    );
+
+    fn rope_partial(&self, y: &mut Tensor<f32>, start_pos: usize, theta: f32, scaling: RopeScaling, rotary_dim: usize);
+
+    fn softmax(&self, y: &mut Tensor<f32>, masks: &[&Tensor<f32>]);
+
+    // softmax(Q @ K^T / sqrt(d)) @ V with causal (and optional sliding-window) masking
+    fn flash_attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        k: &Tensor<f32>,
+        v: &Tensor<f32>,
+        window: Option<usize>,
+        softcap: Option<f32>,
+    );
+
+    // y = act(x) * y, SwiGLU with Activation::Silu
+    fn gated_activation(&self, y: &mut Tensor<f32>, x: &Tensor<f32>, act: Activation);
+
+    fn softcap(&self, y: &mut Tensor<f32>, cap: f32);
+}
+
+// The operators.rs kernels (SIMD and, with the `parallel` feature, multi-threaded), the default backend
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cpu;
+
+impl Ops for Cpu {
+    fn gather(&self, y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<f32>) {
+        OP::gather(y, indices, table)
+    }
+
+    fn rms_norm(&self, y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon: f32) {
+        OP::rms_norm(y, x, w, epsilon)
+    }
+
+    fn matmul_transb(&self, c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<f32>, alpha: f32) {
+        OP::matmul_transb(c, beta, a, b, alpha)
+    }
+
+    fn matmul_transb_int8(&self, c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Int8Tensor, alpha: f32) {
+        OP::matmul_transb_int8(c, beta, a, b, alpha)
+    }
+
+    fn matmul_transb_quant<B: QuantBlock>(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &QuantTensor<B>,
+        alpha: f32,
+    ) {
+        OP::matmul_transb_quant(c, beta, a, b, alpha)
+    }
+
+    fn rope_partial(&self, y: &mut Tensor<f32>, start_pos: usize, theta: f32, scaling: RopeScaling, rotary_dim: usize) {
+        OP::rope_partial(y, start_pos, theta, scaling, rotary_dim)
+    }
+
+    fn softmax(&self, y: &mut Tensor<f32>, masks: &[&Tensor<f32>]) {
+        OP::softmax(y, masks)
+    }
+
+    fn flash_attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        k: &Tensor<f32>,
+        v: &Tensor<f32>,
+        window: Option<usize>,
+        softcap: Option<f32>,
+    ) {
+        OP::flash_attention(out, q, k, v, window, softcap)
+    }
+
+    fn gated_activation(&self, y: &mut Tensor<f32>, x: &Tensor<f32>, act: Activation) {
+        OP::gated_activation(y, x, act)
+    }
+
+    fn softcap(&self, y: &mut Tensor<f32>, cap: f32) {
+        OP::softcap(y, cap)
+    }
+}
+
+// Storage of the projection matrices (LLamaParams<f32, W>), each kind multiplied by the Ops kernel that
+// reads it directly, so Llama<f32, O, W> runs quantized weights without dequantizing them
+pub trait Weight: Send + Sync {
+    // C = beta * C + alpha * A @ self^T
+    fn matmul_transb(&self, ops: &impl Ops, c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, alpha: f32);
+
+    // Bytes held, see LLamaParams::nbytes
+    fn nbytes(&self) -> usize;
+
+    // The matrix itself when stored as f32, so a tied lm_head is counted once
+    fn as_f32(&self) -> Option<&Tensor<f32>> {
+        None
+    }
+}
+
+impl Weight for Tensor<f32> {
+    fn matmul_transb(&self, ops: &impl Ops, c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, alpha: f32) {
+        ops.matmul_transb(c, beta, a, self, alpha)
+    }
+
+    fn nbytes(&self) -> usize {
+        self.size() * std::mem::size_of::<f32>()
+    }
+
+    fn as_f32(&self) -> Option<&Tensor<f32>> {
+        Some(self)
+    }
+}
+
+impl Weight for Int8Tensor {
+    fn matmul_transb(&self, ops: &impl Ops, c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, alpha: f32) {
+        ops.matmul_transb_int8(c, beta, a, self, alpha)
+    }
+
+    fn nbytes(&self) -> usize {
+        Int8Tensor::nbytes(self)
+    }
+}
+
+impl<B: QuantBlock + Send + Sync> Weight for QuantTensor<B> {
+    fn matmul_transb(&self, ops: &impl Ops, c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, alpha: f32) {
+        ops.matmul_transb_quant(c, beta, a, self, alpha)
+    }
+
+    fn nbytes(&self) -> usize {
+        std::mem::size_of_val(self.blocks())
+    }
+}
+
+#[test]
+fn test_custom_backend() {
+    use crate::config::LlamaConfigJson;
+    use crate::model::Llama;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // counts matmuls and runs everything on the CPU kernels
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+    impl Ops for Counting {
+        fn gather(&self, y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<f32>) {
+            Cpu.gather(y, indices, table)
+        }
+        fn rms_norm(&self, y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon: f32) {
+            Cpu.rms_norm(y, x, w, epsilon)
+        }
+        fn matmul_transb(&self, c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<f32>, alpha: f32) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Cpu.matmul_transb(c, beta, a, b, alpha)
+        }
+        fn matmul_transb_int8(&self, c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Int8Tensor, alpha: f32) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Cpu.matmul_transb_int8(c, beta, a, b, alpha)
+        }
+        fn matmul_transb_quant<B: QuantBlock>(
+            &self,
+            c: &mut Tensor<f32>,
+            beta: f32,
+            a: &Tensor<f32>,
+            b: &QuantTensor<B>,
+            alpha: f32,
+        ) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Cpu.matmul_transb_quant(c, beta, a, b, alpha)
+        }
+        fn rope_partial(&self, y: &mut Tensor<f32>, start_pos: usize, theta: f32, scaling: RopeScaling, rotary_dim: usize) {
+            Cpu.rope_partial(y, start_pos, theta, scaling, rotary_dim)
+        }
+        fn softmax(&self, y: &mut Tensor<f32>, masks: &[&Tensor<f32>]) {
+            Cpu.softmax(y, masks)
+        }
+        fn flash_attention(
+            &self,
+            out: &mut Tensor<f32>,
+            q: &Tensor<f32>,
+            k: &Tensor<f32>,
+            v: &Tensor<f32>,
+            window: Option<usize>,
+            softcap: Option<f32>,
+        ) {
+            Cpu.flash_attention(out, q, k, v, window, softcap)
+        }
+        fn gated_activation(&self, y: &mut Tensor<f32>, x: &Tensor<f32>, act: Activation) {
+            Cpu.gated_activation(y, x, act)
+        }
+        fn softcap(&self, y: &mut Tensor<f32>, cap: f32) {
+            Cpu.softcap(y, cap)
+        }
+    }
+
+    let config = LlamaConfigJson::builder().build().unwrap();
+    let prompt = Tensor::new(vec![1, 2, 3], &vec![3]);
+    let cpu = Llama::random(&config, 5);
+    let expected = cpu.forward(&prompt, &mut cpu.new_cache());
+    let counting = Llama::random(&config, 5).with_ops(Counting::default());
+    let logits = counting.forward(&prompt, &mut counting.new_cache());
+    assert_eq!(logits.data(), expected.data());
+    // q, k, v, o, gate, up and down per layer, then the lm head
+    assert_eq!(counting.ops().0.load(Ordering::Relaxed), config.num_hidden_layers * 7 + 1);
+}
@@ -1,22 +1,488 @@
+use crate::operators::{Activation, RopeScaling};
 use serde;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+// Fields beyond the ones below (attention_bias, transformers_version, ...) are ignored
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
-pub(crate) struct LlamaConfigJson {
+pub struct LlamaConfigJson {
+    // e.g. ["LlamaForCausalLM"], empty for configs that predate the field
+    #[serde(default)]
+    pub architectures: Vec<String>,
+    #[serde(default)]
+    pub model_type: Option<String>,
+    #[serde(default = "default_bos_token_id")]
     pub bos_token_id: u32,
-    pub eos_token_id: u32,
+    // a single id or, for chat models such as Llama-3, a list of them
+    #[serde(default = "default_eos_token_id")]
+    pub eos_token_id: TokenIds,
     pub hidden_size: usize,
     pub intermediate_size: usize,
+    #[serde(default = "default_max_position_embeddings")]
     pub max_position_embeddings: usize,
     pub num_attention_heads: usize,
     pub num_hidden_layers: usize,
+    // defaults to num_attention_heads (plain multi-head attention), see from_value
     pub num_key_value_heads: usize,
     pub vocab_size: usize,
     #[serde(default = "default_rms_norm_eps")]
     pub rms_norm_eps: f32,
     #[serde(default = "default_rope_theta")]
     pub rope_theta: f32,
+    #[serde(default = "default_torch_dtype")]
     pub torch_dtype: String,
     #[serde(default = "default_tie_word_embeddings")]
     pub tie_word_embeddings: bool,
+    #[serde(default)]
+    pub quantization_config: Option<QuantizationConfig>,
+    #[serde(default)]
+    pub rope_scaling: Option<RopeScalingConfig>,
+    // Mistral-style local attention span, None (or null) attends to the whole context
+    #[serde(default)]
+    pub sliding_window: Option<usize>,
+    // per-head width when it is not hidden_size / num_attention_heads (Gemma, some Qwen variants)
+    #[serde(default)]
+    pub head_dim: Option<usize>,
+    // fraction of every head that RoPE rotates (Phi uses 0.4 or 0.5), the rest carries no position
+    #[serde(default = "default_partial_rotary_factor")]
+    pub partial_rotary_factor: f32,
+    // activation of the gated FFN: "silu" for Llama/Mistral, "gelu" / "gelu_new" / "gelu_pytorch_tanh"
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: String,
+    // Gemma's name for it, which takes precedence over hidden_act when present
+    #[serde(default)]
+    pub hidden_activation: Option<String>,
+    // tanh caps as Gemma-2 configs write them, score = cap * tanh(score / cap); None leaves scores and
+    // logits unbounded. Honoured for any architecture this crate accepts
+    #[serde(default)]
+    pub attn_logit_softcapping: Option<f32>,
+    #[serde(default)]
+    pub final_logit_softcapping: Option<f32>,
+}
+
+// `rope_scaling` of long-context fine-tunes, e.g. {"type": "linear", "factor": 4.0}
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct RopeScalingConfig {
+    // newer transformers versions write `rope_type` instead of `type`
+    #[serde(rename = "type", alias = "rope_type")]
+    pub scaling_type: String,
+    // required by every type but "default"
+    #[serde(default)]
+    pub factor: Option<f32>,
+    #[serde(default)]
+    pub original_max_position_embeddings: Option<usize>,
+    // YaRN only, the reference defaults apply when absent
+    #[serde(default)]
+    pub beta_fast: Option<f32>,
+    #[serde(default)]
+    pub beta_slow: Option<f32>,
+    #[serde(default)]
+    pub attention_factor: Option<f32>,
+}
+
+// Builds configs for small in-memory models, e.g. for tests and benchmarks. Starts from a
+// 2-layer, 64-wide Llama with 4 heads and a 256 token vocabulary
+pub struct LlamaConfigBuilder {
+    config: LlamaConfigJson,
+}
+
+impl LlamaConfigBuilder {
+    pub fn new() -> Self {
+        let config = LlamaConfigJson::from_value(serde_json::json!({
+            "hidden_size": 64, "intermediate_size": 192, "max_position_embeddings": 256,
+            "num_attention_heads": 4, "num_hidden_layers": 2, "vocab_size": 256
+        }))
+        .unwrap();
+        LlamaConfigBuilder { config }
+    }
+
+    pub fn hidden_size(mut self, hidden_size: usize) -> Self {
+        self.config.hidden_size = hidden_size;
+        self
+    }
+
+    pub fn intermediate_size(mut self, intermediate_size: usize) -> Self {
+        self.config.intermediate_size = intermediate_size;
+        self
+    }
+
+    pub fn num_hidden_layers(mut self, num_hidden_layers: usize) -> Self {
+        self.config.num_hidden_layers = num_hidden_layers;
+        self
+    }
+
+    // query heads and key/value heads, equal for plain multi-head attention
+    pub fn heads(mut self, num_attention_heads: usize, num_key_value_heads: usize) -> Self {
+        self.config.num_attention_heads = num_attention_heads;
+        self.config.num_key_value_heads = num_key_value_heads;
+        self
+    }
+
+    pub fn head_dim(mut self, head_dim: usize) -> Self {
+        self.config.head_dim = Some(head_dim);
+        self
+    }
+
+    pub fn vocab_size(mut self, vocab_size: usize) -> Self {
+        self.config.vocab_size = vocab_size;
+        self
+    }
+
+    pub fn max_position_embeddings(mut self, max_position_embeddings: usize) -> Self {
+        self.config.max_position_embeddings = max_position_embeddings;
+        self
+    }
+
+    pub fn tie_word_embeddings(mut self, tie_word_embeddings: bool) -> Self {
+        self.config.tie_word_embeddings = tie_word_embeddings;
+        self
+    }
+
+    pub fn rope_theta(mut self, rope_theta: f32) -> Self {
+        self.config.rope_theta = rope_theta;
+        self
+    }
+
+    pub fn hidden_act(mut self, hidden_act: &str) -> Self {
+        self.config.hidden_act = hidden_act.to_string();
+        self
+    }
+
+    pub fn sliding_window(mut self, sliding_window: usize) -> Self {
+        self.config.sliding_window = Some(sliding_window);
+        self
+    }
+
+    // The finished config, checked with LlamaConfigJson::validate
+    pub fn build(self) -> Result<LlamaConfigJson, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+impl Default for LlamaConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Model families whose checkpoints this crate knows how to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Llama,
+    // Llama weights plus sliding-window attention
+    Mistral,
+    // Llama layout with embeddings scaled by sqrt(hidden_size) and RMSNorm weights stored as w - 1
+    Gemma,
+}
+
+impl Architecture {
+    // Hugging Face class name ("LlamaForCausalLM") or model_type ("llama")
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "LlamaForCausalLM" | "llama" => Some(Architecture::Llama),
+            "MistralForCausalLM" | "mistral" => Some(Architecture::Mistral),
+            "GemmaForCausalLM" | "gemma" => Some(Architecture::Gemma),
+            _ => None,
+        }
+    }
+}
+
+impl LlamaConfigJson {
+    pub fn builder() -> LlamaConfigBuilder {
+        LlamaConfigBuilder::new()
+    }
+
+    // Parse a config.json, filling in defaults serde can't express on its own
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let value: serde_json::Value = serde_json::from_reader(File::open(path)?)?;
+        Ok(Self::from_value(value)?)
+    }
+
+    // num_key_value_heads falls back to num_attention_heads, like transformers does, and
+    // tie_word_embeddings to true for Gemma, whose GemmaConfig defaults it that way
+    pub fn from_value(mut value: serde_json::Value) -> serde_json::Result<Self> {
+        if let Some(fields) = value.as_object_mut() {
+            if fields.get("num_key_value_heads").is_none_or(|v| v.is_null()) {
+                let heads = fields.get("num_attention_heads").cloned().unwrap_or_default();
+                fields.insert("num_key_value_heads".to_string(), heads);
+            }
+            let names = [fields.get("architectures").and_then(|a| a.get(0)), fields.get("model_type")];
+            let gemma = names.into_iter().flatten().find_map(|name| name.as_str()).and_then(Architecture::from_name);
+            if gemma == Some(Architecture::Gemma) && !fields.contains_key("tie_word_embeddings") {
+                fields.insert("tie_word_embeddings".to_string(), true.into());
+            }
+        }
+        serde_json::from_value(value)
+    }
+
+    // Width of one attention head; q/k/v projections are num_heads * head_dim rows wide
+    pub fn head_dim(&self) -> usize {
+        self.head_dim.unwrap_or(self.hidden_size / self.num_attention_heads)
+    }
+
+    // Number of leading dimensions per head that RoPE rotates
+    pub fn rotary_dim(&self) -> usize {
+        (self.head_dim() as f32 * self.partial_rotary_factor) as usize
+    }
+
+    // The first entry of `architectures`, falling back to `model_type` and then to Llama for bare configs.
+    // Anything else (Qwen2, Gemma-2, ...) is an error rather than being run with the wrong layer layout
+    pub fn architecture(&self) -> Result<Architecture, ConfigError> {
+        let name = match (self.architectures.first(), &self.model_type) {
+            (Some(name), _) | (None, Some(name)) => name,
+            (None, None) => return Ok(Architecture::Llama),
+        };
+        Architecture::from_name(name).ok_or_else(|| ConfigError::UnsupportedArchitecture { name: name.clone() })
+    }
+
+    // FFN activation named by hidden_activation or else hidden_act. Gemma configs without
+    // hidden_activation still say "gelu" in hidden_act, but transformers runs them with the tanh approximation
+    pub fn activation(&self) -> Result<Activation, ConfigError> {
+        let name = match (&self.hidden_activation, self.architecture()) {
+            (Some(name), _) => name,
+            (None, Ok(Architecture::Gemma)) => "gelu_pytorch_tanh",
+            (None, _) => &self.hidden_act,
+        };
+        Activation::from_name(name).ok_or_else(|| ConfigError::UnsupportedActivation { name: name.to_string() })
+    }
+
+    // Check the invariants the model relies on, so a malformed config.json fails here instead of
+    // as an out-of-bounds panic in the middle of a forward pass
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.architecture()?;
+        self.activation()?;
+        self.rope_scaling()?;
+        let sizes = [
+            ("hidden_size", self.hidden_size),
+            ("intermediate_size", self.intermediate_size),
+            ("num_attention_heads", self.num_attention_heads),
+            ("num_hidden_layers", self.num_hidden_layers),
+            ("num_key_value_heads", self.num_key_value_heads),
+            ("vocab_size", self.vocab_size),
+            ("max_position_embeddings", self.max_position_embeddings),
+            ("sliding_window", self.sliding_window.unwrap_or(1)),
+            ("head_dim", self.head_dim.unwrap_or(1)),
+        ];
+        if let Some((field, _)) = sizes.iter().find(|(_, size)| *size == 0) {
+            return Err(ConfigError::Zero { field });
+        }
+        let (hidden_size, num_attention_heads) = (self.hidden_size, self.num_attention_heads);
+        if self.head_dim.is_none() && hidden_size % num_attention_heads != 0 {
+            return Err(ConfigError::HeadsDontDivideHidden { hidden_size, num_attention_heads });
+        }
+        let num_key_value_heads = self.num_key_value_heads;
+        if num_attention_heads % num_key_value_heads != 0 {
+            return Err(ConfigError::KvHeadsDontDivideHeads { num_attention_heads, num_key_value_heads });
+        }
+        let head_dim = self.head_dim();
+        if !head_dim.is_multiple_of(2) {
+            return Err(ConfigError::OddHeadDim { head_dim });
+        }
+        let rotary_dim = self.rotary_dim();
+        if rotary_dim == 0 || !rotary_dim.is_multiple_of(2) || rotary_dim > head_dim {
+            return Err(ConfigError::InvalidRotaryDim { rotary_dim, head_dim });
+        }
+        let eos = self.eos_token_id.to_vec().into_iter().map(|id| ("eos_token_id", id));
+        for (field, id) in [("bos_token_id", self.bos_token_id)].into_iter().chain(eos) {
+            if id as usize >= self.vocab_size {
+                return Err(ConfigError::TokenOutOfVocab { field, id, vocab_size: self.vocab_size });
+            }
+        }
+        Ok(())
+    }
+
+    // RoPE scaling for the rope operator, RopeScaling::None when the config has no `rope_scaling`. Types
+    // the operator doesn't implement (llama3, longrope, ...) are an error rather than running unscaled
+    pub fn rope_scaling(&self) -> Result<RopeScaling, ConfigError> {
+        let Some(scaling) = &self.rope_scaling else {
+            return Ok(RopeScaling::None);
+        };
+        let scaling_type = scaling.scaling_type.as_str();
+        let factor = || {
+            let scaling_type = scaling_type.to_string();
+            scaling.factor.ok_or(ConfigError::RopeScalingWithoutFactor { scaling_type })
+        };
+        let original_max_position_embeddings =
+            scaling.original_max_position_embeddings.unwrap_or(self.max_position_embeddings);
+        Ok(match scaling_type {
+            "default" => RopeScaling::None,
+            "linear" => RopeScaling::Linear { factor: factor()? },
+            "dynamic" => RopeScaling::DynamicNtk { factor: factor()?, original_max_position_embeddings },
+            "ntk" => RopeScaling::Ntk { factor: factor()? },
+            "yarn" => {
+                let factor = factor()?;
+                let RopeScaling::Yarn { beta_fast, beta_slow, attention_factor, .. } =
+                    RopeScaling::yarn(factor, original_max_position_embeddings)
+                else {
+                    unreachable!()
+                };
+                RopeScaling::Yarn {
+                    factor,
+                    original_max_position_embeddings,
+                    beta_fast: scaling.beta_fast.unwrap_or(beta_fast),
+                    beta_slow: scaling.beta_slow.unwrap_or(beta_slow),
+                    attention_factor: scaling.attention_factor.unwrap_or(attention_factor),
+                }
+            }
+            name => return Err(ConfigError::UnsupportedRopeScaling { name: name.to_string() }),
+        })
+    }
+}
+
+// An inconsistent config.json, see LlamaConfigJson::validate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    Zero { field: &'static str },
+    HeadsDontDivideHidden { hidden_size: usize, num_attention_heads: usize },
+    KvHeadsDontDivideHeads { num_attention_heads: usize, num_key_value_heads: usize },
+    // RoPE rotates pairs of dimensions
+    OddHeadDim { head_dim: usize },
+    // partial_rotary_factor must select an even, non-empty part of the head
+    InvalidRotaryDim { rotary_dim: usize, head_dim: usize },
+    TokenOutOfVocab { field: &'static str, id: u32, vocab_size: usize },
+    UnsupportedArchitecture { name: String },
+    UnsupportedActivation { name: String },
+    UnsupportedRopeScaling { name: String },
+    RopeScalingWithoutFactor { scaling_type: String },
+    // config.json is missing or not valid JSON
+    Io { path: PathBuf, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Zero { field } => write!(f, "config field {field} must not be 0"),
+            ConfigError::HeadsDontDivideHidden { hidden_size, num_attention_heads } => write!(
+                f,
+                "hidden_size {hidden_size} is not divisible by num_attention_heads {num_attention_heads}"
+            ),
+            ConfigError::KvHeadsDontDivideHeads { num_attention_heads, num_key_value_heads } => write!(
+                f,
+                "num_key_value_heads {num_key_value_heads} does not divide num_attention_heads {num_attention_heads}"
+            ),
+            ConfigError::OddHeadDim { head_dim } => write!(f, "head dimension {head_dim} must be even for RoPE"),
+            ConfigError::InvalidRotaryDim { rotary_dim, head_dim } => write!(
+                f,
+                "partial_rotary_factor gives {rotary_dim} rotary dims for heads of {head_dim}, expected an even count"
+            ),
+            ConfigError::TokenOutOfVocab { field, id, vocab_size } => {
+                write!(f, "{field} {id} is out of range for vocab_size {vocab_size}")
+            }
+            ConfigError::UnsupportedArchitecture { name } => {
+                let expected = "LlamaForCausalLM, MistralForCausalLM or GemmaForCausalLM";
+                write!(f, "unsupported architecture {name}, expected {expected}")
+            }
+            ConfigError::UnsupportedActivation { name } => {
+                write!(f, "unsupported hidden_act {name}, expected silu or one of the gelu variants")
+            }
+            ConfigError::UnsupportedRopeScaling { name } => {
+                write!(f, "unsupported rope_scaling type {name}, expected default, linear, dynamic, ntk or yarn")
+            }
+            ConfigError::RopeScalingWithoutFactor { scaling_type } => {
+                write!(f, "rope_scaling type {scaling_type} needs a factor")
+            }
+            ConfigError::Io { path, message } => write!(f, "cannot read {}: {message}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// `quantization_config` of GPTQ/AWQ exports
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct QuantizationConfig {
+    pub quant_method: String, // "gptq" or "awq"
+    pub bits: usize,
+    pub group_size: i64, // -1 quantizes each column as a single group
+    // "gptq" stores zero points minus one, "gptq_v2" stores them as is
+    #[serde(default = "default_checkpoint_format")]
+    pub checkpoint_format: String,
+}
+
+// generation_config.json, the sampling defaults a model was released with. Every field is optional,
+// a missing file behaves like an empty one
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct GenerationConfigJson {
+    #[serde(default)]
+    pub bos_token_id: Option<u32>,
+    // chat fine-tunes often list several, e.g. [128001, 128008, 128009] for Llama-3
+    #[serde(default)]
+    pub eos_token_id: Option<TokenIds>,
+    #[serde(default)]
+    pub do_sample: Option<bool>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub top_k: Option<u32>,
+    #[serde(default)]
+    pub max_new_tokens: Option<usize>,
+}
+
+// A token id field that may hold a single id or a list of them
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum TokenIds {
+    One(u32),
+    Many(Vec<u32>),
+}
+
+impl TokenIds {
+    // The first (or only) id
+    pub fn first(&self) -> u32 {
+        match self {
+            TokenIds::One(id) => *id,
+            TokenIds::Many(ids) => ids[0],
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u32> {
+        match self {
+            TokenIds::One(id) => vec![*id],
+            TokenIds::Many(ids) => ids.clone(),
+        }
+    }
+}
+
+impl GenerationConfigJson {
+    // `model_dir/generation_config.json`, or the empty default when the model doesn't ship one
+    pub fn from_dir(model_dir: impl AsRef<Path>) -> Self {
+        match File::open(model_dir.as_ref().join("generation_config.json")) {
+            Ok(file) => serde_json::from_reader(file).unwrap(),
+            Err(_) => Self::default(),
+        }
+    }
+
+    // Sampling parameters (top_p, top_k, temperature) for random_sample(); fields the file leaves out
+    // keep the given defaults and do_sample = false means greedy decoding
+    pub fn sampling(&self, top_p: f32, top_k: u32, temperature: f32) -> (f32, u32, f32) {
+        if self.do_sample == Some(false) {
+            return (1., 1, 0.);
+        }
+        (self.top_p.unwrap_or(top_p), self.top_k.unwrap_or(top_k), self.temperature.unwrap_or(temperature))
+    }
+}
+
+#[inline(always)]
+const fn default_bos_token_id() -> u32 {
+    1
+}
+
+#[inline(always)]
+const fn default_eos_token_id() -> TokenIds {
+    TokenIds::One(2)
+}
+
+#[inline(always)]
+const fn default_max_position_embeddings() -> usize {
+    2048
+}
+
+fn default_torch_dtype() -> String {
+    "float32".to_string()
 }
 
 #[inline(always)]
@@ -29,7 +495,143 @@ const fn default_rope_theta() -> f32 {
     1e4
 }
 
+#[inline(always)]
+const fn default_partial_rotary_factor() -> f32 {
+    1.
+}
+
+fn default_hidden_act() -> String {
+    "silu".to_string()
+}
+
 #[inline(always)]
 const fn default_tie_word_embeddings() -> bool {
     false
 }
+
+fn default_checkpoint_format() -> String {
+    "gptq".to_string()
+}
+
+#[test]
+fn test_validate_config() {
+    use crate::params::tiny_config;
+    assert_eq!(tiny_config(2, false).validate(), Ok(()));
+    let broken = |edit: fn(&mut LlamaConfigJson)| {
+        let mut config = tiny_config(2, false);
+        edit(&mut config);
+        config.validate().unwrap_err()
+    };
+    assert_eq!(broken(|c| c.vocab_size = 0), ConfigError::Zero { field: "vocab_size" });
+    assert_eq!(
+        broken(|c| c.num_attention_heads = 3),
+        ConfigError::HeadsDontDivideHidden { hidden_size: 8, num_attention_heads: 3 }
+    );
+    assert_eq!(
+        broken(|c| c.num_key_value_heads = 3),
+        ConfigError::KvHeadsDontDivideHeads { num_attention_heads: 2, num_key_value_heads: 3 }
+    );
+    assert_eq!(broken(|c| c.hidden_size = 6), ConfigError::OddHeadDim { head_dim: 3 });
+    // an explicit head_dim need not divide hidden_size
+    let mut config = tiny_config(2, false);
+    config.num_attention_heads = 3;
+    config.head_dim = Some(4);
+    assert_eq!(config.validate(), Ok(()));
+    assert_eq!(config.head_dim(), 4);
+    config.partial_rotary_factor = 0.5;
+    assert_eq!(config.rotary_dim(), 2);
+    assert_eq!(config.validate(), Ok(()));
+    config.partial_rotary_factor = 0.25;
+    assert_eq!(config.validate(), Err(ConfigError::InvalidRotaryDim { rotary_dim: 1, head_dim: 4 }));
+    let err = broken(|c| c.eos_token_id = TokenIds::Many(vec![2, 10]));
+    assert_eq!(err.to_string(), "eos_token_id 10 is out of range for vocab_size 10");
+}
+
+#[test]
+fn test_architecture() {
+    use crate::params::tiny_config;
+    let mut config = tiny_config(1, false);
+    assert_eq!(config.architecture(), Ok(Architecture::Llama));
+    config.model_type = Some("mistral".to_string());
+    assert_eq!(config.architecture(), Ok(Architecture::Mistral));
+    config.architectures = vec!["Qwen2ForCausalLM".to_string()];
+    let err = ConfigError::UnsupportedArchitecture { name: "Qwen2ForCausalLM".to_string() };
+    assert_eq!(config.architecture(), Err(err.clone()));
+    assert_eq!(config.validate(), Err(err));
+
+    let mut config = tiny_config(1, false);
+    config.hidden_act = "gelu_new".to_string();
+    assert_eq!(config.activation(), Ok(Activation::GeluTanh));
+    config.hidden_act = "relu".to_string();
+    assert_eq!(config.validate(), Err(ConfigError::UnsupportedActivation { name: "relu".to_string() }));
+
+    // Gemma ties its embeddings unless told otherwise and runs the tanh GELU whatever hidden_act says
+    let gemma = |extra: serde_json::Value| {
+        let mut value = serde_json::json!({
+            "architectures": ["GemmaForCausalLM"], "hidden_size": 8, "intermediate_size": 16, "head_dim": 4,
+            "num_attention_heads": 2, "num_hidden_layers": 1, "vocab_size": 10, "hidden_act": "gelu"
+        });
+        value.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        LlamaConfigJson::from_value(value).unwrap()
+    };
+    let config = gemma(serde_json::json!({}));
+    assert_eq!((config.architecture(), config.activation()), (Ok(Architecture::Gemma), Ok(Activation::GeluTanh)));
+    assert!(config.tie_word_embeddings);
+    let config = gemma(serde_json::json!({"hidden_activation": "gelu", "tie_word_embeddings": false}));
+    assert_eq!(config.activation(), Ok(Activation::Gelu));
+    assert!(!config.tie_word_embeddings);
+    // Gemma-2 adds norms around every block and is still refused
+    let config = gemma(serde_json::json!({"architectures": ["Gemma2ForCausalLM"]}));
+    assert!(config.validate().unwrap_err().to_string().starts_with("unsupported architecture Gemma2ForCausalLM"));
+}
+
+#[test]
+fn test_config_defaults() {
+    // a sparse config with an eos list and fields this crate doesn't know
+    let config = LlamaConfigJson::from_value(serde_json::json!({
+        "hidden_size": 8, "intermediate_size": 16, "num_attention_heads": 2, "num_hidden_layers": 1,
+        "vocab_size": 10, "eos_token_id": [2, 9], "attention_bias": false, "rope_scaling": null,
+        "transformers_version": "4.44.0"
+    }))
+    .unwrap();
+    assert_eq!(config.num_key_value_heads, 2);
+    assert_eq!(config.eos_token_id, TokenIds::Many(vec![2, 9]));
+    assert_eq!((config.bos_token_id, config.torch_dtype.as_str()), (1, "float32"));
+    assert_eq!(config.max_position_embeddings, 2048);
+    assert!(!config.tie_word_embeddings && config.rope_scaling.is_none());
+    assert_eq!(config.activation(), Ok(Activation::Silu));
+    assert_eq!(config.validate(), Ok(()));
+}
+
+#[test]
+fn test_rope_scaling_config() {
+    let with_scaling = |scaling: serde_json::Value| {
+        LlamaConfigJson::from_value(serde_json::json!({
+            "hidden_size": 8, "intermediate_size": 16, "num_attention_heads": 2, "num_hidden_layers": 1,
+            "vocab_size": 10, "max_position_embeddings": 4096, "rope_scaling": scaling
+        }))
+        .unwrap()
+    };
+    // "default" needs no factor, newer configs spell the type rope_type
+    assert_eq!(with_scaling(serde_json::json!({"rope_type": "default"})).rope_scaling(), Ok(RopeScaling::None));
+    let linear = with_scaling(serde_json::json!({"type": "linear", "factor": 4.0}));
+    assert_eq!(linear.rope_scaling(), Ok(RopeScaling::Linear { factor: 4. }));
+    let dynamic = with_scaling(serde_json::json!({"rope_type": "dynamic", "factor": 2.0}));
+    let expected = RopeScaling::DynamicNtk { factor: 2., original_max_position_embeddings: 4096 };
+    assert_eq!(dynamic.rope_scaling(), Ok(expected));
+    let yarn = with_scaling(serde_json::json!({"rope_type": "yarn", "factor": 4.0, "beta_fast": 16.0}));
+    let Ok(RopeScaling::Yarn { beta_fast, beta_slow, .. }) = yarn.rope_scaling() else { panic!() };
+    assert_eq!((beta_fast, beta_slow), (16., 1.));
+
+    // Llama-3.1 and Phi-3 scaling are reported, not run as something else
+    let llama3 = with_scaling(serde_json::json!({
+        "rope_type": "llama3", "factor": 8.0, "low_freq_factor": 1.0, "high_freq_factor": 4.0,
+        "original_max_position_embeddings": 8192
+    }));
+    let err = ConfigError::UnsupportedRopeScaling { name: "llama3".to_string() };
+    assert_eq!(llama3.validate(), Err(err));
+    let longrope = with_scaling(serde_json::json!({"type": "longrope", "short_factor": [1.0], "long_factor": [2.0]}));
+    assert_eq!(longrope.validate(), Err(ConfigError::UnsupportedRopeScaling { name: "longrope".to_string() }));
+    let err = with_scaling(serde_json::json!({"type": "linear"})).validate().unwrap_err();
+    assert_eq!(err.to_string(), "rope_scaling type linear needs a factor");
+}
@@ -0,0 +1,406 @@
+// How to generate (GenerationConfig), when to stop and what was produced, see Llama::generate()
+use crate::sampling::{
+    log_softmax, top_k, FrequencyPenalty, LogitBias, MinLength, MinP, RepetitionPenalty, Sampler, Temperature, TopK,
+    TopP,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+// Token ids to text, typically a tokenizer's decode
+pub type Decode = Box<dyn Fn(&[u32]) -> String>;
+
+// Ends generation at extra token ids (on top of the model's eos ids) or once the decoded output contains
+// one of the stop strings. The model only sees token ids, so stop strings come with a decode function
+#[derive(Default)]
+pub struct StopCriteria {
+    pub token_ids: Vec<u32>,
+    pub strings: Vec<String>,
+    decode: Option<Decode>,
+}
+
+impl StopCriteria {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn token_ids(mut self, token_ids: &[u32]) -> Self {
+        self.token_ids.extend_from_slice(token_ids);
+        self
+    }
+
+    // Stop once the output contains any of `strings`, e.g.
+    // `.strings(&["\nUser:"], move |ids| tokenizer.decode(ids, true).unwrap())`
+    pub fn strings(mut self, strings: &[&str], decode: impl Fn(&[u32]) -> String + 'static) -> Self {
+        self.strings.extend(strings.iter().map(|s| s.to_string()));
+        self.decode = Some(Box::new(decode));
+        self
+    }
+
+    // Text of `tokens` if stop strings are set
+    pub(crate) fn decode(&self, tokens: &[u32]) -> Option<String> {
+        self.decode.as_ref().filter(|_| !self.strings.is_empty()).map(|decode| decode(tokens))
+    }
+
+    // Byte offset and stop string of the earliest match in `text`
+    pub(crate) fn find(&self, text: &str) -> Option<(usize, &str)> {
+        let matches = self.strings.iter().filter_map(|s| Some((text.find(s.as_str())?, s.as_str())));
+        matches.min_by_key(|&(pos, _)| pos)
+    }
+}
+
+// Everything Llama::generate() needs besides the prompt. Build one with GenerationConfig::builder(), the
+// defaults sample at temperature 1 from the whole vocabulary for up to 256 new tokens
+pub struct GenerationConfig {
+    pub max_new_tokens: usize,
+    pub min_new_tokens: usize,          // eos and stop token ids are masked until this many were generated
+    pub temperature: f32,               // 0 is greedy decoding
+    pub top_k: usize,                   // 0 keeps every token
+    pub top_p: f32,                     // 1 keeps every token
+    pub min_p: f32,                     // 0 keeps every token
+    pub repetition_penalty: f32,        // 1 is off, see RepetitionPenalty
+    pub frequency_penalty: f32,         // 0 is off, see FrequencyPenalty
+    pub presence_penalty: f32,          // 0 is off
+    pub penalty_window: Option<usize>,  // penalize only the last this many context tokens
+    pub logit_bias: HashMap<u32, f32>,  // added to these tokens' logits, f32::NEG_INFINITY bans one
+    pub stop: StopCriteria,
+    pub seed: Option<u64>,              // reproducible sampling when set
+    pub logprobs: Option<usize>,        // report per-token log probabilities with this many alternatives
+    pub guidance_scale: f32,            // classifier-free guidance, 1 is off
+    pub negative_prompt: Vec<u32>,      // what guidance steers away from, empty for the last prompt token
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        GenerationConfig {
+            max_new_tokens: 256,
+            min_new_tokens: 0,
+            temperature: 1.,
+            top_k: 0,
+            top_p: 1.,
+            min_p: 0.,
+            repetition_penalty: 1.,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+            penalty_window: None,
+            logit_bias: HashMap::new(),
+            stop: StopCriteria::new(),
+            seed: None,
+            logprobs: None,
+            guidance_scale: 1.,
+            negative_prompt: Vec::new(),
+        }
+    }
+}
+
+impl GenerationConfig {
+    pub fn builder() -> GenerationConfigBuilder {
+        GenerationConfigBuilder { config: GenerationConfig::default() }
+    }
+
+    // Greedy decoding of up to `max_new_tokens` tokens
+    pub fn greedy(max_new_tokens: usize) -> Self {
+        GenerationConfig { max_new_tokens, temperature: 0., ..Default::default() }
+    }
+
+    // The processor chain these settings describe for a prompt of `prompt_len` tokens: logit bias, min
+    // length, then penalties, temperature, top-k, top-p and min-p. Bias and penalties come before
+    // temperature so that greedy decoding still sees them
+    pub fn sampler(&self, prompt_len: usize, eos_token_ids: &[u32]) -> Sampler {
+        let mut sampler = Sampler::default();
+        if !self.logit_bias.is_empty() {
+            sampler = sampler.with(LogitBias(self.logit_bias.clone()));
+        }
+        if self.min_new_tokens > 0 {
+            let banned = [eos_token_ids, &self.stop.token_ids].concat();
+            sampler = sampler.with(MinLength { min_len: prompt_len + self.min_new_tokens, token_ids: banned });
+        }
+        if self.repetition_penalty != 1. {
+            let (penalty, window) = (self.repetition_penalty, self.penalty_window);
+            sampler = sampler.with(RepetitionPenalty { penalty, window });
+        }
+        if self.frequency_penalty != 0. || self.presence_penalty != 0. {
+            let penalty = FrequencyPenalty::new(self.frequency_penalty, self.presence_penalty);
+            sampler = sampler.with(FrequencyPenalty { window: self.penalty_window, ..penalty });
+        }
+        sampler = sampler.with(Temperature(self.temperature));
+        if self.temperature > 0. {
+            sampler = sampler.with(TopK(self.top_k)).with(TopP(self.top_p)).with(MinP(self.min_p));
+        }
+        match self.seed {
+            Some(seed) => sampler.with_seed(seed),
+            None => sampler,
+        }
+    }
+
+    fn validate(&self) -> Result<(), InvalidGenerationConfig> {
+        let check = |ok: bool, field: &'static str, reason: &str| match ok {
+            true => Ok(()),
+            false => Err(InvalidGenerationConfig { field, reason: reason.to_string() }),
+        };
+        check(self.temperature >= 0. && self.temperature.is_finite(), "temperature", "must be finite and >= 0")?;
+        check(self.top_p > 0. && self.top_p <= 1., "top_p", "must be in (0, 1]")?;
+        check((0. ..=1.).contains(&self.min_p), "min_p", "must be in [0, 1]")?;
+        check(self.repetition_penalty > 0., "repetition_penalty", "must be > 0")?;
+        let bias_ok = self.logit_bias.values().all(|&bias| bias < f32::INFINITY);
+        check(bias_ok, "logit_bias", "values must be finite or -inf")?;
+        check(self.guidance_scale.is_finite(), "guidance_scale", "must be finite")?;
+        check(self.min_new_tokens <= self.max_new_tokens, "min_new_tokens", "must not exceed max_new_tokens")
+    }
+}
+
+// Builds a GenerationConfig, e.g.
+// `GenerationConfig::builder().max_new_tokens(64).temperature(0.7).top_p(0.9).seed(42).build()?`
+pub struct GenerationConfigBuilder {
+    config: GenerationConfig,
+}
+
+impl GenerationConfigBuilder {
+    pub fn max_new_tokens(mut self, max_new_tokens: usize) -> Self {
+        self.config.max_new_tokens = max_new_tokens;
+        self
+    }
+
+    pub fn min_new_tokens(mut self, min_new_tokens: usize) -> Self {
+        self.config.min_new_tokens = min_new_tokens;
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.config.temperature = temperature;
+        self
+    }
+
+    pub fn top_k(mut self, top_k: usize) -> Self {
+        self.config.top_k = top_k;
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.config.top_p = top_p;
+        self
+    }
+
+    pub fn min_p(mut self, min_p: f32) -> Self {
+        self.config.min_p = min_p;
+        self
+    }
+
+    pub fn repetition_penalty(mut self, repetition_penalty: f32) -> Self {
+        self.config.repetition_penalty = repetition_penalty;
+        self
+    }
+
+    // OpenAI-style penalties, see FrequencyPenalty
+    pub fn penalties(mut self, frequency_penalty: f32, presence_penalty: f32) -> Self {
+        self.config.frequency_penalty = frequency_penalty;
+        self.config.presence_penalty = presence_penalty;
+        self
+    }
+
+    pub fn penalty_window(mut self, penalty_window: usize) -> Self {
+        self.config.penalty_window = Some(penalty_window);
+        self
+    }
+
+    // Bias added to the logits of single tokens before anything else, e.g. a large one on "Yes" and "No"
+    // to all but force one of them, or f32::NEG_INFINITY to ban a token
+    pub fn logit_bias(mut self, token: u32, bias: f32) -> Self {
+        self.config.logit_bias.insert(token, bias);
+        self
+    }
+
+    pub fn stop(mut self, stop: StopCriteria) -> Self {
+        self.config.stop = stop;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = Some(seed);
+        self
+    }
+
+    // Return the log probability of every generated token and of the `top_n` most likely tokens at its
+    // position, 0 for just the former
+    pub fn logprobs(mut self, top_n: usize) -> Self {
+        self.config.logprobs = Some(top_n);
+        self
+    }
+
+    // Classifier-free guidance: every token is drawn from the prompt's log probabilities pushed `scale`
+    // times as far away from those after `negative_prompt` (empty for just the prompt's last token),
+    // see sampling::guide()
+    pub fn guidance(mut self, scale: f32, negative_prompt: &[u32]) -> Self {
+        self.config.guidance_scale = scale;
+        self.config.negative_prompt = negative_prompt.to_vec();
+        self
+    }
+
+    // The finished config, with every setting checked for its valid range
+    pub fn build(self) -> Result<GenerationConfig, InvalidGenerationConfig> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidGenerationConfig {
+    pub field: &'static str,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidGenerationConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid generation config: {} {}", self.field, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidGenerationConfig {}
+
+// Which condition ended a generation
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    Eos(u32), // one of the model's eos ids
+    Token(u32), // a StopCriteria token id
+    String(String), // a StopCriteria string, cut from the output
+    MaxTokens, // the requested number of new tokens
+    ContextFull, // the KV cache ran out of positions
+    Cancelled, // the generate_stream() callback asked to stop
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Eos(id) => write!(f, "end of sequence token {id}"),
+            StopReason::Token(id) => write!(f, "stop token {id}"),
+            StopReason::String(s) => write!(f, "stop string {s:?}"),
+            StopReason::MaxTokens => write!(f, "maximum number of new tokens"),
+            StopReason::ContextFull => write!(f, "context length"),
+            StopReason::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+// Log probability of a generated token and the `top` most likely tokens at its position (most likely
+// first) with theirs, like OpenAI's `logprobs`. These come from the model's distribution, before
+// temperature or any other processor of the sampler reshaped it
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenLogprob {
+    pub token: u32,
+    pub logprob: f32,
+    pub top: Vec<(u32, f32)>,
+}
+
+impl TokenLogprob {
+    pub(crate) fn new(token: u32, logits: &[f32], top_n: usize) -> Self {
+        let logprobs = log_softmax(logits);
+        TokenLogprob { token, logprob: logprobs[token as usize], top: top_k(&logprobs, top_n) }
+    }
+}
+
+// New tokens (ending with the eos or stop token that fired, if any) and why generation stopped.
+// `text` is the decoded output, up to the stop string, when the criteria had stop strings, `logprobs`
+// has one entry per token when GenerationConfig::logprobs asked for them
+#[derive(Clone, Debug, PartialEq)]
+pub struct Generation {
+    pub tokens: Vec<u32>,
+    pub text: Option<String>,
+    pub logprobs: Option<Vec<TokenLogprob>>,
+    pub stop: StopReason,
+}
+
+impl Generation {
+    // The Generation of the new `tokens`, cut before the stop string if that is what ended it
+    pub(crate) fn finish(
+        mut tokens: Vec<u32>,
+        mut logprobs: Option<Vec<TokenLogprob>>,
+        stop: &StopCriteria,
+        reason: StopReason,
+    ) -> Generation {
+        let mut text = stop.decode(&tokens);
+        if let (StopReason::String(_), Some(full)) = (&reason, &mut text) {
+            // cut the stop string and the tokens that spell any part of it
+            let (pos, _) = stop.find(full).unwrap();
+            full.truncate(pos);
+            while stop.decode(&tokens).is_some_and(|t| t.len() > pos) {
+                tokens.pop();
+            }
+        }
+        if let Some(logprobs) = &mut logprobs {
+            logprobs.truncate(tokens.len());
+        }
+        Generation { tokens, text, logprobs, stop: reason }
+    }
+}
+
+#[test]
+fn test_stop_criteria() {
+    let decode = |ids: &[u32]| ids.iter().map(|&id| char::from(b'a' + id as u8)).collect();
+    let stop = StopCriteria::new().token_ids(&[9]).strings(&["cd", "b"], decode);
+    assert_eq!(stop.token_ids, vec![9]);
+    assert_eq!(stop.decode(&[0, 2, 3]).as_deref(), Some("acd"));
+    assert_eq!(stop.find("abcd"), Some((1, "b")));
+    assert_eq!(stop.find("acd"), Some((1, "cd")));
+    assert_eq!(stop.find("ace"), None);
+    assert_eq!(StopCriteria::new().decode(&[0]), None);
+    assert_eq!(StopReason::String("\n".to_string()).to_string(), "stop string \"\\n\"");
+}
+
+#[test]
+fn test_generation_config() {
+    let config = GenerationConfig::builder().max_new_tokens(8).temperature(0.5).top_k(2).seed(1).build().unwrap();
+    assert_eq!((config.max_new_tokens, config.top_k, config.top_p, config.seed), (8, 2, 1., Some(1)));
+    let err = |builder: GenerationConfigBuilder| builder.build().err().map(|e| e.to_string());
+    let top_p = err(GenerationConfig::builder().top_p(0.));
+    assert_eq!(top_p.as_deref(), Some("invalid generation config: top_p must be in (0, 1]"));
+    assert_eq!(err(GenerationConfig::builder().max_new_tokens(2).min_new_tokens(3)).unwrap(),
+        "invalid generation config: min_new_tokens must not exceed max_new_tokens");
+    assert_eq!(err(GenerationConfig::builder().temperature(0.)), None);
+    assert_eq!(err(GenerationConfig::builder().logit_bias(1, f32::NAN)).unwrap(),
+        "invalid generation config: logit_bias values must be finite or -inf");
+    assert_eq!(err(GenerationConfig::builder().logit_bias(1, f32::NEG_INFINITY)), None);
+
+    // top-k 2 of probabilities ~0.61, 0.03, 0.14, 0.22, the same draws for the same seed
+    let logits = crate::tensor::Tensor::new(vec![2., -1., 0.5, 1.], &vec![4]);
+    let draws = |config: &GenerationConfig| {
+        let mut sampler = config.sampler(0, &[]);
+        (0..50).map(|_| sampler.sample(&logits, &[])).collect::<Vec<_>>()
+    };
+    assert_eq!(draws(&config), draws(&config));
+    assert!(draws(&config).iter().all(|&t| t == 0 || t == 3));
+    assert!(draws(&GenerationConfig::greedy(8)).iter().all(|&t| t == 0));
+    // the most likely token is eos, masked until two tokens were generated after a one-token prompt
+    let config = GenerationConfig { min_new_tokens: 2, ..GenerationConfig::greedy(8) };
+    let mut sampler = config.sampler(1, &[0]);
+    assert_eq!(sampler.sample(&logits, &[5, 6]), 3);
+    assert_eq!(sampler.sample(&logits, &[5, 6, 7]), 0);
+}
+
+#[test]
+fn test_logit_bias_config() {
+    // probabilities ~0.61, 0.03, 0.14, 0.22; the bias is applied before temperature, so greedy decoding sees it
+    let logits = crate::tensor::Tensor::new(vec![2., -1., 0.5, 1.], &vec![4]);
+    let greedy = |builder: GenerationConfigBuilder| {
+        let config = builder.temperature(0.).build().unwrap();
+        config.sampler(0, &[]).sample(&logits, &[])
+    };
+    assert_eq!(greedy(GenerationConfig::builder()), 0);
+    assert_eq!(greedy(GenerationConfig::builder().logit_bias(0, f32::NEG_INFINITY)), 3);
+    assert_eq!(greedy(GenerationConfig::builder().logit_bias(1, 3.5)), 1);
+    // banning every token but two leaves only those two to sample from
+    let config = GenerationConfig::builder().logit_bias(0, f32::NEG_INFINITY).logit_bias(3, f32::NEG_INFINITY);
+    let mut sampler = config.seed(3).build().unwrap().sampler(0, &[]);
+    let draws: Vec<u32> = (0..100).map(|_| sampler.sample(&logits, &[])).collect();
+    assert!(draws.iter().all(|&t| t == 1 || t == 2) && draws.contains(&1) && draws.contains(&2));
+}
+
+#[test]
+fn test_token_logprob() {
+    let logits = [1f32.ln(), 4f32.ln(), 2f32.ln(), 1f32.ln()];
+    let logprob = TokenLogprob::new(2, &logits, 3);
+    assert!((logprob.logprob - 0.25f32.ln()).abs() < 1e-6);
+    let top: Vec<u32> = logprob.top.iter().map(|&(t, _)| t).collect();
+    assert_eq!(top, vec![1, 2, 0]);
+    assert!((logprob.top[0].1 - 0.5f32.ln()).abs() < 1e-6);
+    assert!(TokenLogprob::new(2, &logits, 0).top.is_empty());
+    assert_eq!(TokenLogprob::new(2, &logits, 9).top.len(), 4);
+}
@@ -0,0 +1,591 @@
+use crate::config::{LlamaConfigJson, TokenIds};
+use crate::names::NameMap;
+use crate::params::{expected_shapes, LLamaParams};
+use crate::quant::{BlockQ8_0, QuantBlock, QK8_0};
+use crate::tensor::Tensor;
+use half::f16;
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+// Reader for llama.cpp's GGUF container (versions 2 and 3): a metadata key/value section,
+// tensor descriptors, then the aligned tensor payloads. Only little-endian files are supported.
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: u64 = 32;
+// name length, dimension count, type and offset of a tensor descriptor with an empty name and no dimensions
+const MIN_TENSOR_INFO_BYTES: usize = 8 + 4 + 4 + 8;
+// arrays of arrays are rare in practice, deeper nesting is rejected before it can exhaust the stack
+const MAX_ARRAY_DEPTH: usize = 4;
+
+// ggml tensor types this reader understands
+pub const GGML_TYPE_F32: u32 = 0;
+pub const GGML_TYPE_F16: u32 = 1;
+pub const GGML_TYPE_Q8_0: u32 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl GgufValue {
+    // Any integer value that fits in u64
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::U8(v) => Some(v as u64),
+            GgufValue::U16(v) => Some(v as u64),
+            GgufValue::U32(v) => Some(v as u64),
+            GgufValue::U64(v) => Some(v),
+            GgufValue::I8(v) => u64::try_from(v).ok(),
+            GgufValue::I16(v) => u64::try_from(v).ok(),
+            GgufValue::I32(v) => u64::try_from(v).ok(),
+            GgufValue::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            GgufValue::F32(v) => Some(v),
+            GgufValue::F64(v) => Some(v as f32),
+            _ => self.as_u64().map(|v| v as f32),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            GgufValue::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GgufTensorInfo {
+    pub name: String,
+    pub shape: Vec<usize>, // row-major, i.e. reversed from the ggml ne[] order stored on disk
+    pub ggml_type: u32,
+    offset: u64, // relative to the start of the data section
+}
+
+pub struct GgufFile {
+    mmap: Arc<Mmap>,
+    pub version: u32,
+    pub metadata: HashMap<String, GgufValue>,
+    pub tensors: Vec<GgufTensorInfo>,
+    data_start: usize,
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.buf.len());
+        let end = end.ok_or_else(|| invalid("unexpected end of GGUF header"))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        usize::try_from(self.u64()?).map_err(|_| invalid("length overflows usize"))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.len()?;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| invalid("string is not valid UTF-8"))
+    }
+
+    fn value(&mut self, value_type: u32) -> io::Result<GgufValue> {
+        self.nested_value(value_type, 0)
+    }
+
+    // `depth` arrays enclose this value
+    fn nested_value(&mut self, value_type: u32, depth: usize) -> io::Result<GgufValue> {
+        Ok(match value_type {
+            0 => GgufValue::U8(self.array::<1>()?[0]),
+            1 => GgufValue::I8(self.array::<1>()?[0] as i8),
+            2 => GgufValue::U16(u16::from_le_bytes(self.array()?)),
+            3 => GgufValue::I16(i16::from_le_bytes(self.array()?)),
+            4 => GgufValue::U32(self.u32()?),
+            5 => GgufValue::I32(i32::from_le_bytes(self.array()?)),
+            6 => GgufValue::F32(f32::from_le_bytes(self.array()?)),
+            7 => GgufValue::Bool(self.array::<1>()?[0] != 0),
+            8 => GgufValue::String(self.string()?),
+            9 => {
+                if depth >= MAX_ARRAY_DEPTH {
+                    return Err(invalid(format!("metadata arrays nested deeper than {MAX_ARRAY_DEPTH}")));
+                }
+                let item_type = self.u32()?;
+                let n = self.len()?;
+                let items = (0..n).map(|_| self.nested_value(item_type, depth + 1)).collect::<io::Result<_>>()?;
+                GgufValue::Array(items)
+            }
+            10 => GgufValue::U64(self.u64()?),
+            11 => GgufValue::I64(i64::from_le_bytes(self.array()?)),
+            12 => GgufValue::F64(f64::from_le_bytes(self.array()?)),
+            t => return Err(invalid(format!("unknown GGUF value type {t}"))),
+        })
+    }
+}
+
+impl GgufFile {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = Arc::new(unsafe { Mmap::map(&file)? });
+        Self::from_mmap(mmap)
+    }
+
+    pub fn from_mmap(mmap: Arc<Mmap>) -> io::Result<Self> {
+        let mut r = Reader { buf: &mmap, pos: 0 };
+        if r.bytes(4)? != GGUF_MAGIC {
+            return Err(invalid("not a GGUF file"));
+        }
+        let version = r.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(invalid(format!("unsupported GGUF version {version}")));
+        }
+        let n_tensors = r.len()?;
+        let n_kv = r.len()?;
+
+        let mut metadata = HashMap::new();
+        for _ in 0..n_kv {
+            let key = r.string()?;
+            let value_type = r.u32()?;
+            metadata.insert(key, r.value(value_type)?);
+        }
+
+        // the count comes from the file, don't reserve more descriptors than the bytes left could hold
+        let mut tensors = Vec::with_capacity(n_tensors.min(r.remaining() / MIN_TENSOR_INFO_BYTES));
+        for _ in 0..n_tensors {
+            let name = r.string()?;
+            let n_dims = r.u32()? as usize;
+            let mut shape = (0..n_dims).map(|_| r.len()).collect::<io::Result<Vec<_>>>()?;
+            shape.reverse();
+            let ggml_type = r.u32()?;
+            let offset = r.u64()?;
+            tensors.push(GgufTensorInfo { name, shape, ggml_type, offset });
+        }
+
+        let alignment = metadata.get("general.alignment").and_then(GgufValue::as_u64).unwrap_or(DEFAULT_ALIGNMENT);
+        let data_start = (r.pos as u64)
+            .checked_next_multiple_of(alignment)
+            .and_then(|start| usize::try_from(start).ok())
+            .ok_or_else(|| invalid(format!("invalid alignment {alignment}")))?;
+        Ok(GgufFile { mmap, version, metadata, tensors, data_start })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata.get(key)
+    }
+
+    pub fn architecture(&self) -> &str {
+        self.get("general.architecture").and_then(GgufValue::as_str).unwrap_or("llama")
+    }
+
+    pub fn tensor_info(&self, name: &str) -> Option<&GgufTensorInfo> {
+        self.tensors.iter().find(|t| t.name == name)
+    }
+
+    // Build the equivalent of config.json from the `<arch>.*` and `tokenizer.ggml.*` metadata
+    pub fn config(&self) -> io::Result<LlamaConfigJson> {
+        let arch = self.architecture();
+        let key = |name: &str| format!("{arch}.{name}");
+        let required = |name: &str| -> io::Result<usize> {
+            let name = key(name);
+            let value = self.get(&name).and_then(GgufValue::as_u64);
+            Ok(value.ok_or_else(|| invalid(format!("missing metadata {name}")))? as usize)
+        };
+        let n_heads = required("attention.head_count")?;
+        let vocab_size = match self.get(&key("vocab_size")).and_then(GgufValue::as_u64) {
+            Some(n) => n as usize,
+            None => match self.get("tokenizer.ggml.tokens").and_then(GgufValue::as_array) {
+                Some(tokens) => tokens.len(),
+                None => self
+                    .tensor_info("token_embd.weight")
+                    .ok_or_else(|| invalid("cannot determine vocab size"))?
+                    .shape[0],
+            },
+        };
+        let token_id = |name: &str, default: u32| {
+            self.get(&format!("tokenizer.ggml.{name}")).and_then(GgufValue::as_u64).map_or(default, |v| v as u32)
+        };
+        let torch_dtype = match self.tensor_info("token_embd.weight").map(|t| t.ggml_type) {
+            Some(GGML_TYPE_F16) => "float16",
+            _ => "float32",
+        };
+        Ok(LlamaConfigJson {
+            architectures: Vec::new(),
+            model_type: Some(arch.to_string()),
+            bos_token_id: token_id("bos_token_id", 1),
+            eos_token_id: TokenIds::One(token_id("eos_token_id", 2)),
+            hidden_size: required("embedding_length")?,
+            intermediate_size: required("feed_forward_length")?,
+            max_position_embeddings: required("context_length")?,
+            num_attention_heads: n_heads,
+            num_hidden_layers: required("block_count")?,
+            num_key_value_heads: self.get(&key("attention.head_count_kv")).and_then(GgufValue::as_u64).map_or(n_heads, |v| v as usize),
+            vocab_size,
+            rms_norm_eps: self.get(&key("attention.layer_norm_rms_epsilon")).and_then(GgufValue::as_f32).unwrap_or(1e-5),
+            rope_theta: self.get(&key("rope.freq_base")).and_then(GgufValue::as_f32).unwrap_or(1e4),
+            torch_dtype: torch_dtype.to_string(),
+            tie_word_embeddings: self.tensor_info("output.weight").is_none(),
+            quantization_config: None,
+            rope_scaling: None,
+            sliding_window: None,
+            head_dim: self.get(&key("attention.key_length")).and_then(GgufValue::as_u64).map(|v| v as usize),
+            partial_rotary_factor: 1.,
+            hidden_act: "silu".to_string(),
+            hidden_activation: None,
+            attn_logit_softcapping: self.get(&key("attn_logit_softcapping")).and_then(GgufValue::as_f32),
+            final_logit_softcapping: self.get(&key("final_logit_softcapping")).and_then(GgufValue::as_f32),
+        })
+    }
+
+    // Tensor `name` (GGUF naming) as f32. F32 payloads are mapped in place, F16 and Q8_0 are converted
+    pub fn tensor_f32(&self, name: &str) -> io::Result<Option<Tensor<f32>>> {
+        let Some(info) = self.tensor_info(name) else {
+            return Ok(None);
+        };
+        // shape and offset come from the file, so every size is checked before it indexes the mapping
+        let out_of_bounds = || invalid(format!("tensor {name} is out of bounds"));
+        let n = info.shape.iter().try_fold(1usize, |n, &d| n.checked_mul(d)).ok_or_else(out_of_bounds)?;
+        let start = usize::try_from(info.offset).ok().and_then(|offset| self.data_start.checked_add(offset));
+        let start = start.ok_or_else(out_of_bounds)?;
+        let bytes_per_elem = |num: usize, den: usize| n.div_ceil(den).checked_mul(num);
+        let n_bytes = match info.ggml_type {
+            GGML_TYPE_F32 => bytes_per_elem(4, 1),
+            GGML_TYPE_F16 => bytes_per_elem(2, 1),
+            GGML_TYPE_Q8_0 => bytes_per_elem(2 + QK8_0, QK8_0),
+            t => return Err(invalid(format!("tensor {name} has unsupported ggml type {t}"))),
+        };
+        let end = n_bytes.and_then(|n_bytes| start.checked_add(n_bytes));
+        let bytes = end.and_then(|end| self.mmap.get(start..end)).ok_or_else(out_of_bounds)?;
+        let data: Vec<f32> = match info.ggml_type {
+            GGML_TYPE_F32 => {
+                if let Some(t) = unsafe { Tensor::from_mmap(&self.mmap, start, &info.shape) } {
+                    return Ok(Some(t));
+                }
+                bytes.chunks(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect()
+            }
+            GGML_TYPE_F16 => bytes.chunks(2).map(|b| f16::from_le_bytes(b.try_into().unwrap()).to_f32()).collect(),
+            _ => {
+                if !n.is_multiple_of(QK8_0) {
+                    return Err(invalid(format!("Q8_0 tensor {name} is not a whole number of blocks")));
+                }
+                let mut data = vec![0f32; n];
+                for (block, y) in bytes.chunks(2 + QK8_0).zip(data.chunks_mut(QK8_0)) {
+                    let scale = f16::from_le_bytes([block[0], block[1]]).to_f32();
+                    let qs = std::array::from_fn(|i| block[2 + i] as i8);
+                    BlockQ8_0 { scale, qs }.dequantize(y);
+                }
+                data
+            }
+        };
+        Ok(Some(Tensor::new(data, &info.shape)))
+    }
+}
+
+// llama.cpp's converter interleaves the two rotary halves of every q/k head for its rope kernel,
+// rows are reordered back here so operators::rope (rotate-half) sees the Hugging Face layout
+fn unpermute_rope_rows(w: &Tensor<f32>, n_heads: usize) -> Tensor<f32> {
+    let (rows, cols) = (w.shape()[0], w.shape()[1]);
+    let half = rows / n_heads / 2;
+    let mut t = w.contiguous();
+    t.reshape(&vec![n_heads, half, 2, cols]);
+    let mut t = t.permute(&[0, 2, 1, 3]).contiguous();
+    t.reshape(&vec![rows, cols]);
+    t
+}
+
+impl LLamaParams<f32> {
+    // Every tensor is decoded up front, so a truncated or malformed payload is an error rather than a panic
+    pub fn from_gguf(gguf: &GgufFile, config: &LlamaConfigJson) -> io::Result<Self> {
+        let names = NameMap::gguf();
+        let mut tensors = HashMap::new();
+        for (name, _) in expected_shapes(config) {
+            let Some(t) = gguf.tensor_f32(&names.map(&name))? else {
+                continue;
+            };
+            let t = if name.ends_with("self_attn.q_proj.weight") {
+                unpermute_rope_rows(&t, config.num_attention_heads)
+            } else if name.ends_with("self_attn.k_proj.weight") {
+                unpermute_rope_rows(&t, config.num_key_value_heads)
+            } else {
+                t
+            };
+            tensors.insert(name, t);
+        }
+        Ok(Self::from_loader(config, |name| tensors.get(name).cloned()))
+    }
+}
+
+// Serialize a minimal GGUF v3 file; tensors are (name, row-major shape, ggml type, raw payload)
+#[cfg(test)]
+pub(crate) fn write_gguf(metadata: &[(&str, GgufValue)], tensors: &[(&str, Vec<usize>, u32, Vec<u8>)]) -> Vec<u8> {
+    fn put_str(out: &mut Vec<u8>, s: &str) {
+        out.extend((s.len() as u64).to_le_bytes());
+        out.extend(s.as_bytes());
+    }
+    fn put_value(out: &mut Vec<u8>, v: &GgufValue) {
+        match v {
+            GgufValue::U8(x) => out.push(*x),
+            GgufValue::I8(x) => out.extend(x.to_le_bytes()),
+            GgufValue::U16(x) => out.extend(x.to_le_bytes()),
+            GgufValue::I16(x) => out.extend(x.to_le_bytes()),
+            GgufValue::U32(x) => out.extend(x.to_le_bytes()),
+            GgufValue::I32(x) => out.extend(x.to_le_bytes()),
+            GgufValue::F32(x) => out.extend(x.to_le_bytes()),
+            GgufValue::Bool(x) => out.push(*x as u8),
+            GgufValue::String(s) => put_str(out, s),
+            GgufValue::Array(items) => {
+                // an empty array needs some item type, the reader never looks at it
+                out.extend(items.first().map_or(0, value_type).to_le_bytes());
+                out.extend((items.len() as u64).to_le_bytes());
+                items.iter().for_each(|item| put_value(out, item));
+            }
+            GgufValue::U64(x) => out.extend(x.to_le_bytes()),
+            GgufValue::I64(x) => out.extend(x.to_le_bytes()),
+            GgufValue::F64(x) => out.extend(x.to_le_bytes()),
+        }
+    }
+    // the reader's type ids, see Reader::value()
+    fn value_type(v: &GgufValue) -> u32 {
+        match v {
+            GgufValue::U8(_) => 0,
+            GgufValue::I8(_) => 1,
+            GgufValue::U16(_) => 2,
+            GgufValue::I16(_) => 3,
+            GgufValue::U32(_) => 4,
+            GgufValue::I32(_) => 5,
+            GgufValue::F32(_) => 6,
+            GgufValue::Bool(_) => 7,
+            GgufValue::String(_) => 8,
+            GgufValue::Array(_) => 9,
+            GgufValue::U64(_) => 10,
+            GgufValue::I64(_) => 11,
+            GgufValue::F64(_) => 12,
+        }
+    }
+    let mut out = GGUF_MAGIC.to_vec();
+    out.extend(3u32.to_le_bytes());
+    out.extend((tensors.len() as u64).to_le_bytes());
+    out.extend((metadata.len() as u64).to_le_bytes());
+    for (key, value) in metadata {
+        put_str(&mut out, key);
+        out.extend(value_type(value).to_le_bytes());
+        put_value(&mut out, value);
+    }
+    let mut offset = 0u64;
+    for (name, shape, ggml_type, data) in tensors {
+        put_str(&mut out, name);
+        out.extend((shape.len() as u32).to_le_bytes());
+        shape.iter().rev().for_each(|&d| out.extend((d as u64).to_le_bytes()));
+        out.extend(ggml_type.to_le_bytes());
+        out.extend(offset.to_le_bytes());
+        offset = (offset + data.len() as u64).next_multiple_of(DEFAULT_ALIGNMENT);
+    }
+    for (_, _, _, data) in tensors {
+        out.resize(out.len().next_multiple_of(DEFAULT_ALIGNMENT as usize), 0);
+        out.extend(data);
+    }
+    out
+}
+
+#[test]
+fn test_gguf_llama() {
+    use crate::params::tiny_config;
+    use crate::quant::Q8Tensor;
+    let config = tiny_config(1, true);
+    let (d, di, vocab) = (config.hidden_size, config.intermediate_size, config.vocab_size);
+    let dqkv = config.head_dim();
+    let f32_bytes = |t: &Tensor<f32>| t.data().iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>();
+    let f16_bytes = |t: &Tensor<f32>| t.data().iter().flat_map(|&x| f16::from_f32(x).to_le_bytes()).collect::<Vec<u8>>();
+    let q8_bytes = |t: &Tensor<f32>| {
+        // blocks run over the flattened data, so regroup rows to whole blocks for quantize()
+        let mut t = t.clone();
+        t.reshape(&vec![t.size() / QK8_0, QK8_0]);
+        Q8Tensor::quantize(&t)
+            .blocks()
+            .iter()
+            .flat_map(|b| f16::from_f32(b.scale).to_le_bytes().into_iter().chain(b.qs.iter().map(|&q| q as u8)))
+            .collect::<Vec<u8>>()
+    };
+    // llama.cpp's permutation applied to Hugging Face q/k rows
+    let permute = |w: &Tensor<f32>, n_heads: usize| {
+        let (rows, cols) = (w.shape()[0], w.shape()[1]);
+        let mut t = w.clone();
+        t.reshape(&vec![n_heads, 2, rows / n_heads / 2, cols]);
+        let mut t = t.permute(&[0, 2, 1, 3]).contiguous();
+        t.reshape(&vec![rows, cols]);
+        t
+    };
+
+    let embed = Tensor::<f32>::randn(&vec![vocab, d], 0);
+    let wq = Tensor::<f32>::randn(&vec![config.num_attention_heads * dqkv, d], 1);
+    let wk = Tensor::<f32>::randn(&vec![config.num_key_value_heads * dqkv, d], 2);
+    let wv = Tensor::<f32>::randn(&vec![config.num_key_value_heads * dqkv, d], 3);
+    let wo = Tensor::<f32>::randn(&vec![d, config.num_attention_heads * dqkv], 4);
+    let up = Tensor::<f32>::randn(&vec![di, d], 5);
+    let gate = Tensor::<f32>::randn(&vec![di, d], 6);
+    let down = Tensor::<f32>::randn(&vec![d, di], 7);
+    let norm = Tensor::<f32>::uniform(&vec![d], 0.5, 1.5, 8);
+    let tensors = vec![
+        ("token_embd.weight", embed.shape().clone(), GGML_TYPE_F32, f32_bytes(&embed)),
+        ("output_norm.weight", vec![d], GGML_TYPE_F32, f32_bytes(&norm)),
+        ("blk.0.attn_norm.weight", vec![d], GGML_TYPE_F32, f32_bytes(&norm)),
+        ("blk.0.ffn_norm.weight", vec![d], GGML_TYPE_F32, f32_bytes(&norm)),
+        ("blk.0.attn_q.weight", wq.shape().clone(), GGML_TYPE_F32, f32_bytes(&permute(&wq, config.num_attention_heads))),
+        ("blk.0.attn_k.weight", wk.shape().clone(), GGML_TYPE_F16, f16_bytes(&permute(&wk, config.num_key_value_heads))),
+        ("blk.0.attn_v.weight", wv.shape().clone(), GGML_TYPE_F16, f16_bytes(&wv)),
+        ("blk.0.attn_output.weight", wo.shape().clone(), GGML_TYPE_F32, f32_bytes(&wo)),
+        ("blk.0.ffn_up.weight", up.shape().clone(), GGML_TYPE_F32, f32_bytes(&up)),
+        ("blk.0.ffn_gate.weight", gate.shape().clone(), GGML_TYPE_F32, f32_bytes(&gate)),
+        ("blk.0.ffn_down.weight", vec![d, di], GGML_TYPE_Q8_0, q8_bytes(&down)),
+    ];
+    let u32v = |v: usize| GgufValue::U32(v as u32);
+    let tokens = GgufValue::Array((0..vocab).map(|i| GgufValue::String(format!("t{i}"))).collect());
+    let metadata = [
+        ("general.architecture", GgufValue::String("llama".into())),
+        ("llama.context_length", u32v(config.max_position_embeddings)),
+        ("llama.embedding_length", u32v(d)),
+        ("llama.feed_forward_length", u32v(di)),
+        ("llama.block_count", u32v(1)),
+        ("llama.attention.head_count", u32v(config.num_attention_heads)),
+        ("llama.attention.head_count_kv", u32v(config.num_key_value_heads)),
+        ("llama.attention.layer_norm_rms_epsilon", GgufValue::F32(1e-6)),
+        ("llama.rope.freq_base", GgufValue::F32(5e5)),
+        ("tokenizer.ggml.tokens", tokens),
+        ("tokenizer.ggml.eos_token_id", u32v(7)),
+    ];
+    let path = std::env::temp_dir().join(format!("tiny_{}.gguf", std::process::id()));
+    std::fs::write(&path, write_gguf(&metadata, &tensors)).unwrap();
+    let gguf = GgufFile::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let loaded = gguf.config().unwrap();
+    assert_eq!((loaded.hidden_size, loaded.intermediate_size, loaded.vocab_size), (d, di, vocab));
+    assert_eq!((loaded.num_attention_heads, loaded.num_key_value_heads), (2, 1));
+    assert_eq!((loaded.eos_token_id.first(), loaded.bos_token_id), (7, 1));
+    assert_eq!(loaded.rope_theta, 5e5);
+    assert!(loaded.tie_word_embeddings);
+
+    let params = LLamaParams::<f32>::from_gguf(&gguf, &loaded).unwrap();
+    assert!(params.embedding_table.is_mapped());
+    assert_eq!(params.lm_head.data(), embed.data());
+    assert_eq!(params.wq[0].data(), wq.data());
+    assert!(params.wk[0].compare(&wk).max_abs_err < 1e-2);
+    assert!(params.wv[0].compare(&wv).max_abs_err < 1e-2);
+    assert!(params.w_down[0].compare(&down).max_abs_err < 0.05);
+    assert!(GgufFile::from_mmap(Arc::new(memmap2::MmapOptions::new().len(4).map_anon().unwrap().make_read_only().unwrap())).is_err());
+
+    // a payload cut short fails to load instead of panicking
+    let mut truncated = write_gguf(&metadata, &tensors);
+    truncated.truncate(truncated.len() - 4);
+    std::fs::write(&path, truncated).unwrap();
+    let gguf = GgufFile::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let err = LLamaParams::<f32>::from_gguf(&gguf, &loaded).err().unwrap();
+    assert!(err.to_string().contains("blk.0.ffn_down.weight is out of bounds"));
+}
+
+#[test]
+fn test_gguf_untrusted_header() {
+    let map = |bytes: &[u8]| {
+        let mut mmap = memmap2::MmapOptions::new().len(bytes.len()).map_anon().unwrap();
+        mmap.copy_from_slice(bytes);
+        GgufFile::from_mmap(Arc::new(mmap.make_read_only().unwrap()))
+    };
+    // every value type round-trips
+    let values = [
+        GgufValue::U8(1),
+        GgufValue::I8(-2),
+        GgufValue::U16(3),
+        GgufValue::I16(-4),
+        GgufValue::U32(5),
+        GgufValue::I32(-6),
+        GgufValue::F32(0.5),
+        GgufValue::Bool(true),
+        GgufValue::String("s".into()),
+        GgufValue::Array(vec![GgufValue::U64(7), GgufValue::U64(8)]),
+        GgufValue::Array(Vec::new()),
+        GgufValue::U64(9),
+        GgufValue::I64(-10),
+        GgufValue::F64(0.25),
+    ];
+    let keys: Vec<String> = (0..values.len()).map(|i| format!("k{i}")).collect();
+    let metadata: Vec<(&str, GgufValue)> = keys.iter().map(String::as_str).zip(values.iter().cloned()).collect();
+    let gguf = map(&write_gguf(&metadata, &[])).unwrap();
+    assert!(keys.iter().zip(&values).all(|(k, v)| gguf.get(k) == Some(v)));
+
+    // a tensor count far beyond what the file holds fails to parse instead of reserving memory for it
+    let mut bytes = write_gguf(&[], &[]);
+    bytes[8..16].copy_from_slice(&(u64::MAX >> 8).to_le_bytes());
+    assert!(map(&bytes).is_err());
+    let zero_alignment = write_gguf(&[("general.alignment", GgufValue::U32(0))], &[]);
+    assert!(map(&zero_alignment).is_err());
+
+    // offsets and shapes that overflow are out of bounds, not a panic
+    let data = vec![0u8; 16];
+    let bytes = write_gguf(&[], &[("a", vec![4], GGML_TYPE_F32, data.clone()), ("b", vec![4], GGML_TYPE_F32, data)]);
+    // b's descriptor: name, dimension count (u32), dimensions (u64), type (u32), offset (u64)
+    let offset_at = bytes.windows(2).rposition(|w| w == [b'b', 1]).unwrap() + 1 + 4 + 8 + 4;
+    let mut huge_offset = bytes.clone();
+    huge_offset[offset_at..offset_at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    let gguf = map(&huge_offset).unwrap();
+    assert_eq!(gguf.tensor_f32("a").unwrap().unwrap().data(), &[0.; 4]);
+    assert!(gguf.tensor_f32("b").unwrap_err().to_string().contains("out of bounds"));
+    let mut huge_shape = bytes;
+    huge_shape[offset_at - 12..offset_at - 4].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(map(&huge_shape).unwrap().tensor_f32("b").is_err());
+
+    // nested arrays past MAX_ARRAY_DEPTH are rejected, not recursed into
+    let nested = |depth: usize| (0..depth).fold(GgufValue::U8(1), |v, _| GgufValue::Array(vec![v]));
+    assert!(map(&write_gguf(&[("k", nested(MAX_ARRAY_DEPTH))], &[])).is_ok());
+    assert!(map(&write_gguf(&[("k", nested(MAX_ARRAY_DEPTH + 1))], &[])).is_err());
+}
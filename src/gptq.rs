@@ -0,0 +1,490 @@
+// GPTQ and AWQ checkpoints (AutoGPTQ / AutoAWQ exports on the Hugging Face Hub). Every linear layer
+// `{prefix}.weight` is replaced by int32-packed `{prefix}.qweight`, `{prefix}.qzeros` and `{prefix}.scales`
+// (plus `{prefix}.g_idx` for act-order GPTQ); these are dequantized to f32 while loading
+use crate::config::{LlamaConfigJson, QuantizationConfig};
+use crate::names::NameMap;
+use crate::params::{expected_shapes, open_shards, read_as, LLamaParams, LoadProgress};
+use crate::tensor::Tensor;
+use safetensors::tensor::TensorView;
+use safetensors::{Dtype, SafeTensors};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+// AWQ interleaves the 8 nibbles of a word, nibble i holds column AWQ_ORDER[i] of its group of 8
+const AWQ_ORDER: [usize; 8] = [0, 2, 4, 6, 1, 3, 5, 7];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PackFormat {
+    // qweight (in / pack, out) packed along the input dim; `zero_offset` is 1 for v1 checkpoints,
+    // which store every zero point minus one
+    Gptq { bits: usize, zero_offset: i32 },
+    // qweight (in, out / 8) packed along the output dim, always 4 bits
+    Awq,
+}
+
+// A GPTQ/AWQ checkpoint this loader can't read, naming the offending tensor where there is one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QuantError {
+    NoQuantizationConfig,
+    UnsupportedMethod { method: String },
+    UnsupportedBits { method: String, bits: usize },
+    Missing { name: String },
+    NotInt32 { name: String, dtype: Dtype },
+    // sizes of the packed tensors don't fit together
+    BadShape { name: String, shape: Vec<usize> },
+}
+
+impl fmt::Display for QuantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuantError::NoQuantizationConfig => write!(f, "config.json has no quantization_config"),
+            QuantError::UnsupportedMethod { method } => {
+                write!(f, "unsupported quantization method {method}, expected gptq or awq")
+            }
+            QuantError::UnsupportedBits { method, bits } => write!(f, "{method} with {bits} bits is not supported"),
+            QuantError::Missing { name } => write!(f, "quantized checkpoint has no tensor {name}"),
+            QuantError::NotInt32 { name, dtype } => {
+                write!(f, "expected tensor {name} to be packed into int32, not {dtype:?}")
+            }
+            QuantError::BadShape { name, shape } => write!(f, "tensor {name} has unexpected shape {shape:?}"),
+        }
+    }
+}
+
+impl std::error::Error for QuantError {}
+
+impl PackFormat {
+    pub fn from_config(quant: &QuantizationConfig) -> Result<Self, QuantError> {
+        let unsupported_bits = || QuantError::UnsupportedBits { method: quant.quant_method.clone(), bits: quant.bits };
+        match quant.quant_method.as_str() {
+            "gptq" => {
+                if !matches!(quant.bits, 2 | 4 | 8) {
+                    return Err(unsupported_bits());
+                }
+                let zero_offset = if quant.checkpoint_format == "gptq_v2" { 0 } else { 1 };
+                Ok(PackFormat::Gptq { bits: quant.bits, zero_offset })
+            }
+            "awq" if quant.bits == 4 => Ok(PackFormat::Awq),
+            "awq" => Err(unsupported_bits()),
+            method => Err(QuantError::UnsupportedMethod { method: method.to_string() }),
+        }
+    }
+
+    fn bits(&self) -> usize {
+        match self {
+            PackFormat::Gptq { bits, .. } => *bits,
+            PackFormat::Awq => 4,
+        }
+    }
+}
+
+// The tensors of one quantized linear layer
+pub struct PackedLinear {
+    pub qweight: Vec<i32>,
+    pub qzeros: Vec<i32>,  // (n_groups, out / pack), packed along the output dim in both formats
+    pub scales: Vec<f32>,  // (n_groups, out)
+    pub g_idx: Option<Vec<i32>>, // (in, ), group of every input column
+    pub in_features: usize,
+    pub out_features: usize,
+}
+
+impl PackedLinear {
+    // Unpack and rescale into a dense (out, in) matrix: w = (q - zero) * scale
+    pub fn dequantize(&self, format: PackFormat) -> Tensor<f32> {
+        let bits = format.bits();
+        let pack = 32 / bits;
+        let mask = (1 << bits) - 1;
+        let (n_in, n_out) = (self.in_features, self.out_features);
+        let n_groups = self.scales.len() / n_out;
+        let group_size = n_in.div_ceil(n_groups);
+        let nibble = |word: i32, i: usize| (word >> (bits * i)) & mask;
+        // position of column `n` inside its word, AWQ shuffles it
+        let slot = |n: usize| match format {
+            PackFormat::Awq => AWQ_ORDER.iter().position(|&c| c == n % pack).unwrap(),
+            PackFormat::Gptq { .. } => n % pack,
+        };
+        let zero_offset = match format {
+            PackFormat::Gptq { zero_offset, .. } => zero_offset,
+            PackFormat::Awq => 0,
+        };
+        let mut data = vec![0f32; n_out * n_in];
+        for k in 0..n_in {
+            let g = match &self.g_idx {
+                Some(g_idx) => g_idx[k] as usize,
+                None => k / group_size,
+            };
+            for n in 0..n_out {
+                let q = match format {
+                    PackFormat::Gptq { .. } => nibble(self.qweight[k / pack * n_out + n], k % pack),
+                    PackFormat::Awq => nibble(self.qweight[k * (n_out / pack) + n / pack], slot(n)),
+                };
+                let zero = nibble(self.qzeros[g * n_out.div_ceil(pack) + n / pack], slot(n)) + zero_offset;
+                data[n * n_in + k] = (q - zero) as f32 * self.scales[g * n_out + n];
+            }
+        }
+        Tensor::new(data, &vec![n_out, n_in])
+    }
+}
+
+// Look up `{prefix}.qweight` and friends for `name` = `{prefix}.weight`; None when the layer is not quantized
+fn packed_linear<'a>(
+    name: &str,
+    format: PackFormat,
+    get_view: impl Fn(&str) -> Option<TensorView<'a>>,
+) -> Result<Option<PackedLinear>, QuantError> {
+    let Some(prefix) = name.strip_suffix(".weight") else {
+        return Ok(None);
+    };
+    let Some(qweight) = get_view(&format!("{prefix}.qweight")) else {
+        return Ok(None);
+    };
+    let view = |suffix: &str| {
+        let name = format!("{prefix}.{suffix}");
+        get_view(&name).ok_or(QuantError::Missing { name })
+    };
+    let int32 = |suffix: &str, view: TensorView| -> Result<Vec<i32>, QuantError> {
+        let name = format!("{prefix}.{suffix}");
+        if view.dtype() != Dtype::I32 {
+            return Err(QuantError::NotInt32 { name, dtype: view.dtype() });
+        }
+        Ok(view.data().chunks_exact(4).map(|b| i32::from_le_bytes(b.try_into().unwrap())).collect())
+    };
+    let bad_shape = |suffix: &str, shape: &[usize]| {
+        QuantError::BadShape { name: format!("{prefix}.{suffix}"), shape: shape.to_vec() }
+    };
+    let scales = read_as::<f32>(&format!("{prefix}.scales"), &view("scales")?);
+    let pack = 32 / format.bits();
+    let (in_features, out_features, n_groups) = match (qweight.shape(), &scales.shape()[..]) {
+        (&[rows, _], &[n_groups, n_out]) if n_groups > 0 => match format {
+            PackFormat::Gptq { .. } => (rows * pack, n_out, n_groups),
+            PackFormat::Awq => (rows, n_out, n_groups),
+        },
+        (&[_, _], shape) => return Err(bad_shape("scales", shape)),
+        (shape, _) => return Err(bad_shape("qweight", shape)),
+    };
+    // dequantize() indexes all of these by the sizes above
+    let qweight_shape = match format {
+        PackFormat::Gptq { .. } => [in_features / pack, out_features],
+        PackFormat::Awq => [in_features, out_features / pack],
+    };
+    if qweight.shape() != qweight_shape || (format == PackFormat::Awq && !out_features.is_multiple_of(pack)) {
+        return Err(bad_shape("qweight", qweight.shape()));
+    }
+    let qzeros_view = view("qzeros")?;
+    if qzeros_view.shape() != [n_groups, out_features.div_ceil(pack)] {
+        return Err(bad_shape("qzeros", qzeros_view.shape()));
+    }
+    let g_idx = match get_view(&format!("{prefix}.g_idx")) {
+        Some(g_idx_view) => {
+            let g_idx = int32("g_idx", g_idx_view)?;
+            if g_idx.len() != in_features || g_idx.iter().any(|&g| !(0..n_groups as i32).contains(&g)) {
+                return Err(bad_shape("g_idx", &[g_idx.len()]));
+            }
+            Some(g_idx)
+        }
+        None => None,
+    };
+    Ok(Some(PackedLinear {
+        qweight: int32("qweight", qweight)?,
+        qzeros: int32("qzeros", qzeros_view)?,
+        scales: scales.data().to_vec(),
+        g_idx,
+        in_features,
+        out_features,
+    }))
+}
+
+impl LLamaParams<f32> {
+    // A single GPTQ/AWQ safetensors file; `config.quantization_config` says which format it is.
+    // Embeddings, norms and lm_head stay unquantized in these exports and are read as usual.
+    // Names go through the NameMap preset detected from the file, like the unquantized loaders
+    pub fn from_quantized_safetensors(safetensor: &SafeTensors, config: &LlamaConfigJson) -> Result<Self, QuantError> {
+        let names = NameMap::detect(safetensor.names().into_iter().map(String::as_str));
+        let get_view = |name: &str| safetensor.tensor(name).ok();
+        load_quantized(config, &names, get_view, |_| {})
+    }
+
+    // Like from_quantized_safetensors, for a model directory with one or more shards
+    pub fn from_quantized_dir(model_dir: impl AsRef<Path>, config: &LlamaConfigJson) -> Result<Self, QuantError> {
+        Self::from_quantized_dir_with_progress(model_dir, config, |_| {})
+    }
+
+    // from_quantized_dir, reporting every tensor as it is loaded
+    pub fn from_quantized_dir_with_progress(
+        model_dir: impl AsRef<Path>,
+        config: &LlamaConfigJson,
+        on_progress: impl FnMut(&LoadProgress),
+    ) -> Result<Self, QuantError> {
+        let (files, weight_map, mmaps) = open_shards(model_dir.as_ref());
+        let shards: Vec<SafeTensors> = mmaps.iter().map(|m| SafeTensors::deserialize(m).unwrap()).collect();
+        let names = match weight_map.is_empty() {
+            true => NameMap::detect(shards[0].names().into_iter().map(String::as_str)),
+            false => NameMap::detect(weight_map.keys().map(String::as_str)),
+        };
+        let get_view = |name: &str| {
+            let shard = match weight_map.get(name) {
+                Some(file) => files.iter().position(|f| f == file).unwrap(),
+                None if weight_map.is_empty() => 0,
+                None => return None,
+            };
+            shards[shard].tensor(name).ok()
+        };
+        load_quantized(config, &names, get_view, on_progress)
+    }
+}
+
+// Every tensor is dequantized up front, so a malformed checkpoint is an error before any layer is built
+fn load_quantized<'a>(
+    config: &LlamaConfigJson,
+    names: &NameMap,
+    get_view: impl Fn(&str) -> Option<TensorView<'a>>,
+    on_progress: impl FnMut(&LoadProgress),
+) -> Result<LLamaParams<f32>, QuantError> {
+    let quant = config.quantization_config.as_ref().ok_or(QuantError::NoQuantizationConfig)?;
+    let format = PackFormat::from_config(quant)?;
+    // a fused qkv_proj stands in for missing q/k/v projections
+    let fused = (0..config.num_hidden_layers).map(|i| format!("model.layers.{i}.self_attn.qkv_proj.weight"));
+    let mut tensors = HashMap::new();
+    for name in expected_shapes(config).into_iter().map(|(name, _)| name).chain(fused) {
+        if let Some(t) = dequantized(&names.map(&name), format, &get_view)? {
+            tensors.insert(name, t);
+        }
+    }
+    let get_tensor = |name: &str| tensors.get(name).cloned();
+    Ok(LLamaParams::load(config, 0..config.num_hidden_layers, get_tensor, |t| t, on_progress))
+}
+
+// Checkpoint tensor `name` as f32, dequantized when it is stored packed
+fn dequantized<'a>(
+    name: &str,
+    format: PackFormat,
+    get_view: impl Fn(&str) -> Option<TensorView<'a>>,
+) -> Result<Option<Tensor<f32>>, QuantError> {
+    Ok(match packed_linear(name, format, &get_view)? {
+        Some(linear) => Some(linear.dequantize(format)),
+        None => get_view(name).map(|view| read_as(name, &view)),
+    })
+}
+
+// Quantize a dense (out, in) matrix the way the exporters lay it out; inverse of PackedLinear::dequantize
+#[cfg(test)]
+pub(crate) fn pack_linear(w: &Tensor<f32>, format: PackFormat, group_size: usize) -> PackedLinear {
+    let bits = format.bits();
+    let (pack, max) = (32 / bits, (1i32 << bits) - 1);
+    let (n_out, n_in) = (w.shape()[0], w.shape()[1]);
+    let n_groups = n_in / group_size;
+    let mut scales = vec![0f32; n_groups * n_out];
+    let mut zeros = vec![0i32; n_groups * n_out];
+    let mut q = vec![0i32; n_in * n_out]; // (in, out)
+    for g in 0..n_groups {
+        for n in 0..n_out {
+            let col = &w.data()[n * n_in + g * group_size..][..group_size];
+            let lo = col.iter().cloned().fold(0f32, f32::min);
+            let hi = col.iter().cloned().fold(0f32, f32::max);
+            let scale = ((hi - lo) / max as f32).max(f32::EPSILON);
+            let zero = (-lo / scale).round() as i32;
+            scales[g * n_out + n] = scale;
+            zeros[g * n_out + n] = zero;
+            for (i, x) in col.iter().enumerate() {
+                q[(g * group_size + i) * n_out + n] = ((x / scale).round() as i32 + zero).clamp(0, max);
+            }
+        }
+    }
+    let slot = |n: usize| match format {
+        PackFormat::Awq => AWQ_ORDER.iter().position(|&c| c == n % pack).unwrap(),
+        PackFormat::Gptq { .. } => n % pack,
+    };
+    let mut qzeros = vec![0i32; n_groups * n_out.div_ceil(pack)];
+    let zero_offset = match format {
+        PackFormat::Gptq { zero_offset, .. } => zero_offset,
+        PackFormat::Awq => 0,
+    };
+    for g in 0..n_groups {
+        for n in 0..n_out {
+            let zero = (zeros[g * n_out + n] - zero_offset) & max;
+            qzeros[g * n_out.div_ceil(pack) + n / pack] |= zero << (bits * slot(n));
+        }
+    }
+    let qweight = match format {
+        PackFormat::Gptq { .. } => {
+            let mut packed = vec![0i32; n_in / pack * n_out];
+            for k in 0..n_in {
+                for n in 0..n_out {
+                    packed[k / pack * n_out + n] |= q[k * n_out + n] << (bits * (k % pack));
+                }
+            }
+            packed
+        }
+        PackFormat::Awq => {
+            let mut packed = vec![0i32; n_in * n_out / pack];
+            for k in 0..n_in {
+                for n in 0..n_out {
+                    packed[k * (n_out / pack) + n / pack] |= q[k * n_out + n] << (bits * slot(n));
+                }
+            }
+            packed
+        }
+    };
+    PackedLinear { qweight, qzeros, scales, g_idx: None, in_features: n_in, out_features: n_out }
+}
+
+#[test]
+fn test_dequantize_gptq_awq() {
+    let w = Tensor::<f32>::randn(&vec![16, 64], 3);
+    let formats = [
+        PackFormat::Gptq { bits: 4, zero_offset: 1 },
+        PackFormat::Gptq { bits: 8, zero_offset: 0 },
+        PackFormat::Awq,
+    ];
+    for format in formats {
+        let linear = pack_linear(&w, format, 32);
+        let deq = linear.dequantize(format);
+        assert_eq!(deq.shape(), &vec![16, 64]);
+        let err = deq.compare(&w).max_abs_err;
+        let tol = if format.bits() == 8 { 0.05 } else { 0.5 };
+        assert!(err < tol, "{format:?}: max error {err}");
+    }
+
+    // act-order checkpoints map input columns to groups through g_idx
+    let format = PackFormat::Gptq { bits: 4, zero_offset: 1 };
+    let mut linear = pack_linear(&w, format, 32);
+    let plain = linear.dequantize(format);
+    linear.g_idx = Some((0..64).map(|k| k / 32).collect());
+    assert_eq!(linear.dequantize(format).data(), plain.data());
+}
+
+#[test]
+fn test_from_quantized_safetensors() {
+    use crate::params::expected_shapes;
+    use safetensors::tensor::serialize;
+    let mut config = LlamaConfigJson::builder()
+        .hidden_size(32)
+        .intermediate_size(64)
+        .heads(2, 2)
+        .num_hidden_layers(1)
+        .vocab_size(10)
+        .build()
+        .unwrap();
+    let quant = QuantizationConfig {
+        quant_method: "gptq".to_string(),
+        bits: 4,
+        group_size: 16,
+        checkpoint_format: "gptq".to_string(),
+    };
+    config.quantization_config = Some(quant);
+    let dense: Vec<(String, Tensor<f32>)> = expected_shapes(&config)
+        .into_iter()
+        .enumerate()
+        .map(|(seed, (name, shape))| (name, Tensor::randn(&shape, seed as u64)))
+        .collect();
+
+    // the AWQ export also renames its tensors, the loader has to find the packed ones through NameMap
+    for format in [PackFormat::Gptq { bits: 4, zero_offset: 1 }, PackFormat::Awq] {
+        let names = match format {
+            PackFormat::Gptq { .. } => NameMap::identity(),
+            PackFormat::Awq => {
+                config.quantization_config.as_mut().unwrap().quant_method = "awq".to_string();
+                NameMap::transformer_h()
+            }
+        };
+        let mut tensors: Vec<(String, Dtype, Vec<usize>, Vec<u8>)> = Vec::new();
+        let int32 = |v: &[i32]| v.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>();
+        for (name, t) in &dense {
+            let name = &names.map(name);
+            let f32s = |t: &Tensor<f32>| t.data().iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>();
+            match name.strip_suffix(".weight").filter(|_| t.shape().len() == 2 && name.contains("layers")) {
+                Some(prefix) => {
+                    let linear = pack_linear(t, format, 16);
+                    let (n_in, n_out) = (linear.in_features, linear.out_features);
+                    let qshape = match format {
+                        PackFormat::Gptq { .. } => vec![n_in / 8, n_out],
+                        PackFormat::Awq => vec![n_in, n_out / 8],
+                    };
+                    let scales: Vec<u8> =
+                        linear.scales.iter().flat_map(|x| half::f16::from_f32(*x).to_le_bytes()).collect();
+                    tensors.push((format!("{prefix}.qweight"), Dtype::I32, qshape, int32(&linear.qweight)));
+                    let zshape = vec![n_in / 16, n_out / 8];
+                    tensors.push((format!("{prefix}.qzeros"), Dtype::I32, zshape, int32(&linear.qzeros)));
+                    tensors.push((format!("{prefix}.scales"), Dtype::F16, vec![n_in / 16, n_out], scales));
+                }
+                None => tensors.push((name.clone(), Dtype::F32, t.shape().clone(), f32s(t))),
+            }
+        }
+        let views = tensors
+            .iter()
+            .map(|(name, dtype, shape, data)| (name.clone(), TensorView::new(*dtype, shape.clone(), data).unwrap()));
+        let bytes = serialize(views, &None).unwrap();
+        let safetensor = SafeTensors::deserialize(&bytes).unwrap();
+        let params = LLamaParams::<f32>::from_quantized_safetensors(&safetensor, &config).unwrap();
+        assert_eq!(params.embedding_table.data(), dense[0].1.data());
+        let (_, w_up) = dense.iter().find(|(name, _)| name.ends_with("up_proj.weight")).unwrap();
+        assert_eq!(params.w_up[0].shape(), w_up.shape());
+        assert!(params.w_up[0].compare(w_up).max_abs_err < 0.5);
+
+        // the directory loader reports every tensor like the unquantized one
+        let dir = std::env::temp_dir().join(format!("quantized_progress_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("model.safetensors"), &bytes).unwrap();
+        let mut loaded = Vec::new();
+        let on_progress = |p: &LoadProgress| loaded.push(p.name.to_string());
+        let from_dir = LLamaParams::<f32>::from_quantized_dir_with_progress(&dir, &config, on_progress).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(from_dir.w_up[0].data(), params.w_up[0].data());
+        assert_eq!(loaded.len(), dense.len());
+    }
+}
+
+#[test]
+fn test_quantized_errors() {
+    let quant = |method: &str, bits| QuantizationConfig {
+        quant_method: method.to_string(),
+        bits,
+        group_size: 16,
+        checkpoint_format: "gptq".to_string(),
+    };
+    assert_eq!(PackFormat::from_config(&quant("gptq", 8)), Ok(PackFormat::Gptq { bits: 8, zero_offset: 1 }));
+    assert_eq!(
+        PackFormat::from_config(&quant("gptq", 3)),
+        Err(QuantError::UnsupportedBits { method: "gptq".to_string(), bits: 3 })
+    );
+    assert_eq!(
+        PackFormat::from_config(&quant("awq", 8)),
+        Err(QuantError::UnsupportedBits { method: "awq".to_string(), bits: 8 })
+    );
+    assert_eq!(
+        PackFormat::from_config(&quant("exl2", 4)),
+        Err(QuantError::UnsupportedMethod { method: "exl2".to_string() })
+    );
+
+    let format = PackFormat::Gptq { bits: 4, zero_offset: 1 };
+    let linear = pack_linear(&Tensor::<f32>::randn(&vec![16, 32], 1), format, 16);
+    let int32 = |v: &[i32]| v.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>();
+    let scales: Vec<u8> = linear.scales.iter().flat_map(|x| x.to_le_bytes()).collect();
+    let (qweight, qzeros) = (int32(&linear.qweight), int32(&linear.qzeros));
+    let tensors = [
+        ("w.qweight", Dtype::I32, vec![4, 16], &qweight),
+        ("w.qzeros", Dtype::I32, vec![2, 2], &qzeros),
+        ("w.scales", Dtype::F32, vec![2, 16], &scales),
+        ("f.qweight", Dtype::F32, vec![4, 16], &qweight),
+        ("f.qzeros", Dtype::I32, vec![2, 2], &qzeros),
+        ("f.scales", Dtype::F32, vec![2, 16], &scales),
+        ("s.qweight", Dtype::I32, vec![4, 16], &qweight),
+        ("s.qzeros", Dtype::I32, vec![2, 2], &qzeros),
+        ("s.scales", Dtype::F32, vec![4, 8], &scales),
+        ("m.qweight", Dtype::I32, vec![4, 16], &qweight),
+        ("m.scales", Dtype::F32, vec![2, 16], &scales),
+    ];
+    let get_view = |name: &str| {
+        let (_, dtype, shape, data) = tensors.iter().find(|(n, ..)| *n == name)?;
+        Some(TensorView::new(*dtype, shape.clone(), data).unwrap())
+    };
+    let deq = packed_linear("w.weight", format, get_view).unwrap().unwrap().dequantize(format);
+    assert_eq!(deq.data(), linear.dequantize(format).data());
+    assert_eq!(packed_linear("dense.weight", format, get_view).unwrap().map(|l| l.in_features), None);
+    let err = |name: &str| packed_linear(name, format, get_view).err().unwrap();
+    assert_eq!(err("f.weight"), QuantError::NotInt32 { name: "f.qweight".to_string(), dtype: Dtype::F32 });
+    assert_eq!(err("s.weight"), QuantError::BadShape { name: "s.qweight".to_string(), shape: vec![4, 16] });
+    assert_eq!(err("m.weight"), QuantError::Missing { name: "m.qzeros".to_string() });
+    assert_eq!(err("m.weight").to_string(), "quantized checkpoint has no tensor m.qzeros");
+}
@@ -0,0 +1,852 @@
+// Constrained decoding: a Grammar recognizes its language one character at a time, and GrammarConstraint
+// masks every token whose text would take the output out of it, so whatever is sampled stays a valid
+// prefix and generation can only end once the output is complete. JsonGrammar accepts any JSON value,
+// JsonSchemaGrammar only those a JSON schema describes
+use crate::sampling::LogitsProcessor;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+pub trait Grammar: Clone + Send {
+    // Consume `c`, false (leaving the state unspecified) if no string of the language continues with it
+    fn feed(&mut self, c: char) -> bool;
+
+    // Whether the input so far is a complete string of the language
+    fn is_complete(&self) -> bool;
+
+    fn feed_str(&mut self, s: &str) -> bool {
+        s.chars().all(|c| self.feed(c))
+    }
+}
+
+// Logits processor enforcing `grammar`. `vocab[id]` is the text token `id` decodes to, tokens with no
+// text (special tokens) are masked except the eos ids, which are only allowed once the grammar is complete
+pub struct GrammarConstraint<G> {
+    grammar: G,
+    vocab: Vec<String>,
+    trie: Vec<TrieNode>,
+    eos_token_ids: Vec<u32>,
+}
+
+// The vocab as a character trie, node 0 is the root. Tokens sharing a prefix share its path, so each
+// step feeds the grammar every distinct prefix once instead of every token from the start
+#[derive(Default)]
+struct TrieNode {
+    children: Vec<(char, usize)>,
+    tokens: Vec<u32>, // tokens whose text ends here
+}
+
+impl<G: Grammar> GrammarConstraint<G> {
+    pub fn new(grammar: G, vocab: Vec<String>, eos_token_ids: &[u32]) -> Self {
+        let mut trie = vec![TrieNode::default()];
+        for (token, text) in vocab.iter().enumerate().filter(|(_, text)| !text.is_empty()) {
+            let mut node = 0;
+            for c in text.chars() {
+                node = match trie[node].children.iter().find(|&&(x, _)| x == c) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        trie.push(TrieNode::default());
+                        let child = trie.len() - 1;
+                        trie[node].children.push((c, child));
+                        child
+                    }
+                };
+            }
+            trie[node].tokens.push(token as u32);
+        }
+        GrammarConstraint { grammar, vocab, trie, eos_token_ids: eos_token_ids.to_vec() }
+    }
+
+    // State after the tokens accepted so far
+    pub fn grammar(&self) -> &G {
+        &self.grammar
+    }
+
+    // Which of the first `n` tokens may come next. Subtrees the grammar rejects are never visited
+    fn allowed(&self, n: usize) -> Vec<bool> {
+        let mut allowed = vec![false; n];
+        let mut pending = vec![(0, self.grammar.clone())];
+        while let Some((node, grammar)) = pending.pop() {
+            for &token in &self.trie[node].tokens {
+                if let Some(a) = allowed.get_mut(token as usize) {
+                    *a = true;
+                }
+            }
+            for &(c, child) in &self.trie[node].children {
+                let mut next = grammar.clone();
+                if next.feed(c) {
+                    pending.push((child, next));
+                }
+            }
+        }
+        let complete = self.grammar.is_complete();
+        for &eos in &self.eos_token_ids {
+            if let Some(a) = allowed.get_mut(eos as usize) {
+                *a = complete;
+            }
+        }
+        allowed
+    }
+}
+
+impl<G: Grammar> LogitsProcessor for GrammarConstraint<G> {
+    fn process(&mut self, logits: &mut [f32], _tokens: &[u32]) {
+        let mut allowed = self.allowed(logits.len());
+        // a vocab that cannot continue the grammar at all ends generation instead of sampling from nothing.
+        // Without eos ids there is no way to end it here, so the logits are left as they are rather than
+        // all masked: generation goes on unconstrained and the output is no longer guaranteed to parse
+        if !allowed.contains(&true) {
+            if self.eos_token_ids.is_empty() {
+                return;
+            }
+            allowed = (0..logits.len()).map(|t| self.eos_token_ids.contains(&(t as u32))).collect();
+        }
+        for (x, keep) in logits.iter_mut().zip(allowed) {
+            if !keep {
+                *x = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    fn accept(&mut self, token: u32) {
+        if let Some(text) = self.vocab.get(token as usize) {
+            self.grammar.feed_str(text);
+        }
+    }
+}
+
+// What JsonGrammar expects next outside of strings, numbers and literals
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Expect {
+    Value,
+    ValueOrClose, // right after '['
+    KeyOrClose,   // right after '{'
+    Key,
+    Colon,
+    CommaOrClose,
+    Done,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Number {
+    Minus,
+    Zero,
+    Int,
+    Dot,
+    Frac,
+    Exp,
+    ExpSign,
+    ExpDigits,
+}
+
+impl Number {
+    fn start(c: char) -> Option<Number> {
+        match c {
+            '-' => Some(Number::Minus),
+            '0' => Some(Number::Zero),
+            '1'..='9' => Some(Number::Int),
+            _ => None,
+        }
+    }
+
+    fn next(self, c: char) -> Option<Number> {
+        use Number::*;
+        match (self, c) {
+            (Minus, '0') => Some(Zero),
+            (Minus, '1'..='9') | (Int, '0'..='9') => Some(Int),
+            (Zero | Int, '.') => Some(Dot),
+            (Dot | Frac, '0'..='9') => Some(Frac),
+            (Zero | Int | Frac, 'e' | 'E') => Some(Exp),
+            (Exp, '+' | '-') => Some(ExpSign),
+            (Exp | ExpSign | ExpDigits, '0'..='9') => Some(ExpDigits),
+            _ => None,
+        }
+    }
+
+    // Whether the number may end here
+    fn is_complete(self) -> bool {
+        matches!(self, Number::Zero | Number::Int | Number::Frac | Number::ExpDigits)
+    }
+}
+
+// Next escape state of a JSON string after `c`, None if `c` can't appear there. The closing quote is
+// left to the caller
+fn string_char(escape: u8, c: char) -> Option<u8> {
+    match (escape, c) {
+        (0, '\\') => Some(1),
+        (0, c) if c >= ' ' => Some(0),
+        (1, '"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't') => Some(0),
+        (1, 'u') => Some(2),
+        (2..=5, c) if c.is_ascii_hexdigit() => Some((escape + 1) % 6),
+        _ => None,
+    }
+}
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r')
+}
+
+// Token being read
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Lexeme {
+    None,
+    String { key: bool, escape: u8 }, // escape: 1 after '\', 2..=5 while reading \uXXXX digits
+    Number(Number),
+    Literal(&'static str, usize), // true, false or null and how much of it was read
+}
+
+// Any single JSON value (RFC 8259) with optional surrounding whitespace
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonGrammar {
+    containers: Vec<char>, // '{' or '[' of every open object and array
+    expect: Expect,
+    lexeme: Lexeme,
+}
+
+impl Default for JsonGrammar {
+    fn default() -> Self {
+        JsonGrammar { containers: Vec::new(), expect: Expect::Value, lexeme: Lexeme::None }
+    }
+}
+
+impl JsonGrammar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn value_done(&mut self) {
+        self.lexeme = Lexeme::None;
+        self.expect = if self.containers.is_empty() { Expect::Done } else { Expect::CommaOrClose };
+    }
+
+    fn close(&mut self, c: char) -> bool {
+        let open = if c == '}' { '{' } else { '[' };
+        if self.containers.pop() != Some(open) {
+            return false;
+        }
+        self.value_done();
+        true
+    }
+
+    fn start_value(&mut self, c: char) -> bool {
+        match c {
+            '{' | '[' => {
+                self.containers.push(c);
+                self.expect = if c == '{' { Expect::KeyOrClose } else { Expect::ValueOrClose };
+            }
+            '"' => self.lexeme = Lexeme::String { key: false, escape: 0 },
+            't' => self.lexeme = Lexeme::Literal("true", 1),
+            'f' => self.lexeme = Lexeme::Literal("false", 1),
+            'n' => self.lexeme = Lexeme::Literal("null", 1),
+            c => match Number::start(c) {
+                Some(n) => self.lexeme = Lexeme::Number(n),
+                None => return false,
+            },
+        }
+        true
+    }
+
+    fn feed_structural(&mut self, c: char) -> bool {
+        if is_whitespace(c) {
+            return true;
+        }
+        match (self.expect, c) {
+            (Expect::ValueOrClose, ']') | (Expect::KeyOrClose, '}') => self.close(c),
+            (Expect::Value | Expect::ValueOrClose, c) => self.start_value(c),
+            (Expect::Key | Expect::KeyOrClose, '"') => {
+                self.lexeme = Lexeme::String { key: true, escape: 0 };
+                true
+            }
+            (Expect::Colon, ':') => {
+                self.expect = Expect::Value;
+                true
+            }
+            (Expect::CommaOrClose, ',') => {
+                self.expect = if self.containers.last() == Some(&'{') { Expect::Key } else { Expect::Value };
+                true
+            }
+            (Expect::CommaOrClose, '}' | ']') => self.close(c),
+            _ => false,
+        }
+    }
+}
+
+impl Grammar for JsonGrammar {
+    fn feed(&mut self, c: char) -> bool {
+        match self.lexeme {
+            Lexeme::None => self.feed_structural(c),
+            Lexeme::String { key, escape: 0 } if c == '"' => {
+                if key {
+                    self.lexeme = Lexeme::None;
+                    self.expect = Expect::Colon;
+                } else {
+                    self.value_done();
+                }
+                true
+            }
+            Lexeme::String { key, escape } => match string_char(escape, c) {
+                Some(escape) => {
+                    self.lexeme = Lexeme::String { key, escape };
+                    true
+                }
+                None => false,
+            },
+            Lexeme::Number(n) => match n.next(c) {
+                Some(n) => {
+                    self.lexeme = Lexeme::Number(n);
+                    true
+                }
+                // the number ended, `c` belongs to what follows it
+                None if n.is_complete() => {
+                    self.value_done();
+                    self.feed_structural(c)
+                }
+                None => false,
+            },
+            Lexeme::Literal(word, read) => {
+                if !word[read..].starts_with(c) {
+                    return false;
+                }
+                if read + 1 == word.len() {
+                    self.value_done();
+                } else {
+                    self.lexeme = Lexeme::Literal(word, read + 1);
+                }
+                true
+            }
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        match self.lexeme {
+            Lexeme::None => self.expect == Expect::Done,
+            // a top-level number only ends with the input
+            Lexeme::Number(n) => n.is_complete() && self.containers.is_empty(),
+            _ => false,
+        }
+    }
+}
+
+// A JSON schema the grammar can't be built from
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaError {
+    // neither an object nor a boolean, or a keyword holding the wrong kind of value
+    NotASchema { schema: String },
+    UnknownType { name: String },
+    // only local references (#/$defs/name, #/definitions/name, ...) are resolved
+    UnresolvedRef { reference: String },
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::NotASchema { schema } => write!(f, "{schema} is not a valid JSON schema"),
+            SchemaError::UnknownType { name } => write!(f, "unknown JSON schema type {name}"),
+            SchemaError::UnresolvedRef { reference } => write!(f, "cannot resolve $ref {reference}"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+// A compiled schema, frames refer to nodes by index so recursive $refs are just cycles
+#[derive(Debug)]
+enum Node {
+    Any,
+    AnyObject, // "type": "object" without properties
+    String,
+    Number { integer: bool },
+    Enum(Vec<String>), // the allowed values as serde_json writes them
+    Array { items: usize },
+    Object { keys: Vec<String>, values: Vec<usize>, required: Vec<bool> }, // keys quoted and escaped
+    AnyOf(Vec<usize>),
+}
+
+struct SchemaCompiler<'a> {
+    root: &'a Value,
+    nodes: Vec<Node>,
+    refs: HashMap<&'a str, usize>,
+}
+
+impl<'a> SchemaCompiler<'a> {
+    fn push(&mut self, node: Node) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn compile(&mut self, schema: &'a Value) -> Result<usize, SchemaError> {
+        let not_a_schema = |schema: &Value| SchemaError::NotASchema { schema: schema.to_string() };
+        let object = match schema {
+            Value::Bool(true) => return Ok(self.push(Node::Any)),
+            Value::Bool(false) => return Ok(self.push(Node::AnyOf(Vec::new()))),
+            Value::Object(object) => object,
+            _ => return Err(not_a_schema(schema)),
+        };
+        if let Some(reference) = object.get("$ref") {
+            let reference = reference.as_str().ok_or_else(|| not_a_schema(reference))?;
+            if let Some(&node) = self.refs.get(reference) {
+                return Ok(node);
+            }
+            let unresolved = || SchemaError::UnresolvedRef { reference: reference.to_string() };
+            let target = reference.strip_prefix('#').and_then(|pointer| self.root.pointer(pointer));
+            // reserved before compiling the target so references back to it close the cycle
+            let node = self.push(Node::AnyOf(Vec::new()));
+            self.refs.insert(reference, node);
+            let target = self.compile(target.ok_or_else(unresolved)?)?;
+            self.nodes[node] = Node::AnyOf(vec![target]);
+            return Ok(node);
+        }
+        if let Some(value) = object.get("const") {
+            return Ok(self.push(Node::Enum(vec![value.to_string()])));
+        }
+        if let Some(values) = object.get("enum") {
+            let values = values.as_array().ok_or_else(|| not_a_schema(values))?;
+            return Ok(self.push(Node::Enum(values.iter().map(Value::to_string).collect())));
+        }
+        // a value matching several oneOf branches is accepted too
+        if let Some(alternatives) = object.get("anyOf").or_else(|| object.get("oneOf")) {
+            let alternatives = alternatives.as_array().ok_or_else(|| not_a_schema(alternatives))?;
+            let alternatives = alternatives.iter().map(|a| self.compile(a)).collect::<Result<_, _>>()?;
+            return Ok(self.push(Node::AnyOf(alternatives)));
+        }
+        let types: Vec<&str> = match object.get("type") {
+            None if object.contains_key("properties") => vec!["object"],
+            None if object.contains_key("items") => vec!["array"],
+            None => return Ok(self.push(Node::Any)),
+            Some(Value::String(name)) => vec![name],
+            Some(Value::Array(names)) => {
+                names.iter().map(|name| name.as_str().ok_or_else(|| not_a_schema(name))).collect::<Result<_, _>>()?
+            }
+            Some(other) => return Err(not_a_schema(other)),
+        };
+        let nodes: Vec<usize> =
+            types.into_iter().map(|name| self.compile_type(name, schema)).collect::<Result<_, _>>()?;
+        match nodes[..] {
+            [node] => Ok(node),
+            _ => Ok(self.push(Node::AnyOf(nodes))),
+        }
+    }
+
+    // `schema` restricted to values of type `name`
+    fn compile_type(&mut self, name: &str, schema: &'a Value) -> Result<usize, SchemaError> {
+        let not_a_schema = |schema: &Value| SchemaError::NotASchema { schema: schema.to_string() };
+        let node = match name {
+            "null" => Node::Enum(vec!["null".to_string()]),
+            "boolean" => Node::Enum(vec!["true".to_string(), "false".to_string()]),
+            "string" => Node::String,
+            "number" | "integer" => Node::Number { integer: name == "integer" },
+            "array" => match schema.get("items") {
+                Some(items) => Node::Array { items: self.compile(items)? },
+                None => Node::Array { items: self.push(Node::Any) },
+            },
+            "object" => match schema.get("properties") {
+                None => Node::AnyObject,
+                Some(Value::Object(properties)) => {
+                    let required = match schema.get("required") {
+                        Some(Value::Array(names)) => names.iter().filter_map(Value::as_str).collect(),
+                        Some(other) => return Err(not_a_schema(other)),
+                        None => Vec::new(),
+                    };
+                    let keys = properties.keys().map(|key| Value::from(key.as_str()).to_string()).collect();
+                    let values = properties.values().map(|value| self.compile(value)).collect::<Result<_, _>>()?;
+                    let required = properties.keys().map(|key| required.contains(&key.as_str())).collect();
+                    Node::Object { keys, values, required }
+                }
+                Some(other) => return Err(not_a_schema(other)),
+            },
+            name => return Err(SchemaError::UnknownType { name: name.to_string() }),
+        };
+        Ok(self.push(node))
+    }
+}
+
+// What an object frame expects next
+#[derive(Clone, Debug, PartialEq)]
+enum ObjectExpect {
+    KeyOrClose, // right after '{'
+    Key,
+    KeyText { live: Vec<usize>, read: usize }, // properties whose quoted key starts with the `read` bytes so far
+    Colon(usize),
+    CommaOrClose,
+}
+
+// One level of what is being read, innermost last
+#[derive(Clone, Debug, PartialEq)]
+enum Frame {
+    Value(usize), // a value of nodes[i] is next, leading whitespace allowed
+    Any(JsonGrammar),
+    String { escape: u8 },
+    Number { number: Number, integer: bool },
+    Literal { node: usize, live: Vec<usize>, read: usize }, // enum values starting with the `read` bytes so far
+    Array { items: usize, open: bool },                     // open until the first item starts
+    Object { node: usize, seen: Vec<bool>, expect: ObjectExpect },
+}
+
+// Values a JSON schema describes, with optional surrounding whitespace. Understands type (a name or a list),
+// properties and required, items, enum, const, anyOf and oneOf, and local $refs including recursive ones.
+// Objects hold only their listed properties, in any order and each at most once; enum values are matched
+// as serde_json writes them. Other keywords (pattern, minimum, additionalProperties, ...) are not enforced
+#[derive(Clone, Debug)]
+pub struct JsonSchemaGrammar {
+    nodes: Arc<Vec<Node>>,
+    stacks: Vec<Vec<Frame>>, // every reading of the input so far, anyOf branches split it
+}
+
+impl JsonSchemaGrammar {
+    pub fn new(schema: &Value) -> Result<Self, SchemaError> {
+        let mut compiler = SchemaCompiler { root: schema, nodes: Vec::new(), refs: HashMap::new() };
+        let root = compiler.compile(schema)?;
+        Ok(JsonSchemaGrammar { nodes: Arc::new(compiler.nodes), stacks: vec![vec![Frame::Value(root)]] })
+    }
+
+    // Feed `c` to one reading, pushing whatever readings continue with it onto `out`. `depth` counts anyOf
+    // expansions that consumed nothing, a $ref cycle that never does matches nothing
+    fn feed_stack(&self, mut stack: Vec<Frame>, c: char, depth: usize, out: &mut Vec<Vec<Frame>>) {
+        let Some(frame) = stack.pop() else {
+            // the root value is complete, only trailing whitespace may follow
+            if is_whitespace(c) {
+                out.push(stack);
+            }
+            return;
+        };
+        match frame {
+            Frame::Value(_) if is_whitespace(c) => stack.push(frame),
+            Frame::Value(node) => match &self.nodes[node] {
+                Node::AnyOf(alternatives) if depth <= self.nodes.len() => {
+                    for &alternative in alternatives {
+                        let mut branch = stack.clone();
+                        branch.push(Frame::Value(alternative));
+                        self.feed_stack(branch, c, depth + 1, out);
+                    }
+                    return;
+                }
+                Node::Any => {
+                    stack.push(Frame::Any(JsonGrammar::new()));
+                    return self.feed_stack(stack, c, depth, out);
+                }
+                Node::AnyObject if c == '{' => {
+                    stack.push(Frame::Any(JsonGrammar::new()));
+                    return self.feed_stack(stack, c, depth, out);
+                }
+                Node::Enum(values) => {
+                    stack.push(Frame::Literal { node, live: (0..values.len()).collect(), read: 0 });
+                    return self.feed_stack(stack, c, depth, out);
+                }
+                Node::String if c == '"' => stack.push(Frame::String { escape: 0 }),
+                &Node::Number { integer } => match Number::start(c) {
+                    Some(number) => stack.push(Frame::Number { number, integer }),
+                    None => return,
+                },
+                &Node::Array { items } if c == '[' => stack.push(Frame::Array { items, open: true }),
+                Node::Object { keys, .. } if c == '{' => {
+                    let seen = vec![false; keys.len()];
+                    stack.push(Frame::Object { node, seen, expect: ObjectExpect::KeyOrClose });
+                }
+                _ => return,
+            },
+            Frame::Any(mut grammar) => {
+                let complete = grammar.is_complete();
+                if grammar.feed(c) {
+                    stack.push(Frame::Any(grammar));
+                } else if complete {
+                    return self.feed_stack(stack, c, depth, out);
+                } else {
+                    return;
+                }
+            }
+            // the closing quote ends the string, nothing is pushed back
+            Frame::String { escape: 0 } if c == '"' => {}
+            Frame::String { escape } => match string_char(escape, c) {
+                Some(escape) => stack.push(Frame::String { escape }),
+                None => return,
+            },
+            Frame::Number { number, integer } => {
+                let next = number.next(c).filter(|n| !integer || matches!(n, Number::Zero | Number::Int));
+                match next {
+                    Some(number) => stack.push(Frame::Number { number, integer }),
+                    // the number ended, `c` belongs to what follows it
+                    None if number.is_complete() => return self.feed_stack(stack, c, depth, out),
+                    None => return,
+                }
+            }
+            Frame::Literal { node, mut live, read } => {
+                let Node::Enum(values) = &self.nodes[node] else { unreachable!() };
+                let complete = live.iter().any(|&i| values[i].len() == read);
+                live.retain(|&i| values[i][read..].starts_with(c));
+                if !live.is_empty() {
+                    stack.push(Frame::Literal { node, live, read: read + c.len_utf8() });
+                } else if complete {
+                    return self.feed_stack(stack, c, depth, out);
+                } else {
+                    return;
+                }
+            }
+            Frame::Array { items, open } => match c {
+                c if is_whitespace(c) => stack.push(frame),
+                ']' => {}
+                ',' if !open => {
+                    stack.push(Frame::Array { items, open: false });
+                    stack.push(Frame::Value(items));
+                }
+                _ if open => {
+                    stack.push(Frame::Array { items, open: false });
+                    stack.push(Frame::Value(items));
+                    return self.feed_stack(stack, c, depth, out);
+                }
+                _ => return,
+            },
+            Frame::Object { node, mut seen, expect } => {
+                let Node::Object { keys, values, required } = &self.nodes[node] else { unreachable!() };
+                let can_close = required.iter().zip(&seen).all(|(&required, &seen)| seen || !required);
+                let expect = match (expect, c) {
+                    (ObjectExpect::KeyText { mut live, read }, c) => {
+                        live.retain(|&i| keys[i][read..].starts_with(c));
+                        let read = read + c.len_utf8();
+                        // a quoted key is never the prefix of another, whichever ends here is the one
+                        match live.iter().find(|&&i| keys[i].len() == read) {
+                            Some(&i) => ObjectExpect::Colon(i),
+                            None if !live.is_empty() => ObjectExpect::KeyText { live, read },
+                            None => return,
+                        }
+                    }
+                    (expect, c) if is_whitespace(c) => expect,
+                    (ObjectExpect::KeyOrClose | ObjectExpect::CommaOrClose, '}') if can_close => {
+                        out.push(stack);
+                        return;
+                    }
+                    (ObjectExpect::KeyOrClose | ObjectExpect::Key, '"') => {
+                        let live: Vec<usize> = (0..keys.len()).filter(|&i| !seen[i]).collect();
+                        if live.is_empty() {
+                            return;
+                        }
+                        ObjectExpect::KeyText { live, read: 1 }
+                    }
+                    (ObjectExpect::Colon(i), ':') => {
+                        seen[i] = true;
+                        stack.push(Frame::Object { node, seen, expect: ObjectExpect::CommaOrClose });
+                        stack.push(Frame::Value(values[i]));
+                        out.push(stack);
+                        return;
+                    }
+                    (ObjectExpect::CommaOrClose, ',') if seen.contains(&false) => ObjectExpect::Key,
+                    _ => return,
+                };
+                stack.push(Frame::Object { node, seen, expect });
+            }
+        }
+        out.push(stack);
+    }
+
+    // Whether `frame` may end where the input does, as a top-level number or enum value can
+    fn frame_complete(&self, frame: &Frame) -> bool {
+        match frame {
+            Frame::Any(grammar) => grammar.is_complete(),
+            Frame::Number { number, .. } => number.is_complete(),
+            Frame::Literal { node, live, read } => {
+                let Node::Enum(values) = &self.nodes[*node] else { unreachable!() };
+                live.iter().any(|&i| values[i].len() == *read)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Grammar for JsonSchemaGrammar {
+    fn feed(&mut self, c: char) -> bool {
+        let mut next = Vec::new();
+        for stack in std::mem::take(&mut self.stacks) {
+            self.feed_stack(stack, c, 0, &mut next);
+        }
+        // branches that read the input the same way from here on are kept once
+        for stack in next {
+            if !self.stacks.contains(&stack) {
+                self.stacks.push(stack);
+            }
+        }
+        !self.stacks.is_empty()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.stacks.iter().any(|stack| match &stack[..] {
+            [] => true,
+            [frame] => self.frame_complete(frame),
+            _ => false,
+        })
+    }
+}
+
+#[test]
+fn test_json_grammar() {
+    let check = |s: &str| {
+        let mut g = JsonGrammar::new();
+        (g.feed_str(s), g.is_complete())
+    };
+    for valid in [r#"{"a": [1, -2.5e3, true, null, "x\né"], "b": {}}"#, "  42 ", "0", "[]", r#""\"""#] {
+        assert_eq!(check(valid), (true, true), "{valid}");
+    }
+    for invalid in ["{a}", "[1,]", "01", r#""\x""#, "[1 2]", "{\"a\" 1}", "tru e", "1 2", "[}"] {
+        assert!(!check(invalid).0, "{invalid}");
+    }
+    for prefix in [r#"{"a": [1"#, "-", "1.", "\"abc", "{\"a\":", "nul"] {
+        assert_eq!(check(prefix), (true, false), "{prefix}");
+    }
+}
+
+#[test]
+fn test_grammar_constraint() {
+    use crate::sampling::{LogitBias, Sampler};
+    use crate::tensor::Tensor;
+    use std::collections::HashMap;
+    let vocab: Vec<String> = ["{", "}", "\"", "a", "\":", "1", ", ", "[", "]", "true", "x", "", "0.", ":"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let eos = 11;
+    for seed in 0..20 {
+        // any token goes as far as the grammar is concerned, eos as soon as the value is complete
+        let bias = LogitBias(HashMap::from([(eos, 4.)]));
+        let constraint = GrammarConstraint::new(JsonGrammar::new(), vocab.clone(), &[eos]);
+        let mut sampler = Sampler::default().with(bias).with(constraint).with_seed(seed);
+        let (mut tokens, mut text) = (Vec::new(), String::new());
+        for step in 0..200 {
+            let logits = Tensor::<f32>::randn(&vec![vocab.len()], seed * 1000 + step);
+            let token = sampler.sample(&logits, &tokens);
+            if token == eos {
+                break;
+            }
+            tokens.push(token);
+            text += &vocab[token as usize];
+        }
+        assert!(JsonGrammar::new().feed_str(&text), "{text}");
+        if tokens.len() < 200 {
+            assert!(serde_json::from_str::<serde_json::Value>(&text).is_ok(), "{text}");
+        }
+    }
+
+    // with a schema every finished output has the required properties with the right types
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {"a": {"type": "array", "items": {"type": "number"}}, "x": {"type": "boolean"}},
+        "required": ["a"]
+    });
+    let vocab: Vec<String> = ["{", "}", "\"", "a", "\":", "1", ", ", "[", "]", "true", "x", "", "0.", ":", "\"x\": "]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    for seed in 0..20 {
+        let grammar = JsonSchemaGrammar::new(&schema).unwrap();
+        let constraint = GrammarConstraint::new(grammar, vocab.clone(), &[eos]);
+        let bias = LogitBias(HashMap::from([(eos, 4.)]));
+        let mut sampler = Sampler::default().with(bias).with(constraint).with_seed(seed);
+        let (mut tokens, mut text) = (Vec::new(), String::new());
+        let finished = (0..200).any(|step| {
+            let token = sampler.sample(&Tensor::<f32>::randn(&vec![vocab.len()], seed * 1000 + step), &tokens);
+            tokens.push(token);
+            text += &vocab[token as usize];
+            token == eos
+        });
+        assert!(JsonSchemaGrammar::new(&schema).unwrap().feed_str(&text), "{text}");
+        if finished {
+            let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+            assert!(value["a"].as_array().unwrap().iter().all(|x| x.is_number()), "{text}");
+            assert!(value.get("x").is_none_or(|x| x.is_boolean()), "{text}");
+        }
+    }
+}
+
+#[test]
+fn test_grammar_mask() {
+    let vocab: Vec<String> = ["t", "tr", "true", "tx", "{", "{\"", "1", "12", "", "]", " "]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    // the trie walk agrees with feeding every token on its own
+    for prefix in ["", "t", "[", "[1", "{\"a\": 1"] {
+        let mut grammar = JsonGrammar::new();
+        assert!(grammar.feed_str(prefix));
+        let constraint = GrammarConstraint::new(grammar.clone(), vocab.clone(), &[8]);
+        let expected: Vec<bool> = (0..vocab.len())
+            .map(|t| match t {
+                8 => grammar.is_complete(),
+                t => !vocab[t].is_empty() && grammar.clone().feed_str(&vocab[t]),
+            })
+            .collect();
+        assert_eq!(constraint.allowed(vocab.len()), expected, "{prefix}");
+    }
+
+    // once nothing continues the grammar only eos is left, and without eos ids the logits are untouched
+    let mut done = JsonGrammar::new();
+    assert!(done.feed_str("true"));
+    let mut logits = vec![0.5; vocab.len()];
+    GrammarConstraint::new(done.clone(), vec!["}".to_string(); vocab.len()], &[8]).process(&mut logits, &[]);
+    assert!(logits.iter().enumerate().all(|(t, &x)| (t == 8) == x.is_finite()));
+    let mut logits = vec![0.5; vocab.len()];
+    GrammarConstraint::new(done, vec!["}".to_string(); vocab.len()], &[]).process(&mut logits, &[]);
+    assert_eq!(logits, vec![0.5; vocab.len()]);
+}
+
+#[test]
+fn test_json_schema_grammar() {
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "name": {"type": "string"},
+            "age": {"type": "integer"},
+            "tags": {"type": "array", "items": {"enum": ["a", "b", 1, 12]}},
+            "nick": {"anyOf": [{"type": "string"}, {"type": "null"}]},
+            "child": {"$ref": "#"},
+            "score": {"type": ["number", "boolean"]},
+            "extra": {}
+        },
+        "required": ["name", "age"]
+    });
+    let check = |s: &str| {
+        let mut g = JsonSchemaGrammar::new(&schema).unwrap();
+        (g.feed_str(s), g.is_complete())
+    };
+    let valid = [
+        r#"{"name": "x", "age": 3}"#,
+        r#" { "age" : -10 , "name":"\"é\"", "tags": ["a", 12, 1, "b"], "nick": null } "#,
+        r#"{"name": "x", "age": 0, "child": {"age": 1, "name": "y", "score": 1.5e3}, "score": false}"#,
+        r#"{"name": "x", "age": 0, "nick": "n", "tags": [], "extra": {"any": [1, {}]}}"#,
+    ];
+    for valid in valid {
+        assert_eq!(check(valid), (true, true), "{valid}");
+        assert!(serde_json::from_str::<Value>(valid).is_ok());
+    }
+    let invalid = [
+        r#"{"name": "x"}"#,                    // missing a required property
+        r#"{"name": "x", "age": 1.5}"#,        // not an integer
+        r#"{"name": "x", "name": "y""#,        // repeated property
+        r#"{"name": 1"#,                       // wrong type
+        r#"{"other": 1"#,                      // unlisted property
+        r#"{"name": "x", "age": 1, "tags": ["c"]"#,
+        r#"{"name": "x", "age": 1, "tags": [123]"#,
+        r#"{"name": "x", "age": 1, "child": []"#,
+        r#"{"name": "x", "age": 1,}"#,
+        r#"[]"#,
+    ];
+    for invalid in invalid {
+        assert!(!check(invalid).0, "{invalid}");
+    }
+    for prefix in [r#"{"na"#, r#"{"name": "x", "age": 1"#, r#"{"name": "x", "age": 1, "tags": [1"#, r#"{"#] {
+        assert_eq!(check(prefix), (true, false), "{prefix}");
+    }
+
+    // top-level scalars may end with the input
+    let mut g = JsonSchemaGrammar::new(&serde_json::json!({"enum": [1, 12, "x"]})).unwrap();
+    assert!(g.feed('1') && g.is_complete() && g.feed('2') && g.is_complete() && !g.feed('3'));
+    let mut g = JsonSchemaGrammar::new(&serde_json::json!({"type": "integer"})).unwrap();
+    assert!(g.feed_str(" -40 ") && g.is_complete());
+    assert!(!JsonSchemaGrammar::new(&serde_json::json!({"type": "integer"})).unwrap().feed_str("4."));
+    assert!(!JsonSchemaGrammar::new(&serde_json::json!(false)).unwrap().feed('1'));
+    let cycle = serde_json::json!({"$defs": {"a": {"$ref": "#/$defs/a"}}, "$ref": "#/$defs/a"});
+    assert!(!JsonSchemaGrammar::new(&cycle).unwrap().feed('1'));
+
+    let error = |schema| JsonSchemaGrammar::new(&schema).unwrap_err();
+    assert_eq!(error(serde_json::json!({"type": "date"})), SchemaError::UnknownType { name: "date".to_string() });
+    let missing = error(serde_json::json!({"$ref": "#/$defs/missing"}));
+    assert_eq!(missing, SchemaError::UnresolvedRef { reference: "#/$defs/missing".to_string() });
+    assert_eq!(error(serde_json::json!(1)), SchemaError::NotASchema { schema: "1".to_string() });
+}
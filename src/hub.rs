@@ -0,0 +1,66 @@
+// Hugging Face Hub downloads (`hub` feature). Files go to the shared HF cache (HF_HOME, ~/.cache/huggingface),
+// interrupted downloads resume from where they stopped and finished files are not fetched again
+use crate::config::LlamaConfigJson;
+use crate::model::Llama;
+use crate::params::LLamaParams;
+use hf_hub::api::sync::{Api, ApiError, ApiRepo};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::path::PathBuf;
+
+const OPTIONAL_FILES: [&str; 2] = ["tokenizer.json", "generation_config.json"];
+
+// Fetch config, tokenizer and (possibly sharded) safetensors of `repo_id` (e.g. "org/model"),
+// returning the local snapshot directory they all live in
+pub fn download(repo_id: &str) -> Result<PathBuf, ApiError> {
+    let repo = Api::new()?.model(repo_id.to_string());
+    let config = repo.get("config.json")?;
+    for file in OPTIONAL_FILES {
+        // not every repo ships these, the loaders fall back to defaults
+        if let Err(e) = repo.get(file) {
+            if !is_not_found(&e) {
+                return Err(e);
+            }
+        }
+    }
+    for file in weight_files(&repo)? {
+        repo.get(&file)?;
+    }
+    Ok(config.parent().unwrap().to_path_buf())
+}
+
+// model.safetensors, or every shard named in model.safetensors.index.json
+fn weight_files(repo: &ApiRepo) -> Result<BTreeSet<String>, ApiError> {
+    match repo.get("model.safetensors.index.json") {
+        Ok(index) => {
+            let index: serde_json::Value = serde_json::from_reader(File::open(index)?).map_err(std::io::Error::from)?;
+            let files = index["weight_map"].as_object().into_iter().flatten();
+            Ok(files.filter_map(|(_, file)| file.as_str().map(str::to_string)).collect())
+        }
+        Err(e) if is_not_found(&e) => Ok(BTreeSet::from(["model.safetensors".to_string()])),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_not_found(e: &ApiError) -> bool {
+    match e {
+        ApiError::RequestError(e) => matches!(**e, ureq::Error::Status(404, _)),
+        _ => false,
+    }
+}
+
+impl LLamaParams<f32> {
+    // Download `repo_id` into the local cache (if needed) and load its weights together with the config
+    pub fn from_hub(repo_id: &str) -> Result<(Self, LlamaConfigJson), ApiError> {
+        let model_dir = download(repo_id)?;
+        let config = LlamaConfigJson::from_file(model_dir.join("config.json"))?;
+        let params = LLamaParams::<f32>::from_dir(&model_dir, &config);
+        Ok((params, config))
+    }
+}
+
+impl Llama<f32> {
+    pub fn from_hub(repo_id: &str) -> Result<Self, ApiError> {
+        Ok(Llama::from_safetensors(download(repo_id)?))
+    }
+}
@@ -1,17 +1,86 @@
-use std::{usize, vec};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::vec;
 
+use crate::backend::{Ops, Weight};
+use crate::model::Llama;
+use crate::params::{read_as, FloatDType};
+use crate::quant;
 use crate::tensor::Tensor;
+use half::{bf16, f16};
+use safetensors::tensor::TensorView;
+use safetensors::{Dtype, SafeTensors};
+
+// What a forward pass needs from a cache: room for the keys/values of the next positions of every
+// layer and the ones to attend over. Implemented by KVCache and by a PagedKVCache sequence
+pub trait KVStore {
+    // Positions cached in every layer, the next token goes to position len()
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Store the (seq, dim) keys and values of the next positions of `layer`
+    fn append(&mut self, layer: usize, k: &Tensor<f32>, v: &Tensor<f32>);
+
+    // Store a whole prompt at once: `k[layer]` and `v[layer]` are the (seq, dim) keys and values of every
+    // layer, each copied with one append, e.g. to seed a cache with another one's view()s
+    fn extend(&mut self, k: &[Tensor<f32>], v: &[Tensor<f32>]) {
+        assert_eq!(k.len(), v.len(), "keys and values for a different number of layers");
+        for (layer, (k, v)) in k.iter().zip(v).enumerate() {
+            self.append(layer, k, v);
+        }
+    }
+
+    // (keys, values) of positions `rows` of `layer`, each (rows.len(), dim) and contiguous
+    fn view(&self, layer: usize, rows: Range<usize>) -> (Tensor<f32>, Tensor<f32>);
+
+    // Forget every position from `len` on, so the next forward pass continues from there (regenerating
+    // an answer, backtracking). Lengths beyond len() are left alone
+    fn truncate(&mut self, len: usize);
+
+    // Bytes of keys and values this cache holds allocated, used or not
+    fn nbytes(&self) -> usize;
+}
+
+// The caches of a Llama::forward_batch(), one per sequence. They are handed out one at a time so that
+// the sequences of a single PagedKVCache can be batched, see PagedKVCache::batch()
+pub trait KVBatch {
+    fn num_sequences(&self) -> usize;
+
+    // Run `f` on the cache of sequence `i`
+    fn with_sequence(&mut self, i: usize, f: &mut dyn FnMut(&mut dyn KVStore));
+}
+
+impl KVBatch for [&mut dyn KVStore] {
+    fn num_sequences(&self) -> usize {
+        self.len()
+    }
+
+    fn with_sequence(&mut self, i: usize, f: &mut dyn FnMut(&mut dyn KVStore)) {
+        f(&mut *self[i])
+    }
+}
+
+// Keys and values of every layer, allocated once for max_seq_len positions. append() writes new rows in
+// place and view() hands out zero-copy slices, so a decode step never reallocates or copies the history
 pub struct KVCache<T> {
     k_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
     v_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
-    #[allow(unused)]
     max_seq_len: usize,
     dim: usize,
-    length: usize, // length of the current sequence
+    lengths: Vec<usize>, // positions stored per layer, layers catch up one by one during a forward pass
 }
 
 impl<T: Default + Copy> KVCache<T> {
     pub fn new(n_layers: usize, max_seq_len: usize, dim: usize, init_len: usize) -> Self {
+        assert!(init_len <= max_seq_len);
         KVCache {
             k_cache: (0..n_layers)
                 .map(|_| Tensor::default(&vec![max_seq_len, dim]))
@@ -19,25 +88,1216 @@ impl<T: Default + Copy> KVCache<T> {
             v_cache: (0..n_layers)
                 .map(|_| Tensor::default(&vec![max_seq_len, dim]))
                 .collect(),
-            max_seq_len: max_seq_len,
-            dim: dim,
-            length: init_len,
+            max_seq_len,
+            dim,
+            lengths: vec![init_len; n_layers],
         }
     }
 
-    pub fn k_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
-        self.k_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
+    // Store the (seq, dim) keys and values of the next positions of `layer`. Views taken earlier keep
+    // sharing the buffer, so drop them first or the write has to copy the whole layer
+    pub fn append(&mut self, layer: usize, k: &Tensor<T>, v: &Tensor<T>) {
+        let (dim, start) = (self.dim, self.lengths[layer]);
+        let rows = k.size() / dim;
+        assert!(k.size() == rows * dim && v.size() == k.size(), "expected (seq, {dim}) keys and values");
+        assert!(
+            start + rows <= self.max_seq_len,
+            "KV cache full: {start} + {rows} positions exceed max_seq_len {}",
+            self.max_seq_len
+        );
+        let (k, v) = (k.contiguous(), v.contiguous());
+        self.k_cache[layer].make_mut()[start * dim..][..rows * dim].copy_from_slice(k.data());
+        self.v_cache[layer].make_mut()[start * dim..][..rows * dim].copy_from_slice(v.data());
+        self.lengths[layer] += rows;
     }
 
-    pub fn v_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
-        self.v_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
+    // (keys, values) of positions `rows` of `layer`, each (rows.len(), dim), e.g. view(layer, 0..len)
+    pub fn view(&self, layer: usize, rows: Range<usize>) -> (Tensor<T>, Tensor<T>) {
+        assert!(rows.start <= rows.end && rows.end <= self.lengths[layer], "rows {rows:?} not cached");
+        let shape = vec![rows.len(), self.dim];
+        let offset = rows.start * self.dim;
+        (self.k_cache[layer].slice(offset, &shape), self.v_cache[layer].slice(offset, &shape))
     }
 
-    pub fn increment(&mut self, seq_len: usize){
-        self.length += seq_len;
+    // Positions cached in every layer
+    pub fn len(&self) -> usize {
+        self.lengths.iter().copied().min().unwrap_or(0)
     }
 
-    pub fn len(&self) -> usize {
-        self.length
+    // Drop positions from `len` on in every layer, the rows are overwritten by the next append
+    pub fn truncate(&mut self, len: usize) {
+        self.lengths.iter_mut().for_each(|l| *l = (*l).min(len));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Positions the cache has room for
+    pub fn capacity(&self) -> usize {
+        self.max_seq_len
+    }
+
+    // Bytes of the preallocated buffers, all max_seq_len positions of every layer
+    pub fn nbytes(&self) -> usize {
+        2 * self.k_cache.len() * self.max_seq_len * self.dim * std::mem::size_of::<T>()
+    }
+}
+
+// Snapshots as safetensors: `tokens` (U32) plus `layers.{i}.k` / `layers.{i}.v` (len, dim) stored as T,
+// with max_seq_len in the metadata
+impl<T: FloatDType> KVCache<T> {
+    // Write the cached positions together with the `tokens` they were computed from, so a conversation
+    // can be resumed after a restart with load()
+    pub fn save(&self, path: impl AsRef<Path>, tokens: &[u32]) -> io::Result<()> {
+        let len = self.len();
+        if tokens.len() != len {
+            let msg = format!("{} tokens for {len} cached positions", tokens.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let tokens = tokens.iter().flat_map(|t| t.to_le_bytes()).collect();
+        let mut tensors = vec![("tokens".to_string(), Dtype::U32, tokens)];
+        for layer in 0..self.k_cache.len() {
+            let (k, v) = self.view(layer, 0..len);
+            tensors.push((format!("layers.{layer}.k"), T::DTYPE, to_le_bytes(k.data())));
+            tensors.push((format!("layers.{layer}.v"), T::DTYPE, to_le_bytes(v.data())));
+        }
+        let views = tensors.iter().map(|(name, dtype, data)| {
+            let shape = if name == "tokens" { vec![len] } else { vec![len, self.dim] };
+            (name.clone(), TensorView::new(*dtype, shape, data).unwrap())
+        });
+        let metadata = HashMap::from([("max_seq_len".to_string(), self.max_seq_len.to_string())]);
+        safetensors::serialize_to_file(views, &Some(metadata), path.as_ref()).map_err(invalid_data)
+    }
+
+    // A cache written by save() and the tokens it holds, ready to continue with the next token.
+    // Snapshots of another dtype are converted
+    pub fn load(path: impl AsRef<Path>) -> io::Result<(Self, Vec<u32>)> {
+        let bytes = std::fs::read(path)?;
+        let (_, metadata) = SafeTensors::read_metadata(&bytes).map_err(invalid_data)?;
+        let safetensor = SafeTensors::deserialize(&bytes).map_err(invalid_data)?;
+        let tokens = safetensor.tensor("tokens").map_err(invalid_data)?;
+        let tokens: Vec<u32> =
+            tokens.data().chunks_exact(4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).collect();
+        let max_seq_len = metadata.metadata().as_ref().and_then(|m| m.get("max_seq_len")?.parse().ok());
+        let max_seq_len = max_seq_len.ok_or_else(|| invalid_data("missing max_seq_len metadata"))?;
+        let n_layers = safetensor.names().iter().filter(|name| name.ends_with(".k")).count();
+        let layer = |layer: usize, kv: &str| -> io::Result<Tensor<T>> {
+            let name = format!("layers.{layer}.{kv}");
+            Ok(read_as(&name, &safetensor.tensor(&name).map_err(invalid_data)?))
+        };
+        let dim = if n_layers == 0 { 0 } else { layer(0, "k")?.shape()[1] };
+        if tokens.len() > max_seq_len {
+            return Err(invalid_data(format!("{} tokens exceed max_seq_len {max_seq_len}", tokens.len())));
+        }
+        let mut cache = KVCache::new(n_layers, max_seq_len, dim, 0);
+        for i in 0..n_layers {
+            let (k, v) = (layer(i, "k")?, layer(i, "v")?);
+            if k.shape() != &vec![tokens.len(), dim] || v.shape() != k.shape() {
+                return Err(invalid_data(format!("layer {i} does not hold ({}, {dim}) keys and values", tokens.len())));
+            }
+            cache.append(i, &k, &v);
+        }
+        Ok((cache, tokens))
+    }
+}
+
+fn to_le_bytes<T: FloatDType>(data: &[T]) -> Vec<u8> {
+    let mut values = vec![0.; data.len()];
+    T::slice_to_f32(data, &mut values);
+    match T::DTYPE {
+        Dtype::F16 => values.iter().flat_map(|&x| f16::from_f32(x).to_le_bytes()).collect(),
+        Dtype::BF16 => values.iter().flat_map(|&x| bf16::from_f32(x).to_le_bytes()).collect(),
+        _ => values.iter().flat_map(|x| x.to_le_bytes()).collect(),
+    }
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl KVStore for KVCache<f32> {
+    fn len(&self) -> usize {
+        KVCache::len(self)
+    }
+
+    fn append(&mut self, layer: usize, k: &Tensor<f32>, v: &Tensor<f32>) {
+        KVCache::append(self, layer, k, v)
+    }
+
+    fn view(&self, layer: usize, rows: Range<usize>) -> (Tensor<f32>, Tensor<f32>) {
+        KVCache::view(self, layer, rows)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        KVCache::truncate(self, len)
+    }
+
+    fn nbytes(&self) -> usize {
+        KVCache::nbytes(self)
+    }
+}
+
+// Half-precision caches halve the memory, keys and values are rounded on append and widened back to
+// f32 by view() so attention still runs in f32
+impl KVStore for KVCache<f16> {
+    fn len(&self) -> usize {
+        KVCache::len(self)
+    }
+
+    fn append(&mut self, layer: usize, k: &Tensor<f32>, v: &Tensor<f32>) {
+        KVCache::append(self, layer, &k.cast(), &v.cast())
+    }
+
+    fn view(&self, layer: usize, rows: Range<usize>) -> (Tensor<f32>, Tensor<f32>) {
+        let (k, v) = KVCache::view(self, layer, rows);
+        (k.to_f32(), v.to_f32())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        KVCache::truncate(self, len)
+    }
+
+    fn nbytes(&self) -> usize {
+        KVCache::nbytes(self)
+    }
+}
+
+impl KVStore for KVCache<bf16> {
+    fn len(&self) -> usize {
+        KVCache::len(self)
+    }
+
+    fn append(&mut self, layer: usize, k: &Tensor<f32>, v: &Tensor<f32>) {
+        KVCache::append(self, layer, &k.cast(), &v.cast())
+    }
+
+    fn view(&self, layer: usize, rows: Range<usize>) -> (Tensor<f32>, Tensor<f32>) {
+        let (k, v) = KVCache::view(self, layer, rows);
+        (k.to_f32(), v.to_f32())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        KVCache::truncate(self, len)
+    }
+
+    fn nbytes(&self) -> usize {
+        KVCache::nbytes(self)
+    }
+}
+
+// Values sharing one scale in a Q8KVCache row
+pub const KV_QUANT_BLOCK: usize = 32;
+
+// KVCache storing keys and values as symmetric int8 with an f32 scale per KV_QUANT_BLOCK values of each
+// row, a little over a quarter of the f32 size. Rows are quantized on append and dequantized by view()
+pub struct Q8KVCache {
+    k_codes: Vec<Vec<i8>>,   // (max_seq_len, dim) x layers
+    v_codes: Vec<Vec<i8>>,   // (max_seq_len, dim) x layers
+    k_scales: Vec<Vec<f32>>, // (max_seq_len, dim / KV_QUANT_BLOCK rounded up) x layers
+    v_scales: Vec<Vec<f32>>, // (max_seq_len, dim / KV_QUANT_BLOCK rounded up) x layers
+    max_seq_len: usize,
+    dim: usize,
+    lengths: Vec<usize>,
+}
+
+impl Q8KVCache {
+    pub fn new(n_layers: usize, max_seq_len: usize, dim: usize) -> Self {
+        let scales = max_seq_len * dim.div_ceil(KV_QUANT_BLOCK);
+        Q8KVCache {
+            k_codes: vec![vec![0; max_seq_len * dim]; n_layers],
+            v_codes: vec![vec![0; max_seq_len * dim]; n_layers],
+            k_scales: vec![vec![0.; scales]; n_layers],
+            v_scales: vec![vec![0.; scales]; n_layers],
+            max_seq_len,
+            dim,
+            lengths: vec![0; n_layers],
+        }
+    }
+
+    // Bytes held by the codes and scales
+    pub fn nbytes(&self) -> usize {
+        let codes: usize = self.k_codes.iter().chain(&self.v_codes).map(Vec::len).sum();
+        let scales: usize = self.k_scales.iter().chain(&self.v_scales).map(Vec::len).sum();
+        codes + scales * std::mem::size_of::<f32>()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.max_seq_len
+    }
+}
+
+// Quantize whole rows of `x` into `codes`/`scales`, one scale per KV_QUANT_BLOCK values of a row
+fn quantize_rows(x: &[f32], dim: usize, codes: &mut [i8], scales: &mut [f32]) {
+    let blocks = dim.div_ceil(KV_QUANT_BLOCK);
+    for ((row, codes), scales) in x.chunks(dim).zip(codes.chunks_mut(dim)).zip(scales.chunks_mut(blocks)) {
+        for ((x, q), scale) in row.chunks(KV_QUANT_BLOCK).zip(codes.chunks_mut(KV_QUANT_BLOCK)).zip(scales) {
+            *scale = quant::quantize_i8(x, q);
+        }
+    }
+}
+
+fn dequantize_rows(codes: &[i8], scales: &[f32], dim: usize) -> Vec<f32> {
+    let blocks = dim.div_ceil(KV_QUANT_BLOCK);
+    let mut out = Vec::with_capacity(codes.len());
+    for (row, scales) in codes.chunks(dim).zip(scales.chunks(blocks)) {
+        for (q, &scale) in row.chunks(KV_QUANT_BLOCK).zip(scales) {
+            out.extend(q.iter().map(|&q| q as f32 * scale));
+        }
+    }
+    out
+}
+
+impl KVStore for Q8KVCache {
+    fn len(&self) -> usize {
+        self.lengths.iter().copied().min().unwrap_or(0)
+    }
+
+    fn append(&mut self, layer: usize, k: &Tensor<f32>, v: &Tensor<f32>) {
+        let (dim, start) = (self.dim, self.lengths[layer]);
+        let rows = k.size() / dim;
+        assert!(k.size() == rows * dim && v.size() == k.size(), "expected (seq, {dim}) keys and values");
+        assert!(start + rows <= self.max_seq_len, "KV cache full: max_seq_len is {}", self.max_seq_len);
+        let blocks = dim.div_ceil(KV_QUANT_BLOCK);
+        let (codes, scales) = (start * dim..(start + rows) * dim, start * blocks..(start + rows) * blocks);
+        let (k, v) = (k.contiguous(), v.contiguous());
+        let (k_codes, k_scales) = (&mut self.k_codes[layer][codes.clone()], &mut self.k_scales[layer][scales.clone()]);
+        quantize_rows(k.data(), dim, k_codes, k_scales);
+        quantize_rows(v.data(), dim, &mut self.v_codes[layer][codes], &mut self.v_scales[layer][scales]);
+        self.lengths[layer] += rows;
+    }
+
+    fn view(&self, layer: usize, rows: Range<usize>) -> (Tensor<f32>, Tensor<f32>) {
+        assert!(rows.start <= rows.end && rows.end <= self.lengths[layer], "rows {rows:?} not cached");
+        let (dim, blocks) = (self.dim, self.dim.div_ceil(KV_QUANT_BLOCK));
+        let (codes, scales) = (rows.start * dim..rows.end * dim, rows.start * blocks..rows.end * blocks);
+        let shape = vec![rows.len(), dim];
+        let k = dequantize_rows(&self.k_codes[layer][codes.clone()], &self.k_scales[layer][scales.clone()], dim);
+        let v = dequantize_rows(&self.v_codes[layer][codes], &self.v_scales[layer][scales], dim);
+        (Tensor::new(k, &shape), Tensor::new(v, &shape))
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.lengths.iter_mut().for_each(|l| *l = (*l).min(len));
+    }
+
+    fn nbytes(&self) -> usize {
+        Q8KVCache::nbytes(self)
+    }
+}
+
+// Cache for sliding-window models that keeps only the positions attention can still reach, in ring
+// buffers of window + max_chunk - 1 rows per layer, so memory stays fixed however long generation
+// runs. len() keeps counting absolute positions, which is what RoPE rotates by and what view() takes
+pub struct SlidingKVCache {
+    k_cache: Vec<Tensor<f32>>, // (capacity, dim) x layers, position p lives in row p % capacity
+    v_cache: Vec<Tensor<f32>>, // (capacity, dim) x layers
+    window: usize,
+    capacity: usize,
+    dim: usize,
+    lengths: Vec<usize>, // positions appended per layer, including the evicted ones
+}
+
+impl SlidingKVCache {
+    // Room for forward passes of up to `max_chunk` tokens attending `window` positions back
+    pub fn new(n_layers: usize, window: usize, dim: usize, max_chunk: usize) -> Self {
+        assert!(window > 0 && max_chunk > 0);
+        let capacity = window + max_chunk - 1;
+        SlidingKVCache {
+            k_cache: (0..n_layers).map(|_| Tensor::default(&vec![capacity, dim])).collect(),
+            v_cache: (0..n_layers).map(|_| Tensor::default(&vec![capacity, dim])).collect(),
+            window,
+            capacity,
+            dim,
+            lengths: vec![0; n_layers],
+        }
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    // Rows held per layer
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Ring rows of positions [start, start + n) as at most two (row, count) runs
+    fn runs(&self, start: usize, n: usize) -> impl Iterator<Item = (usize, usize)> {
+        let row = start % self.capacity;
+        let first = n.min(self.capacity - row);
+        [(row, first), (0, n - first)].into_iter().filter(|&(_, n)| n > 0)
+    }
+}
+
+impl KVStore for SlidingKVCache {
+    fn len(&self) -> usize {
+        self.lengths.iter().copied().min().unwrap_or(0)
+    }
+
+    fn append(&mut self, layer: usize, k: &Tensor<f32>, v: &Tensor<f32>) {
+        let (dim, start) = (self.dim, self.lengths[layer]);
+        let rows = k.size() / dim;
+        assert!(k.size() == rows * dim && v.size() == k.size(), "expected (seq, {dim}) keys and values");
+        let max_chunk = self.capacity - self.window + 1;
+        assert!(rows <= max_chunk, "{rows} positions at once, this sliding cache takes at most {max_chunk}");
+        let (k, v) = (k.contiguous(), v.contiguous());
+        let mut src = 0;
+        for (row, n) in self.runs(start, rows).collect::<Vec<_>>() {
+            let (dst, from) = (row * dim..(row + n) * dim, src * dim..(src + n) * dim);
+            self.k_cache[layer].make_mut()[dst.clone()].copy_from_slice(&k.data()[from.clone()]);
+            self.v_cache[layer].make_mut()[dst].copy_from_slice(&v.data()[from]);
+            src += n;
+        }
+        self.lengths[layer] += rows;
+    }
+
+    // Zero-copy unless the range wraps around the end of the ring
+    fn view(&self, layer: usize, rows: Range<usize>) -> (Tensor<f32>, Tensor<f32>) {
+        let len = self.lengths[layer];
+        assert!(rows.start <= rows.end && rows.end <= len, "rows {rows:?} not cached");
+        assert!(rows.start + self.capacity >= len, "rows {rows:?} already evicted, {len} positions cached");
+        let (dim, shape) = (self.dim, vec![rows.len(), self.dim]);
+        let runs: Vec<_> = self.runs(rows.start, rows.len()).collect();
+        if let [(row, _)] = runs[..] {
+            return (self.k_cache[layer].slice(row * dim, &shape), self.v_cache[layer].slice(row * dim, &shape));
+        }
+        let gather = |cache: &Tensor<f32>| {
+            let data = runs.iter().flat_map(|&(row, n)| &cache.data()[row * dim..(row + n) * dim]);
+            Tensor::new(data.copied().collect(), &shape)
+        };
+        (gather(&self.k_cache[layer]), gather(&self.v_cache[layer]))
+    }
+
+    // Only within what the ring still holds: the next pass needs the window - 1 positions before `len`
+    fn truncate(&mut self, len: usize) {
+        let max_len = self.lengths.iter().copied().max().unwrap_or(0);
+        assert!(
+            len >= max_len || len + self.capacity + 1 >= max_len + self.window,
+            "cannot rewind to {len}, positions before {} were evicted",
+            max_len + self.window - 1 - self.capacity
+        );
+        self.lengths.iter_mut().for_each(|l| *l = (*l).min(len));
+    }
+    fn nbytes(&self) -> usize {
+        2 * self.k_cache.len() * self.capacity * self.dim * std::mem::size_of::<f32>()
+    }
+}
+
+pub type SeqId = u64;
+
+// Keys and values of `block_size` consecutive positions of one sequence, for every layer
+struct KVBlock<T> {
+    k: Vec<T>, // (n_layers, block_size, dim)
+    v: Vec<T>,
+}
+
+struct PagedSequence {
+    block_table: Vec<usize>, // block holding positions [i * block_size, (i + 1) * block_size)
+    lengths: Vec<usize>,     // positions stored per layer
+}
+
+// KV storage for many sequences at once. Memory comes in fixed-size blocks taken from a shared pool
+// as a sequence grows (up to max_blocks in total) and goes back to the pool when the sequence is
+// freed, so nothing is reserved for the longest possible context up front.
+// Full blocks can be registered under the hash of the tokens that produced them (prefix caching):
+// later sequences starting with the same tokens attach those blocks instead of recomputing them.
+// Blocks are reference counted, unreferenced registered blocks stay reusable until the pool runs dry
+pub struct PagedKVCache<T> {
+    n_layers: usize,
+    dim: usize,
+    block_size: usize,
+    max_blocks: usize,
+    blocks: Vec<KVBlock<T>>,
+    ref_counts: Vec<usize>,
+    free_blocks: Vec<usize>,
+    prefix_blocks: HashMap<u64, usize>, // prefix hash -> block
+    block_hashes: Vec<Option<u64>>,     // prefix hash a block is registered under
+    cached_blocks: VecDeque<usize>,     // unreferenced but registered, evicted oldest first
+    sequences: HashMap<SeqId, PagedSequence>,
+    next_id: SeqId,
+}
+
+// Every block of the pool is in use
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutOfBlocks {
+    pub needed: usize,
+    pub available: usize,
+}
+
+impl fmt::Display for OutOfBlocks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KV cache needs {} more blocks but only {} are free", self.needed, self.available)
+    }
+}
+
+impl std::error::Error for OutOfBlocks {}
+
+impl<T: Default + Copy> PagedKVCache<T> {
+    pub fn new(n_layers: usize, dim: usize, block_size: usize, max_blocks: usize) -> Self {
+        assert!(block_size > 0);
+        PagedKVCache {
+            n_layers,
+            dim,
+            block_size,
+            max_blocks,
+            blocks: Vec::new(),
+            ref_counts: Vec::new(),
+            free_blocks: Vec::new(),
+            prefix_blocks: HashMap::new(),
+            block_hashes: Vec::new(),
+            cached_blocks: VecDeque::new(),
+            sequences: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    // Start an empty sequence, it owns no blocks until something is appended
+    pub fn add_sequence(&mut self) -> SeqId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sequences.insert(id, PagedSequence { block_table: Vec::new(), lengths: vec![0; self.n_layers] });
+        id
+    }
+
+    // New sequence continuing from everything `id` holds, e.g. one beam of a beam search. The blocks are
+    // shared until either sequence writes into one of them, which then gets its own copy
+    pub fn fork(&mut self, id: SeqId) -> SeqId {
+        let seq = &self.sequences[&id];
+        let (block_table, lengths) = (seq.block_table.clone(), seq.lengths.clone());
+        block_table.iter().for_each(|&block| self.ref_counts[block] += 1);
+        let child = self.next_id;
+        self.next_id += 1;
+        self.sequences.insert(child, PagedSequence { block_table, lengths });
+        child
+    }
+
+    // Drop sequence `id` and return its blocks to the pool
+    pub fn free_sequence(&mut self, id: SeqId) {
+        if let Some(seq) = self.sequences.remove(&id) {
+            seq.block_table.into_iter().for_each(|block| self.release_block(block));
+        }
+    }
+
+    // Make sure `id` has blocks for `positions` more tokens, so the appends of the next forward pass
+    // cannot run out of memory halfway through the layers
+    pub fn reserve(&mut self, id: SeqId, positions: usize) -> Result<(), OutOfBlocks> {
+        let seq = &self.sequences[&id];
+        let start = seq.lengths.iter().copied().min().unwrap_or(0);
+        let end = seq.lengths.iter().copied().max().unwrap_or(0) + positions;
+        let new_blocks = end.div_ceil(self.block_size).saturating_sub(seq.block_table.len());
+        // writing into a block shared with another sequence copies it first, do that now
+        let shared: Vec<usize> = (start / self.block_size..seq.block_table.len())
+            .filter(|&i| positions > 0 && self.ref_counts[seq.block_table[i]] > 1)
+            .collect();
+        let (needed, available) = (new_blocks + shared.len(), self.num_free_blocks());
+        if needed > available {
+            return Err(OutOfBlocks { needed, available });
+        }
+        for i in shared {
+            self.writable_block(id, i);
+        }
+        for _ in 0..new_blocks {
+            let block = self.allocate_block();
+            self.sequences.get_mut(&id).unwrap().block_table.push(block);
+        }
+        Ok(())
+    }
+
+    // A block with a reference count of 1: a free one, a new one, or the oldest cached prefix block
+    fn allocate_block(&mut self) -> usize {
+        let block = if let Some(block) = self.free_blocks.pop() {
+            block
+        } else if self.blocks.len() < self.max_blocks {
+            let size = self.n_layers * self.block_size * self.dim;
+            self.blocks.push(KVBlock { k: vec![T::default(); size], v: vec![T::default(); size] });
+            self.ref_counts.push(0);
+            self.block_hashes.push(None);
+            self.blocks.len() - 1
+        } else {
+            let block = self.cached_blocks.pop_front().expect("KV cache out of blocks");
+            self.unregister(block);
+            block
+        };
+        self.ref_counts[block] = 1;
+        block
+    }
+
+    fn release_block(&mut self, block: usize) {
+        self.ref_counts[block] -= 1;
+        if self.ref_counts[block] == 0 {
+            if self.block_hashes[block].is_some() {
+                self.cached_blocks.push_back(block);
+            } else {
+                self.free_blocks.push(block);
+            }
+        }
+    }
+
+    fn unregister(&mut self, block: usize) {
+        if let Some(hash) = self.block_hashes[block].take() {
+            self.prefix_blocks.remove(&hash);
+        }
+    }
+
+    // Make the full `block` findable under `hash`, see prefix_hash(). A hash already registered keeps
+    // its block
+    pub fn register_prefix(&mut self, hash: u64, block: usize) {
+        if self.block_hashes[block].is_none() && !self.prefix_blocks.contains_key(&hash) {
+            self.prefix_blocks.insert(hash, block);
+            self.block_hashes[block] = Some(hash);
+        }
+    }
+
+    // Block registered under `hash`, still holding the keys and values of that prefix
+    pub fn lookup_prefix(&self, hash: u64) -> Option<usize> {
+        self.prefix_blocks.get(&hash).copied()
+    }
+
+    // Start the empty sequence `id` with the full `blocks` of a cached prefix, sharing them
+    pub fn attach_prefix(&mut self, id: SeqId, blocks: &[usize]) {
+        assert!(self.len(id) == 0 && self.sequences[&id].block_table.is_empty(), "sequence {id} is not empty");
+        for &block in blocks {
+            if self.ref_counts[block] == 0 {
+                self.cached_blocks.retain(|&b| b != block);
+            }
+            self.ref_counts[block] += 1;
+        }
+        let seq = self.sequences.get_mut(&id).unwrap();
+        seq.block_table.extend_from_slice(blocks);
+        seq.lengths.iter_mut().for_each(|l| *l = blocks.len() * self.block_size);
+    }
+
+    // Block `i` of `id`, copied first if other sequences share it and unregistered since its contents change
+    fn writable_block(&mut self, id: SeqId, i: usize) -> usize {
+        let block = self.sequences[&id].block_table[i];
+        if self.ref_counts[block] == 1 {
+            self.unregister(block);
+            return block;
+        }
+        let copy = self.allocate_block();
+        let (k, v) = (self.blocks[block].k.clone(), self.blocks[block].v.clone());
+        self.blocks[copy] = KVBlock { k, v };
+        self.release_block(block);
+        self.sequences.get_mut(&id).unwrap().block_table[i] = copy;
+        copy
+    }
+
+    // Positions cached in every layer of sequence `id`
+    pub fn len(&self, id: SeqId) -> usize {
+        self.sequences[&id].lengths.iter().copied().min().unwrap_or(0)
+    }
+
+    // Drop the positions of `id` from `len` on, blocks no longer needed go back to the pool
+    pub fn truncate(&mut self, id: SeqId, len: usize) {
+        let bs = self.block_size;
+        let seq = self.sequences.get_mut(&id).unwrap();
+        seq.lengths.iter_mut().for_each(|l| *l = (*l).min(len));
+        let keep = seq.lengths.iter().copied().max().unwrap_or(0).div_ceil(bs);
+        if keep < seq.block_table.len() {
+            let dropped: Vec<usize> = seq.block_table.drain(keep..).collect();
+            dropped.into_iter().for_each(|block| self.release_block(block));
+        }
+    }
+
+    pub fn block_table(&self, id: SeqId) -> &[usize] {
+        &self.sequences[&id].block_table
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    // Blocks that can still be handed out: unused ones (including cached prefixes, which are evicted on
+    // demand) plus what max_blocks allows
+    pub fn num_free_blocks(&self) -> usize {
+        self.free_blocks.len() + self.cached_blocks.len() + self.max_blocks - self.blocks.len()
+    }
+
+    fn block_nbytes(&self) -> usize {
+        2 * self.n_layers * self.block_size * self.dim * std::mem::size_of::<T>()
+    }
+
+    // Bytes of the blocks allocated so far, in use, cached or free
+    pub fn nbytes(&self) -> usize {
+        self.blocks.len() * self.block_nbytes()
+    }
+
+    // Bytes of the blocks sequence `id` holds, prefix blocks shared with others included
+    pub fn sequence_nbytes(&self, id: SeqId) -> usize {
+        self.sequences[&id].block_table.len() * self.block_nbytes()
+    }
+
+    // KVStore view of sequence `id` for Llama::forward
+    pub fn sequence(&mut self, id: SeqId) -> PagedSequenceMut<'_, T> {
+        assert!(self.sequences.contains_key(&id), "unknown sequence {id}");
+        PagedSequenceMut { cache: self, id }
+    }
+
+    // KVBatch of sequences `ids` for Llama::forward_batch
+    pub fn batch(&mut self, ids: &[SeqId]) -> PagedBatchMut<'_, T> {
+        assert!(ids.iter().all(|id| self.sequences.contains_key(id)), "unknown sequence in {ids:?}");
+        PagedBatchMut { cache: self, ids: ids.to_vec() }
+    }
+
+    // Positions [start, start + n) of `layer` as (block, row within it, rows) runs
+    fn runs(&self, start: usize, n: usize) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        let bs = self.block_size;
+        let mut pos = start;
+        std::iter::from_fn(move || {
+            if pos >= start + n {
+                return None;
+            }
+            let (block, row) = (pos / bs, pos % bs);
+            let rows = (bs - row).min(start + n - pos);
+            pos += rows;
+            Some((block, row, rows))
+        })
+    }
+}
+
+// Hash identifying `tokens` following the prefix hashed as `parent` (0 at the start of a sequence)
+pub fn prefix_hash(parent: u64, tokens: &[u32]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (parent, tokens).hash(&mut hasher);
+    hasher.finish()
+}
+
+// Independent sequences (e.g. the conversations of a server) decoded in any interleaving, each with its
+// own KV state in one shared PagedKVCache pool and the tokens that state was computed from. Every
+// full block is registered for prefix caching, see create_with_prefix()
+pub struct CacheManager {
+    cache: PagedKVCache<f32>,
+    tokens: HashMap<SeqId, Vec<u32>>,
+    block_hashes: HashMap<SeqId, Vec<u64>>, // prefix hash of every full block of a sequence
+}
+
+impl CacheManager {
+    pub fn new(cache: PagedKVCache<f32>) -> Self {
+        CacheManager { cache, tokens: HashMap::new(), block_hashes: HashMap::new() }
+    }
+
+    // Start an empty sequence
+    pub fn create(&mut self) -> SeqId {
+        let id = self.cache.add_sequence();
+        self.tokens.insert(id, Vec::new());
+        self.block_hashes.insert(id, Vec::new());
+        id
+    }
+
+    // Start a sequence for `prompt` that already holds the longest cached prefix of it, in whole blocks
+    // and leaving at least the last token to compute. Returns the id and how many tokens were reused,
+    // forward() the rest: `manager.forward(&model, id, &prompt[reused..])`
+    pub fn create_with_prefix(&mut self, prompt: &[u32]) -> (SeqId, usize) {
+        let id = self.create();
+        let bs = self.cache.block_size();
+        let (mut hashes, mut blocks) = (Vec::new(), Vec::new());
+        for chunk in prompt[..prompt.len().saturating_sub(1)].chunks_exact(bs) {
+            let hash = prefix_hash(hashes.last().copied().unwrap_or(0), chunk);
+            let Some(block) = self.cache.lookup_prefix(hash) else {
+                break;
+            };
+            hashes.push(hash);
+            blocks.push(block);
+        }
+        let reused = blocks.len() * bs;
+        self.cache.attach_prefix(id, &blocks);
+        self.tokens.insert(id, prompt[..reused].to_vec());
+        self.block_hashes.insert(id, hashes);
+        (id, reused)
+    }
+
+    // Forget sequence `id`, its blocks go back to the pool
+    pub fn remove(&mut self, id: SeqId) {
+        self.cache.free_sequence(id);
+        self.tokens.remove(&id);
+        self.block_hashes.remove(&id);
+    }
+
+    pub fn contains(&self, id: SeqId) -> bool {
+        self.tokens.contains_key(&id)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = SeqId> + '_ {
+        self.tokens.keys().copied()
+    }
+
+    // Tokens whose keys and values sequence `id` holds
+    pub fn tokens(&self, id: SeqId) -> &[u32] {
+        &self.tokens[&id]
+    }
+
+    // Rewind sequence `id` to its first `len` tokens
+    pub fn truncate(&mut self, id: SeqId, len: usize) {
+        self.cache.truncate(id, len);
+        self.tokens.get_mut(&id).unwrap().truncate(len);
+        self.block_hashes.get_mut(&id).unwrap().truncate(len / self.cache.block_size());
+    }
+
+    pub fn pool(&self) -> &PagedKVCache<f32> {
+        &self.cache
+    }
+
+    // Feed `input` to sequence `id` and return the next-token logits. Blocks for the whole input are
+    // reserved first, so a full pool is reported before any layer has been written
+    pub fn forward<O: Ops, W: Weight>(
+        &mut self,
+        model: &Llama<f32, O, W>,
+        id: SeqId,
+        input: &[u32],
+    ) -> Result<Tensor<f32>, OutOfBlocks> {
+        self.cache.reserve(id, input.len())?;
+        let input_tensor = Tensor::new(input.to_vec(), &vec![input.len()]);
+        let logits = model.forward(&input_tensor, &mut self.cache.sequence(id));
+        self.tokens.get_mut(&id).unwrap().extend_from_slice(input);
+        self.register_blocks(id);
+        Ok(logits)
+    }
+
+    // forward() of several sequences in one batched pass, returning (batch, vocab) logits in the order
+    // of `inputs`. Blocks for every input are reserved before anything is computed, and when one of them
+    // doesn't fit those already reserved for the others go back to the pool
+    pub fn forward_batch<O: Ops, W: Weight>(
+        &mut self,
+        model: &Llama<f32, O, W>,
+        inputs: &[(SeqId, &[u32])],
+    ) -> Result<Tensor<f32>, OutOfBlocks> {
+        for (i, &(id, input)) in inputs.iter().enumerate() {
+            if let Err(err) = self.cache.reserve(id, input.len()) {
+                for &(id, _) in &inputs[..i] {
+                    self.cache.truncate(id, self.tokens[&id].len());
+                }
+                return Err(err);
+            }
+        }
+        let ids: Vec<SeqId> = inputs.iter().map(|&(id, _)| id).collect();
+        let input_slices: Vec<&[u32]> = inputs.iter().map(|&(_, input)| input).collect();
+        let logits = model.forward_batch(&input_slices, &mut self.cache.batch(&ids));
+        for &(id, input) in inputs {
+            self.tokens.get_mut(&id).unwrap().extend_from_slice(input);
+            self.register_blocks(id);
+        }
+        Ok(logits)
+    }
+
+    // Register the blocks of sequence `id` filled since the last call
+    fn register_blocks(&mut self, id: SeqId) {
+        let (bs, tokens) = (self.cache.block_size(), &self.tokens[&id]);
+        let hashes = self.block_hashes.get_mut(&id).unwrap();
+        while hashes.len() < tokens.len() / bs {
+            let i = hashes.len();
+            let hash = prefix_hash(hashes.last().copied().unwrap_or(0), &tokens[i * bs..(i + 1) * bs]);
+            self.cache.register_prefix(hash, self.cache.sequences[&id].block_table[i]);
+            hashes.push(hash);
+        }
+    }
+}
+
+// One sequence of a PagedKVCache, borrowed for a forward pass
+pub struct PagedSequenceMut<'a, T> {
+    cache: &'a mut PagedKVCache<T>,
+    id: SeqId,
+}
+
+impl<T: Default + Copy> PagedSequenceMut<'_, T> {
+    fn write(&mut self, layer: usize, k: &[T], v: &[T]) {
+        let (dim, bs) = (self.cache.dim, self.cache.block_size);
+        let start = self.cache.sequences[&self.id].lengths[layer];
+        let rows = k.len() / dim;
+        let n_blocks = (start + rows).div_ceil(bs);
+        while self.cache.sequences[&self.id].block_table.len() < n_blocks {
+            let block = self.cache.allocate_block();
+            self.cache.sequences.get_mut(&self.id).unwrap().block_table.push(block);
+        }
+        let runs: Vec<_> = self.cache.runs(start, rows).collect();
+        let mut src = 0;
+        for (i, row, n) in runs {
+            let block = self.cache.writable_block(self.id, i);
+            let dst = (layer * bs + row) * dim..(layer * bs + row + n) * dim;
+            let block = &mut self.cache.blocks[block];
+            block.k[dst.clone()].copy_from_slice(&k[src..src + n * dim]);
+            block.v[dst].copy_from_slice(&v[src..src + n * dim]);
+            src += n * dim;
+        }
+        self.cache.sequences.get_mut(&self.id).unwrap().lengths[layer] += rows;
+    }
+
+    // Copy positions `rows` of `layer` out of the blocks into contiguous (rows.len(), dim) buffers
+    fn gather(&self, layer: usize, rows: Range<usize>) -> (Vec<T>, Vec<T>) {
+        let (dim, bs) = (self.cache.dim, self.cache.block_size);
+        let seq = &self.cache.sequences[&self.id];
+        assert!(rows.start <= rows.end && rows.end <= seq.lengths[layer], "rows {rows:?} not cached");
+        let (mut k, mut v) = (Vec::with_capacity(rows.len() * dim), Vec::with_capacity(rows.len() * dim));
+        for (i, row, n) in self.cache.runs(rows.start, rows.len()) {
+            let block = &self.cache.blocks[seq.block_table[i]];
+            let src = (layer * bs + row) * dim..(layer * bs + row + n) * dim;
+            k.extend_from_slice(&block.k[src.clone()]);
+            v.extend_from_slice(&block.v[src]);
+        }
+        (k, v)
+    }
+}
+
+impl KVStore for PagedSequenceMut<'_, f32> {
+    fn len(&self) -> usize {
+        self.cache.len(self.id)
+    }
+
+    fn append(&mut self, layer: usize, k: &Tensor<f32>, v: &Tensor<f32>) {
+        assert!(k.size().is_multiple_of(self.cache.dim) && v.size() == k.size());
+        let (k, v) = (k.contiguous(), v.contiguous());
+        self.write(layer, k.data(), v.data());
+    }
+
+    fn view(&self, layer: usize, rows: Range<usize>) -> (Tensor<f32>, Tensor<f32>) {
+        let shape = vec![rows.len(), self.cache.dim];
+        let (k, v) = self.gather(layer, rows);
+        (Tensor::new(k, &shape), Tensor::new(v, &shape))
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.cache.truncate(self.id, len)
+    }
+
+    fn nbytes(&self) -> usize {
+        self.cache.sequence_nbytes(self.id)
+    }
+}
+
+// Several sequences of a PagedKVCache, borrowed for a batched forward pass
+pub struct PagedBatchMut<'a, T> {
+    cache: &'a mut PagedKVCache<T>,
+    ids: Vec<SeqId>,
+}
+
+impl KVBatch for PagedBatchMut<'_, f32> {
+    fn num_sequences(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn with_sequence(&mut self, i: usize, f: &mut dyn FnMut(&mut dyn KVStore)) {
+        f(&mut self.cache.sequence(self.ids[i]))
+    }
+}
+
+#[test]
+fn test_append_and_view() {
+    let mut cache = KVCache::<f32>::new(2, 8, 3, 0);
+    let rows = |start: usize, n: usize| {
+        Tensor::new((start * 3..(start + n) * 3).map(|x| x as f32).collect(), &vec![n, 3])
+    };
+    cache.append(0, &rows(0, 2), &rows(10, 2));
+    // layer 1 hasn't caught up yet
+    assert_eq!(cache.len(), 0);
+    cache.append(1, &rows(0, 2), &rows(10, 2));
+    assert_eq!(cache.len(), 2);
+    for layer in 0..2 {
+        cache.append(layer, &rows(2, 1), &rows(12, 1));
+    }
+    let (k, v) = cache.view(1, 1..3);
+    assert_eq!(k.shape(), &vec![2, 3]);
+    assert_eq!(k.data(), rows(1, 2).data());
+    assert_eq!(v.data(), rows(11, 2).data());
+    // the views share the preallocated buffer
+    let buffer = cache.k_cache[1].data().as_ptr();
+    drop((k, v));
+    cache.append(1, &rows(3, 1), &rows(13, 1));
+    assert_eq!(cache.k_cache[1].data().as_ptr(), buffer);
+    assert_eq!((cache.len(), cache.capacity()), (3, 8));
+}
+
+#[test]
+fn test_paged_cache() {
+    let mut cache = PagedKVCache::<f32>::new(2, 3, 4, 5);
+    let rows = |start: usize, n: usize| {
+        Tensor::new((start * 3..(start + n) * 3).map(|x| x as f32).collect(), &vec![n, 3])
+    };
+    let (a, b) = (cache.add_sequence(), cache.add_sequence());
+    // sequences interleave, each only takes the blocks it fills
+    for layer in 0..2 {
+        cache.sequence(a).append(layer, &rows(0, 6), &rows(100, 6));
+        cache.sequence(b).append(layer, &rows(50, 3), &rows(150, 3));
+    }
+    assert_eq!((cache.len(a), cache.len(b)), (6, 3));
+    assert_eq!((cache.block_table(a).len(), cache.block_table(b).len()), (2, 1));
+    for layer in 0..2 {
+        cache.sequence(a).append(layer, &rows(6, 1), &rows(106, 1));
+    }
+    // positions 2..7 of a span both of its blocks
+    let (k, v) = cache.sequence(a).view(1, 2..7);
+    assert_eq!(k.data(), rows(2, 5).data());
+    assert_eq!(v.data(), rows(102, 5).data());
+    assert_eq!(cache.sequence(b).view(0, 0..3).0.data(), rows(50, 3).data());
+
+    assert_eq!(cache.num_free_blocks(), 2);
+    assert_eq!(cache.reserve(b, 12), Err(OutOfBlocks { needed: 3, available: 2 }));
+    // freed blocks go back to the pool and are reused
+    cache.free_sequence(a);
+    assert_eq!(cache.num_free_blocks(), 4);
+    cache.reserve(b, 12).unwrap();
+    assert_eq!(cache.block_table(b).len(), 4);
+    assert_eq!(cache.blocks.len(), 4);
+}
+
+#[test]
+fn test_reserve_forked() {
+    let mut cache = PagedKVCache::<f32>::new(1, 2, 4, 3);
+    let rows = |n: usize| Tensor::new(vec![1.; n * 2], &vec![n, 2]);
+    let a = cache.add_sequence();
+    cache.sequence(a).append(0, &rows(5), &rows(5));
+    let b = cache.fork(a);
+    // the shared partial block is copied by reserve, which takes the last free block
+    cache.reserve(b, 1).unwrap();
+    assert_eq!(cache.block_table(b).len(), 2);
+    assert_ne!(cache.block_table(b)[1], cache.block_table(a)[1]);
+    assert_eq!(cache.num_free_blocks(), 0);
+    cache.sequence(b).append(0, &rows(1), &rows(1));
+    assert_eq!(cache.len(b), 6);
+    // a owns its partial block again, only growing past it needs a block
+    assert_eq!(cache.reserve(a, 4), Err(OutOfBlocks { needed: 1, available: 0 }));
+}
+
+#[test]
+fn test_paged_forward() {
+    use crate::config::LlamaConfigJson;
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 6);
+    let (dim, layers) = (config.num_key_value_heads * config.head_dim(), config.num_hidden_layers);
+    let mut paged = PagedKVCache::<f32>::new(layers, dim, 4, 16);
+    let mut dense = llama.new_cache();
+    let id = paged.add_sequence();
+    for step in [vec![3, 1, 4, 1, 5, 9], vec![2], vec![6, 5]] {
+        let input = Tensor::new(step.clone(), &vec![step.len()]);
+        let expected = llama.forward(&input, &mut dense);
+        let logits = llama.forward(&input, &mut paged.sequence(id));
+        assert!(logits.compare(&expected).max_abs_err < 1e-6);
+    }
+    assert_eq!(paged.block_table(id).len(), 3);
+}
+
+#[test]
+fn test_quantized_kv_cache() {
+    use crate::config::LlamaConfigJson;
+    // 48-wide rows leave a partial quantization block
+    let config = LlamaConfigJson::builder().hidden_size(96).heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 7);
+    let dim = config.num_key_value_heads * config.head_dim();
+    let (mut dense, mut half, mut q8) = (llama.new_cache(), llama.new_cache_of::<f16>(), llama.new_q8_cache());
+    assert_eq!(q8.nbytes(), 2 * 2 * 256 * (dim + 2 * 4));
+    for step in [vec![3, 1, 4, 1, 5, 9], vec![2]] {
+        let input = Tensor::new(step.clone(), &vec![step.len()]);
+        let expected = llama.forward(&input, &mut dense);
+        assert!(llama.forward(&input, &mut half).compare(&expected).max_abs_err < 1e-3);
+        assert!(llama.forward(&input, &mut q8).compare(&expected).max_abs_err < 1e-2);
+    }
+    // stored rows come back within half a quantization step (layer 0 sees the same inputs in both)
+    let (k, _) = KVStore::view(&dense, 0, 0..7);
+    let (k8, _) = q8.view(0, 0..7);
+    for (row, row8) in k.data().chunks(dim).zip(k8.data().chunks(dim)) {
+        for (x, y) in row.chunks(KV_QUANT_BLOCK).zip(row8.chunks(KV_QUANT_BLOCK)) {
+            let amax = x.iter().fold(0f32, |m, v| m.max(v.abs()));
+            assert!(x.iter().zip(y).all(|(a, b)| (a - b).abs() <= amax / 254. + 1e-7));
+        }
+    }
+}
+
+#[test]
+fn test_sliding_kv_cache() {
+    use crate::config::LlamaConfigJson;
+    let config = LlamaConfigJson::builder().heads(4, 2).sliding_window(4).build().unwrap();
+    let llama = Llama::random(&config, 8);
+    let mut dense = llama.new_cache();
+    let mut sliding = llama.new_sliding_cache(3);
+    assert_eq!(sliding.capacity(), 6);
+    // run well past the ring size, in prefill chunks and single decode steps
+    let tokens: Vec<u32> = (0..25).map(|i| (i * 37 % 251) as u32).collect();
+    for chunk in tokens[..9].chunks(3).chain(tokens[9..].chunks(1)) {
+        let input = Tensor::new(chunk.to_vec(), &vec![chunk.len()]);
+        let expected = llama.forward(&input, &mut dense);
+        let logits = llama.forward(&input, &mut sliding);
+        assert!(logits.compare(&expected).max_abs_err < 1e-5);
+    }
+    // positions keep counting for RoPE while only the last rows are held
+    assert_eq!(KVStore::len(&sliding), 25);
+    let (k, _) = sliding.view(0, 19..25);
+    let (expected, _) = KVStore::view(&dense, 0, 19..25);
+    assert_eq!(k.data(), expected.data());
+}
+
+#[test]
+fn test_cache_manager() {
+    use crate::config::LlamaConfigJson;
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 9);
+    let mut manager = llama.new_cache_manager(4, 6);
+    let (a, b) = (manager.create(), manager.create());
+    let (mut dense_a, mut dense_b) = (llama.new_cache(), llama.new_cache());
+    // steps of the two conversations interleave, each must see only its own history
+    let steps = [(a, vec![1, 2, 3, 4, 5]), (b, vec![9, 8]), (a, vec![6]), (b, vec![7, 6, 5]), (a, vec![7])];
+    for (id, input) in steps {
+        let dense = if id == a { &mut dense_a } else { &mut dense_b };
+        let expected = llama.forward(&Tensor::new(input.clone(), &vec![input.len()]), dense);
+        let logits = manager.forward(&llama, id, &input).unwrap();
+        assert!(logits.compare(&expected).max_abs_err < 1e-6);
+    }
+    assert_eq!(manager.tokens(a), &[1, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(manager.pool().num_free_blocks(), 2);
+    // too long for the pool: rejected up front and nothing recorded
+    let err = manager.forward(&llama, b, &[1; 12]).unwrap_err();
+    assert_eq!(err, OutOfBlocks { needed: 3, available: 2 });
+    assert_eq!(manager.tokens(b).len(), 5);
+    // a batch whose second input doesn't fit keeps no blocks for the first
+    let err = manager.forward_batch(&llama, &[(a, &[1; 4]), (b, &[1; 12])]).unwrap_err();
+    assert_eq!(err, OutOfBlocks { needed: 3, available: 1 });
+    assert_eq!((manager.pool().num_free_blocks(), manager.pool().block_table(a).len()), (2, 2));
+    manager.remove(a);
+    assert!(!manager.contains(a));
+    assert_eq!(manager.ids().collect::<Vec<_>>(), vec![b]);
+    manager.forward(&llama, b, &[1; 12]).unwrap();
+}
+
+#[test]
+fn test_truncate() {
+    use crate::config::LlamaConfigJson;
+    let config = LlamaConfigJson::builder().heads(4, 2).sliding_window(4).build().unwrap();
+    let llama = Llama::random(&config, 10);
+    let input = |tokens: &[u32]| Tensor::new(tokens.to_vec(), &vec![tokens.len()]);
+    let (mut dense, mut q8, mut sliding) = (llama.new_cache(), llama.new_q8_cache(), llama.new_sliding_cache(5));
+    let mut manager = llama.new_cache_manager(2, 8);
+    let id = manager.create();
+    let prompt = [4, 8, 15, 16, 23];
+    let mut generated = None;
+    for tokens in [&prompt[..], &[42], &[7]] {
+        generated = Some(llama.forward(&input(tokens), &mut dense));
+        llama.forward(&input(tokens), &mut q8);
+        llama.forward(&input(tokens), &mut sliding);
+        manager.forward(&llama, id, tokens).unwrap();
+    }
+    // rewind past the two generated tokens and take a different branch
+    KVStore::truncate(&mut dense, 5);
+    q8.truncate(5);
+    sliding.truncate(5);
+    manager.truncate(id, 5);
+    assert_eq!((KVStore::len(&dense), manager.tokens(id).len(), manager.pool().block_table(id).len()), (5, 5, 3));
+    let mut fresh = llama.new_cache();
+    llama.forward(&input(&prompt), &mut fresh);
+    let branch = llama.forward(&input(&[99]), &mut fresh);
+    assert!(llama.forward(&input(&[99]), &mut dense).compare(&branch).max_abs_err < 1e-6);
+    assert!(llama.forward(&input(&[99]), &mut sliding).compare(&branch).max_abs_err < 1e-5);
+    assert!(llama.forward(&input(&[99]), &mut q8).compare(&branch).max_abs_err < 1e-2);
+    assert!(manager.forward(&llama, id, &[99]).unwrap().compare(&branch).max_abs_err < 1e-6);
+    assert!(generated.unwrap().compare(&branch).max_abs_err > 1e-6);
+}
+
+#[test]
+fn test_prefix_cache() {
+    use crate::config::LlamaConfigJson;
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 11);
+    let mut manager = llama.new_cache_manager(4, 8);
+    let system: Vec<u32> = (20..30).collect();
+    let request = |question: &[u32]| [&system[..], question].concat();
+    let expected = |prompt: &[u32]| {
+        llama.forward(&Tensor::new(prompt.to_vec(), &vec![prompt.len()]), &mut llama.new_cache())
+    };
+
+    let first = request(&[1, 2, 3]);
+    let (a, reused) = manager.create_with_prefix(&first);
+    assert_eq!(reused, 0);
+    manager.forward(&llama, a, &first).unwrap();
+    // the 8 tokens of the two full system prompt blocks are shared
+    let second = request(&[4, 5]);
+    let (b, reused) = manager.create_with_prefix(&second);
+    assert_eq!(reused, 8);
+    let logits = manager.forward(&llama, b, &second[reused..]).unwrap();
+    assert!(logits.compare(&expected(&second)).max_abs_err < 1e-5);
+    assert_eq!(manager.pool().block_table(a)[..2], manager.pool().block_table(b)[..2]);
+
+    // rewinding into a shared block copies it, and cached blocks outlive their sequences
+    manager.truncate(b, 6);
+    let logits = manager.forward(&llama, b, &[7, 7]).unwrap();
+    assert!(logits.compare(&expected(&[&system[..6], &[7, 7]].concat())).max_abs_err < 1e-5);
+    assert_ne!(manager.pool().block_table(a)[1], manager.pool().block_table(b)[1]);
+    manager.remove(a);
+    let third = request(&[6]);
+    let (c, reused) = manager.create_with_prefix(&third);
+    assert_eq!(reused, 8);
+    let logits = manager.forward(&llama, c, &third[reused..]).unwrap();
+    assert!(logits.compare(&expected(&third)).max_abs_err < 1e-5);
+    // a prompt that is entirely cached still leaves its last token to compute
+    let (_, reused) = manager.create_with_prefix(&system[..8]);
+    assert_eq!(reused, 4);
+}
+
+#[test]
+fn test_save_load() {
+    use crate::config::LlamaConfigJson;
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 12);
+    let prompt = vec![3, 1, 4, 1, 5];
+    let next = Tensor::new(vec![9], &vec![1]);
+    let mut cache = llama.new_cache();
+    llama.forward(&Tensor::new(prompt.clone(), &vec![5]), &mut cache);
+    let path = std::env::temp_dir().join(format!("kvcache_{}.safetensors", std::process::id()));
+    assert!(cache.save(&path, &prompt[..4]).is_err());
+    cache.save(&path, &prompt).unwrap();
+    let expected = llama.forward(&next, &mut cache);
+
+    let (mut restored, tokens) = KVCache::<f32>::load(&path).unwrap();
+    assert_eq!(tokens, prompt);
+    assert_eq!((restored.len(), restored.capacity()), (5, cache.capacity()));
+    assert_eq!(llama.forward(&next, &mut restored).data(), expected.data());
+    // an f32 snapshot loaded into an f16 cache
+    let (mut half, _) = KVCache::<f16>::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(llama.forward(&next, &mut half).compare(&expected).max_abs_err < 1e-3);
+}
+
+#[test]
+fn test_extend() {
+    use crate::config::LlamaConfigJson;
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 14);
+    let mut source = llama.new_cache();
+    llama.prefill(&[5, 6, 7, 8], &mut source);
+    let layers = 0..config.num_hidden_layers;
+    let (k, v): (Vec<_>, Vec<_>) = layers.map(|layer| source.view(layer, 0..4)).unzip();
+    let next = Tensor::new(vec![9], &vec![1]);
+    let expected = llama.forward(&next, &mut source);
+
+    let mut dense = llama.new_cache();
+    dense.extend(&k, &v);
+    assert_eq!(KVStore::len(&dense), 4);
+    assert_eq!(llama.forward(&next, &mut dense).data(), expected.data());
+    let mut paged = PagedKVCache::<f32>::new(config.num_hidden_layers, k[0].shape()[1], 3, 8);
+    let id = paged.add_sequence();
+    paged.sequence(id).extend(&k, &v);
+    assert_eq!(llama.forward(&next, &mut paged.sequence(id)).data(), expected.data());
+}
+
+#[test]
+fn test_fork() {
+    use crate::config::LlamaConfigJson;
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 19);
+    let input = |tokens: &[u32]| Tensor::new(tokens.to_vec(), &vec![tokens.len()]);
+    let mut paged = PagedKVCache::<f32>::new(config.num_hidden_layers, 2 * config.hidden_size / 4, 4, 16);
+    let parent = paged.add_sequence();
+    llama.forward(&input(&[1, 2, 3, 4, 5, 6]), &mut paged.sequence(parent));
+    let child = paged.fork(parent);
+    assert_eq!(paged.block_table(child), paged.block_table(parent));
+    assert_eq!(paged.num_free_blocks(), 14);
+
+    // diverging writes copy the shared partial block, the full one stays shared
+    let from_child = llama.forward(&input(&[7]), &mut paged.sequence(child));
+    let from_parent = llama.forward(&input(&[8]), &mut paged.sequence(parent));
+    assert_eq!(paged.block_table(child)[0], paged.block_table(parent)[0]);
+    assert_ne!(paged.block_table(child)[1], paged.block_table(parent)[1]);
+    for (last, logits) in [(7, from_child), (8, from_parent)] {
+        let expected = llama.forward(&input(&[1, 2, 3, 4, 5, 6, last]), &mut llama.new_cache());
+        assert!(logits.compare(&expected).max_abs_err < 1e-5);
     }
+    paged.free_sequence(parent);
+    paged.free_sequence(child);
+    assert_eq!(paged.num_free_blocks(), 16);
 }
@@ -0,0 +1,21 @@
+// Llama-family inference on the CPU: load a checkpoint (model, params), run it (model, kvcache, scheduler)
+// and pick tokens (sampling, generation). main.rs is a small story-generation demo on top
+pub mod backend;
+pub mod config;
+pub mod generation;
+pub mod gguf;
+pub mod gptq;
+pub mod grammar;
+#[cfg(feature = "hub")]
+pub mod hub;
+pub mod kvcache;
+pub mod model;
+pub mod names;
+pub mod operators;
+pub mod params;
+pub mod pytorch;
+pub mod quant;
+pub mod sampling;
+pub mod scheduler;
+pub mod simd;
+pub mod tensor;
@@ -1,28 +1,45 @@
-mod config;
-mod kvcache;
-mod model;
-mod operators;
-mod params;
-mod tensor;
-
+use learning_lm_rust::generation::GenerationConfig;
+use learning_lm_rust::model;
+use std::io::{self, Write};
+use std::ops::ControlFlow;
 use std::path::PathBuf;
 use tokenizers::Tokenizer;
 
 fn main() {
     let project_dir = env!("CARGO_MANIFEST_DIR");
     let model_dir = PathBuf::from(project_dir).join("models").join("story");
-    let llama = model::Llama::<f32>::from_safetensors(&model_dir);
+    let llama = model::Llama::from_dir(&model_dir, |p| {
+        eprint!("\rLoading weights {}/{}", p.tensors_loaded, p.tensors_total)
+    })
+    .unwrap_or_else(|e| panic!("{e}"));
+    eprintln!();
     let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json")).unwrap();
     let input = "Once upon a time";
     let binding = tokenizer.encode(input, true).unwrap();
     let input_ids = binding.get_ids();
     print!("\n{}", input);
-    let output_ids = llama.generate(
-        input_ids,
-        500,
-        0.9,
-        4,
-        1.,
-    );
-    println!("{}", tokenizer.decode(&output_ids, true).unwrap());
+    // sampling defaults from generation_config.json
+    let generation_config = llama.generation_config();
+    let (top_p, top_k, temperature) = generation_config.sampling(0.9, 4, 1.);
+    let config = GenerationConfig::builder()
+        .max_new_tokens(generation_config.max_new_tokens.unwrap_or(500))
+        .temperature(temperature)
+        .top_k(top_k as usize)
+        .top_p(top_p)
+        .build()
+        .unwrap_or_else(|e| panic!("{e}"));
+    // print the story as it is generated, decoding everything so far so multi-token characters come out whole
+    let mut output_ids = Vec::new();
+    let mut printed = 0;
+    llama.generate_stream(input_ids, &config, |id| {
+        output_ids.push(id);
+        let text = tokenizer.decode(&output_ids, true).unwrap();
+        if let Some(new) = text.get(printed..) {
+            print!("{new}");
+            io::stdout().flush().unwrap();
+            printed = text.len();
+        }
+        ControlFlow::Continue(())
+    });
+    println!();
 }
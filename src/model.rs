@@ -1,14 +1,82 @@
-use std::fs::File;
 use std::vec;
 
-use crate::config::LlamaConfigJson;
-use crate::kvcache::KVCache;
+use crate::backend::{Cpu, Ops, Weight};
+use crate::config::{Architecture, ConfigError, GenerationConfigJson, LlamaConfigJson};
+use crate::generation::{Generation, GenerationConfig, StopCriteria, StopReason, TokenLogprob};
+use crate::gguf::GgufFile;
+use crate::kvcache::{CacheManager, KVBatch, KVCache, KVStore, PagedKVCache, Q8KVCache, SeqId, SlidingKVCache};
 use crate::operators as OP;
-use crate::params::LLamaParams;
-use crate::tensor::Tensor;
-use safetensors::SafeTensors;
+use crate::params::{LLamaParams, LoadProgress, QuantizeOnLoad};
+use crate::pytorch::PytorchCheckpoint;
+use crate::sampling::{guide, log_softmax, top_k, Sampler};
+use crate::tensor::{Tensor, TensorPool};
+use std::ops::ControlFlow;
 use std::path::Path;
-pub struct Llama<T> {
+use std::sync::Mutex;
+// How Llama::embed() turns the hidden states of every token into one vector
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pooling {
+    Last, // the last token's, which attended to all the others
+    Mean, // the average over all tokens
+}
+
+impl Pooling {
+    // (d,) vector of the (tokens, d) `states`
+    pub fn pool(self, states: &Tensor<f32>) -> Tensor<f32> {
+        let (n, d) = (states.shape()[0], states.shape()[1]);
+        match self {
+            Pooling::Last => states.slice((n - 1) * d, &vec![d]).contiguous(),
+            Pooling::Mean => {
+                let mut mean = states.mean_axis(0, false);
+                mean.reshape(&vec![d]);
+                mean
+            }
+        }
+    }
+}
+
+// Result of Llama::perplexity(): `nll[i]` is the negative log likelihood (in nats) of token i + 1 given
+// the ones before it in its window
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Perplexity {
+    pub nll: Vec<f32>,
+}
+
+impl Perplexity {
+    pub fn mean_nll(&self) -> f32 {
+        self.nll.iter().sum::<f32>() / self.nll.len().max(1) as f32
+    }
+
+    // exp of the mean NLL, lower is better
+    pub fn perplexity(&self) -> f32 {
+        self.mean_nll().exp()
+    }
+}
+
+// Bytes a model and its caches hold, see Llama::memory_stats()
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryStats {
+    pub weights: usize,         // parameters, memory-mapped ones included
+    pub scratch: usize,         // forward-pass buffers kept for reuse, grows with the longest chunk seen
+    pub kv_caches: Vec<usize>,  // each cache passed in, e.g. one per sequence
+    pub kv_per_position: usize, // f32 keys and values of one position in every layer
+}
+
+impl MemoryStats {
+    pub fn total(&self) -> usize {
+        self.weights + self.scratch + self.kv_caches.iter().sum::<usize>()
+    }
+
+    // Further f32 cache positions (over all sequences) that fit in `budget` bytes on top of total()
+    pub fn positions_within(&self, budget: usize) -> usize {
+        budget.saturating_sub(self.total()) / self.kv_per_position.max(1)
+    }
+}
+
+// Generic over the operator backend `O`, the operators.rs kernels unless with_ops() picks another, and
+// the storage `W` of the projection matrices, e.g. Int8Tensor from from_safetensors_quantized()
+pub struct Llama<T, O = Cpu, W = Tensor<T>> {
+    architecture: Architecture, // Llama, Mistral or Gemma
     vocab: usize,           // vocab size
     n_layers: usize,        // number of layers
     n_q_h: usize,           // number of heads for q
@@ -18,168 +86,906 @@ pub struct Llama<T> {
     di: usize,              // dimension of intermediate states
     eps: f32,               // epsilon for RMS normalization
     rope_theta: f32,        // rope theta for rope initialization
+    rope_scaling: OP::RopeScaling, // long-context position scaling
+    rotary_dim: usize,      // leading dims of each head that RoPE rotates, dqkv unless partial
+    sliding_window: Option<usize>, // attend to at most this many past positions (Mistral)
+    activation: OP::Activation, // applied to the FFN gate, SiLU for Llama
+    attn_logit_softcapping: Option<f32>, // tanh cap on attention scores
+    final_logit_softcapping: Option<f32>, // tanh cap on the output logits
+    embed_scale: Option<f32>, // embeddings are multiplied by this (sqrt(d) for Gemma)
     max_seq_len: usize,     // maximum sequence length
-    params: LLamaParams<T>, // trained weights of this model
+    params: LLamaParams<T, W>, // trained weights of this model
     bos_token_id: u32,      // start token id
     eos_token_id: u32,      // end token id
+    eos_token_ids: Vec<u32>, // every id that ends generation, eos_token_id first
+    generation_config: GenerationConfigJson, // released sampling defaults
+    pool: Mutex<TensorPool<T>>, // scratch buffers reused across forward passes
+    ops: O,                 // backend that runs the operators
 }
 
 impl Llama<f32> {
     pub fn from_safetensors(model_dir: impl AsRef<Path>) -> Self {
-        let config = File::open(model_dir.as_ref().join("config.json")).unwrap();
-        let config: LlamaConfigJson = serde_json::from_reader(config).unwrap();
-        let model_file = std::fs::read(model_dir.as_ref().join("model.safetensors")).unwrap();
-        let safetensor = SafeTensors::deserialize(&model_file).unwrap();
-        let params = LLamaParams::from_safetensors(&safetensor, &config);
+        Self::from_safetensors_with_progress(model_dir, |_| {})
+    }
 
+    // Like from_safetensors, calling `on_progress` after each tensor so callers can render progress
+    pub fn from_safetensors_with_progress(model_dir: impl AsRef<Path>, on_progress: impl FnMut(&LoadProgress)) -> Self {
+        Self::from_dir(model_dir, on_progress).unwrap_or_else(|e| panic!("Invalid config.json: {e}"))
+    }
+
+    // Read and check `model_dir/config.json`, then load the weights in whichever format the directory holds.
+    // Architectures this implementation can't run are an error rather than weights read with the wrong layout
+    pub fn from_dir(model_dir: impl AsRef<Path>, on_progress: impl FnMut(&LoadProgress)) -> Result<Self, ConfigError> {
+        let model_dir = model_dir.as_ref();
+        let path = model_dir.join("config.json");
+        let config = LlamaConfigJson::from_file(&path)
+            .map_err(|e| ConfigError::Io { path, message: e.to_string() })?;
+        config.validate()?;
+        let has_safetensors = model_dir.join("model.safetensors").exists()
+            || model_dir.join("model.safetensors.index.json").exists();
+        let params = if !has_safetensors && model_dir.join("pytorch_model.bin").exists() {
+            // older repos only ship a torch.save state dict
+            let mut checkpoint = PytorchCheckpoint::open(model_dir.join("pytorch_model.bin")).unwrap();
+            LLamaParams::<f32>::from_pytorch_with_progress(&mut checkpoint, &config, on_progress)
+                .unwrap_or_else(|e| panic!("{e}"))
+        } else if config.quantization_config.is_some() {
+            // GPTQ/AWQ export, dequantized to f32 while loading
+            LLamaParams::<f32>::from_quantized_dir_with_progress(model_dir, &config, on_progress)
+                .unwrap_or_else(|e| panic!("{e}"))
+        } else {
+            LLamaParams::<f32>::from_dir_with_progress(model_dir, &config, on_progress)
+        };
+        Ok(Self::from_params(&config, params).with_generation_config(GenerationConfigJson::from_dir(model_dir)))
+    }
+
+    // Load a llama.cpp GGUF checkpoint, the config comes from the file's metadata
+    pub fn from_gguf(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let gguf = GgufFile::open(path)?;
+        let config = gguf.config()?;
+        config.validate().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let params = LLamaParams::<f32>::from_gguf(&gguf, &config)?;
+        Ok(Self::from_params(&config, params))
+    }
+
+    // Randomly initialized model for `config`, see LLamaParams::random
+    pub fn random(config: &LlamaConfigJson, seed: u64) -> Self {
+        Self::from_params(config, LLamaParams::random(config, seed))
+    }
+}
+
+impl<W: Weight + QuantizeOnLoad> Llama<f32, Cpu, W> {
+    // from_safetensors() with the projection matrices converted to `W` as they are loaded, e.g.
+    // `Llama::<f32, Cpu, Int8Tensor>::from_safetensors_quantized(dir)` for a quarter of the f32 weights
+    pub fn from_safetensors_quantized(model_dir: impl AsRef<Path>) -> Self {
+        let model_dir = model_dir.as_ref();
+        let config = LlamaConfigJson::from_file(model_dir.join("config.json")).unwrap();
+        config.validate().unwrap_or_else(|e| panic!("Invalid config.json: {e}"));
+        let params = LLamaParams::<f32, W>::from_dir_quantized(model_dir, &config);
+        Self::from_params(&config, params).with_generation_config(GenerationConfigJson::from_dir(model_dir))
+    }
+}
+
+impl<W: Weight> Llama<f32, Cpu, W> {
+    fn from_params(config: &LlamaConfigJson, mut params: LLamaParams<f32, W>) -> Self {
+        // a pipeline stage (LLamaParams::from_dir_layers) has no embedding-to-logits path on its own
+        assert!(
+            params.layers == (0..config.num_hidden_layers),
+            "Llama needs every decoder layer, these params only hold layers {:?} of {}",
+            params.layers,
+            config.num_hidden_layers
+        );
+        let architecture = config.architecture().unwrap_or_else(|e| panic!("{e}"));
+        if architecture == Architecture::Gemma {
+            // Gemma's RMSNorm multiplies by (1 + w), fold the 1 in once so the usual kernel applies
+            let plus_one = |w: &Tensor<f32>| Tensor::new(w.data().iter().map(|w| 1. + w).collect(), w.shape());
+            for w in params.rms_att_w.iter_mut().chain(&mut params.rms_ffn_w).chain([&mut params.rms_out_w]) {
+                *w = plus_one(w);
+            }
+        }
         Self {
+            architecture,
             vocab: config.vocab_size,
             n_layers: config.num_hidden_layers,
             n_q_h: config.num_attention_heads,
             n_kv_h: config.num_key_value_heads,
             d: config.hidden_size,
-            dqkv: config.hidden_size / config.num_attention_heads,
+            dqkv: config.head_dim(),
             di: config.intermediate_size,
             eps: config.rms_norm_eps,
             rope_theta: config.rope_theta,
+            rope_scaling: config.rope_scaling().unwrap_or_else(|e| panic!("{e}")),
+            rotary_dim: config.rotary_dim(),
+            activation: config.activation().unwrap_or_else(|e| panic!("{e}")),
+            sliding_window: config.sliding_window,
+            attn_logit_softcapping: config.attn_logit_softcapping,
+            final_logit_softcapping: config.final_logit_softcapping,
+            embed_scale: (architecture == Architecture::Gemma).then(|| (config.hidden_size as f32).sqrt()),
             max_seq_len: config.max_position_embeddings,
-            params: params,
+            params,
             bos_token_id: config.bos_token_id,
-            eos_token_id: config.eos_token_id,
+            eos_token_id: config.eos_token_id.first(),
+            eos_token_ids: config.eos_token_id.to_vec(),
+            generation_config: GenerationConfigJson::default(),
+            pool: Mutex::new(TensorPool::new()),
+            ops: Cpu,
+        }
+    }
+
+    // The same model running its operators on `ops` instead of the CPU kernels
+    pub fn with_ops<O: Ops>(self, ops: O) -> Llama<f32, O, W> {
+        Llama {
+            architecture: self.architecture,
+            vocab: self.vocab,
+            n_layers: self.n_layers,
+            n_q_h: self.n_q_h,
+            n_kv_h: self.n_kv_h,
+            d: self.d,
+            dqkv: self.dqkv,
+            di: self.di,
+            eps: self.eps,
+            rope_theta: self.rope_theta,
+            rope_scaling: self.rope_scaling,
+            rotary_dim: self.rotary_dim,
+            activation: self.activation,
+            sliding_window: self.sliding_window,
+            attn_logit_softcapping: self.attn_logit_softcapping,
+            final_logit_softcapping: self.final_logit_softcapping,
+            embed_scale: self.embed_scale,
+            max_seq_len: self.max_seq_len,
+            params: self.params,
+            bos_token_id: self.bos_token_id,
+            eos_token_id: self.eos_token_id,
+            eos_token_ids: self.eos_token_ids,
+            generation_config: self.generation_config,
+            pool: self.pool,
+            ops,
+        }
+    }
+}
+
+impl<O: Ops, W: Weight> Llama<f32, O, W> {
+    pub fn ops(&self) -> &O {
+        &self.ops
+    }
+
+
+    // Adopt the sampling defaults and extra eos ids of a generation_config.json
+    pub fn with_generation_config(mut self, generation_config: GenerationConfigJson) -> Self {
+        for id in generation_config.eos_token_id.iter().flat_map(|ids| ids.to_vec()) {
+            if !self.eos_token_ids.contains(&id) {
+                self.eos_token_ids.push(id);
+            }
+        }
+        self.generation_config = generation_config;
+        self
+    }
+
+    pub fn generation_config(&self) -> &GenerationConfigJson {
+        &self.generation_config
+    }
+
+    pub fn architecture(&self) -> Architecture {
+        self.architecture
+    }
+
+    // RoPE base the model was trained with, config.json's rope_theta (1e4 when absent)
+    pub fn rope_theta(&self) -> f32 {
+        self.rope_theta
+    }
+
+    // Replace the RoPE base, e.g. to experiment with context extension without editing config.json
+    pub fn with_rope_theta(mut self, rope_theta: f32) -> Self {
+        self.rope_theta = rope_theta;
+        self
+    }
+
+    // Whether `token` ends generation, checking config.json's eos id and all from generation_config.json
+    pub fn is_eos(&self, token: u32) -> bool {
+        self.eos_token_ids.contains(&token)
+    }
+
+    // Every id that ends generation, config.json's eos id first
+    pub fn eos_token_ids(&self) -> &[u32] {
+        &self.eos_token_ids
+    }
+
+    // Token an empty prompt starts from
+    pub fn bos_token_id(&self) -> u32 {
+        self.bos_token_id
+    }
+
+    // Positions the model was trained for, the capacity of new_cache()
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    // What the weights, the scratch buffers and `caches` occupy, to size max_seq_len or the number of
+    // concurrent sequences before running out of memory
+    pub fn memory_stats(&self, caches: &[&dyn KVStore]) -> MemoryStats {
+        MemoryStats {
+            weights: self.params.nbytes(),
+            scratch: self.pool.lock().unwrap().capacity() * std::mem::size_of::<f32>(),
+            kv_caches: caches.iter().map(|cache| cache.nbytes()).collect(),
+            kv_per_position: 2 * self.n_layers * self.n_kv_h * self.dqkv * std::mem::size_of::<f32>(),
         }
     }
 
     pub fn new_cache(&self) -> KVCache<f32> {
+        self.new_cache_of()
+    }
+
+    // Cache storing keys and values as T, e.g. f16 or bf16 for half the memory of new_cache()
+    pub fn new_cache_of<C: Default + Copy>(&self) -> KVCache<C> {
         KVCache::new(self.n_layers, self.max_seq_len, self.n_kv_h * self.dqkv, 0)
     }
 
-    pub fn forward(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
-        let seq_len = input.size();
-        let past_seq_len = cache.len();
-        cache.increment(seq_len);
-        let total_seq_len = past_seq_len + seq_len;
-        let n_groups = self.n_q_h / self.n_kv_h;
+    // Fixed-size cache holding only the last sliding_window positions (plus room for forward passes
+    // of up to `max_chunk` tokens), for models with a sliding window; see SlidingKVCache
+    pub fn new_sliding_cache(&self, max_chunk: usize) -> SlidingKVCache {
+        let window = self.sliding_window.expect("the model attends to the whole context, use new_cache()");
+        SlidingKVCache::new(self.n_layers, window, self.n_kv_h * self.dqkv, max_chunk)
+    }
+
+    // Manager for many concurrent sequences sharing at most `max_blocks` blocks of `block_size` positions
+    pub fn new_cache_manager(&self, block_size: usize, max_blocks: usize) -> CacheManager {
+        CacheManager::new(PagedKVCache::new(self.n_layers, self.n_kv_h * self.dqkv, block_size, max_blocks))
+    }
+
+    // Int8 cache with per-block scales, see Q8KVCache
+    pub fn new_q8_cache(&self) -> Q8KVCache {
+        Q8KVCache::new(self.n_layers, self.max_seq_len, self.n_kv_h * self.dqkv)
+    }
+
+    // Logits for the token after `input`, which continues whatever `cache` holds (a KVCache or a
+    // PagedKVCache sequence)
+    pub fn forward(&self, input: &Tensor<u32>, cache: &mut impl KVStore) -> Tensor<f32> {
+        let cache: &mut dyn KVStore = cache;
+        self.forward_batch(&[input.data()], &mut [cache][..])
+    }
+
+    // forward() of several sequences at once, `inputs[i]` continuing cache i of `caches` (a slice of
+    // KVStores or a PagedKVCache::batch()). The inputs are packed into one (total tokens, d) batch so
+    // every projection and MLP is a single matmul over all of them, only RoPE and attention run per
+    // sequence. Returns (batch, vocab) logits, row i for `inputs[i]`
+    pub fn forward_batch(&self, inputs: &[&[u32]], caches: &mut (impl KVBatch + ?Sized)) -> Tensor<f32> {
+        self.forward_packed(inputs, caches, false, None)
+    }
+
+    // Like forward(), returning the (seq, vocab) logits of every position of `input` rather than just
+    // the last one, e.g. to score a text or verify several proposed tokens at once
+    pub fn forward_all(&self, input: &Tensor<u32>, cache: &mut impl KVStore) -> Tensor<f32> {
+        let cache: &mut dyn KVStore = cache;
+        self.forward_packed(&[input.data()], &mut [cache][..], true, None)
+    }
+
+    // Hidden states of `tokens` run from an empty cache: the embeddings, then the output of every layer,
+    // the last one after the final norm (what the LM head sees), like Hugging Face's hidden_states.
+    // n_layers + 1 tensors of (tokens, d)
+    pub fn hidden_states(&self, tokens: &[u32]) -> Vec<Tensor<f32>> {
+        let mut states = Vec::with_capacity(self.n_layers + 1);
+        let cache: &mut dyn KVStore = &mut self.new_cache();
+        self.forward_packed(&[tokens], &mut [cache][..], false, Some(&mut states));
+        let last = states.pop().unwrap();
+        let mut normed = Tensor::default(last.shape());
+        self.ops.rms_norm(&mut normed, &last, &self.params.rms_out_w, self.eps);
+        states.push(normed);
+        states
+    }
+
+    // One (d,) vector for `tokens`, e.g. for retrieval: the final hidden states pooled with `pooling`.
+    // Pool an earlier entry of hidden_states() for another layer's
+    pub fn embed(&self, tokens: &[u32], pooling: Pooling) -> Tensor<f32> {
+        pooling.pool(self.hidden_states(tokens).last().unwrap())
+    }
+
+    // How well the model predicts `token_ids`, evaluated with a sliding window of max_seq_len tokens that
+    // moves `stride` tokens at a time. Each token is scored once, in the first window that predicts it, so
+    // a smaller stride gives every token more context at the cost of more forward passes. Windows must
+    // overlap for every token to be predicted, stride = max_seq_len - 1 is the cheapest. Windows run
+    // PREFILL_CHUNK tokens per pass
+    pub fn perplexity(&self, token_ids: &[u32], stride: usize) -> Perplexity {
+        assert!(token_ids.len() >= 2, "need at least two tokens to score");
+        assert!(stride > 0 && stride < self.max_seq_len, "stride must be in 1..{}", self.max_seq_len);
+        let mut nll = Vec::with_capacity(token_ids.len() - 1);
+        let mut begin = 0;
+        loop {
+            let end = (begin + self.max_seq_len).min(token_ids.len());
+            // the logits at position p predict token p + 1, tokens up to nll.len() were scored already
+            let mut cache = self.new_cache();
+            for start in (begin..end - 1).step_by(PREFILL_CHUNK) {
+                let chunk = &token_ids[start..(start + PREFILL_CHUNK).min(end - 1)];
+                let logits = self.forward_all(&Tensor::new(chunk.to_vec(), &vec![chunk.len()]), &mut cache);
+                for (i, row) in logits.data().chunks(self.vocab).enumerate() {
+                    let target = start + i + 1;
+                    if target > nll.len() {
+                        nll.push(-log_softmax(row)[token_ids[target] as usize]);
+                    }
+                }
+            }
+            if end == token_ids.len() {
+                break;
+            }
+            begin += stride;
+        }
+        Perplexity { nll }
+    }
+
+    // forward_batch(), with logits for every packed position if `all_positions` and a copy of the
+    // packed residual stream after the embedding and every layer pushed to `states`
+    fn forward_packed(
+        &self,
+        inputs: &[&[u32]],
+        caches: &mut (impl KVBatch + ?Sized),
+        all_positions: bool,
+        mut states: Option<&mut Vec<Tensor<f32>>>,
+    ) -> Tensor<f32> {
+        let n_caches = caches.num_sequences();
+        assert_eq!(inputs.len(), n_caches, "{} inputs for {n_caches} caches", inputs.len());
+        assert!(inputs.iter().all(|input| !input.is_empty()), "empty input");
+        let batch = inputs.len();
+        let seq_len: usize = inputs.iter().map(|input| input.len()).sum();
+        // first packed row and number of rows of every sequence, and the positions its cache already holds
+        let spans: Vec<(usize, usize)> = inputs
+            .iter()
+            .scan(0, |row, input| {
+                *row += input.len();
+                Some((*row - input.len(), input.len()))
+            })
+            .collect();
+        let mut past_lens = Vec::with_capacity(batch);
+        for i in 0..batch {
+            caches.with_sequence(i, &mut |cache| past_lens.push(cache.len()));
+        }
 
-        // Some pre-allocated buffers that will be reused
-        let mut residual = Tensor::<f32>::default(&vec![seq_len, self.d]);
-        let mut hidden_states = Tensor::<f32>::default(&vec![seq_len, self.d]);
-        let mut q_buf = Tensor::<f32>::default(&vec![seq_len, self.n_q_h * self.dqkv]);
-        let mut att_scores =
-            Tensor::<f32>::default(&vec![self.n_kv_h, n_groups, seq_len, total_seq_len]);
-        let mut gate_buf = Tensor::<f32>::default(&vec![seq_len, self.di]);
-        let mut up_buf = Tensor::<f32>::default(&vec![seq_len, self.di]);
+        // Some pre-allocated buffers that will be reused, borrowed from the pool so
+        // repeated decode steps don't reallocate them
+        let mut residual = self.take(&vec![seq_len, self.d]);
+        let mut hidden_states = self.take(&vec![seq_len, self.d]);
+        // attention output, wider or narrower than d when head_dim is set explicitly
+        let mut att_out = self.take(&vec![seq_len, self.n_q_h * self.dqkv]);
+        let mut q_buf = self.take(&vec![seq_len, self.n_q_h * self.dqkv]);
+        let mut k_buf = self.take(&vec![seq_len, self.n_kv_h * self.dqkv]);
+        let mut v_buf = self.take(&vec![seq_len, self.n_kv_h * self.dqkv]);
+        let mut gate_buf = self.take(&vec![seq_len, self.di]);
+        let mut up_buf = self.take(&vec![seq_len, self.di]);
 
         // Computation Starts Here
         // Embedding lookup
-        OP::gather(&mut residual, input, &self.params.embedding_table);
+        let input = Tensor::new(inputs.concat(), &vec![seq_len]);
+        self.ops.gather(&mut residual, &input, &self.params.embedding_table);
+        if let Some(scale) = self.embed_scale {
+            residual.scale_(scale);
+        }
+        let mut save = |residual: &Tensor<f32>| {
+            if let Some(states) = &mut states {
+                states.push(Tensor::new(residual.data().to_vec(), residual.shape()));
+            }
+        };
+        save(&residual);
 
         for layer in 0..self.n_layers {
-            OP::rms_norm(
+            self.ops.rms_norm(
                 &mut hidden_states,
                 &residual,
                 &self.params.rms_att_w[layer],
                 self.eps,
             );
 
-            let q = (&mut q_buf).reshape(&vec![seq_len, self.n_q_h * self.dqkv]); // (seq, n_h * dqkv)
-            let k = &mut cache.k_cache(layer, past_seq_len); // (seq, n_kv_h * dqkv)
-            let v = &mut cache.v_cache(layer, past_seq_len); // (seq, n_kv_h * dqkv)
-            OP::matmul_transb(q, 0., &hidden_states, &self.params.wq[layer], 1.0);
-            OP::matmul_transb(k, 0., &hidden_states, &self.params.wk[layer], 1.0);
-            OP::matmul_transb(v, 0., &hidden_states, &self.params.wv[layer], 1.0);
-            OP::rope(
-                q.reshape(&vec![seq_len, self.n_q_h, self.dqkv]),
-                past_seq_len,
-                self.rope_theta,
-            );
-            OP::rope(
-                k.reshape(&vec![seq_len, self.n_kv_h, self.dqkv]),
-                past_seq_len,
-                self.rope_theta,
-            );
+            let q = q_buf.reshape(&vec![seq_len, self.n_q_h * self.dqkv]); // (seq, n_h * dqkv)
+            let k = k_buf.reshape(&vec![seq_len, self.n_kv_h * self.dqkv]); // (seq, n_kv_h * dqkv)
+            let v = &mut v_buf; // (seq, n_kv_h * dqkv)
+            self.params.wq[layer].matmul_transb(&self.ops, q, 0., &hidden_states, 1.0);
+            self.params.wk[layer].matmul_transb(&self.ops, k, 0., &hidden_states, 1.0);
+            self.params.wv[layer].matmul_transb(&self.ops, v, 0., &hidden_states, 1.0);
+            self.rope_rows(q, self.n_q_h, &spans, &past_lens);
+            self.rope_rows(k, self.n_kv_h, &spans, &past_lens);
 
-            let full_k = &mut cache.k_cache(layer, 0); // (total_seq, n_kv_h * dqkv)
-            let full_v = &mut cache.v_cache(layer, 0); // (total_seq, n_kv_h * dqkv)
+            for i in 0..batch {
+                let ((start, len), past_seq_len) = (spans[i], past_lens[i]);
+                let rows = |x: &Tensor<f32>| {
+                    let width = x.size() / seq_len;
+                    x.slice(start * width, &vec![len, width])
+                };
+                // with a sliding window the oldest query still only needs the last `window` keys,
+                // everything before that is skipped rather than masked
+                let kv_start = self.sliding_window.map_or(0, |w| (past_seq_len + 1).saturating_sub(w));
+                let mut kv = None;
+                caches.with_sequence(i, &mut |cache| {
+                    cache.append(layer, &rows(k), &rows(v));
+                    kv = Some(cache.view(layer, kv_start..past_seq_len + len));
+                });
+                // (total_seq - kv_start, n_kv_h * dqkv) each
+                let (full_k, full_v) = &kv.unwrap();
 
-            todo!("self_attention(...)");
-            todo!("down_proj matmul and add residual");
+                // streams over the cached keys, the (heads, seq, kv_len) scores are never materialized
+                let (window, softcap) = (self.sliding_window, self.attn_logit_softcapping);
+                if batch == 1 {
+                    self.ops.flash_attention(&mut att_out, q, full_k, full_v, window, softcap);
+                } else {
+                    let mut out = self.take(&vec![len, self.n_q_h * self.dqkv]);
+                    let q = q.slice(start * self.n_q_h * self.dqkv, &vec![len, self.n_q_h, self.dqkv]);
+                    self.ops.flash_attention(&mut out, &q, full_k, full_v, window, softcap);
+                    let width = self.n_q_h * self.dqkv;
+                    att_out.make_mut()[start * width..(start + len) * width].copy_from_slice(out.data());
+                    self.recycle(out);
+                }
+            }
+            // residual += o_proj(attention)
+            self.params.wo[layer].matmul_transb(&self.ops, &mut residual, 1.0, &att_out, 1.0);
 
-            todo!("mlp(...)");
+            mlp(
+                &self.ops,
+                &mut residual,
+                &mut hidden_states,
+                &mut gate_buf,
+                &mut up_buf,
+                &self.params.w_up[layer],
+                &self.params.w_down[layer],
+                &self.params.w_gate[layer],
+                &self.params.rms_ffn_w[layer],
+                self.eps,
+                self.activation,
+            );
+            save(&residual);
         }
 
-        // No matter what seq_len, the output is always a 1D vector of length vocab,
-        // which contains the probabilities for the next token.
-        let mut logits = Tensor::<f32>::default(&vec![1, self.vocab]);
-        let mut hidden_states = hidden_states.slice((seq_len - 1) * self.d, &vec![1, self.d]);
-        let residual = residual.slice((seq_len - 1) * self.d, &vec![self.d]);
+        // No matter what seq_len, the output is one vector of length vocab per sequence (or position),
+        // which contains the probabilities for its next token.
+        let out_rows: Vec<usize> = match all_positions {
+            true => (0..seq_len).collect(),
+            false => spans.iter().map(|&(start, len)| start + len - 1).collect(),
+        };
+        let mut logits = self.take(&vec![out_rows.len(), self.vocab]);
+        let mut last_residual = self.take(&vec![out_rows.len(), self.d]);
+        let mut last_hidden = self.take(&vec![out_rows.len(), self.d]);
+        let last = last_residual.make_mut();
+        for (i, &row) in out_rows.iter().enumerate() {
+            last[i * self.d..(i + 1) * self.d].copy_from_slice(&residual.data()[row * self.d..(row + 1) * self.d]);
+        }
 
-        OP::rms_norm(
-            &mut hidden_states,
-            &residual,
+        self.ops.rms_norm(
+            &mut last_hidden,
+            &last_residual,
             &self.params.rms_out_w,
             self.eps,
         );
 
-        OP::matmul_transb(&mut logits, 0., &hidden_states, &self.params.lm_head, 1.0);
+        self.params.lm_head.matmul_transb(&self.ops, &mut logits, 0., &last_hidden, 1.0);
+        if let Some(cap) = self.final_logit_softcapping {
+            self.ops.softcap(&mut logits, cap);
+        }
+
+        for buf in [residual, hidden_states, att_out, q_buf, k_buf, v_buf, gate_buf, up_buf, last_residual, last_hidden] {
+            self.recycle(buf);
+        }
+
+        logits
+    }
+
+    // A zeroed scratch buffer from the pool. The lock is only held while taking or returning one, so
+    // forward passes on other threads and memory_stats() never wait for a whole pass
+    fn take(&self, shape: &Vec<usize>) -> Tensor<f32> {
+        self.pool.lock().unwrap().take(shape)
+    }
+
+    fn recycle(&self, buf: Tensor<f32>) {
+        self.pool.lock().unwrap().recycle(buf)
+    }
 
+    // Rotate the packed (seq, n_heads * dqkv) queries or keys of every sequence by its own positions,
+    // which start where its cache ends, leaving `x` shaped (seq, n_heads, dqkv)
+    fn rope_rows(&self, x: &mut Tensor<f32>, n_heads: usize, spans: &[(usize, usize)], past_lens: &[usize]) {
+        let width = n_heads * self.dqkv;
+        let rope = |y: &mut Tensor<f32>, len: usize, past_len: usize| {
+            let y = y.reshape(&vec![len, n_heads, self.dqkv]);
+            self.ops.rope_partial(y, past_len, self.rope_theta, self.rope_scaling, self.rotary_dim);
+        };
+        if let [(_, len)] = spans {
+            return rope(x, *len, past_lens[0]);
+        }
+        for (&(start, len), &past_len) in spans.iter().zip(past_lens) {
+            let rows = start * width..(start + len) * width;
+            let mut y = self.take(&vec![len, width]);
+            y.make_mut().copy_from_slice(&x.data()[rows.clone()]);
+            rope(&mut y, len, past_len);
+            x.make_mut()[rows].copy_from_slice(y.data());
+            self.recycle(y);
+        }
+        x.reshape(&vec![x.size() / width, n_heads, self.dqkv]);
+    }
+
+    // Deterministic decoding keeping the `num_beams` most likely continuations at every step, returning
+    // the best finished one. Scores are log probabilities divided by length^length_penalty (0 prefers
+    // short answers, 1 and above longer ones). Beams live in one paged cache where a beam forked from
+    // another shares its blocks until they diverge
+    pub fn beam_search(&self, token_ids: &[u32], max_len: usize, num_beams: usize, length_penalty: f32) -> Vec<u32> {
+        assert!(num_beams > 0, "num_beams must be at least 1");
+        let prompt = if token_ids.is_empty() { vec![self.bos_token_id] } else { token_ids.to_vec() };
+        // a prompt filling the whole context leaves nothing to decode, so don't prefill it at all
+        let max_len = max_len.min(self.max_seq_len.saturating_sub(prompt.len()));
+        if max_len == 0 {
+            return Vec::new();
+        }
+        const BLOCK_SIZE: usize = 16;
+        // every beam's blocks, plus those of the beams being replaced while the next ones are forked
+        let max_blocks = (2 * num_beams + 1) * (prompt.len() + max_len).div_ceil(BLOCK_SIZE);
+        let mut cache = PagedKVCache::<f32>::new(self.n_layers, self.n_kv_h * self.dqkv, BLOCK_SIZE, max_blocks);
+        let seq = cache.add_sequence();
+        let logits = self.prefill(&prompt, &mut cache.sequence(seq));
+        let mut beams = vec![Beam { seq, tokens: Vec::new(), score: 0., logits }];
+        let normalized = |score: f32, len: usize| score / (len.max(1) as f32).powf(length_penalty);
+        let mut finished: Vec<(Vec<u32>, f32)> = Vec::new();
+
+        while !beams.is_empty() && finished.len() < num_beams {
+            // the best num_beams extensions of every beam are enough to find the best num_beams overall
+            let mut candidates = Vec::new();
+            for (b, beam) in beams.iter().enumerate() {
+                let top = top_k(&log_softmax(beam.logits.data()), num_beams);
+                candidates.extend(top.into_iter().map(|(t, lp)| (b, t, beam.score + lp)));
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+            let mut next = Vec::new();
+            for (b, token, score) in candidates {
+                if next.len() == num_beams {
+                    break;
+                }
+                let tokens = [&beams[b].tokens[..], &[token]].concat();
+                if self.is_eos(token) || tokens.len() >= max_len {
+                    let score = normalized(score, tokens.len());
+                    finished.push((tokens, score));
+                } else {
+                    next.push((cache.fork(beams[b].seq), tokens, score));
+                }
+            }
+            beams.iter().for_each(|beam| cache.free_sequence(beam.seq));
+            beams = next
+                .into_iter()
+                .map(|(seq, tokens, score)| {
+                    let input = Tensor::new(vec![*tokens.last().unwrap()], &vec![1]);
+                    let logits = self.forward(&input, &mut cache.sequence(seq));
+                    Beam { seq, tokens, score, logits }
+                })
+                .collect();
+        }
+        let active = beams.iter().map(|beam| (beam.tokens.clone(), normalized(beam.score, beam.tokens.len())));
+        let best = finished.into_iter().chain(active);
+        best.max_by(|a, b| a.1.total_cmp(&b.1)).map(|(tokens, _)| tokens).unwrap_or_default()
+    }
+
+    // Ingest a whole prompt, PREFILL_CHUNK tokens per forward pass, and return the logits for the token
+    // after it. Every layer's keys and values of a chunk go into `cache` with a single copy
+    pub fn prefill(&self, prompt: &[u32], cache: &mut impl KVStore) -> Tensor<f32> {
+        self.prefill_chunked(prompt, cache, PREFILL_CHUNK, |_| ControlFlow::Continue(())).unwrap()
+    }
+
+    // prefill() with at most `chunk_size` tokens per forward pass, which bounds the activations (and
+    // scratch buffers) of a long prompt to those of `chunk_size` tokens. `on_chunk` is told how many
+    // prompt tokens are in `cache` after every chunk, ControlFlow::Break stops there and returns None;
+    // prefilling `prompt[cache.len()..]` later picks up where it left off
+    pub fn prefill_chunked(
+        &self,
+        prompt: &[u32],
+        cache: &mut impl KVStore,
+        chunk_size: usize,
+        mut on_chunk: impl FnMut(usize) -> ControlFlow<()>,
+    ) -> Option<Tensor<f32>> {
+        assert!(!prompt.is_empty(), "empty prompt");
+        assert!(chunk_size > 0, "chunk_size must be at least 1");
+        let mut logits = None;
+        for (i, chunk) in prompt.chunks(chunk_size).enumerate() {
+            logits = Some(self.forward(&Tensor::new(chunk.to_vec(), &vec![chunk.len()]), cache));
+            if on_chunk(i * chunk_size + chunk.len()).is_break() {
+                return None;
+            }
+        }
         logits
     }
 
-    pub fn generate(
+    // Continue `token_ids` (just bos if empty) as `config` describes, until an eos id, one of its stop
+    // conditions or max_new_tokens, reporting which condition fired
+    pub fn generate(&self, token_ids: &[u32], config: &GenerationConfig) -> Generation {
+        self.generate_stream(token_ids, config, |_| ControlFlow::Continue(()))
+    }
+
+    // Like generate(), handing every token to `on_token` as soon as it is sampled, e.g. to print it;
+    // ControlFlow::Break ends generation after that token. Tokens of a stop string are streamed before
+    // it is complete, only the returned Generation has them cut
+    pub fn generate_stream(
         &self,
         token_ids: &[u32],
-        max_len: usize,
-        top_p: f32,
-        top_k: u32,
-        temperature: f32,
-    ) -> Vec<u32>{
-        let mut result = Vec::<u32>::new();
-        
-        todo!("实现文本生成");
-        
-        result
+        config: &GenerationConfig,
+        on_token: impl FnMut(u32) -> ControlFlow<()>,
+    ) -> Generation {
+        let mut sampler = config.sampler(token_ids.len().max(1), &self.eos_token_ids);
+        self.generate_with(token_ids, config, &mut sampler, on_token)
+    }
+
+    // Like generate_stream(), picking each token with `sampler`'s processor chain instead of the one
+    // `config` describes, which then only supplies the length limits and stop criteria
+    pub fn generate_with(
+        &self,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+        sampler: &mut Sampler,
+        mut on_token: impl FnMut(u32) -> ControlFlow<()>,
+    ) -> Generation {
+        let stop = &config.stop;
+        let mut cache = self.new_cache();
+        let mut tokens = if token_ids.is_empty() { vec![self.bos_token_id] } else { token_ids.to_vec() };
+        let prompt_len = tokens.len();
+        let negative_len = if config.negative_prompt.is_empty() { 1 } else { config.negative_prompt.len() };
+        // a prompt that fills the context still yields the token after it, a longer one does not fit at all
+        if prompt_len > cache.capacity() || (config.guidance_scale != 1. && negative_len > cache.capacity()) {
+            let logprobs = config.logprobs.map(|_| Vec::new());
+            return Generation::finish(Vec::new(), logprobs, stop, StopReason::ContextFull);
+        }
+        let mut logits = self.prefill(&tokens, &mut cache);
+        // with guidance a second sequence continues the negative prompt with the same tokens
+        let mut negative = (config.guidance_scale != 1.).then(|| {
+            let prompt = match config.negative_prompt.is_empty() {
+                true => &tokens[prompt_len - 1..],
+                false => &config.negative_prompt[..],
+            };
+            let mut negative_cache = self.new_cache();
+            let negative_logits = self.prefill(prompt, &mut negative_cache);
+            (negative_cache, negative_logits)
+        });
+        let mut logprobs = config.logprobs.map(|_| Vec::new());
+        // sample from the logits of the last position, then feed the token back until a stop condition
+        let reason = loop {
+            if tokens.len() - prompt_len >= config.max_new_tokens {
+                break StopReason::MaxTokens;
+            }
+            // with guidance the token, and its logprobs, come from the guided distribution
+            let guided = negative.as_ref().map(|(_, uncond)| {
+                Tensor::new(guide(logits.data(), uncond.data(), config.guidance_scale), &vec![self.vocab])
+            });
+            let scores = guided.as_ref().unwrap_or(&logits);
+            let next = sampler.sample(scores, &tokens);
+            tokens.push(next);
+            if let (Some(logprobs), Some(top_n)) = (&mut logprobs, config.logprobs) {
+                logprobs.push(TokenLogprob::new(next, scores.data(), top_n));
+            }
+            if on_token(next).is_break() {
+                break StopReason::Cancelled;
+            }
+            if let Some(reason) = self.stop_reason(&tokens[prompt_len..], stop) {
+                break reason;
+            }
+            if cache.len() >= cache.capacity() || negative.as_ref().is_some_and(|(c, _)| c.len() >= c.capacity()) {
+                break StopReason::ContextFull;
+            }
+            match &mut negative {
+                // both sequences in one batched pass
+                Some((negative_cache, uncond)) => {
+                    let mut caches: [&mut dyn KVStore; 2] = [&mut cache, negative_cache];
+                    let both = self.forward_batch(&[&[next], &[next]], &mut caches[..]);
+                    logits = both.slice(0, &vec![1, self.vocab]);
+                    *uncond = both.slice(self.vocab, &vec![1, self.vocab]);
+                }
+                None => logits = self.forward(&Tensor::new(vec![next], &vec![1]), &mut cache),
+            }
+        };
+        Generation::finish(tokens.split_off(prompt_len), logprobs, stop, reason)
+    }
+
+    // generate() drafting with the smaller `draft` model: every round it proposes up to `num_draft`
+    // tokens, one target forward pass scores all of them, and each is kept with probability
+    // min(1, p/q) of the target's over the draft's processed distribution. The first rejected one is
+    // replaced by a draw from what is left of the target's, and when all are kept the same pass yields
+    // one more token, so the output follows the target's distribution exactly (greedy decoding gives
+    // the same tokens as generate()) at up to num_draft + 1 tokens per target pass. Both models need
+    // the same vocabulary, and `config`'s processors must not keep state between tokens
+    pub fn generate_speculative<D: Ops, DW: Weight>(
+        &self,
+        draft: &Llama<f32, D, DW>,
+        token_ids: &[u32],
+        config: &GenerationConfig,
+        num_draft: usize,
+    ) -> Generation {
+        assert_eq!(draft.vocab, self.vocab, "draft and target models have different vocabularies");
+        assert_eq!(config.guidance_scale, 1., "speculative decoding does not support guidance");
+        let stop = &config.stop;
+        let (mut cache, mut draft_cache) = (self.new_cache(), draft.new_cache());
+        let mut tokens = if token_ids.is_empty() { vec![self.bos_token_id] } else { token_ids.to_vec() };
+        let prompt_len = tokens.len();
+        let capacity = cache.capacity().min(draft_cache.capacity());
+        if prompt_len > capacity {
+            return Generation::finish(Vec::new(), config.logprobs.map(|_| Vec::new()), stop, StopReason::ContextFull);
+        }
+        // both caches hold every token but the last, which the next round feeds
+        if prompt_len > 1 {
+            self.prefill(&tokens[..prompt_len - 1], &mut cache);
+            draft.prefill(&tokens[..prompt_len - 1], &mut draft_cache);
+        }
+        let mut sampler = config.sampler(prompt_len, &self.eos_token_ids);
+        let mut draft_sampler = config.sampler(prompt_len, &self.eos_token_ids);
+        let mut logprobs = config.logprobs.map(|_| Vec::new());
+        let reason = 'generate: loop {
+            let remaining = config.max_new_tokens - (tokens.len() - prompt_len);
+            if remaining == 0 {
+                break StopReason::MaxTokens;
+            }
+            if cache.len() >= capacity {
+                break StopReason::ContextFull;
+            }
+            // every round yields one token more than it keeps proposals
+            let n_draft = num_draft.min(remaining - 1).min(capacity - cache.len() - 1);
+            let (mut proposed, mut draft_probs) = (Vec::with_capacity(n_draft), Vec::with_capacity(n_draft));
+            if n_draft > 0 {
+                // the draft first catches up on the tokens it has not seen, at most the last two
+                let unseen = tokens[draft_cache.len()..].to_vec();
+                let mut logits = draft.forward(&Tensor::new(unseen.clone(), &vec![unseen.len()]), &mut draft_cache);
+                let mut context = tokens.clone();
+                for i in 0..n_draft {
+                    let probs = draft_sampler.distribution(&logits, &context);
+                    let token = draft_sampler.draw_from(&probs);
+                    proposed.push(token);
+                    draft_probs.push(probs);
+                    context.push(token);
+                    if i + 1 < n_draft {
+                        logits = draft.forward(&Tensor::new(vec![token], &vec![1]), &mut draft_cache);
+                    }
+                }
+            }
+            // row i has the target's logits for proposal i, the row after the last one those for a bonus token
+            let input = [&tokens[tokens.len() - 1..], &proposed[..]].concat();
+            let rows = self.forward_all(&Tensor::new(input, &vec![n_draft + 1]), &mut cache);
+            let row = |i: usize| rows.slice(i * self.vocab, &vec![self.vocab]);
+            let mut new = Vec::with_capacity(n_draft + 1);
+            for i in 0..=n_draft {
+                let context = [&tokens[..], &proposed[..i]].concat();
+                let probs = sampler.distribution(&row(i), &context);
+                match proposed.get(i) {
+                    Some(&token) => match verify(&mut sampler, &probs, &draft_probs[i], token) {
+                        None => new.push(token),
+                        Some(replacement) => {
+                            new.push(replacement);
+                            break;
+                        }
+                    },
+                    None => new.push(sampler.draw_from(&probs)),
+                }
+            }
+            for (i, &token) in new.iter().enumerate() {
+                tokens.push(token);
+                if let (Some(logprobs), Some(top_n)) = (&mut logprobs, config.logprobs) {
+                    logprobs.push(TokenLogprob::new(token, row(i).data(), top_n));
+                }
+                if let Some(reason) = self.stop_reason(&tokens[prompt_len..], stop) {
+                    break 'generate reason;
+                }
+            }
+            // forget the rejected proposals, the new last token is fed next round
+            cache.truncate(tokens.len() - 1);
+            draft_cache.truncate(tokens.len() - 1);
+        };
+        Generation::finish(tokens.split_off(prompt_len), logprobs, stop, reason)
+    }
+
+    // The condition, if any, the last of the `generated` tokens meets
+    pub(crate) fn stop_reason(&self, generated: &[u32], stop: &StopCriteria) -> Option<StopReason> {
+        let &last = generated.last()?;
+        if self.is_eos(last) {
+            return Some(StopReason::Eos(last));
+        }
+        if stop.token_ids.contains(&last) {
+            return Some(StopReason::Token(last));
+        }
+        let text = stop.decode(generated)?;
+        stop.find(&text).map(|(_, s)| StopReason::String(s.to_string()))
     }
 }
 
+// Speculative sampling's accept/reject step for `proposed`, drawn by the draft with probabilities
+// `draft_probs`: None keeps it, else the replacement drawn from the target's `probs` minus the draft's
+fn verify(sampler: &mut Sampler, probs: &[f32], draft_probs: &[f32], proposed: u32) -> Option<u32> {
+    let (p, q) = (probs[proposed as usize], draft_probs[proposed as usize]);
+    if sampler.uniform() * q < p {
+        return None;
+    }
+    let mut residual: Vec<f32> = probs.iter().zip(draft_probs).map(|(p, q)| (p - q).max(0.)).collect();
+    let total: f32 = residual.iter().sum();
+    if total > 0. {
+        residual.iter_mut().for_each(|r| *r /= total);
+    } else {
+        // the two distributions only differ by rounding
+        residual = probs.to_vec();
+    }
+    Some(sampler.draw_from(&residual))
+}
+
+// Prompt tokens per forward pass of prefill()
+pub const PREFILL_CHUNK: usize = 512;
+
+// A hypothesis of beam_search(): its sequence in the paged cache, generated tokens and their total log
+// probability, and the logits for its next token
+struct Beam {
+    seq: SeqId,
+    tokens: Vec<u32>,
+    score: f32,
+    logits: Tensor<f32>,
+}
+
+// Reference attention that materializes the full score matrix, see OP::flash_attention
+#[cfg(test)]
 fn self_attention(
     hidden_states: &mut Tensor<f32>, // (seq, n_kv_h * n_groups * dqkv)
     att_scores: &mut Tensor<f32>,    // (n_kv_h, n_groups, seq, total_seq)
     q: &Tensor<f32>,                 // (seq, n_kv_h * n_groups * dqkv)
     k: &Tensor<f32>,                 // (total_seq, n_kv_h * dqkv)
     v: &Tensor<f32>,                 // (total_seq, n_kv_h * dqkv)
-    n_kv_h: usize,
-    n_groups: usize,
-    seq_len: usize,
-    total_seq_len: usize,
-    dqkv: usize,
+    window: Option<usize>, // sliding window, keys older than this are masked out
 ) {
-    todo!("Implement self_attention");
+    use crate::simd;
+    let (n_kv_h, n_groups, seq_len, total_seq_len) = match att_scores.shape()[..] {
+        [n_kv_h, n_groups, seq_len, total_seq_len] => (n_kv_h, n_groups, seq_len, total_seq_len),
+        _ => panic!("att_scores must be (n_kv_h, n_groups, seq, total_seq)"),
+    };
+    let dqkv = k.shape()[1] / n_kv_h;
+    let scale = 1. / (dqkv as f32).sqrt();
+    let (q_data, k_data, v_data) = (q.data(), k.data(), v.data());
+    let q_stride = n_kv_h * n_groups * dqkv;
+    let kv_stride = n_kv_h * dqkv;
+    // score = Q @ K.T / sqrt(dim), query head h * n_groups + g shares kv head h
+    let scores = att_scores.make_mut();
+    for h in 0..n_kv_h {
+        for g in 0..n_groups {
+            let head = h * n_groups + g;
+            for i in 0..seq_len {
+                let q_row = &q_data[i * q_stride + head * dqkv..][..dqkv];
+                let row = &mut scores[((h * n_groups + g) * seq_len + i) * total_seq_len..][..total_seq_len];
+                for (j, score) in row.iter_mut().enumerate() {
+                    *score = simd::dot(q_row, &k_data[j * kv_stride + h * dqkv..][..dqkv]) * scale;
+                }
+            }
+        }
+    }
+    OP::masked_softmax_window(att_scores, window);
+    // x = attn @ V
+    let scores = att_scores.data();
+    let out = hidden_states.make_mut();
+    out.fill(0.);
+    for h in 0..n_kv_h {
+        for g in 0..n_groups {
+            let head = h * n_groups + g;
+            for i in 0..seq_len {
+                let row = &scores[((h * n_groups + g) * seq_len + i) * total_seq_len..][..total_seq_len];
+                let out_row = &mut out[i * q_stride + head * dqkv..][..dqkv];
+                for (j, &p) in row.iter().enumerate() {
+                    simd::axpy(p, &v_data[j * kv_stride + h * dqkv..][..dqkv], out_row);
+                }
+            }
+        }
+    }
 }
 
+// Signature kept flat to match the per-layer weight vectors in LLamaParams
+#[allow(clippy::too_many_arguments)]
 fn mlp(
+    ops: &impl Ops,
     residual: &mut Tensor<f32>,
     hidden_states: &mut Tensor<f32>,
     gate: &mut Tensor<f32>,
     up: &mut Tensor<f32>,
-    w_up: &Tensor<f32>,
-    w_down: &Tensor<f32>,
-    w_gate: &Tensor<f32>,
+    w_up: &impl Weight,
+    w_down: &impl Weight,
+    w_gate: &impl Weight,
     rms_w: &Tensor<f32>,
     eps: f32,
+    activation: OP::Activation,
 ){
-    OP::rms_norm(hidden_states, residual, rms_w, eps);
+    ops.rms_norm(hidden_states, residual, rms_w, eps);
  
-    *gate = OP::multiple(hidden_states, &w_gate.transpose(vec![1, 0]));
+    w_gate.matmul_transb(ops, gate, 0., hidden_states, 1.);
  
-    *up = OP::multiple(hidden_states, &w_up.transpose(vec![1, 0]));
+    w_up.matmul_transb(ops, up, 0., hidden_states, 1.);
  
-    OP::silu(up, gate);
+    ops.gated_activation(up, gate, activation);
  
-    *hidden_states = up.clone();
+    w_down.matmul_transb(ops, hidden_states, 0., up, 1.);
  
-    *hidden_states = OP::multiple(hidden_states, &w_down.transpose(vec![1, 0]));
- 
-    *residual = OP::add(residual, hidden_states);
+    residual.add_(hidden_states);
 }
 
 #[test]
@@ -197,6 +1003,7 @@ pub fn test_mlp() {
     let rms_w = Tensor::<f32>::new(vec![1., 1.], &vec![d]);
     let eps = 1e-6;
     mlp(
+        &Cpu,
         &mut residual,
         &mut hidden_states,
         &mut gate_buf,
@@ -206,6 +1013,7 @@ pub fn test_mlp() {
         &w_gate,
         &rms_w,
         eps,
+        OP::Activation::Silu,
     );
 
     assert!(residual.close_to(
@@ -234,6 +1042,8 @@ pub fn test_load_safetensors() {
     assert_eq!(model.d, 128);
     assert_eq!(model.dqkv, 16);
     assert_eq!(model.di, 384);
+    assert!(model.params.wq[0].is_mapped());
+    assert_eq!(model.params.w_down.len(), model.n_layers);
 
     assert!(float_eq(&model.params.embedding_table.data()[50], &0.14453125, 1e-6));
     assert_eq!(model.params.lm_head.data()[10], model.params.embedding_table.data()[10]);
@@ -249,3 +1059,665 @@ pub fn test_load_safetensors() {
     assert!(float_eq(&model.params.wo[0].data()[100], &0.01965332, 1e-6));
 
 }
+
+#[test]
+fn test_generation_config() {
+    let config = LlamaConfigJson::builder().num_hidden_layers(1).build().unwrap();
+    let generation_config: GenerationConfigJson = serde_json::from_value(serde_json::json!({
+        "bos_token_id": 1, "eos_token_id": [2, 7], "do_sample": true, "temperature": 0.6, "top_p": 0.9
+    }))
+    .unwrap();
+    let llama = Llama::random(&config, 0).with_generation_config(generation_config);
+    assert!(llama.is_eos(2) && llama.is_eos(7) && !llama.is_eos(3));
+    assert_eq!(llama.generation_config().sampling(1., 4, 1.), (0.9, 4, 0.6));
+
+    let greedy: GenerationConfigJson = serde_json::from_str(r#"{"eos_token_id": 2, "do_sample": false}"#).unwrap();
+    assert_eq!(greedy.sampling(0.9, 4, 1.).2, 0.);
+    assert_eq!(greedy.eos_token_id.unwrap().to_vec(), vec![2]);
+}
+
+#[test]
+fn test_self_attention_sliding_window() {
+    let (n_kv_h, n_groups, dqkv, seq_len, total_seq_len) = (2, 2, 4, 2, 6);
+    let q = Tensor::<f32>::randn(&vec![seq_len, n_kv_h * n_groups * dqkv], 1);
+    let k = Tensor::<f32>::randn(&vec![total_seq_len, n_kv_h * dqkv], 2);
+    let v = Tensor::<f32>::randn(&vec![total_seq_len, n_kv_h * dqkv], 3);
+    let mut windowed = Tensor::<f32>::default(&vec![seq_len, n_kv_h * n_groups * dqkv]);
+    let mut scores = Tensor::<f32>::default(&vec![n_kv_h, n_groups, seq_len, total_seq_len]);
+    self_attention(&mut windowed, &mut scores, &q, &k, &v, Some(3));
+
+    // the last query (position 5) sees positions 3..6 only, same as plain attention over those keys
+    let (k_tail, v_tail) = (k.slice(3 * n_kv_h * dqkv, &vec![3, 8]), v.slice(3 * n_kv_h * dqkv, &vec![3, 8]));
+    let q_last = q.slice(n_kv_h * n_groups * dqkv, &vec![1, 16]);
+    let mut expected = Tensor::<f32>::default(&vec![1, 16]);
+    let mut scores = Tensor::<f32>::default(&vec![n_kv_h, n_groups, 1, 3]);
+    self_attention(&mut expected, &mut scores, &q_last, &k_tail, &v_tail, None);
+    let last = windowed.slice(16, &vec![1, 16]);
+    assert!(last.compare(&expected).max_abs_err < 1e-6);
+}
+
+#[test]
+fn test_rope_theta_override() {
+    let config = LlamaConfigJson::builder().build().unwrap();
+    assert_eq!(Llama::random(&config, 0).rope_theta(), 1e4);
+    let llama = Llama::random(&config, 0).with_rope_theta(1e6);
+    assert_eq!(llama.rope_theta(), 1e6);
+
+    // Llama-3 style configs carry their own base
+    let llama3 = LlamaConfigJson::builder().rope_theta(5e5).build().unwrap();
+    assert_eq!(Llama::random(&llama3, 0).rope_theta(), 5e5);
+}
+
+#[test]
+fn test_logit_softcapping() {
+    let mut config = LlamaConfigJson::builder().build().unwrap();
+    let prompt = Tensor::new(vec![5, 17, 42], &vec![3]);
+    let llama = Llama::random(&config, 4);
+    let logits = llama.forward(&prompt, &mut llama.new_cache());
+    // random weights give small logits, a cap well below them must bound every value
+    let cap = logits.data().iter().fold(0f32, |m, x| m.max(x.abs())) / 2.;
+    config.final_logit_softcapping = Some(cap);
+    config.attn_logit_softcapping = Some(1e-3);
+    let capped = Llama::random(&config, 4);
+    let capped_logits = capped.forward(&prompt, &mut capped.new_cache());
+    assert!(capped_logits.data().iter().all(|x| x.abs() < cap));
+    assert!(capped_logits.compare(&logits).max_abs_err > 1e-3);
+    // a cap far above the scores changes nothing
+    config.final_logit_softcapping = Some(1e6);
+    config.attn_logit_softcapping = Some(1e6);
+    let loose = Llama::random(&config, 4);
+    assert!(loose.forward(&prompt, &mut loose.new_cache()).compare(&logits).max_abs_err < 1e-5);
+}
+
+#[test]
+fn test_gemma() {
+    // Gemma is Llama with its embeddings scaled by sqrt(d) and RMSNorm weights shifted by one
+    let mut config = LlamaConfigJson::builder().heads(4, 2).hidden_act("gelu_pytorch_tanh").build().unwrap();
+    let params = LLamaParams::<f32>::random(&config, 9);
+    let mut shifted = LLamaParams::<f32>::random(&config, 9);
+    shifted.embedding_table.scale_((config.hidden_size as f32).sqrt());
+    let plus_one = |w: &Tensor<f32>| Tensor::new(w.data().iter().map(|w| w + 1.).collect(), w.shape());
+    shifted.rms_att_w = params.rms_att_w.iter().map(plus_one).collect();
+    shifted.rms_ffn_w = params.rms_ffn_w.iter().map(plus_one).collect();
+    shifted.rms_out_w = plus_one(&params.rms_out_w);
+    let llama = Llama::from_params(&config, shifted);
+    config.model_type = Some("gemma".to_string());
+    config.hidden_act = "gelu".to_string();
+    let gemma = Llama::from_params(&config, params);
+    assert_eq!(gemma.architecture, Architecture::Gemma);
+
+    let prompt = Tensor::new(vec![5, 17, 42, 3], &vec![4]);
+    let expected = llama.forward(&prompt, &mut llama.new_cache());
+    assert!(gemma.forward(&prompt, &mut gemma.new_cache()).compare(&expected).max_abs_err < 1e-4);
+}
+
+#[test]
+fn test_grouped_query_attention() {
+    // a GQA model must match the multi-head model whose k/v heads are repeated for every query head
+    let gqa_config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let gqa = Llama::random(&gqa_config, 3);
+    let mha_config = LlamaConfigJson::builder().heads(4, 4).build().unwrap();
+    let mut params = LLamaParams::random(&gqa_config, 3);
+    let (dqkv, n_groups) = (gqa.dqkv, gqa.n_q_h / gqa.n_kv_h);
+    let rows: Vec<u32> = (0..gqa.n_q_h * dqkv).map(|r| ((r / dqkv / n_groups) * dqkv + r % dqkv) as u32).collect();
+    params.wk = params.wk.iter().map(|w| w.index_select(0, &rows)).collect();
+    params.wv = params.wv.iter().map(|w| w.index_select(0, &rows)).collect();
+    let mha = Llama::from_params(&mha_config, params);
+
+    let prompt = Tensor::new(vec![5, 17, 42, 3, 99], &vec![5]);
+    let (mut gqa_cache, mut mha_cache) = (gqa.new_cache(), mha.new_cache());
+    let logits = gqa.forward(&prompt, &mut gqa_cache);
+    assert!(logits.compare(&mha.forward(&prompt, &mut mha_cache)).max_abs_err < 1e-5);
+    // decoding the next token against the cache agrees as well
+    let next = Tensor::new(vec![7], &vec![1]);
+    let logits = gqa.forward(&next, &mut gqa_cache);
+    assert!(logits.compare(&mha.forward(&next, &mut mha_cache)).max_abs_err < 1e-5);
+    // and with running the whole sequence at once
+    let full = gqa.forward(&Tensor::new(vec![5, 17, 42, 3, 99, 7], &vec![6]), &mut gqa.new_cache());
+    assert!(logits.compare(&full).max_abs_err < 1e-5);
+}
+
+#[test]
+fn test_from_params_needs_every_layer() {
+    use crate::params::{tiny_checkpoint, tiny_config};
+    use safetensors::SafeTensors;
+    let config = tiny_config(2, true);
+    let bytes = tiny_checkpoint(&config, |_| true);
+    let safetensor = SafeTensors::deserialize(&bytes).unwrap();
+    let stage = LLamaParams::<f32>::from_safetensors_layers(&safetensor, &config, 1..2);
+    assert!(std::panic::catch_unwind(|| Llama::from_params(&config, stage)).is_err());
+    let all = LLamaParams::<f32>::from_safetensors_layers(&safetensor, &config, 0..2);
+    assert_eq!(Llama::from_params(&config, all).n_layers, 2);
+}
+
+#[test]
+fn test_from_dir_errors() {
+    let dir = std::env::temp_dir().join(format!("from_dir_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let missing = Llama::from_dir(&dir, |_| {}).err().unwrap();
+    assert!(matches!(missing, ConfigError::Io { ref path, .. } if *path == dir.join("config.json")));
+    std::fs::write(dir.join("config.json"), "{\"hidden_size\": ").unwrap();
+    let malformed = Llama::from_dir(&dir, |_| {}).err().unwrap();
+    let mut config = LlamaConfigJson::builder().build().unwrap();
+    config.architectures = vec!["Qwen2ForCausalLM".to_string()];
+    std::fs::write(dir.join("config.json"), serde_json::to_string(&config).unwrap()).unwrap();
+    let unsupported = Llama::from_dir(&dir, |_| {}).err().unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    assert!(matches!(malformed, ConfigError::Io { .. }));
+    assert_eq!(unsupported, ConfigError::UnsupportedArchitecture { name: "Qwen2ForCausalLM".to_string() });
+}
+
+#[test]
+fn test_quantized_weights() {
+    use crate::params::tiny_checkpoint;
+    use crate::quant::{Int8Tensor, Q8Tensor};
+    use safetensors::SafeTensors;
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let bytes = tiny_checkpoint(&config, |_| true);
+    let dir = std::env::temp_dir().join(format!("quantized_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("config.json"), serde_json::to_string(&config).unwrap()).unwrap();
+    std::fs::write(dir.join("model.safetensors"), &bytes).unwrap();
+    let dense = Llama::from_safetensors(&dir);
+    let int8 = Llama::<f32, Cpu, Int8Tensor>::from_safetensors_quantized(&dir);
+    std::fs::remove_dir_all(dir).unwrap();
+    let safetensor = SafeTensors::deserialize(&bytes).unwrap();
+    let q8 = Llama::from_params(&config, LLamaParams::<f32, Q8Tensor>::from_safetensors_quantized(&safetensor, &config));
+
+    let prompt = Tensor::new(vec![5, 17, 42, 3], &vec![4]);
+    let expected = dense.forward(&prompt, &mut dense.new_cache());
+    let scale = expected.data().iter().fold(0f32, |m, x| m.max(x.abs()));
+    for logits in [int8.forward(&prompt, &mut int8.new_cache()), q8.forward(&prompt, &mut q8.new_cache())] {
+        assert!(logits.compare(&expected).max_abs_err < 0.05 * scale);
+    }
+    // codes plus a scale per row or block instead of 4 bytes a weight
+    let projections = dense.memory_stats(&[]).weights - 4 * (2 * config.vocab_size * config.hidden_size);
+    assert!(int8.memory_stats(&[]).weights < dense.memory_stats(&[]).weights - projections / 2);
+    assert!(q8.memory_stats(&[]).weights < dense.memory_stats(&[]).weights - projections / 2);
+    // and the stepwise decoding paths run on them as well
+    let greedy = GenerationConfig::greedy(4);
+    assert_eq!(int8.generate(&[5, 17], &greedy).tokens.len(), 4);
+}
+
+#[test]
+fn test_flash_attention_matches_reference() {
+    let (n_kv_h, n_groups, dqkv) = (2, 3, 8);
+    // more keys than one flash block, with and without a window
+    for (seq_len, total_seq_len, window) in [(5, 5, None), (3, 150, None), (70, 150, Some(40)), (1, 200, Some(65))] {
+        let q = Tensor::<f32>::randn(&vec![seq_len, n_kv_h * n_groups, dqkv], 1);
+        let k = Tensor::<f32>::randn(&vec![total_seq_len, n_kv_h * dqkv], 2);
+        let v = Tensor::<f32>::randn(&vec![total_seq_len, n_kv_h * dqkv], 3);
+        let mut expected = Tensor::<f32>::default(&vec![seq_len, n_kv_h * n_groups * dqkv]);
+        let mut scores = Tensor::<f32>::default(&vec![n_kv_h, n_groups, seq_len, total_seq_len]);
+        self_attention(&mut expected, &mut scores, &q, &k, &v, window);
+        let mut out = Tensor::<f32>::default(&vec![seq_len, n_kv_h * n_groups * dqkv]);
+        OP::flash_attention(&mut out, &q, &k, &v, window, None);
+        assert!(out.compare(&expected).max_abs_err < 1e-5, "{seq_len} x {total_seq_len}");
+    }
+}
+
+#[test]
+fn test_memory_stats() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 13);
+    let (d, di, vocab) = (config.hidden_size, config.intermediate_size, config.vocab_size);
+    let kv_dim = 2 * d / 4;
+    let layer = 2 * d + 2 * d * d + 2 * kv_dim * d + 3 * d * di;
+    let stats = llama.memory_stats(&[]);
+    assert_eq!(stats.weights, 4 * (2 * vocab * d + d + config.num_hidden_layers * layer));
+    // tied embeddings are one buffer
+    let mut params = LLamaParams::<f32>::random(&config, 13);
+    params.lm_head = params.embedding_table.clone();
+    assert_eq!(params.nbytes(), stats.weights - 4 * vocab * d);
+    assert_eq!(stats.kv_per_position, 4 * 2 * config.num_hidden_layers * kv_dim);
+
+    let mut cache = llama.new_cache();
+    llama.forward(&Tensor::new(vec![1, 2, 3], &vec![3]), &mut cache);
+    let half = llama.new_cache_of::<half::f16>();
+    let stats = llama.memory_stats(&[&cache, &half]);
+    // every buffer of the pass went back to the pool: residual, hidden states, attention output, q, k, v,
+    // gate, up and the final row's residual and hidden states
+    let (q_dim, seq) = (d, 3);
+    assert_eq!(stats.scratch, 4 * (seq * (2 * d + 2 * q_dim + 2 * kv_dim + 2 * di) + 2 * d));
+    // and the next pass of the same length reuses them all
+    llama.forward(&Tensor::new(vec![1, 2, 3], &vec![3]), &mut llama.new_cache());
+    assert_eq!(llama.memory_stats(&[]).scratch, stats.scratch);
+    let full = config.max_position_embeddings * stats.kv_per_position;
+    assert_eq!(stats.kv_caches, vec![full, full / 2]);
+    assert_eq!(stats.positions_within(stats.total() + full), config.max_position_embeddings);
+    assert_eq!(stats.positions_within(0), 0);
+}
+
+#[test]
+fn test_prefill() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 15);
+    let prompt = [3, 1, 4, 1, 5, 9];
+    let mut cache = llama.new_cache();
+    let logits = llama.prefill(&prompt, &mut cache);
+    // the same as feeding the prompt one token at a time
+    let mut stepwise = llama.new_cache();
+    let mut expected = None;
+    for &token in &prompt {
+        expected = Some(llama.forward(&Tensor::new(vec![token], &vec![1]), &mut stepwise));
+    }
+    assert!(logits.compare(&expected.unwrap()).max_abs_err < 1e-5);
+    assert_eq!(cache.len(), prompt.len());
+    for layer in 0..config.num_hidden_layers {
+        let ((k, v), (k_step, v_step)) = (cache.view(layer, 0..6), stepwise.view(layer, 0..6));
+        assert!(k.compare(&k_step).max_abs_err < 1e-5 && v.compare(&v_step).max_abs_err < 1e-5);
+    }
+}
+
+#[test]
+fn test_forward_batch() {
+    for config in [
+        LlamaConfigJson::builder().heads(4, 2).build().unwrap(),
+        LlamaConfigJson::builder().heads(4, 2).sliding_window(3).build().unwrap(),
+    ] {
+        let llama = Llama::random(&config, 23);
+        let prompts: [&[u32]; 3] = [&[3, 1, 4, 1, 5], &[9], &[2, 6, 5]];
+        // each sequence on its own
+        let mut expected_caches: Vec<_> = prompts.iter().map(|_| llama.new_cache()).collect();
+        let expected: Vec<_> = prompts.iter().zip(&mut expected_caches).map(|(p, c)| llama.prefill(p, c)).collect();
+        let mut caches: Vec<_> = prompts.iter().map(|_| llama.new_cache()).collect();
+        let mut stores: Vec<&mut dyn KVStore> = caches.iter_mut().map(|c| c as &mut dyn KVStore).collect();
+        let logits = llama.forward_batch(&prompts, &mut stores[..]);
+        assert_eq!(logits.shape(), &vec![3, config.vocab_size]);
+        for (i, e) in expected.iter().enumerate() {
+            let row = logits.slice(i * config.vocab_size, &vec![1, config.vocab_size]);
+            assert!(row.compare(e).max_abs_err < 1e-5, "sequence {i}");
+        }
+        // then a decode step of every sequence, continuing caches of different lengths
+        let next: Vec<u32> = expected.iter().map(|e| e.argmax().0).collect();
+        let steps: Vec<&[u32]> = next.chunks(1).collect();
+        let logits = llama.forward_batch(&steps, &mut stores[..]);
+        for (i, cache) in expected_caches.iter_mut().enumerate() {
+            let e = llama.forward(&Tensor::new(vec![next[i]], &vec![1]), cache);
+            let row = logits.slice(i * config.vocab_size, &vec![1, config.vocab_size]);
+            assert!(row.compare(&e).max_abs_err < 1e-5, "sequence {i}");
+            assert_eq!(stores[i].len(), prompts[i].len() + 1);
+        }
+    }
+}
+
+#[test]
+fn test_prefill_chunked() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 24);
+    let prompt = [3, 1, 4, 1, 5, 9, 2];
+    let expected = llama.forward(&Tensor::new(prompt.to_vec(), &vec![prompt.len()]), &mut llama.new_cache());
+    let mut cache = llama.new_cache();
+    let mut progress = Vec::new();
+    let logits = llama.prefill_chunked(&prompt, &mut cache, 3, |done| {
+        progress.push(done);
+        ControlFlow::Continue(())
+    });
+    assert!(logits.unwrap().compare(&expected).max_abs_err < 1e-5);
+    assert_eq!((progress, cache.len()), (vec![3, 6, 7], 7));
+
+    // cancelling after the first chunk keeps it cached, resuming finishes the prompt
+    let mut cache = llama.new_cache();
+    assert!(llama.prefill_chunked(&prompt, &mut cache, 4, |_| ControlFlow::Break(())).is_none());
+    assert_eq!(cache.len(), 4);
+    let logits = llama.prefill(&prompt[cache.len()..], &mut cache);
+    assert!(logits.compare(&expected).max_abs_err < 1e-5);
+}
+
+#[test]
+fn test_embed() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 28);
+    let tokens = [3, 1, 4, 1, 5];
+    let states = llama.hidden_states(&tokens);
+    assert_eq!(states.len(), config.num_hidden_layers + 1);
+    assert!(states.iter().all(|s| s.shape() == &vec![tokens.len(), config.hidden_size]));
+    // the first entry is the embedding rows of the tokens
+    let row = |t: &Tensor<f32>, i: usize| t.data()[i * config.hidden_size..(i + 1) * config.hidden_size].to_vec();
+    assert_eq!(row(&states[0], 2), row(&llama.params.embedding_table, 4));
+    // the LM head applied to the last token's final state gives forward()'s logits
+    let last = Pooling::Last.pool(&states[config.num_hidden_layers]);
+    let mut logits = Tensor::default(&vec![1, config.vocab_size]);
+    OP::matmul_transb(&mut logits, 0., &last.slice(0, &vec![1, config.hidden_size]), &llama.params.lm_head, 1.);
+    let expected = llama.forward(&Tensor::new(tokens.to_vec(), &vec![tokens.len()]), &mut llama.new_cache());
+    assert!(logits.compare(&expected).max_abs_err < 1e-4);
+
+    assert_eq!(llama.embed(&tokens, Pooling::Last).data(), last.data());
+    let mean = llama.embed(&tokens, Pooling::Mean);
+    assert_eq!(mean.shape(), &vec![config.hidden_size]);
+    let final_states = &states[config.num_hidden_layers];
+    for i in [0, 7, config.hidden_size - 1] {
+        let expected = (0..tokens.len()).map(|t| final_states.data()[t * config.hidden_size + i]).sum::<f32>() / 5.;
+        assert!((mean.data()[i] - expected).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn test_perplexity() {
+    let config = LlamaConfigJson::builder().heads(4, 2).max_position_embeddings(8).build().unwrap();
+    let llama = Llama::random(&config, 29);
+    let tokens: Vec<u32> = (0..20).map(|i| (i * 7 + 3) % config.vocab_size as u32).collect();
+    // NLL of tokens[target] in the first window of 8 moving by `stride` that holds it
+    let nll = |stride: usize, target: usize| {
+        let start = (0..).step_by(stride).find(|&begin| begin + 8 > target).unwrap();
+        let context = Tensor::new(tokens[start..target].to_vec(), &vec![target - start]);
+        -log_softmax(llama.forward(&context, &mut llama.new_cache()).data())[tokens[target] as usize]
+    };
+    // windows overlapping by one token, where only a few tokens get long contexts, and by six
+    for stride in [7, 2] {
+        let ppl = llama.perplexity(&tokens, stride);
+        assert_eq!(ppl.nll.len(), 19);
+        for target in [1, 7, 8, 9, 13, 15, 19] {
+            assert!((ppl.nll[target - 1] - nll(stride, target)).abs() < 1e-4, "{stride} {target}");
+        }
+    }
+    let sliding = llama.perplexity(&tokens, 2);
+    let mean = sliding.nll.iter().sum::<f32>() / 19.;
+    assert!((sliding.perplexity() - mean.exp()).abs() < 1e-3 * mean.exp());
+    assert!(sliding.mean_nll() > 0.);
+}
+
+#[test]
+fn test_generate() {
+    use crate::sampling::{LogitsProcessor, Temperature};
+    use std::sync::Arc;
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 16);
+    let prompt = [3, 1, 4];
+    // temperature 0 is greedy decoding
+    let output = llama.generate(&prompt, &GenerationConfig::greedy(5)).tokens;
+    let mut cache = llama.new_cache();
+    let mut logits = llama.prefill(&prompt, &mut cache);
+    let mut expected = Vec::new();
+    while expected.len() < 5 {
+        let next = logits.argmax().0;
+        expected.push(next);
+        if llama.is_eos(next) {
+            break;
+        }
+        logits = llama.forward(&Tensor::new(vec![next], &vec![1]), &mut cache);
+    }
+    assert_eq!(output, expected);
+    let sampling = GenerationConfig::builder().max_new_tokens(5).top_p(0.9).top_k(4).build().unwrap();
+    let sampled = llama.generate(&prompt, &sampling).tokens;
+    assert!(sampled.len() <= 5 && sampled.iter().all(|&t| (t as usize) < config.vocab_size));
+    let seeded = |seed| {
+        let config = GenerationConfig::builder().max_new_tokens(5).temperature(2.).seed(seed).build().unwrap();
+        llama.generate(&prompt, &config).tokens
+    };
+    assert_eq!(seeded(3), seeded(3));
+    // min_new_tokens masks eos, here the first greedy token
+    let mut eager = Llama::random(&config, 16);
+    eager.eos_token_ids = vec![output[0]];
+    assert_eq!(eager.generate(&prompt, &GenerationConfig::greedy(5)).tokens, output[..1]);
+    let min_len = GenerationConfig { min_new_tokens: 3, ..GenerationConfig::greedy(5) };
+    let forced = eager.generate(&prompt, &min_len).tokens;
+    assert!(forced.len() >= 3 && !forced[..3].contains(&eager.eos_token_ids[0]));
+    // processors see the prompt and everything generated so far
+    struct Context(Arc<Mutex<Vec<Vec<u32>>>>);
+    impl LogitsProcessor for Context {
+        fn process(&mut self, _logits: &mut [f32], tokens: &[u32]) {
+            self.0.lock().unwrap().push(tokens.to_vec());
+        }
+    }
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut sampler = Sampler::default().with(Context(seen.clone())).with(Temperature(0.));
+    let continue_ = |_| ControlFlow::Continue(());
+    assert_eq!(llama.generate_with(&prompt, &GenerationConfig::greedy(5), &mut sampler, continue_).tokens, output);
+    let expected: Vec<Vec<u32>> = (0..output.len()).map(|i| [&prompt[..], &output[..i]].concat()).collect();
+    assert_eq!(*seen.lock().unwrap(), expected);
+}
+
+#[test]
+fn test_generate_seeded() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 17);
+    let prompt = [3, 1, 4];
+    let sampling = |seed| GenerationConfig::builder().max_new_tokens(8).temperature(1.5).seed(seed).build().unwrap();
+    let output = llama.generate(&prompt, &sampling(7));
+    assert_eq!(llama.generate(&prompt, &sampling(7)), output);
+
+    // the seed alone decides the draws: the same sampler over the model's own logits gives the same tokens
+    let mut sampler = sampling(7).sampler(prompt.len(), &llama.eos_token_ids);
+    let mut cache = llama.new_cache();
+    let mut logits = llama.prefill(&prompt, &mut cache);
+    let mut tokens = prompt.to_vec();
+    while tokens.len() - prompt.len() < 8 {
+        let next = sampler.sample(&logits, &tokens);
+        tokens.push(next);
+        if llama.is_eos(next) {
+            break;
+        }
+        logits = llama.forward(&Tensor::new(vec![next], &vec![1]), &mut cache);
+    }
+    assert_eq!(tokens[prompt.len()..], output.tokens);
+
+    // and other seeds draw other tokens
+    assert!((0..8).any(|seed| llama.generate(&prompt, &sampling(seed)).tokens != output.tokens));
+}
+
+#[test]
+fn test_generate_stop() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 18);
+    let prompt = [3, 1, 4];
+    let greedy = llama.generate(&prompt, &GenerationConfig::greedy(6));
+    assert_eq!(greedy.stop, StopReason::MaxTokens);
+    assert_eq!((greedy.tokens.len(), greedy.text), (6, None));
+
+    // stop token ids end generation on the token, which is kept
+    let stop = StopCriteria::new().token_ids(&[greedy.tokens[2]]);
+    let output = llama.generate(&prompt, &GenerationConfig { stop, ..GenerationConfig::greedy(6) });
+    let first = greedy.tokens.iter().position(|&t| t == greedy.tokens[2]).unwrap();
+    assert_eq!(output.stop, StopReason::Token(greedy.tokens[2]));
+    assert_eq!(output.tokens, greedy.tokens[..=first]);
+
+    // two characters per token, the stop string starts in the middle of the third one
+    let decode = |ids: &[u32]| ids.iter().map(|id| format!("{:02}", id % 100)).collect::<String>();
+    let text = decode(&greedy.tokens);
+    let needle = text[5..8].to_string();
+    let stop = StopCriteria::new().strings(&[needle.as_str()], decode);
+    let output = llama.generate(&prompt, &GenerationConfig { stop, ..GenerationConfig::greedy(6) });
+    let pos = text.find(needle.as_str()).unwrap();
+    assert_eq!(output.stop, StopReason::String(needle.clone()));
+    assert_eq!(output.text.as_deref(), Some(&text[..pos]));
+    assert_eq!(output.tokens, greedy.tokens[..pos / 2]);
+
+    // with several eos ids any of them ends generation
+    let mut llama = llama;
+    llama.eos_token_ids.push(greedy.tokens[0]);
+    let output = llama.generate(&prompt, &GenerationConfig::greedy(6));
+    assert_eq!((output.tokens, output.stop), (vec![greedy.tokens[0]], StopReason::Eos(greedy.tokens[0])));
+}
+
+#[test]
+fn test_generate_prompt_past_context() {
+    let config = LlamaConfigJson::builder().heads(4, 2).max_position_embeddings(8).build().unwrap();
+    let llama = Llama::random(&config, 18);
+    let draft = Llama::random(&LlamaConfigJson::builder().max_position_embeddings(8).build().unwrap(), 26);
+    let greedy = GenerationConfig { logprobs: Some(2), ..GenerationConfig::greedy(4) };
+    let too_long: Vec<u32> = (1..=9).collect();
+    for output in [llama.generate(&too_long, &greedy), llama.generate_speculative(&draft, &too_long, &greedy, 2)] {
+        assert_eq!((output.tokens.len(), output.stop), (0, StopReason::ContextFull));
+        assert_eq!(output.logprobs, Some(Vec::new()));
+    }
+    // a prompt filling the context exactly still gets its next token
+    let full = &too_long[..8];
+    for output in [llama.generate(full, &greedy), llama.generate_speculative(&draft, full, &greedy, 2)] {
+        assert_eq!((output.tokens.len(), output.stop), (1, StopReason::ContextFull));
+    }
+    let guided = GenerationConfig { guidance_scale: 1.5, negative_prompt: too_long.clone(), ..greedy };
+    assert_eq!(llama.generate(&[1, 2], &guided).stop, StopReason::ContextFull);
+}
+
+#[test]
+fn test_generate_logprobs() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 22);
+    let prompt = [3, 1, 4];
+    assert_eq!(llama.generate(&prompt, &GenerationConfig::greedy(4)).logprobs, None);
+    let config = GenerationConfig { logprobs: Some(3), ..GenerationConfig::greedy(4) };
+    let output = llama.generate(&prompt, &config);
+    let logprobs = output.logprobs.unwrap();
+    assert_eq!(logprobs.iter().map(|l| l.token).collect::<Vec<_>>(), output.tokens);
+    let mut cache = llama.new_cache();
+    let mut logits = llama.prefill(&prompt, &mut cache);
+    for (l, &t) in logprobs.iter().zip(&output.tokens) {
+        // greedy picks the most likely token, reported first among the alternatives
+        assert_eq!(l.top.len(), 3);
+        assert_eq!(l.top[0], (t, l.logprob));
+        assert!((l.logprob - log_softmax(logits.data())[t as usize]).abs() < 1e-5);
+        assert!(l.top.windows(2).all(|w| w[0].1 >= w[1].1));
+        logits = llama.forward(&Tensor::new(vec![t], &vec![1]), &mut cache);
+    }
+}
+
+#[test]
+fn test_generate_speculative() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 25);
+    let draft = Llama::random(&LlamaConfigJson::builder().num_hidden_layers(1).build().unwrap(), 26);
+    let prompt = [3, 1, 4];
+    // greedy decoding gives the target's own tokens whatever the draft proposes
+    let greedy = GenerationConfig { logprobs: Some(2), ..GenerationConfig::greedy(9) };
+    let expected = llama.generate(&prompt, &greedy);
+    for num_draft in [1, 3, 20] {
+        let output = llama.generate_speculative(&draft, &prompt, &greedy, num_draft);
+        assert_eq!((&output.tokens, &output.stop), (&expected.tokens, &expected.stop), "{num_draft}");
+        for (a, b) in output.logprobs.unwrap().iter().zip(expected.logprobs.as_ref().unwrap()) {
+            assert_eq!(a.token, b.token);
+            assert!((a.logprob - b.logprob).abs() < 1e-4);
+        }
+    }
+    assert_eq!(llama.generate_speculative(&llama, &prompt, &greedy, 4).tokens, expected.tokens);
+    assert_eq!(llama.generate_speculative(&draft, &[], &greedy, 4).tokens, llama.generate(&[], &greedy).tokens);
+
+    // sampling is reproducible for a seed and stops at a stop token like generate()
+    let sampling = |seed| GenerationConfig::builder().max_new_tokens(8).temperature(1.5).seed(seed).build().unwrap();
+    let sampled = llama.generate_speculative(&draft, &prompt, &sampling(1), 3);
+    assert_eq!(sampled, llama.generate_speculative(&draft, &prompt, &sampling(1), 3));
+    assert!(sampled.tokens.len() <= 8 && sampled.tokens.iter().all(|&t| (t as usize) < config.vocab_size));
+    let stop = StopCriteria::new().token_ids(&[sampled.tokens[1]]);
+    let config = GenerationConfig { stop, ..sampling(1) };
+    let stopped = llama.generate_speculative(&draft, &prompt, &config, 3);
+    assert_eq!(stopped.stop, StopReason::Token(sampled.tokens[1]));
+    assert!(stopped.tokens.len() <= 2);
+}
+
+#[test]
+fn test_speculative_verify() {
+    // whatever the draft proposes, kept proposals and replacements together follow the target's distribution
+    let (probs, draft_probs) = ([0.5, 0.3, 0.2, 0.], [0.1, 0.6, 0., 0.3]);
+    let mut sampler = Sampler::default().with_seed(7);
+    let mut draft = Sampler::default().with_seed(8);
+    let mut counts = [0; 4];
+    let n = 20000;
+    for _ in 0..n {
+        let proposed = draft.draw_from(&draft_probs);
+        let token = verify(&mut sampler, &probs, &draft_probs, proposed).unwrap_or(proposed);
+        counts[token as usize] += 1;
+    }
+    for (count, p) in counts.iter().zip(probs) {
+        assert!((*count as f32 / n as f32 - p).abs() < 0.02, "{counts:?}");
+    }
+}
+
+#[test]
+fn test_guidance() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 30);
+    let (prompt, negative) = ([3, 1, 4], [2, 7, 1, 8]);
+    let guided = |scale| GenerationConfig::builder().max_new_tokens(6).temperature(0.).guidance(scale, &negative);
+    // scale 0 follows the negative prompt alone, scale 1 (off) the prompt alone
+    let off = llama.generate(&prompt, &guided(1.).build().unwrap());
+    assert_eq!(off, llama.generate(&prompt, &GenerationConfig::greedy(6)));
+    let ignored = llama.generate(&prompt, &guided(0.).build().unwrap());
+    assert_eq!(ignored.tokens, llama.generate(&negative, &GenerationConfig::greedy(6)).tokens);
+
+    // every token is the argmax of the guided log probabilities, both sequences fed the same tokens, and
+    // its logprob is taken from that distribution
+    let output = llama.generate(&prompt, &guided(3.).logprobs(2).build().unwrap());
+    let (mut cond_cache, mut uncond_cache) = (llama.new_cache(), llama.new_cache());
+    let (mut cond, mut uncond) = (llama.prefill(&prompt, &mut cond_cache), llama.prefill(&negative, &mut uncond_cache));
+    for (&token, logprob) in output.tokens.iter().zip(output.logprobs.unwrap()) {
+        let guided = Tensor::new(guide(cond.data(), uncond.data(), 3.), &vec![config.vocab_size]);
+        assert_eq!(token, guided.argmax().0);
+        assert!((logprob.logprob - log_softmax(guided.data())[token as usize]).abs() < 1e-5);
+        cond = llama.forward(&Tensor::new(vec![token], &vec![1]), &mut cond_cache);
+        uncond = llama.forward(&Tensor::new(vec![token], &vec![1]), &mut uncond_cache);
+    }
+    // an empty negative prompt stands for the prompt's last token
+    let config = GenerationConfig { negative_prompt: Vec::new(), ..guided(0.).build().unwrap() };
+    assert_eq!(llama.generate(&prompt, &config).tokens, llama.generate(&[4], &GenerationConfig::greedy(6)).tokens);
+}
+
+#[test]
+fn test_beam_search() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 20);
+    let prompt = [3, 1, 4];
+    // total log probability of `output` after the prompt
+    let score = |output: &[u32]| {
+        let mut cache = llama.new_cache();
+        let mut logits = llama.prefill(&prompt, &mut cache);
+        let mut total = 0.;
+        for &t in output {
+            total += log_softmax(logits.data())[t as usize];
+            logits = llama.forward(&Tensor::new(vec![t], &vec![1]), &mut cache);
+        }
+        total
+    };
+    let greedy = llama.generate(&prompt, &GenerationConfig::greedy(6)).tokens;
+    assert_eq!(llama.beam_search(&prompt, 6, 1, 1.), greedy);
+    let beams = llama.beam_search(&prompt, 6, 4, 1.);
+    assert!(!beams.is_empty() && beams.len() <= 6);
+    assert!(score(&beams) / beams.len() as f32 >= score(&greedy) / greedy.len() as f32 - 1e-4);
+
+    // a prompt as long as the context, or longer, has no room left
+    let config = LlamaConfigJson::builder().heads(4, 2).max_position_embeddings(4).build().unwrap();
+    let llama = Llama::random(&config, 20);
+    assert!(llama.beam_search(&[3, 1, 4, 1], 6, 2, 1.).is_empty());
+    assert!(llama.beam_search(&[3, 1, 4, 1, 5, 9], 6, 2, 1.).is_empty());
+    assert_eq!(llama.beam_search(&[3, 1, 4], 6, 2, 1.).len(), 1);
+}
+
+#[test]
+fn test_generate_stream() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 21);
+    let prompt = [3, 1, 4];
+    let config = GenerationConfig::greedy(6);
+    let full = llama.generate(&prompt, &config);
+    let mut streamed = Vec::new();
+    let output = llama.generate_stream(&prompt, &config, |token| {
+        streamed.push(token);
+        ControlFlow::Continue(())
+    });
+    assert_eq!((&output, &streamed), (&full, &full.tokens));
+
+    // breaking keeps the token it was handed
+    let output = llama.generate_stream(&prompt, &config, |_| ControlFlow::Break(()));
+    assert_eq!((output.tokens, output.stop), (full.tokens[..1].to_vec(), StopReason::Cancelled));
+}
+
+#[test]
+fn test_generate_stream_cancel() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let mut llama = Llama::random(&config, 21);
+    llama.eos_token_ids.clear();
+    let prompt = [3, 1, 4];
+    let config = GenerationConfig::builder().max_new_tokens(6).logprobs(0).temperature(0.).build().unwrap();
+    let full = llama.generate(&prompt, &config);
+    // cancelling after the third token returns exactly the tokens streamed so far, with their logprobs
+    let mut streamed = Vec::new();
+    let output = llama.generate_stream(&prompt, &config, |token| {
+        streamed.push(token);
+        match streamed.len() {
+            3 => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(()),
+        }
+    });
+    assert_eq!(output.stop, StopReason::Cancelled);
+    assert_eq!((&output.tokens, &streamed[..]), (&streamed, &full.tokens[..3]));
+    assert_eq!(output.logprobs.as_deref(), full.logprobs.as_ref().map(|l| &l[..3]));
+}
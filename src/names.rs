@@ -0,0 +1,100 @@
+// Maps the Hugging Face llama tensor names the loaders ask for (model.layers.N.self_attn.q_proj.weight, ...)
+// to the names a checkpoint actually uses. Templates may contain `{i}` for the layer index.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NameMap {
+    rules: Vec<(String, String)>, // (canonical template, checkpoint template), first match wins
+}
+
+const LAYER: &str = "{i}";
+
+// Canonical names and what each preset calls them
+const PRESETS: [(&str, &str, &str); 12] = [
+    // (canonical, transformer.h, gguf)
+    ("model.embed_tokens.weight", "transformer.wte.weight", "token_embd.weight"),
+    ("model.norm.weight", "transformer.ln_f.weight", "output_norm.weight"),
+    ("lm_head.weight", "lm_head.weight", "output.weight"),
+    ("model.layers.{i}.input_layernorm.weight", "transformer.h.{i}.ln_1.weight", "blk.{i}.attn_norm.weight"),
+    ("model.layers.{i}.self_attn.q_proj.weight", "transformer.h.{i}.attn.q_proj.weight", "blk.{i}.attn_q.weight"),
+    ("model.layers.{i}.self_attn.k_proj.weight", "transformer.h.{i}.attn.k_proj.weight", "blk.{i}.attn_k.weight"),
+    ("model.layers.{i}.self_attn.v_proj.weight", "transformer.h.{i}.attn.v_proj.weight", "blk.{i}.attn_v.weight"),
+    ("model.layers.{i}.self_attn.o_proj.weight", "transformer.h.{i}.attn.o_proj.weight", "blk.{i}.attn_output.weight"),
+    ("model.layers.{i}.post_attention_layernorm.weight", "transformer.h.{i}.ln_2.weight", "blk.{i}.ffn_norm.weight"),
+    ("model.layers.{i}.mlp.gate_proj.weight", "transformer.h.{i}.mlp.gate_proj.weight", "blk.{i}.ffn_gate.weight"),
+    ("model.layers.{i}.mlp.up_proj.weight", "transformer.h.{i}.mlp.up_proj.weight", "blk.{i}.ffn_up.weight"),
+    ("model.layers.{i}.mlp.down_proj.weight", "transformer.h.{i}.mlp.down_proj.weight", "blk.{i}.ffn_down.weight"),
+];
+
+impl NameMap {
+    // Hugging Face layout, names are used as is
+    pub fn identity() -> Self {
+        Self::default()
+    }
+
+    // GPT-2 style `transformer.h.N.*` exports
+    pub fn transformer_h() -> Self {
+        NameMap { rules: PRESETS.iter().map(|(c, t, _)| (c.to_string(), t.to_string())).collect() }
+    }
+
+    // llama.cpp GGUF names
+    pub fn gguf() -> Self {
+        NameMap { rules: PRESETS.iter().map(|(c, _, g)| (c.to_string(), g.to_string())).collect() }
+    }
+
+    // Pick the preset whose final norm name is among `names`, falling back to identity()
+    pub fn detect<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+        let names: Vec<&str> = names.into_iter().collect();
+        [Self::identity(), Self::transformer_h(), Self::gguf()]
+            .into_iter()
+            .find(|preset| names.contains(&preset.map("model.norm.weight").as_str()))
+            .unwrap_or_default()
+    }
+
+    // Override one name, e.g. `.with("lm_head.weight", "embed_out.weight")` or per-layer with `{i}`
+    pub fn with(mut self, canonical: &str, checkpoint: &str) -> Self {
+        self.rules.insert(0, (canonical.to_string(), checkpoint.to_string()));
+        self
+    }
+
+    // Checkpoint name for `canonical`, unchanged when no rule applies
+    pub fn map(&self, canonical: &str) -> String {
+        for (from, to) in &self.rules {
+            match from.split_once(LAYER) {
+                None if from == canonical => return to.clone(),
+                Some((prefix, suffix)) => {
+                    let layer = canonical
+                        .strip_prefix(prefix)
+                        .and_then(|rest| rest.strip_suffix(suffix))
+                        .filter(|layer| layer.parse::<usize>().is_ok());
+                    if let Some(layer) = layer {
+                        return to.replace(LAYER, layer);
+                    }
+                }
+                None => {}
+            }
+        }
+        canonical.to_string()
+    }
+}
+
+#[test]
+fn test_name_map() {
+    let hf = NameMap::identity();
+    assert_eq!(hf.map("model.layers.3.mlp.up_proj.weight"), "model.layers.3.mlp.up_proj.weight");
+    let h = NameMap::transformer_h();
+    assert_eq!(h.map("model.layers.12.input_layernorm.weight"), "transformer.h.12.ln_1.weight");
+    assert_eq!(h.map("model.embed_tokens.weight"), "transformer.wte.weight");
+    assert_eq!(NameMap::gguf().map("model.layers.0.self_attn.o_proj.weight"), "blk.0.attn_output.weight");
+    // not a layer index, left alone
+    assert_eq!(h.map("model.layers.x.input_layernorm.weight"), "model.layers.x.input_layernorm.weight");
+
+    let custom = NameMap::transformer_h()
+        .with("lm_head.weight", "embed_out.weight")
+        .with("model.layers.{i}.mlp.down_proj.weight", "transformer.h.{i}.mlp.c_proj.weight");
+    assert_eq!(custom.map("lm_head.weight"), "embed_out.weight");
+    assert_eq!(custom.map("model.layers.5.mlp.down_proj.weight"), "transformer.h.5.mlp.c_proj.weight");
+    assert_eq!(custom.map("model.layers.5.mlp.up_proj.weight"), "transformer.h.5.mlp.up_proj.weight");
+
+    assert_eq!(NameMap::detect(["transformer.wte.weight", "transformer.ln_f.weight"]), NameMap::transformer_h());
+    assert_eq!(NameMap::detect(["output_norm.weight"]), NameMap::gguf());
+    assert_eq!(NameMap::detect(["model.norm.weight"]), NameMap::identity());
+}
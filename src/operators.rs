@@ -1,4 +1,8 @@
+use crate::quant::{self, Int8Tensor, QuantBlock, QuantTensor};
+use crate::simd;
 use crate::tensor::Tensor;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
 // get (row) vectors from a 2D table given a list of indices
 pub fn gather(y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<f32>) {
@@ -7,31 +11,100 @@ pub fn gather(y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<f32>) {
     assert!(table_shape.len() == 2);
     let dim = table_shape[1];
     assert!(y.size() == length * dim);
-    for i in 0..length {
-        let src = &table.data()[indices.data()[i] as usize * dim..][..dim];
-        let dst = &mut unsafe { y.data_mut() }[i * dim..][..dim];
-        dst.copy_from_slice(src);
-    }
+    let rows = table.index_select(0, indices.data());
+    y.make_mut().copy_from_slice(rows.data());
 }
 
 // RoPE: Rotary Positional Embedding
 pub fn rope(y: &mut Tensor<f32>, start_pos: usize, theta: f32) {
+    rope_scaled(y, start_pos, theta, RopeScaling::None)
+}
+
+// How positions beyond the pretraining context are squeezed into the rotary frequencies
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RopeScaling {
+    None,
+    // positions are divided by `factor`
+    Linear { factor: f32 },
+    // theta grows with the sequence length once it exceeds the original context (dynamic NTK-aware scaling)
+    DynamicNtk { factor: f32, original_max_position_embeddings: usize },
+    // fixed NTK-aware scaling, theta * factor^(d / (d - 2)) at every length
+    Ntk { factor: f32 },
+    // YaRN: high-frequency dimensions keep their frequency, low-frequency ones are interpolated like
+    // Linear, with a ramp between the dimensions completing beta_fast and beta_slow rotations over the
+    // original context; cos/sin are multiplied by attention_factor
+    Yarn { factor: f32, original_max_position_embeddings: usize, beta_fast: f32, beta_slow: f32, attention_factor: f32 },
+}
+
+impl RopeScaling {
+    // YaRN with the reference defaults: beta_fast 32, beta_slow 1 and attention_factor 0.1 * ln(factor) + 1
+    pub fn yarn(factor: f32, original_max_position_embeddings: usize) -> Self {
+        let attention_factor = if factor > 1. { 0.1 * factor.ln() + 1. } else { 1. };
+        RopeScaling::Yarn { factor, original_max_position_embeddings, beta_fast: 32., beta_slow: 1., attention_factor }
+    }
+}
+
+// RoPE with `scaling` applied; y is (seq, n_heads, head_dim) starting at position `start_pos`
+pub fn rope_scaled(y: &mut Tensor<f32>, start_pos: usize, theta: f32, scaling: RopeScaling) {
+    let head_dim = y.shape()[2];
+    rope_partial(y, start_pos, theta, scaling, head_dim)
+}
+
+// RoPE on the first `rotary_dim` dimensions of every head only, the rest pass through unchanged
+// (partial_rotary_factor, used by Phi). Frequencies are spaced over rotary_dim, not the head width
+pub fn rope_partial(y: &mut Tensor<f32>, start_pos: usize, theta: f32, scaling: RopeScaling, rotary_dim: usize) {
     let shape = y.shape();
     assert!(shape.len() == 3);
     let seq_len = shape[0];
     let n_heads = shape[1];
-    let d = shape[2];
-    let data = unsafe { y.data_mut() };
+    let head_dim = shape[2];
+    assert!(rotary_dim <= head_dim && rotary_dim.is_multiple_of(2));
+    let d = rotary_dim;
+    // inverse frequency of every rotated pair and a magnitude applied to cos/sin
+    let inv_freq = |theta: f32| -> Vec<f32> { (0..d / 2).map(|i| theta.powf(-((i * 2) as f32) / d as f32)).collect() };
+    let (inv_freq, mscale) = match scaling {
+        RopeScaling::None => (inv_freq(theta), 1.),
+        RopeScaling::Linear { factor } => (inv_freq(theta).iter().map(|f| f / factor).collect(), 1.),
+        RopeScaling::DynamicNtk { factor, original_max_position_embeddings: max } => {
+            let total_len = start_pos + seq_len;
+            if total_len > max {
+                let ratio = factor * total_len as f32 / max as f32 - (factor - 1.);
+                (inv_freq(theta * ratio.powf(d as f32 / (d as f32 - 2.))), 1.)
+            } else {
+                (inv_freq(theta), 1.)
+            }
+        }
+        RopeScaling::Ntk { factor } => (inv_freq(theta * factor.powf(d as f32 / (d as f32 - 2.))), 1.),
+        RopeScaling::Yarn { factor, original_max_position_embeddings: max, beta_fast, beta_slow, attention_factor } => {
+            // pair index whose wavelength fits `rotations` times into the original context
+            let correction_dim =
+                |rotations: f32| d as f32 * (max as f32 / (rotations * 2. * std::f32::consts::PI)).ln() / (2. * theta.ln());
+            let low = correction_dim(beta_fast).floor().max(0.);
+            let high = correction_dim(beta_slow).ceil().min(d as f32 - 1.);
+            let high = if high == low { high + 1e-3 } else { high };
+            let inv_freq = inv_freq(theta)
+                .iter()
+                .enumerate()
+                .map(|(i, &extrapolated)| {
+                    let keep = 1. - ((i as f32 - low) / (high - low)).clamp(0., 1.);
+                    extrapolated / factor * (1. - keep) + extrapolated * keep
+                })
+                .collect();
+            (inv_freq, attention_factor)
+        }
+    };
+    let data = y.make_mut();
     for tok in 0..seq_len {
-        let pos = start_pos + tok;
+        let pos = (start_pos + tok) as f32;
         for head in 0..n_heads {
             for i in 0..d / 2 {
-                let a = data[tok * n_heads * d + head * d + i];
-                let b = data[tok * n_heads * d + head * d + i + d / 2];
-                let freq = pos as f32 / theta.powf((i * 2) as f32 / d as f32);
-                let (sin, cos) = freq.sin_cos();
-                data[tok * n_heads * d + head * d + i] = a * cos - b * sin;
-                data[tok * n_heads * d + head * d + i + d / 2] = b * cos + a * sin;
+                let base = tok * n_heads * head_dim + head * head_dim;
+                let a = data[base + i];
+                let b = data[base + i + d / 2];
+                let (sin, cos) = (pos * inv_freq[i]).sin_cos();
+                let (sin, cos) = (sin * mscale, cos * mscale);
+                data[base + i] = a * cos - b * sin;
+                data[base + i + d / 2] = b * cos + a * sin;
             }
         }
     }
@@ -40,55 +113,198 @@ pub fn rope(y: &mut Tensor<f32>, start_pos: usize, theta: f32) {
 // softmax(x) = exp(x - max) / sum(exp(x - max))
 // y = softmax(mask(x))
 pub fn masked_softmax(y: &mut Tensor<f32>) {
+    masked_softmax_window(y, None)
+}
+
+// Causal softmax where query i additionally only sees the last `window` keys up to and including
+// its own position (sliding-window attention); None is plain causal masking
+pub fn masked_softmax_window(y: &mut Tensor<f32>, window: Option<usize>) {
     let ndim = y.shape().len();
     assert!(ndim >= 2);
-    let seq_len = y.shape()[ndim - 2];
-    let total_seq_len = y.shape()[ndim - 1];
-    let batch = y.size() / (seq_len * total_seq_len);
-    let data = unsafe { y.data_mut() };
-    for b in 0..batch {
-        let base = b * seq_len * total_seq_len;
-        for i in 0..seq_len {
-            let offset = base + i * total_seq_len;
-            let boundary = total_seq_len - seq_len + i + 1;
-
-            let max = data[offset..offset + boundary]
-                .iter()
-                .fold(data[offset], |a, b| a.max(*b));
+    let mask = causal_mask(y.shape()[ndim - 2], y.shape()[ndim - 1], window);
+    softmax(y, &[&mask]);
+}
 
-            let sum = (0..boundary)
-                .map(|j| {
-                    let e = (data[offset + j] - max).exp();
-                    data[offset + j] = e;
-                    e
-                })
-                .sum::<f32>();
+// Softmax over the last dim of y after adding every mask to it. Masks broadcast against y like numpy
+// (missing leading dims and dims of size 1 repeat), so a (seq, total_seq) causal mask and a
+// (batch, 1, 1, total_seq) padding mask can both apply to (batch, heads, seq, total_seq) scores.
+// Masked entries are -inf and end up 0; a row with every entry masked becomes all zeros
+pub fn softmax(y: &mut Tensor<f32>, masks: &[&Tensor<f32>]) {
+    let ndim = y.shape().len();
+    assert!(ndim >= 1);
+    let n = y.shape()[ndim - 1];
+    let rows_shape = y.shape()[..ndim - 1].to_vec();
+    let masks: Vec<(&[f32], Vec<usize>)> = masks
+        .iter()
+        .map(|mask| {
+            let shape = mask.shape();
+            assert!(shape.len() <= ndim && shape.last() == Some(&n), "mask {shape:?} does not broadcast to {:?}", y.shape());
+            // left-pad with 1s to y's rank, without the last dim
+            let mut padded = vec![1; ndim - shape.len()];
+            padded.extend_from_slice(&shape[..shape.len() - 1]);
+            assert!(padded.iter().zip(&rows_shape).all(|(&m, &r)| m == r || m == 1), "mask {shape:?} does not broadcast to {:?}", y.shape());
+            (mask.data(), padded)
+        })
+        .collect();
+    for_each_chunk_mut(y.make_mut(), n, |r, row| {
+        for (data, shape) in &masks {
+            let offset = broadcast_row(r, &rows_shape, shape) * n;
+            row.iter_mut().zip(&data[offset..offset + n]).for_each(|(x, m)| *x += m);
+        }
+        let max = row.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+        if max == f32::NEG_INFINITY {
+            row.iter_mut().for_each(|x| *x = 0.0);
+            return;
+        }
+        let sum = row
+            .iter_mut()
+            .map(|x| {
+                *x = (*x - max).exp();
+                *x
+            })
+            .sum::<f32>();
+        row.iter_mut().for_each(|x| *x /= sum);
+    });
+}
 
-            (0..boundary).for_each(|j| data[offset + j] /= sum);
-            (boundary..total_seq_len).for_each(|j| data[offset + j] = 0.0);
+// Row of a broadcast mask (shape `mask_rows`) that row r of a tensor with shape `rows` reads
+fn broadcast_row(mut r: usize, rows: &[usize], mask_rows: &[usize]) -> usize {
+    let (mut row, mut stride) = (0, 1);
+    for (&dim, &mask_dim) in rows.iter().zip(mask_rows).rev() {
+        if mask_dim != 1 {
+            row += r % dim * stride;
         }
+        r /= dim;
+        stride *= mask_dim;
+    }
+    row
+}
+
+// (seq_len, total_seq_len) additive mask, 0 where query i (at position total_seq_len - seq_len + i) may
+// attend and -inf elsewhere: keys after it and, with a window, keys more than `window` - 1 before it
+pub fn causal_mask(seq_len: usize, total_seq_len: usize, window: Option<usize>) -> Tensor<f32> {
+    assert!(seq_len <= total_seq_len);
+    let mut data = vec![f32::NEG_INFINITY; seq_len * total_seq_len];
+    for (i, row) in data.chunks_exact_mut(total_seq_len).enumerate() {
+        let boundary = total_seq_len - seq_len + i + 1;
+        let start = window.map_or(0, |w| boundary.saturating_sub(w));
+        row[start..boundary].iter_mut().for_each(|x| *x = 0.0);
+    }
+    Tensor::new(data, &vec![seq_len, total_seq_len])
+}
+
+// (batch, 1, 1, total_seq_len) additive mask hiding the padded keys of each sequence in a batch,
+// sequence b owns keys [start, start + len) of its row given as (start, len) in `valid`
+pub fn padding_mask(valid: &[(usize, usize)], total_seq_len: usize) -> Tensor<f32> {
+    let mut data = vec![f32::NEG_INFINITY; valid.len() * total_seq_len];
+    for (row, &(start, len)) in data.chunks_exact_mut(total_seq_len).zip(valid) {
+        assert!(start + len <= total_seq_len);
+        row[start..start + len].iter_mut().for_each(|x| *x = 0.0);
     }
+    Tensor::new(data, &vec![valid.len(), 1, 1, total_seq_len])
+}
+
+// Keys scored per step of flash_attention, small enough for the scores to live on the stack
+const FLASH_BLOCK: usize = 64;
+
+// softmax(Q @ K^T / sqrt(d), causal and optional sliding-window mask) @ V without materializing the
+// (heads, seq, total_seq) score matrix: keys are visited in blocks while a running max and softmax
+// denominator rescale the partial output (online softmax). q is (seq, n_q_h, dqkv) with query i at
+// position total_seq - seq + i; k and v are (total_seq, n_kv_h * dqkv) and each kv head serves
+// n_q_h / n_kv_h consecutive query heads. out is (seq, n_q_h * dqkv). With `softcap` the scaled scores
+// go through softcap() before the softmax
+pub fn flash_attention(
+    out: &mut Tensor<f32>,
+    q: &Tensor<f32>,
+    k: &Tensor<f32>,
+    v: &Tensor<f32>,
+    window: Option<usize>,
+    softcap: Option<f32>,
+) {
+    let (seq_len, n_q_h, dqkv) = (q.shape()[0], q.shape()[1], q.shape()[2]);
+    let (total_seq_len, kv_width) = (k.shape()[0], k.shape()[1]);
+    let n_kv_h = kv_width / dqkv;
+    assert!(n_q_h % n_kv_h == 0 && v.shape() == k.shape() && out.size() == seq_len * n_q_h * dqkv);
+    let n_groups = n_q_h / n_kv_h;
+    let scale = 1. / (dqkv as f32).sqrt();
+    let (q_data, k_data, v_data) = (q.data(), k.data(), v.data());
+    // one chunk per (query, head) output row
+    for_each_chunk_mut(out.make_mut(), dqkv, |r, acc| {
+        let (i, head) = (r / n_q_h, r % n_q_h);
+        let kv_offset = (head / n_groups) * dqkv;
+        let q_row = &q_data[r * dqkv..][..dqkv];
+        let end = total_seq_len - seq_len + i + 1;
+        let start = window.map_or(0, |w| end.saturating_sub(w));
+        let (mut max, mut sum) = (f32::NEG_INFINITY, 0f32);
+        let mut scores = [0f32; FLASH_BLOCK];
+        acc.fill(0.);
+        for b0 in (start..end).step_by(FLASH_BLOCK) {
+            let block = &mut scores[..(end - b0).min(FLASH_BLOCK)];
+            for (j, score) in block.iter_mut().enumerate() {
+                *score = simd::dot(q_row, &k_data[(b0 + j) * kv_width + kv_offset..][..dqkv]) * scale;
+            }
+            if let Some(cap) = softcap {
+                block.iter_mut().for_each(|x| *x = softcap_value(*x, cap));
+            }
+            let new_max = block.iter().fold(max, |a, &b| a.max(b));
+            // rescale what was accumulated under the old max
+            let correction = (max - new_max).exp();
+            sum *= correction;
+            simd::scale(correction, acc);
+            for (j, score) in block.iter().enumerate() {
+                let p = (score - new_max).exp();
+                sum += p;
+                simd::axpy(p, &v_data[(b0 + j) * kv_width + kv_offset..][..dqkv], acc);
+            }
+            max = new_max;
+        }
+        simd::scale(1. / sum, acc);
+    });
+}
+
+// y = cap * tanh(y / cap), squashes attention scores or logits smoothly into (-cap, cap)
+pub fn softcap(y: &mut Tensor<f32>, cap: f32) {
+    assert!(cap > 0.);
+    y.make_mut().iter_mut().for_each(|x| *x = softcap_value(*x, cap));
+}
+
+#[inline]
+fn softcap_value(x: f32, cap: f32) -> f32 {
+    cap * (x / cap).tanh()
+}
+
+// Run `f(index, chunk)` over consecutive `chunk`-sized pieces of `data`,
+// spread across threads when the `parallel` feature is enabled
+pub fn for_each_chunk_mut<T: Send>(data: &mut [T], chunk: usize, f: impl Fn(usize, &mut [T]) + Send + Sync) {
+    #[cfg(feature = "parallel")]
+    data.par_chunks_mut(chunk).enumerate().for_each(|(i, c)| f(i, c));
+    #[cfg(not(feature = "parallel"))]
+    data.chunks_mut(chunk).enumerate().for_each(|(i, c)| f(i, c));
 }
 
 pub fn rms_norm(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon: f32) {
+    // strided inputs (e.g. a column slice of a wider buffer) are compacted first
+    let (x, w) = (&x.contiguous(), &w.contiguous());
     let x_len = x.size();
  
     let w_len = w.size();
  
     let x_silce_num = x_len / w_len;
  
-    let y_data = unsafe { y.data_mut() };
+    let y_data = y.make_mut();
     let w_data = w.data();
  
     for i in 0..x_silce_num{
         let slice = x.slice(w_len*i, &vec![w_len]); // 创建一个更长生命周期的值
         let x_slice = slice.data();
-        let sum_of_squares: f32 = x_slice.iter().map(|&xj| xj * xj).sum();
+        let sum_of_squares = simd::dot(x_slice, x_slice);
         let rms = (sum_of_squares / w_len as f32 + epsilon).sqrt();
  
+        let y_slice = &mut y_data[w_len*i..][..w_len];
         for j in 0..w_len{
-            y_data[w_len*i + j] = x_slice[j] * w_data[j] / rms;
+            y_slice[j] = x_slice[j] * w_data[j];
         }
+        simd::scale(1. / rms, y_slice);
     }
 }
 pub fn sigmoid(x: f32) -> f32{
@@ -102,7 +318,8 @@ pub fn silu(y: &mut Tensor<f32>, x: &Tensor<f32>) {
     let len = y.size();
     assert!(len == x.size());
  
-    let y_data = unsafe { y.data_mut() };
+    let x = x.contiguous();
+    let y_data = y.make_mut();
     let x_data = x.data();
  
     // 逐元素计算
@@ -111,79 +328,293 @@ pub fn silu(y: &mut Tensor<f32>, x: &Tensor<f32>) {
     }
 }
 
+// Activation of the gate projection in the gated FFN, from the config's `hidden_act`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activation {
+    Silu,
+    // x * Phi(x) with the exact normal CDF
+    Gelu,
+    // tanh approximation of Gelu (GPT-2's gelu_new, gelu_pytorch_tanh)
+    GeluTanh,
+}
+
+impl Activation {
+    // Hugging Face ACT2FN name, None for activations without a kernel here
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "silu" | "swish" => Some(Activation::Silu),
+            "gelu" => Some(Activation::Gelu),
+            "gelu_new" | "gelu_pytorch_tanh" | "gelu_fast" => Some(Activation::GeluTanh),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self, x: f32) -> f32 {
+        match self {
+            Activation::Silu => x * sigmoid(x),
+            Activation::Gelu => 0.5 * x * (1. + erf(x * std::f32::consts::FRAC_1_SQRT_2)),
+            Activation::GeluTanh => {
+                let inner = (2. / std::f32::consts::PI).sqrt() * (x + 0.044715 * x * x * x);
+                0.5 * x * (1. + inner.tanh())
+            }
+        }
+    }
+}
+
+// Error function, Abramowitz and Stegun 7.1.26 (absolute error below 1.5e-7)
+fn erf(x: f32) -> f32 {
+    let t = 1. / (1. + 0.3275911 * x.abs());
+    let poly = t * (0.2548296 + t * (-0.28449674 + t * (1.4214138 + t * (-1.4531521 + t * 1.0614054))));
+    (1. - poly * (-x * x).exp()).copysign(x)
+}
+
+// y = gelu(x) * y, silu's counterpart for GELU-gated FFNs
+pub fn gelu(y: &mut Tensor<f32>, x: &Tensor<f32>) {
+    gated_activation(y, x, Activation::Gelu)
+}
+
+// y = act(x) * y element-wise
+pub fn gated_activation(y: &mut Tensor<f32>, x: &Tensor<f32>, act: Activation) {
+    assert!(y.size() == x.size());
+    let x = x.contiguous();
+    y.make_mut().iter_mut().zip(x.data()).for_each(|(y, &x)| *y *= act.apply(x));
+}
+
+// C = A + B, B is broadcast against A (e.g. a (hidden,) bias over (seq, hidden))
 pub fn add<T>(tensor_a: &mut Tensor<T>, tensor_b: &mut Tensor<T>) -> Tensor<T>
 where
     T: Default + Copy + std::ops::Add<Output = T> + std::ops::Mul<Output = T> + std::fmt::Display,
 {
-    assert!(tensor_a.size() == tensor_b.size());
-    
-    let mut c_data = vec![T::default(); tensor_a.size()];
-
-    let c_shape = tensor_a.shape();
-    
-    for i in 0..tensor_a.size(){
-        c_data[i] = tensor_a.data()[i] + tensor_b.data()[i];
-    }
-
-    Tensor::new(c_data, &c_shape)
+    tensor_a.add(tensor_b)
 }
 
+// C = A @ B, both operands may be strided views (e.g. a transposed weight)
 pub fn multiple<T>(tensor_a: &Tensor<T>, tensor_b: &Tensor<T>) -> Tensor<T>
 where
     T: Default + Copy + std::ops::Add<Output = T> + std::ops::Mul<Output = T> + std::fmt::Display,
 {
+    let (m, n) = (tensor_a.shape()[0], tensor_a.shape()[1]);
     let other_row = tensor_b.shape()[1];
+    assert!(tensor_b.shape()[0] == n);
     
-    let mut c_data = vec![T::default(); tensor_a.shape()[0] * other_row];
+    let mut c_data = vec![T::default(); m * other_row];
  
-    let c_shape = vec![tensor_a.shape()[0], other_row];
+    let c_shape = vec![m, other_row];
+
+    let (a_data, a_strides) = (tensor_a.strided_data(), tensor_a.strides());
+    let (b_data, b_strides) = (tensor_b.strided_data(), tensor_b.strides());
  
-    // 进行矩阵乘法并将结果累加到c
-    for i in 0..tensor_a.shape()[0] {
+    for i in 0..m {
         for j in 0..other_row {
             let mut elem: T = T::default();
-            for k in 0..tensor_a.shape()[1] {
-                // 计算每个元素的乘积
-                let a_value = tensor_a.data()[i * tensor_a.shape()[1] + k];
-                let b_value = tensor_b.data()[k * other_row + j];
+            for k in 0..n {
+                let a_value = a_data[i * a_strides[0] + k * a_strides[1]];
+                let b_value = b_data[k * b_strides[0] + j * b_strides[1]];
                 elem = elem + a_value * b_value;
- 
-                // 输出当前的乘积和累加值
-                println!("计算: {} * {} = {}", a_value, b_value, a_value * b_value);
-                println!("当前累加值: {}", elem);
             }
-            // 将计算结果累加到 c_data 中
-            c_data[i * other_row + j] = c_data[i * other_row + j] + elem;
- 
-            // 输出当前结果矩阵 c_data 的值
-            println!("c_data[{}] = {}", i * other_row + j, c_data[i * other_row + j]);
+            c_data[i * other_row + j] = elem;
         }
     }
  
     Tensor::new(c_data, &c_shape)
 }
  
+// Matmuls below this many multiply-adds stay on the calling thread, handing them out costs more than it saves
+const PAR_MIN_WORK: usize = 1 << 16;
+// Output columns per task when a matmul is split by columns
+const PAR_COL_BLOCK: usize = 64;
+
+// Threads that for_each_chunk_mut() spreads work over, 1 without the `parallel` feature
+pub fn num_threads() -> usize {
+    #[cfg(feature = "parallel")]
+    return rayon::current_num_threads();
+    #[cfg(not(feature = "parallel"))]
+    1
+}
+
+// Size the global rayon pool (RAYON_NUM_THREADS works too). Must run before the first parallel
+// operator, afterwards the pool is fixed and this returns an error
+#[cfg(feature = "parallel")]
+pub fn set_num_threads(n: usize) -> Result<(), rayon::ThreadPoolBuildError> {
+    rayon::ThreadPoolBuilder::new().num_threads(n).build_global()
+}
+
 // C = beta * C + alpha * A @ B^T
 // hint: You don't need to do an explicit transpose of B
 pub fn matmul_transb(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<f32>, alpha: f32) {
-    let mut c_data = unsafe { c.data_mut() };
+    assert!(a.shape().len() == 2 && b.shape().len() == 2, "matmul_transb() needs 2-D a and b");
+    let (m, k) = (a.shape()[0], a.shape()[1]);
+    let n = b.shape()[0];
+    assert!(b.shape()[1] == k, "a is {:?} but b is {:?}, expected (n, {k})", a.shape(), b.shape());
+    assert!(c.size() == m * n, "c has {} elements, expected ({m}, {n})", c.size());
+    let c_data = c.make_mut();
+    if a.is_contiguous() && b.is_contiguous() {
+        // rows of A and B are both dense, so every output is a single dot product
+        let (a_data, b_data) = (a.data(), b.data());
+        let b_row = |j: usize| &b_data[j * k..][..k];
+        // C[i, j0..j0 + c.len()]
+        let kernel = |i: usize, j0: usize, c: &mut [f32]| {
+            let a_row = &a_data[i * k..][..k];
+            let tail = j0 + c.len() / 4 * 4;
+            // four columns at a time share each load of a_row
+            let mut blocks = c.chunks_exact_mut(4);
+            for (jb, c) in (&mut blocks).enumerate() {
+                let j = j0 + jb * 4;
+                let dots = simd::dot4(a_row, [b_row(j), b_row(j + 1), b_row(j + 2), b_row(j + 3)]);
+                for (c, d) in c.iter_mut().zip(dots) {
+                    *c = beta * *c + alpha * d;
+                }
+            }
+            for (j, c) in blocks.into_remainder().iter_mut().enumerate() {
+                *c = beta * *c + alpha * simd::dot(a_row, b_row(tail + j));
+            }
+        };
+        if m * n * k < PAR_MIN_WORK {
+            c_data.chunks_mut(n).enumerate().for_each(|(i, c_row)| kernel(i, 0, c_row));
+        } else if m >= TILE_M {
+            // prefill: enough rows to reuse every weight tile several times
+            matmul_transb_tiled(c_data, beta, a_data, b_data, alpha, n, k);
+        } else if m >= num_threads() {
+            for_each_chunk_mut(c_data, n, |i, c_row| kernel(i, 0, c_row));
+        } else {
+            // decode: a single row, split its columns (the weight matrix rows) instead
+            for (i, c_row) in c_data.chunks_mut(n).enumerate() {
+                for_each_chunk_mut(c_row, PAR_COL_BLOCK, |jb, c| kernel(i, jb * PAR_COL_BLOCK, c));
+            }
+        }
+        return;
+    }
     for elem in c_data.iter_mut(){
-        *elem = *elem * beta;
+        *elem *= beta;
     }
+    // zero-copy view, multiple() reads B through its strides
     let b = b.transpose(vec![1, 0]);
-    println!("计算后的b矩阵：");
-    for i in 0..b.shape()[0]{
-        for j in 0..b.shape()[1] {
-            print!("{:.2} ", b.data()[i * b.shape()[1] + j]);
+    let elems =  multiple(a, &b);
+    let elems = elems.data();
+    for (c, e) in c_data.iter_mut().zip(elems) {
+        *c += e * alpha;
+    }
+}
+
+// Cache blocking for matmul_transb_tiled: a TILE_N x TILE_K block of B (64 KiB) stays in L2 while
+// TILE_M rows of A stream past it, instead of re-reading all of B for every row
+const TILE_M: usize = 16;
+const TILE_N: usize = 64;
+const TILE_K: usize = 256;
+
+// The contiguous matmul_transb, tiled over M, N and K; row tiles are independent and run in parallel
+fn matmul_transb_tiled(c_data: &mut [f32], beta: f32, a: &[f32], b: &[f32], alpha: f32, n: usize, k: usize) {
+    for_each_chunk_mut(c_data, TILE_M * n, |ib, c_tile| {
+        let (i0, rows) = (ib * TILE_M, c_tile.len() / n);
+        c_tile.iter_mut().for_each(|c| *c *= beta);
+        for j0 in (0..n).step_by(TILE_N) {
+            let j1 = (j0 + TILE_N).min(n);
+            for k0 in (0..k).step_by(TILE_K) {
+                let k1 = (k0 + TILE_K).min(k);
+                let b_row = |j: usize| &b[j * k + k0..j * k + k1];
+                for r in 0..rows {
+                    let a_row = &a[(i0 + r) * k + k0..(i0 + r) * k + k1];
+                    let c_row = &mut c_tile[r * n..][..n];
+                    let mut j = j0;
+                    while j + 4 <= j1 {
+                        let dots = simd::dot4(a_row, [b_row(j), b_row(j + 1), b_row(j + 2), b_row(j + 3)]);
+                        for (c, d) in c_row[j..j + 4].iter_mut().zip(dots) {
+                            *c += alpha * d;
+                        }
+                        j += 4;
+                    }
+                    for (j, c) in (j..j1).zip(&mut c_row[j..j1]) {
+                        *c += alpha * simd::dot(a_row, b_row(j));
+                    }
+                }
+            }
+        }
+    });
+}
+
+// C = beta * C + alpha * A @ B^T with half-precision (f16 or bf16) weights B, accumulated in f32
+pub fn matmul_transb_half<W>(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<W>, alpha: f32)
+where
+    W: Copy + Default + Into<f32>,
+{
+    let (m, k) = (a.shape()[0], a.shape()[1]);
+    let n = b.shape()[0];
+    assert!(b.shape()[1] == k);
+    assert!(c.size() == m * n);
+    assert!(a.is_contiguous() && b.is_contiguous(), "matmul_transb_half() needs contiguous operands");
+    let (a_data, b_data) = (a.data(), b.data());
+    let c_data = c.make_mut();
+    for i in 0..m {
+        let a_row = &a_data[i * k..][..k];
+        for j in 0..n {
+            let b_row = &b_data[j * k..][..k];
+            let sum: f32 = a_row.iter().zip(b_row).map(|(x, w)| x * (*w).into()).sum();
+            c_data[i * n + j] = beta * c_data[i * n + j] + alpha * sum;
         }
-        println!();
     }
-    let elems =  multiple(&a, &b);
-    let elems = elems.data();
-    for i in 0..elems.len(){
-        c_data[i] += elems[i]* alpha;
-    } 
-    
+}
+
+// C = beta * C + alpha * A @ B^T with block-quantized weights B, dequantized on the fly
+pub fn matmul_transb_quant<B: QuantBlock>(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &QuantTensor<B>,
+    alpha: f32,
+) {
+    let (m, k) = (a.shape()[0], a.shape()[1]);
+    let n = b.shape()[0];
+    assert!(b.shape()[1] == k);
+    assert!(c.size() == m * n);
+    assert!(a.is_contiguous(), "matmul_transb_quant() needs a contiguous input");
+    let a_data = a.data();
+    let c_data = c.make_mut();
+    for i in 0..m {
+        let a_row = &a_data[i * k..][..k];
+        for j in 0..n {
+            let sum: f32 = b
+                .row(j)
+                .iter()
+                .zip(a_row.chunks(B::SIZE))
+                .map(|(block, x)| block.dot(x))
+                .sum();
+            c_data[i * n + j] = beta * c_data[i * n + j] + alpha * sum;
+        }
+    }
+}
+
+// Activations are quantized in blocks of this many values for matmul_transb_int8, each with its own scale
+const INT8_ACT_BLOCK: usize = 32;
+
+// C = beta * C + alpha * A @ B^T with B as int8 codes and one scale per row (Int8Tensor). Every row of A
+// is quantized to int8 per INT8_ACT_BLOCK values on the fly, so the inner loop is an integer dot product
+pub fn matmul_transb_int8(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Int8Tensor, alpha: f32) {
+    let (m, k) = (a.shape()[0], a.shape()[1]);
+    let n = b.shape()[0];
+    assert!(b.shape().len() == 2 && b.shape()[1] == k);
+    assert!(c.size() == m * n);
+    assert!(a.is_contiguous(), "matmul_transb_int8() needs a contiguous input");
+    let blocks = k.div_ceil(INT8_ACT_BLOCK);
+    let mut a_q = vec![0i8; m * k];
+    let mut a_scales = vec![0f32; m * blocks];
+    for ((row, q), scales) in a.data().chunks(k).zip(a_q.chunks_mut(k)).zip(a_scales.chunks_mut(blocks)) {
+        for ((x, q), scale) in row.chunks(INT8_ACT_BLOCK).zip(q.chunks_mut(INT8_ACT_BLOCK)).zip(scales) {
+            *scale = quant::quantize_i8(x, q);
+        }
+    }
+    for_each_chunk_mut(c.make_mut(), n, |i, c_row| {
+        let (a_row, a_scales) = (&a_q[i * k..][..k], &a_scales[i * blocks..][..blocks]);
+        for (j, c) in c_row.iter_mut().enumerate() {
+            let (b_row, b_scale) = b.row(j);
+            let sum: f32 = a_row
+                .chunks(INT8_ACT_BLOCK)
+                .zip(b_row.chunks(INT8_ACT_BLOCK))
+                .zip(a_scales)
+                .map(|((x, w), scale)| simd::dot_i8(x, w) as f32 * scale)
+                .sum();
+            *c = beta * *c + alpha * b_scale * sum;
+        }
+    });
 }
 
 // Dot product of two tensors (treated as vectors)
@@ -191,6 +622,7 @@ pub fn matmul_transb(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor
 pub fn dot(x: &Tensor<f32>, y: &Tensor<f32>) -> f32 {
     let len = x.size();
     assert!(len == y.size());
+    assert!(x.is_contiguous() && y.is_contiguous(), "dot() needs contiguous operands");
     let x_ = x.data();
     let y_ = y.data();
     let mut sum = 0.0;
@@ -204,13 +636,7 @@ pub fn dot(x: &Tensor<f32>, y: &Tensor<f32>) -> f32 {
 pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32) -> u32 {
     assert!(x.shape()[x.shape().len() - 1] == x.size());
     if temperature <= 0. || top_k < 2 || top_p <= 0. {
-        return x
-            .data()
-            .iter()
-            .enumerate()
-            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
-            .unwrap()
-            .0 as _;
+        return x.argmax().0;
     }
 
     #[derive(Clone, Copy, PartialEq, Debug)]
@@ -238,31 +664,39 @@ pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32)
         #[inline]
         fn from((i, p): (usize, &f32)) -> Self {
             Self {
-                val: p.clone(),
+                val: *p,
                 tok: i as _,
             }
         }
     }
 
-    // sort
+    // partial selection: the top_k largest logits are moved to the front in O(vocab), only those are sorted
     let mut logits = x
         .data()
         .iter()
         .enumerate()
         .map(Probability::from)
         .collect::<Vec<_>>();
+    let k = (top_k as usize).min(logits.len());
+    if k < logits.len() {
+        logits.select_nth_unstable(k - 1);
+        logits.truncate(k);
+    }
     logits.sort_unstable();
-    let max = core::mem::replace(&mut logits[0].val, 1.);
-    // softmax & sum
-    for i in 1..logits.len() {
-        logits[i].val = logits[i - 1].val + ((logits[i].val - max) / temperature).exp();
+    let max = logits[0].val;
+    // softmax over the whole vocab (top_p is a fraction of it) & cumulative sum over the candidates
+    let total: f32 = x.data().iter().map(|&v| ((v - max) / temperature).exp()).sum();
+    let mut sum = 0.;
+    for p in logits.iter_mut() {
+        sum += ((p.val - max) / temperature).exp();
+        p.val = sum;
     }
-    // topk & topp & random
-    let pk = logits[(top_k as usize).min(logits.len()) - 1].val;
-    let pp = logits[logits.len() - 1].val * top_p;
-    let plimit = rand::random::<f32>() * f32::min(pk, pp);
-    // sample
-    logits.iter().find(|p| p.val >= plimit).unwrap().tok
+    // topp: of those, the smallest prefix whose cumulative probability reaches top_p (the nucleus)
+    let pp = total * top_p;
+    let n = (logits.partition_point(|p| p.val < pp) + 1).min(k);
+    // sample, renormalized over the n kept tokens
+    let plimit = rand::random::<f32>() * logits[n - 1].val;
+    logits[..n].iter().find(|p| p.val >= plimit).unwrap().tok
 }
 
 // Your implementation should at least pass the following tests:
@@ -277,6 +711,27 @@ fn test_silu() {
     ));
 }
 
+#[test]
+fn test_gelu() {
+    let x = Tensor::<f32>::new(vec![-3., -1., 0., 0.5, 1., 2.], &vec![2, 3]);
+    let mut y = Tensor::<f32>::new(vec![1.; 6], &vec![2, 3]);
+    gelu(&mut y, &x);
+    // x * Phi(x) from torch.nn.functional.gelu
+    let expected = Tensor::<f32>::new(vec![-0.0040497, -0.15865525, 0., 0.34573123, 0.8413448, 1.9544997], &vec![2, 3]);
+    assert!(y.close_to(&expected, 1e-4));
+    let mut y = Tensor::<f32>::new(vec![2.; 6], &vec![2, 3]);
+    gated_activation(&mut y, &x, Activation::GeluTanh);
+    let expected = Tensor::<f32>::new(vec![-0.0072748, -0.317616, 0., 0.691428, 1.682384, 3.9091954], &vec![2, 3]);
+    assert!(y.close_to(&expected, 1e-4));
+    // Silu through the generic path matches the silu op
+    let (mut a, mut b) = (Tensor::<f32>::new(vec![0.5; 6], &vec![2, 3]), Tensor::<f32>::new(vec![0.5; 6], &vec![2, 3]));
+    silu(&mut a, &x);
+    gated_activation(&mut b, &x, Activation::Silu);
+    assert!(a.close_to(&b, 1e-6));
+    assert_eq!(Activation::from_name("gelu_pytorch_tanh"), Some(Activation::GeluTanh));
+    assert_eq!(Activation::from_name("relu2"), None);
+}
+
 #[test]
 fn test_rms_norm() {
     let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
@@ -292,6 +747,22 @@ fn test_rms_norm() {
     ));
 }
 
+#[test]
+fn test_strided_inputs() {
+    let wide = Tensor::<f32>::new(vec![0., 1., 2., 0., 3., 4.], &vec![2, 3]);
+    let x = wide.slice_dims(&[0..2, 1..3]);
+    let mut y = Tensor::<f32>::default(&vec![2, 2]);
+    let w = Tensor::<f32>::new(vec![1., 2.], &vec![2]);
+    rms_norm(&mut y, &x, &w, 1e-6);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(vec![0.6324554, 2.5298216, 0.8485281, 2.2627416], &vec![2, 2]),
+        1e-3
+    ));
+    let mut c = Tensor::<f32>::default(&vec![2, 2]);
+    matmul_transb(&mut c, 0., &x, &x, 1.);
+    assert!(c.close_to(&Tensor::<f32>::new(vec![5., 11., 11., 25.], &vec![2, 2]), 1e-3));
+}
+
 #[test]
 fn test_matmul_transb() {
     let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
@@ -302,4 +773,353 @@ fn test_matmul_transb() {
         &Tensor::<f32>::new(vec![15., 34., 35., 81.], &vec![2, 2]),
         1e-3
     ));
+    // a with the wrong number of rows or columns is rejected instead of read out of step
+    let run = |a: Tensor<f32>| std::panic::catch_unwind(|| matmul_transb(&mut c.clone(), 1., &a, &b, 1.));
+    assert!(run(Tensor::new(vec![1.; 3], &vec![1, 3])).is_err());
+    assert!(run(Tensor::new(vec![1.; 4], &vec![2, 2])).is_err());
+    assert!(run(Tensor::new(vec![1.; 6], &vec![6])).is_err());
+}
+
+#[test]
+fn test_matmul_transb_large() {
+    // big enough for the parallel paths: one row split by columns, a few rows, then tiles
+    // with ragged edges in every dimension
+    for m in [1, 8, 37] {
+        let a = Tensor::<f32>::randn(&vec![m, 600], 1);
+        let b = Tensor::<f32>::randn(&vec![301, 600], 2);
+        let mut c = Tensor::<f32>::new(vec![1.; m * 301], &vec![m, 301]);
+        matmul_transb(&mut c, 0.5, &a, &b, 2.);
+        let mut expected = multiple(&a, &b.transpose(vec![1, 0]));
+        expected.scale_(2.);
+        expected.make_mut().iter_mut().for_each(|x| *x += 0.5);
+        assert!(c.compare(&expected).max_abs_err < 1e-3);
+    }
+}
+
+#[test]
+fn test_matmul_transb_narrow() {
+    // output widths around the 4-column micro-kernel and inner dims off the vector width
+    for (m, n, k) in [(1, 1, 1), (2, 3, 5), (3, 4, 9), (2, 5, 16), (1, 7, 17), (4, 9, 33)] {
+        let a = Tensor::<f32>::randn(&vec![m, k], 5);
+        let b = Tensor::<f32>::randn(&vec![n, k], 6);
+        let mut c = Tensor::<f32>::new(vec![1.; m * n], &vec![m, n]);
+        matmul_transb(&mut c, 0.5, &a, &b, 2.);
+        let mut expected = multiple(&a, &b.transpose(vec![1, 0]));
+        expected.make_mut().iter_mut().for_each(|x| *x = 2. * *x + 0.5);
+        assert!(c.compare(&expected).max_abs_err < 1e-4, "{m}x{n}x{k}");
+    }
+}
+
+#[test]
+fn test_matmul_transb_tiled() {
+    // exactly one tile, one past it in every dimension, and several with ragged last tiles
+    let shapes = [
+        (TILE_M, TILE_N, TILE_K),
+        (TILE_M + 1, TILE_N + 1, TILE_K + 1),
+        (2 * TILE_M - 1, 2 * TILE_N + 3, 3 * TILE_K - 5),
+        (1, 3, 2),
+    ];
+    for (m, n, k) in shapes {
+        let a = Tensor::<f32>::randn(&vec![m, k], 7);
+        let b = Tensor::<f32>::randn(&vec![n, k], 8);
+        let mut c = vec![1.; m * n];
+        matmul_transb_tiled(&mut c, -1., a.data(), b.data(), 0.5, n, k);
+        let mut expected = multiple(&a, &b.transpose(vec![1, 0]));
+        expected.make_mut().iter_mut().for_each(|x| *x = 0.5 * *x - 1.);
+        assert!(Tensor::new(c, &vec![m, n]).compare(&expected).max_abs_err < 1e-3, "{m}x{n}x{k}");
+    }
+}
+
+#[test]
+fn test_matmul_transb_half() {
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let b = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let expected = Tensor::<f32>::new(vec![15., 34., 35., 81.], &vec![2, 2]);
+    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    matmul_transb_half(&mut c, 1., &a, &b.to_f16(), 1.);
+    assert!(c.close_to(&expected, 1e-3));
+    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    matmul_transb_half(&mut c, 1., &a, &b.to_bf16(), 1.);
+    assert!(c.close_to(&expected, 1e-3));
+}
+
+#[test]
+fn test_matmul_transb_q8() {
+    use crate::quant::Q8Tensor;
+    let a = Tensor::<f32>::new((0..64).map(|i| i as f32 / 64.).collect(), &vec![2, 32]);
+    let b = Tensor::<f32>::new((0..96).map(|i| (i as f32 - 48.) / 32.).collect(), &vec![3, 32]);
+    let mut expected = Tensor::<f32>::default(&vec![2, 3]);
+    matmul_transb(&mut expected, 0., &a, &b, 1.);
+    let mut c = Tensor::<f32>::default(&vec![2, 3]);
+    matmul_transb_quant(&mut c, 0., &a, &Q8Tensor::quantize(&b), 1.);
+    assert!(c.close_to(&expected, 1e-2));
+}
+
+#[test]
+fn test_softcap() {
+    let mut y = Tensor::<f32>::new(vec![-100., -1., 0., 1., 100.], &vec![5]);
+    softcap(&mut y, 30.);
+    let expected: Vec<f32> = [-100f32, -1., 0., 1., 100.].iter().map(|x| 30. * (x / 30.).tanh()).collect();
+    assert_eq!(y.data(), &expected);
+    assert!(y.data()[4] < 30. && (y.data()[3] - 1.).abs() < 1e-3);
+
+    // flash_attention caps the scores it streams like the explicit scores -> softcap -> softmax path
+    let (seq_len, total_seq_len, n_q_h, n_kv_h, dqkv) = (3, 70, 4, 2, 8);
+    let q = Tensor::<f32>::randn(&vec![seq_len, n_q_h, dqkv], 15).scale_(4.).clone();
+    let k = Tensor::<f32>::randn(&vec![total_seq_len, n_kv_h * dqkv], 16);
+    let v = Tensor::<f32>::randn(&vec![total_seq_len, n_kv_h * dqkv], 17);
+    let mut scores = Tensor::<f32>::default(&vec![n_q_h, seq_len, total_seq_len]);
+    let (q_data, k_data, v_data) = (q.data(), k.data(), v.data());
+    // key or value j of the kv head query head h reads
+    let kv_row = |j: usize, h: usize| {
+        let row = j * n_kv_h + h * n_kv_h / n_q_h;
+        row * dqkv..(row + 1) * dqkv
+    };
+    for (r, row) in scores.make_mut().chunks_mut(total_seq_len).enumerate() {
+        let (h, i) = (r / seq_len, r % seq_len);
+        let q_row = &q_data[(i * n_q_h + h) * dqkv..][..dqkv];
+        for (j, score) in row.iter_mut().enumerate() {
+            *score = simd::dot(q_row, &k_data[kv_row(j, h)]) / (dqkv as f32).sqrt();
+        }
+    }
+    softcap(&mut scores, 2.);
+    masked_softmax(&mut scores);
+    let mut expected = vec![0.; seq_len * n_q_h * dqkv];
+    for (r, row) in scores.data().chunks(total_seq_len).enumerate() {
+        let (h, i) = (r / seq_len, r % seq_len);
+        for (j, &p) in row.iter().enumerate() {
+            simd::axpy(p, &v_data[kv_row(j, h)], &mut expected[(i * n_q_h + h) * dqkv..][..dqkv]);
+        }
+    }
+    let mut out = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+    flash_attention(&mut out, &q, &k, &v, None, Some(2.));
+    assert!(out.data().iter().zip(&expected).all(|(a, b)| (a - b).abs() < 1e-5));
+}
+
+#[test]
+fn test_flash_attention_gqa() {
+    // each kv head serves n_q_h / n_kv_h consecutive query heads: the same as plain multi-head attention
+    // over k and v with every kv head repeated for its group
+    let (seq_len, total_seq_len, n_q_h, n_kv_h, dqkv) = (3, 5, 6, 2, 4);
+    let q = Tensor::<f32>::randn(&vec![seq_len, n_q_h, dqkv], 21);
+    let k = Tensor::<f32>::randn(&vec![total_seq_len, n_kv_h * dqkv], 22);
+    let v = Tensor::<f32>::randn(&vec![total_seq_len, n_kv_h * dqkv], 23);
+    let repeat = |t: &Tensor<f32>| {
+        let data = t.data().chunks(dqkv).flat_map(|head| head.repeat(n_q_h / n_kv_h)).collect();
+        Tensor::new(data, &vec![total_seq_len, n_q_h * dqkv])
+    };
+    let mut out = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+    let mut expected = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+    flash_attention(&mut out, &q, &k, &v, None, None);
+    flash_attention(&mut expected, &q, &repeat(&k), &repeat(&v), None, None);
+    assert!(out.close_to(&expected, 1e-6));
+
+    // query heads of different groups read different kv heads
+    let head = |t: &Tensor<f32>, h: usize| t.data()[h * dqkv..][..dqkv].to_vec();
+    let same_q = Tensor::new(q.data()[..dqkv].repeat(seq_len * n_q_h), &vec![seq_len, n_q_h, dqkv]);
+    flash_attention(&mut out, &same_q, &k, &v, None, None);
+    assert_eq!(head(&out, 0), head(&out, 2));
+    assert_ne!(head(&out, 2), head(&out, 3));
+}
+
+#[test]
+fn test_matmul_transb_int8() {
+    // 80 columns leave a partial activation block and a SIMD tail
+    let a = Tensor::<f32>::randn(&vec![3, 80], 11);
+    let b = Int8Tensor::quantize(&Tensor::<f32>::randn(&vec![5, 80], 12));
+    let mut expected = Tensor::<f32>::new(vec![1.; 15], &vec![3, 5]);
+    matmul_transb(&mut expected, 0.5, &a, &b.dequantize(), 2.);
+    let mut c = Tensor::<f32>::new(vec![1.; 15], &vec![3, 5]);
+    matmul_transb_int8(&mut c, 0.5, &a, &b, 2.);
+    // only the activation rounding differs, about 1/254 of each block's largest value per element
+    assert!(c.compare(&expected).max_abs_err < 0.25);
+    // exactly representable activations leave no rounding at all
+    let a = Tensor::<f32>::new((0..160).map(|i| [-127., -1., 0., 1., 127.][i % 5]).collect(), &vec![2, 80]);
+    let mut expected = Tensor::<f32>::default(&vec![2, 5]);
+    matmul_transb(&mut expected, 0., &a, &b.dequantize(), 1.);
+    let mut c = Tensor::<f32>::default(&vec![2, 5]);
+    matmul_transb_int8(&mut c, 0., &a, &b, 1.);
+    assert!(c.close_to(&expected, 1e-4));
+}
+
+#[test]
+fn test_matmul_transb_q4() {
+    use crate::quant::{Q4KTensor, Q4Tensor};
+    let a = Tensor::<f32>::new((0..512).map(|i| (i % 17) as f32 / 17.).collect(), &vec![2, 256]);
+    let b = Tensor::<f32>::new((0..768).map(|i| ((i * 7 % 29) as f32 - 14.) / 14.).collect(), &vec![3, 256]);
+    // the fused kernels must agree with a plain matmul over the dequantized weights
+    let q4 = Q4Tensor::quantize(&b);
+    let mut expected = Tensor::<f32>::default(&vec![2, 3]);
+    matmul_transb(&mut expected, 0., &a, &q4.dequantize(), 1.);
+    let mut c = Tensor::<f32>::default(&vec![2, 3]);
+    matmul_transb_quant(&mut c, 0., &a, &q4, 1.);
+    assert!(c.close_to(&expected, 1e-4));
+    let q4k = Q4KTensor::quantize(&b);
+    matmul_transb(&mut expected, 0., &a, &q4k.dequantize(), 1.);
+    matmul_transb_quant(&mut c, 0., &a, &q4k, 1.);
+    assert!(c.close_to(&expected, 1e-4));
+}
+
+#[test]
+fn test_masked_softmax() {
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 1., 2., 3., 0., 0., 0., 5., 5., 5.], &vec![2, 2, 3]);
+    masked_softmax(&mut y);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(
+            vec![0.26894142, 0.7310586, 0., 0.09003057, 0.24472848, 0.66524094, 0.5, 0.5, 0., 0.33333334, 0.33333334, 0.33333334],
+            &vec![2, 2, 3]
+        ),
+        1e-3
+    ));
+}
+
+#[test]
+fn test_masked_softmax_window() {
+    // 3 queries at the end of 5 keys, each sees itself and one key before
+    let mut y = Tensor::<f32>::new(vec![0.; 15], &vec![3, 5]);
+    masked_softmax_window(&mut y, Some(2));
+    let expected = [0., 0.5, 0.5, 0., 0., 0., 0., 0.5, 0.5, 0., 0., 0., 0., 0.5, 0.5];
+    assert_eq!(y.data(), &expected);
+    // a window wider than the context is plain causal masking
+    let mut wide = Tensor::<f32>::randn(&vec![2, 3, 5], 4);
+    let mut causal = wide.clone();
+    masked_softmax_window(&mut wide, Some(16));
+    masked_softmax(&mut causal);
+    assert_eq!(wide.data(), causal.data());
+}
+
+#[test]
+fn test_softmax_masks() {
+    let x = Tensor::<f32>::randn(&vec![2, 3, 4, 6], 8);
+    // no mask is a plain softmax over every row
+    let mut plain = x.clone();
+    softmax(&mut plain, &[]);
+    for row in plain.data().chunks(6) {
+        assert!((row.iter().sum::<f32>() - 1.).abs() < 1e-5);
+    }
+    // the causal mask is the same as the built-in causal softmax
+    let (mut masked, mut causal) = (x.clone(), x.clone());
+    softmax(&mut masked, &[&causal_mask(4, 6, None)]);
+    masked_softmax(&mut causal);
+    assert_eq!(masked.data(), causal.data());
+
+    // batch 0 is left-padded by 2, batch 1 has 5 valid keys; every head and query sees the same keys
+    let pad = padding_mask(&[(2, 4), (0, 5)], 6);
+    let mut y = x.clone();
+    softmax(&mut y, &[&pad]);
+    for (r, row) in y.data().chunks(6).enumerate() {
+        let (start, len) = if r < 12 { (2, 4) } else { (0, 5) };
+        let mut expected = Tensor::new(x.data()[r * 6 + start..r * 6 + start + len].to_vec(), &vec![1, len]);
+        softmax(&mut expected, &[]);
+        assert!(row[..start].iter().chain(&row[start + len..]).all(|&p| p == 0.));
+        assert!(row[start..start + len].iter().zip(expected.data()).all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+    // with 3 keys of left padding the first query only sees padding once combined with a causal mask
+    let mut y = x.clone();
+    softmax(&mut y, &[&causal_mask(4, 6, None), &padding_mask(&[(3, 3), (0, 5)], 6)]);
+    assert!(y.data()[..6].iter().all(|&p| p == 0.));
+    assert!((y.data()[6..12].iter().sum::<f32>() - 1.).abs() < 1e-5);
+}
+
+#[test]
+fn test_rope_scaling() {
+    // linear scaling by 2 at position 6 equals plain rope at position 3
+    let token = Tensor::<f32>::randn(&vec![1, 2, 8], 0);
+    let mut scaled = token.clone();
+    rope_scaled(&mut scaled, 6, 1e4, RopeScaling::Linear { factor: 2. });
+    let mut plain = token.clone();
+    rope(&mut plain, 3, 1e4);
+    assert!(scaled.compare(&plain).max_abs_err < 1e-5);
+
+    let x = Tensor::<f32>::randn(&vec![4, 2, 8], 1);
+
+    // dynamic NTK leaves sequences within the original context untouched
+    let ntk = RopeScaling::DynamicNtk { factor: 4., original_max_position_embeddings: 16 };
+    let mut short = x.clone();
+    rope_scaled(&mut short, 0, 1e4, ntk);
+    let mut plain = x.clone();
+    rope(&mut plain, 0, 1e4);
+    assert_eq!(short.data(), plain.data());
+    // and raises theta beyond it: 4 * 20 / 16 - 3 = 2, theta * 2^(8 / 6)
+    let mut long = x.clone();
+    rope_scaled(&mut long, 16, 1e4, ntk);
+    let mut expected = x.clone();
+    rope(&mut expected, 16, 1e4 * 2f32.powf(8. / 6.));
+    assert!(long.compare(&expected).max_abs_err < 1e-5);
+}
+
+#[test]
+fn test_rope_partial() {
+    let x = Tensor::<f32>::randn(&vec![3, 2, 8], 5);
+    let mut partial = x.clone();
+    rope_partial(&mut partial, 2, 1e4, RopeScaling::None, 4);
+    // the first 4 dims of each head match full RoPE on 4-wide heads, the last 4 are untouched
+    let mut narrow = x.slice_dims(&[0..3, 0..2, 0..4]).contiguous();
+    rope(&mut narrow, 2, 1e4);
+    assert_eq!(partial.slice_dims(&[0..3, 0..2, 0..4]).contiguous().data(), narrow.data());
+    let tail = |t: &Tensor<f32>| t.slice_dims(&[0..3, 0..2, 4..8]).contiguous();
+    assert_eq!(tail(&partial).data(), tail(&x).data());
+}
+
+#[test]
+fn test_rope_ntk_yarn() {
+    let x = Tensor::<f32>::randn(&vec![1, 1, 64], 6);
+    // static NTK is plain RoPE with a larger base
+    let mut ntk = x.clone();
+    rope_scaled(&mut ntk, 100, 1e4, RopeScaling::Ntk { factor: 4. });
+    let mut expected = x.clone();
+    rope(&mut expected, 100, 1e4 * 4f32.powf(64. / 62.));
+    assert!(ntk.compare(&expected).max_abs_err < 1e-4);
+
+    // with a 2048 context, pairs below 8 complete more than 32 rotations and are kept as is,
+    // pairs from 21 on complete less than one and are interpolated like linear scaling
+    let yarn = RopeScaling::Yarn {
+        factor: 4.,
+        original_max_position_embeddings: 2048,
+        beta_fast: 32.,
+        beta_slow: 1.,
+        attention_factor: 1.,
+    };
+    let (mut scaled, mut plain, mut linear) = (x.clone(), x.clone(), x.clone());
+    rope_scaled(&mut scaled, 100, 1e4, yarn);
+    rope(&mut plain, 100, 1e4);
+    rope_scaled(&mut linear, 100, 1e4, RopeScaling::Linear { factor: 4. });
+    let pair = |t: &Tensor<f32>, i: usize| [t.data()[i], t.data()[i + 32]];
+    for i in 0..32 {
+        let expected = if i < 8 { pair(&plain, i) } else if i >= 21 { pair(&linear, i) } else { continue };
+        let got = pair(&scaled, i);
+        assert!((got[0] - expected[0]).abs() < 1e-4 && (got[1] - expected[1]).abs() < 1e-4, "pair {i}");
+    }
+    // attention_factor scales every rotated value
+    let mut boosted = x.clone();
+    rope_scaled(&mut boosted, 100, 1e4, RopeScaling::yarn(4., 2048));
+    let mscale = 0.1 * 4f32.ln() + 1.;
+    assert!((boosted.data()[40] - scaled.data()[40] * mscale).abs() < 1e-4);
+}
+
+#[test]
+fn test_random_sample_top_p() {
+    // probabilities 0.5, 0.3, 0.2
+    let x = Tensor::<f32>::new(vec![0.2f32.ln(), 0.5f32.ln(), 0.3f32.ln()], &vec![3]);
+    let sample = |top_p| (0..200).map(|_| random_sample(&x, top_p, 3, 1.)).collect::<Vec<_>>();
+    assert!(sample(0.4).iter().all(|&t| t == 1));
+    let nucleus = sample(0.7);
+    assert!(nucleus.iter().all(|&t| t != 0) && nucleus.contains(&1) && nucleus.contains(&2));
+    assert!(sample(1.).contains(&0));
+    assert_eq!(random_sample(&x, 0.9, 1, 1.), 1);
+}
+
+#[test]
+fn test_random_sample_top_k() {
+    let x = Tensor::<f32>::randn(&vec![1000], 17);
+    let mut ranked: Vec<u32> = (0..1000).collect();
+    ranked.sort_by(|&a, &b| x.data()[b as usize].total_cmp(&x.data()[a as usize]));
+    for _ in 0..100 {
+        let token = random_sample(&x, 1., 3, 2.);
+        assert!(ranked[..3].contains(&token));
+    }
+    // temperature flattens the distribution the top-p cutoff is taken over
+    let x = Tensor::<f32>::new(vec![1., 0., 0.], &vec![3]);
+    assert!((0..100).all(|_| random_sample(&x, 0.5, 3, 0.5) == 0));
+    assert!((0..100).any(|_| random_sample(&x, 0.5, 3, 10.) != 0));
 }
@@ -1,90 +1,1111 @@
+use crate::backend::Weight;
 use crate::config::LlamaConfigJson;
-use crate::tensor::Tensor;
+use crate::names::NameMap;
+use crate::operators as OP;
+use crate::quant::{Int8Tensor, QuantBlock, QuantTensor};
+use crate::tensor::{Float, Tensor};
+use half::{bf16, f16};
+use memmap2::Mmap;
+use safetensors::tensor::TensorView;
 use safetensors::{SafeTensors, Dtype};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::mem;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
  
-pub struct LLamaParams<T> {
+// Projection matrices are stored as W, plain tensors unless quantized on load (see QuantizeOnLoad)
+pub struct LLamaParams<T, W = Tensor<T>> {
     // token_id to embedding lookup table
     pub embedding_table: Tensor<T>, // (vocab_size, dim)
     // decoder layer
     pub rms_att_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
-    pub wq: Vec<Tensor<T>>,        // (n_heads * head_size, hidden_size) x layers
-    pub wk: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
-    pub wv: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
-    pub wo: Vec<Tensor<T>>,        // (hidden_size, n_heads * head_size) x layers
+    pub wq: Vec<W>,        // (n_heads * head_size, hidden_size) x layers
+    pub wk: Vec<W>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wv: Vec<W>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wo: Vec<W>,        // (hidden_size, n_heads * head_size) x layers
     // ffn layer
     pub rms_ffn_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
-    pub w_up: Vec<Tensor<T>>,      // (intermediate_size, hidden_size) x layers
-    pub w_gate: Vec<Tensor<T>>,    // (intermediate_size, hidden_size) x layers
-    pub w_down: Vec<Tensor<T>>,    // (hidden_size, intermediate_size) x layers
+    pub w_up: Vec<W>,      // (intermediate_size, hidden_size) x layers
+    pub w_gate: Vec<W>,    // (intermediate_size, hidden_size) x layers
+    pub w_down: Vec<W>,    // (hidden_size, intermediate_size) x layers
     // output
     pub rms_out_w: Tensor<T>, // (hidden_size, )
-    pub lm_head: Tensor<T>,   // (vocab_size, dim)
+    pub lm_head: W,           // (vocab_size, dim)
+    // checkpoint layers held, wq[0] is layer layers.start (all of them unless loaded with a layer range)
+    pub layers: Range<usize>,
 }
  
-impl LLamaParams<f32> {
+// Floating point types a parameter set can be loaded as, DTYPE is the safetensors dtype stored as is
+pub trait FloatDType: Float {
+    const DTYPE: Dtype;
+}
+
+impl FloatDType for f32 {
+    const DTYPE: Dtype = Dtype::F32;
+}
+
+impl FloatDType for f16 {
+    const DTYPE: Dtype = Dtype::F16;
+}
+
+impl FloatDType for bf16 {
+    const DTYPE: Dtype = Dtype::BF16;
+}
+
+// One code path for every compute dtype: tensors already stored as T are kept bit-exact (and used in
+// place when memory-mapped), other float dtypes are converted on load
+impl<T: FloatDType> LLamaParams<T> {
+    // Randomly initialized weights for `config` (normal, std 0.02; norms are ones), reproducible from `seed`.
+    // Lets tests and benchmarks build a model without any checkpoint on disk
+    pub fn random(config: &LlamaConfigJson, seed: u64) -> Self {
+        let shapes = expected_shapes(config);
+        LLamaParams::from_loader(config, |name| {
+            let i = shapes.iter().position(|(n, _)| n == name)?;
+            let shape = &shapes[i].1;
+            let t = if name.ends_with("norm.weight") {
+                Tensor::new(vec![1.; shape.iter().product()], shape)
+            } else {
+                let mut t = Tensor::<f32>::randn(shape, seed.wrapping_add(i as u64));
+                t.scale_(0.02);
+                t
+            };
+            Some(t.cast())
+        })
+    }
+
     pub fn from_safetensors(safetensor: &SafeTensors, config: &LlamaConfigJson) -> Self {
-        let get_tensor = |name: &str| -> Tensor<f32> {
-            let tensor_view = safetensor.tensor(name).expect(&format!("Tensor {} not found", name));
-            if tensor_view.dtype() != Dtype::F32 {
-                panic!("Expected tensor {} to have dtype F32, but found {:?}", name, tensor_view.dtype());
+        load_as(safetensor, config)
+    }
+
+    // Like from_safetensors, but weights stored as T are used in place from the mapped file instead of copied
+    pub fn from_mmap(mmap: &Arc<Mmap>, config: &LlamaConfigJson) -> Self {
+        let safetensor = SafeTensors::deserialize(mmap).unwrap();
+        let names = NameMap::detect(safetensor.names().into_iter().map(String::as_str));
+        let get_tensor = |name: &str| -> Option<Tensor<T>> {
+            let tensor_view = safetensor.tensor(&names.map(name)).ok()?;
+            Some(map_or_read(mmap, name, &tensor_view))
+        };
+        Self::from_loader(config, get_tensor)
+    }
+
+    // Load from a model directory holding either model.safetensors or shards listed in
+    // model.safetensors.index.json, every file is memory-mapped
+    pub fn from_dir(model_dir: impl AsRef<Path>, config: &LlamaConfigJson) -> Self {
+        Self::from_dir_with_progress(model_dir, config, |_| {})
+    }
+
+    // from_dir, reporting every tensor as it is loaded
+    pub fn from_dir_with_progress(
+        model_dir: impl AsRef<Path>,
+        config: &LlamaConfigJson,
+        on_progress: impl FnMut(&LoadProgress),
+    ) -> Self {
+        Self::from_dir_with_names(model_dir, config, None, on_progress)
+    }
+
+    // from_dir for checkpoints with non-standard tensor names, `names` None detects a built-in preset
+    pub fn from_dir_with_names(
+        model_dir: impl AsRef<Path>,
+        config: &LlamaConfigJson,
+        names: Option<NameMap>,
+        on_progress: impl FnMut(&LoadProgress),
+    ) -> Self {
+        load_dir(model_dir.as_ref(), config, 0..config.num_hidden_layers, names, |t| t, on_progress)
+    }
+
+    // Only decoder layers [layers.start, layers.end), e.g. one stage of a pipeline-parallel split.
+    // Embeddings, final norm and lm_head are loaded too so any stage can be first or last
+    pub fn from_dir_layers(model_dir: impl AsRef<Path>, config: &LlamaConfigJson, layers: Range<usize>) -> Self {
+        load_dir(model_dir.as_ref(), config, layers, None, |t| t, |_| {})
+    }
+
+    pub fn from_safetensors_layers(safetensor: &SafeTensors, config: &LlamaConfigJson, layers: Range<usize>) -> Self {
+        let names = NameMap::detect(safetensor.names().into_iter().map(String::as_str));
+        let get_tensor = |name: &str| -> Option<Tensor<T>> {
+            let tensor_view = safetensor.tensor(&names.map(name)).ok()?;
+            Some(read_as(name, &tensor_view))
+        };
+        Self::load(config, layers, get_tensor, |t| t, |_| {})
+    }
+}
+
+// Memory-map model.safetensors or every shard of model.safetensors.index.json and load from them,
+// converting each projection matrix with `weight` as soon as it is read
+fn load_dir<T: FloatDType, W>(
+    model_dir: &Path,
+    config: &LlamaConfigJson,
+    layers: Range<usize>,
+    names: Option<NameMap>,
+    weight: impl Fn(Tensor<T>) -> W,
+    on_progress: impl FnMut(&LoadProgress),
+) -> LLamaParams<T, W> {
+    let (files, weight_map, mmaps) = open_shards(model_dir);
+    let shards: Vec<SafeTensors> = mmaps.iter().map(|m| SafeTensors::deserialize(m).unwrap()).collect();
+    let names = names.unwrap_or_else(|| match weight_map.is_empty() {
+        true => NameMap::detect(shards[0].names().into_iter().map(String::as_str)),
+        false => NameMap::detect(weight_map.keys().map(String::as_str)),
+    });
+    let get_tensor = |name: &str| -> Option<Tensor<T>> {
+        let name = &names.map(name);
+        let shard = match weight_map.get(name) {
+            Some(file) => files.iter().position(|f| f == file).unwrap(),
+            None if weight_map.is_empty() => 0,
+            None => return None,
+        };
+        let tensor_view = shards[shard].tensor(name).ok()?;
+        Some(map_or_read(&mmaps[shard], name, &tensor_view))
+    };
+    LLamaParams::load(config, layers, get_tensor, weight, on_progress)
+}
+
+// Shard file names, the tensor -> file map (empty for a single model.safetensors) and the mapped files
+pub(crate) fn open_shards(model_dir: &Path) -> (Vec<String>, HashMap<String, String>, Vec<Arc<Mmap>>) {
+    let index_path = model_dir.join("model.safetensors.index.json");
+    let (files, weight_map) = if index_path.exists() {
+        let index: SafetensorsIndex =
+            serde_json::from_reader(File::open(&index_path).unwrap()).unwrap();
+        let mut files: Vec<String> = index.weight_map.values().cloned().collect();
+        files.sort();
+        files.dedup();
+        (files, index.weight_map)
+    } else {
+        (vec!["model.safetensors".to_string()], HashMap::new())
+    };
+    let mmaps: Vec<Arc<Mmap>> = files
+        .iter()
+        .map(|file| {
+            let file = File::open(model_dir.join(file)).unwrap_or_else(|e| panic!("Cannot open {file}: {e}"));
+            Arc::new(unsafe { Mmap::map(&file).unwrap() })
+        })
+        .collect();
+    (files, weight_map, mmaps)
+}
+
+// The `model.safetensors.index.json` of a sharded checkpoint, mapping tensor names to shard files
+#[derive(serde::Deserialize)]
+struct SafetensorsIndex {
+    weight_map: HashMap<String, String>,
+}
+
+// Zero-copy view into `mmap` for aligned tensors already stored as T, a converted copy otherwise
+fn map_or_read<T: FloatDType>(mmap: &Arc<Mmap>, name: &str, tensor_view: &TensorView) -> Tensor<T> {
+    let start = tensor_view.data().as_ptr() as usize - mmap.as_ptr() as usize;
+    let mapped = match tensor_view.dtype() {
+        // safetensors only guarantees 8-byte alignment of the data section, so this can still fail
+        dtype if dtype == T::DTYPE => unsafe { Tensor::from_mmap(mmap, start, &tensor_view.shape().to_vec()) },
+        _ => None,
+    };
+    mapped.unwrap_or_else(|| read_as(name, tensor_view))
+}
+
+// Elements decoded per task, large enough that thread overhead is negligible
+const DECODE_CHUNK: usize = 1 << 16;
+
+// Decode any floating point safetensors dtype and convert it to T, so e.g. a bf16 checkpoint can be
+// loaded for f32 compute. Values pass through f32, which is exact for every dtype but F64.
+// Every element is independent, so with the `parallel` feature chunks are converted on the rayon pool
+pub(crate) fn read_as<T: Float>(name: &str, tensor_view: &TensorView) -> Tensor<T> {
+    let bytes = tensor_view.data();
+    let dtype = tensor_view.dtype();
+    let elem = match dtype {
+        Dtype::F32 => 4,
+        Dtype::F16 | Dtype::BF16 => 2,
+        Dtype::F64 => 8,
+        dtype => panic!("Expected tensor {} to have a floating point dtype, but found {:?}", name, dtype),
+    };
+    let mut data = vec![T::default(); bytes.len() / elem];
+    OP::for_each_chunk_mut(&mut data, DECODE_CHUNK, |i, out| {
+        let src = bytes[i * DECODE_CHUNK * elem..][..out.len() * elem].chunks_exact(elem);
+        let values: Vec<f32> = match dtype {
+            Dtype::F32 => src.map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect(),
+            Dtype::F16 => src.map(|b| f16::from_le_bytes(b.try_into().unwrap()).to_f32()).collect(),
+            Dtype::BF16 => src.map(|b| bf16::from_le_bytes(b.try_into().unwrap()).to_f32()).collect(),
+            _ => src.map(|b| f64::from_le_bytes(b.try_into().unwrap()) as f32).collect(),
+        };
+        T::slice_from_f32(&values, out);
+    });
+    Tensor::new(data, &tensor_view.shape().to_vec())
+}
+
+// Load every tensor converted to the compute dtype T whatever the checkpoint stores, e.g. `load_as::<f32>(..)`
+pub fn load_as<T: Float>(safetensor: &SafeTensors, config: &LlamaConfigJson) -> LLamaParams<T> {
+    let names = NameMap::detect(safetensor.names().into_iter().map(String::as_str));
+    let get_tensor = |name: &str| -> Option<Tensor<T>> {
+        let tensor_view = safetensor.tensor(&names.map(name)).ok()?;
+        Some(read_as(name, &tensor_view))
+    };
+    LLamaParams::from_loader(config, get_tensor)
+}
+
+// Per-tensor statistics gathered by verify()
+#[derive(Debug, Clone)]
+pub struct TensorReport {
+    pub name: String,
+    pub dtype: Dtype,
+    pub shape: Vec<usize>,
+    pub norm: f32,          // L2 norm of the finite values
+    pub max_abs: f32,
+    pub nan_count: usize,
+    pub inf_count: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub tensors: Vec<TensorReport>,
+    pub missing: Vec<String>,  // expected by the config but absent from the checkpoint
+    pub problems: Vec<String>, // bad dtype, shape or non-finite values
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for t in &self.tensors {
+            writeln!(f, "{:<50} {:?} {:?} norm={:.4} max|x|={:.4}", t.name, t.dtype, t.shape, t.norm, t.max_abs)?;
+        }
+        for name in &self.missing {
+            writeln!(f, "missing: {name}")?;
+        }
+        for problem in &self.problems {
+            writeln!(f, "problem: {problem}")?;
+        }
+        write!(f, "{}", if self.is_ok() { "checkpoint OK" } else { "checkpoint has errors" })
+    }
+}
+
+// Check a checkpoint against `config` without building parameters: every expected tensor exists (tied
+// embeddings and fused qkv_proj are accepted), has a float dtype and the right shape, and is all finite.
+// Meant for telling corrupted downloads apart from model bugs
+pub fn verify(safetensor: &SafeTensors, config: &LlamaConfigJson) -> VerifyReport {
+    let names: Vec<&str> = safetensor.names().into_iter().map(String::as_str).collect();
+    verify_with(config, NameMap::detect(names), |name| safetensor.tensor(name).ok())
+}
+
+// verify() for a model directory, single file or sharded
+pub fn verify_dir(model_dir: impl AsRef<Path>, config: &LlamaConfigJson) -> VerifyReport {
+    let (_, _, mmaps) = open_shards(model_dir.as_ref());
+    let shards: Vec<SafeTensors> = mmaps.iter().map(|m| SafeTensors::deserialize(m).unwrap()).collect();
+    let names = NameMap::detect(shards.iter().flat_map(|s| s.names()).map(String::as_str));
+    verify_with(config, names, |name| shards.iter().find_map(|s| s.tensor(name).ok()))
+}
+
+fn verify_with<'a>(
+    config: &LlamaConfigJson,
+    names: NameMap,
+    get_view: impl Fn(&str) -> Option<TensorView<'a>>,
+) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    let shapes: HashMap<String, Vec<usize>> = expected_shapes(config).into_iter().collect();
+    let exists = |canonical: &str| get_view(&names.map(canonical)).is_some();
+    for (canonical, expected) in expected_shapes(config) {
+        let name = names.map(&canonical);
+        let (name, view, expected) = match get_view(&name) {
+            Some(view) => (name, view, expected),
+            None => {
+                let fused = canonical.replace("q_proj", "qkv_proj");
+                let tolerated = match canonical.as_str() {
+                    "lm_head.weight" => config.tie_word_embeddings && exists("model.embed_tokens.weight"),
+                    "model.embed_tokens.weight" => config.tie_word_embeddings && exists("lm_head.weight"),
+                    c if c.ends_with("self_attn.k_proj.weight") || c.ends_with("self_attn.v_proj.weight") => {
+                        // swap the suffix itself, "qkv_proj" contains "v_proj" so replace() would match twice
+                        exists(&format!("{}qkv_proj.weight", &c[..c.len() - "k_proj.weight".len()]))
+                    }
+                    c if c.ends_with("self_attn.q_proj.weight") && exists(&fused) => {
+                        // check the fused tensor once, in place of q
+                        let rows = |p: &str| shapes[&canonical.replace("q_proj", p)][0];
+                        let expected = vec![rows("q_proj") + rows("k_proj") + rows("v_proj"), expected[1]];
+                        let name = names.map(&fused);
+                        let view = get_view(&name).unwrap();
+                        check_tensor(&mut report, name, &view, &expected);
+                        true
+                    }
+                    _ => false,
+                };
+                if !tolerated {
+                    report.missing.push(name);
+                }
+                continue;
             }
-            let data = tensor_view.data().chunks(4)
-                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
-                .collect();
-            Tensor::new(data, &tensor_view.shape().to_vec())
         };
- 
-        LLamaParams {
-            embedding_table: get_tensor("lm_head.weight"), 
- 
-            rms_att_w: vec![
-                get_tensor("model.layers.0.input_layernorm.weight"),
-                get_tensor("model.layers.1.input_layernorm.weight"),
-            ],
- 
-            wq: vec![
-                get_tensor("model.layers.0.self_attn.q_proj.weight"),
-                get_tensor("model.layers.1.self_attn.q_proj.weight"),
-            ],
- 
-            wk: vec![
-                get_tensor("model.layers.0.self_attn.k_proj.weight"),
-                get_tensor("model.layers.1.self_attn.k_proj.weight"),
-            ],
- 
-            wv: vec![
-                get_tensor("model.layers.0.self_attn.v_proj.weight"),
-                get_tensor("model.layers.1.self_attn.v_proj.weight"),
-            ],
- 
-            wo: vec![
-                get_tensor("model.layers.0.self_attn.o_proj.weight"),
-                get_tensor("model.layers.1.self_attn.o_proj.weight"),
-            ],
- 
-            rms_ffn_w: vec![
-                get_tensor("model.layers.0.post_attention_layernorm.weight"),
-                get_tensor("model.layers.1.post_attention_layernorm.weight"),
-            ],
- 
-            w_up: vec![
-                get_tensor("model.layers.0.mlp.up_proj.weight"),
-                get_tensor("model.layers.1.mlp.up_proj.weight"),
-            ],
- 
-            w_gate: vec![
-                get_tensor("model.layers.0.mlp.gate_proj.weight"),
-                get_tensor("model.layers.1.mlp.gate_proj.weight"),
-            ],
- 
-            w_down: vec![
-                get_tensor("model.layers.0.mlp.down_proj.weight"),
-                get_tensor("model.layers.1.mlp.down_proj.weight"),
-            ],
- 
-            rms_out_w: get_tensor("model.norm.weight"),
-            lm_head: get_tensor("lm_head.weight"),
+        check_tensor(&mut report, name, &view, &expected);
+    }
+    report
+}
+
+fn check_tensor(report: &mut VerifyReport, name: String, view: &TensorView, expected: &[usize]) {
+    if view.shape() != expected {
+        report.problems.push(format!("{name}: expected shape {expected:?}, found {:?}", view.shape()));
+    }
+    if !matches!(view.dtype(), Dtype::F32 | Dtype::F16 | Dtype::BF16 | Dtype::F64) {
+        report.problems.push(format!("{name}: unsupported dtype {:?}", view.dtype()));
+        return;
+    }
+    let t = read_as::<f32>(&name, view);
+    let (mut sum_sq, mut max_abs, mut nan_count, mut inf_count) = (0f64, 0f32, 0, 0);
+    for &x in t.data() {
+        if x.is_nan() {
+            nan_count += 1;
+        } else if x.is_infinite() {
+            inf_count += 1;
+        } else {
+            sum_sq += (x as f64) * (x as f64);
+            max_abs = max_abs.max(x.abs());
         }
     }
+    if nan_count + inf_count > 0 {
+        report.problems.push(format!("{name}: {nan_count} NaN and {inf_count} Inf values"));
+    }
+    report.tensors.push(TensorReport {
+        name,
+        dtype: view.dtype(),
+        shape: view.shape().to_vec(),
+        norm: sum_sq.sqrt() as f32,
+        max_abs,
+        nan_count,
+        inf_count,
+    });
+}
+
+// Reported after each tensor is loaded
+#[derive(Debug, Clone)]
+pub struct LoadProgress<'a> {
+    pub name: &'a str,          // tensor just loaded
+    pub tensors_loaded: usize,
+    pub tensors_total: usize,   // expected count, tied checkpoints load one fewer (a fused qkv_proj counts as 3)
+    pub bytes_read: usize,      // in-memory size of the tensors loaded so far
+    pub elapsed: Duration,      // time spent on this tensor
+}
+
+impl<T: Copy + Clone + Default> LLamaParams<T> {
+    // `get_tensor` returns None for names missing from the checkpoint
+    pub(crate) fn from_loader(config: &LlamaConfigJson, get_tensor: impl Fn(&str) -> Option<Tensor<T>>) -> Self {
+        Self::load(config, 0..config.num_hidden_layers, get_tensor, |t| t, |_| {})
+    }
+
+    // Hugging Face name of every tensor, same order as expected_shapes()
+    pub fn named_tensors(&self) -> Vec<(String, &Tensor<T>)> {
+        let mut tensors = vec![
+            ("model.embed_tokens.weight".to_string(), &self.embedding_table),
+            ("lm_head.weight".to_string(), &self.lm_head),
+            ("model.norm.weight".to_string(), &self.rms_out_w),
+        ];
+        for i in 0..self.wq.len() {
+            let layer = self.layers.start + i;
+            for (suffix, t) in [
+                ("input_layernorm.weight", &self.rms_att_w[i]),
+                ("self_attn.q_proj.weight", &self.wq[i]),
+                ("self_attn.k_proj.weight", &self.wk[i]),
+                ("self_attn.v_proj.weight", &self.wv[i]),
+                ("self_attn.o_proj.weight", &self.wo[i]),
+                ("post_attention_layernorm.weight", &self.rms_ffn_w[i]),
+                ("mlp.up_proj.weight", &self.w_up[i]),
+                ("mlp.gate_proj.weight", &self.w_gate[i]),
+                ("mlp.down_proj.weight", &self.w_down[i]),
+            ] {
+                tensors.push((format!("model.layers.{layer}.{suffix}"), t));
+            }
+        }
+        tensors
+    }
+
+    // Check every tensor against the shape `config` implies
+    pub fn validate(&self, config: &LlamaConfigJson) -> Result<(), ShapeError> {
+        let tensors = self.named_tensors();
+        let layers = self.layers.start..self.layers.end.min(config.num_hidden_layers);
+        let expected_shapes = expected_shapes_in(config, layers);
+        let mut mismatches = Vec::new();
+        if tensors.len() != expected_shapes.len() {
+            mismatches.push(ShapeMismatch {
+                name: "model.layers".to_string(),
+                expected: vec![config.num_hidden_layers],
+                found: vec![self.layers.end],
+            });
+        }
+        for ((name, t), (_, expected)) in tensors.iter().zip(expected_shapes) {
+            if *t.shape() != expected {
+                mismatches.push(ShapeMismatch { name: name.clone(), expected, found: t.shape().clone() });
+            }
+        }
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(ShapeError { mismatches })
+        }
+    }
+}
+
+impl<T: Copy + Clone + Default, W> LLamaParams<T, W> {
+    // Shared by all loaders: `get_tensor` returns None for names missing from the checkpoint, `weight`
+    // converts projection matrices to their storage type, and every tensor is reported and shape-checked
+    pub(crate) fn load(
+        config: &LlamaConfigJson,
+        layers: Range<usize>,
+        get_tensor: impl Fn(&str) -> Option<Tensor<T>>,
+        weight: impl Fn(Tensor<T>) -> W,
+        on_progress: impl FnMut(&LoadProgress),
+    ) -> Self {
+        assert!(
+            layers.start <= layers.end && layers.end <= config.num_hidden_layers,
+            "layer range {layers:?} out of bounds for {} layers",
+            config.num_hidden_layers
+        );
+        let mut expected: HashMap<String, Vec<usize>> = expected_shapes_in(config, layers.clone()).into_iter().collect();
+        let tensors_total = expected.len();
+        // fused checkpoints store q, k and v stacked along the rows of one matrix
+        for i in layers.clone() {
+            let rows = |p: &str| expected[&format!("model.layers.{i}.self_attn.{p}_proj.weight")][0];
+            let fused = vec![rows("q") + rows("k") + rows("v"), config.hidden_size];
+            expected.insert(format!("model.layers.{i}.self_attn.qkv_proj.weight"), fused);
+        }
+        let state = RefCell::new((0, 0, on_progress, Vec::new()));
+        let get_tensor = |name: &str| {
+            let start = Instant::now();
+            let tensor = get_tensor(name)?;
+            let (tensors_loaded, bytes_read, on_progress, mismatches) = &mut *state.borrow_mut();
+            if tensor.shape() != &expected[name] {
+                let (expected, found) = (expected[name].clone(), tensor.shape().clone());
+                mismatches.push(ShapeMismatch { name: name.to_string(), expected, found });
+            }
+            // a fused qkv_proj stands for the three projections tensors_total counts
+            *tensors_loaded += if name.ends_with(".qkv_proj.weight") { 3 } else { 1 };
+            *bytes_read += tensor.size() * mem::size_of::<T>();
+            on_progress(&LoadProgress {
+                name,
+                tensors_loaded: *tensors_loaded,
+                tensors_total,
+                bytes_read: *bytes_read,
+                elapsed: start.elapsed(),
+            });
+            Some(tensor)
+        };
+        let require = |name: &str| get_tensor(name).unwrap_or_else(|| panic!("Tensor {} not found", name));
+        let per_layer = |suffix: &str| -> Vec<Tensor<T>> {
+            layers.clone()
+                .map(|i| require(&format!("model.layers.{i}.{suffix}")))
+                .collect()
+        };
+        let weights = |suffix: &str| -> Vec<W> {
+            layers.clone()
+                .map(|i| weight(require(&format!("model.layers.{i}.{suffix}"))))
+                .collect()
+        };
+        // tied checkpoints usually store only one of the two matrices, share it for both
+        let (embedding_table, lm_head) = match (
+            get_tensor("model.embed_tokens.weight"),
+            get_tensor("lm_head.weight"),
+        ) {
+            (Some(embed), Some(head)) => (embed, weight(head)),
+            (Some(embed), None) if config.tie_word_embeddings => (embed.clone(), weight(embed)),
+            (None, Some(head)) if config.tie_word_embeddings => (head.clone(), weight(head)),
+            (None, None) => panic!("Neither model.embed_tokens.weight nor lm_head.weight found"),
+            (None, Some(_)) => panic!("model.embed_tokens.weight not found and tie_word_embeddings is false"),
+            (Some(_), None) => panic!("lm_head.weight not found and tie_word_embeddings is false"),
+        };
+        // separate q/k/v projections, or row views into a fused qkv_proj (Phi-3 and some exports)
+        let (mut wq, mut wk, mut wv) = (Vec::new(), Vec::new(), Vec::new());
+        for i in layers.clone() {
+            let name = |p: &str| format!("model.layers.{i}.self_attn.{p}_proj.weight");
+            let (q, k, v) = match get_tensor(&name("q")) {
+                Some(q) => (q, require(&name("k")), require(&name("v"))),
+                None => {
+                    let fused = get_tensor(&name("qkv"))
+                        .unwrap_or_else(|| panic!("Neither {} nor {} found", name("q"), name("qkv")));
+                    let rows = |p: &str| expected[&name(p)][0];
+                    let mut parts = fused.split(0, &[rows("q"), rows("k"), rows("v")]).into_iter();
+                    (parts.next().unwrap(), parts.next().unwrap(), parts.next().unwrap())
+                }
+            };
+            wq.push(weight(q));
+            wk.push(weight(k));
+            wv.push(weight(v));
+        }
+        let params = LLamaParams {
+            embedding_table,
+            rms_att_w: per_layer("input_layernorm.weight"),
+            wq,
+            wk,
+            wv,
+            wo: weights("self_attn.o_proj.weight"),
+            rms_ffn_w: per_layer("post_attention_layernorm.weight"),
+            w_up: weights("mlp.up_proj.weight"),
+            w_gate: weights("mlp.gate_proj.weight"),
+            w_down: weights("mlp.down_proj.weight"),
+            rms_out_w: require("model.norm.weight"),
+            lm_head,
+            layers,
+        };
+        // fail here with every bad tensor listed rather than deep inside a matmul
+        let mismatches = state.into_inner().3;
+        if !mismatches.is_empty() {
+            panic!("{}", ShapeError { mismatches });
+        }
+        params
+    }
+}
+
+impl<W: Weight> LLamaParams<f32, W> {
+    // Bytes of all tensors, memory-mapped ones included, counting tied embeddings once
+    pub fn nbytes(&self) -> usize {
+        let dense = [&self.embedding_table, &self.rms_out_w].into_iter().chain(&self.rms_att_w).chain(&self.rms_ffn_w);
+        let weights = [&self.wq, &self.wk, &self.wv, &self.wo, &self.w_up, &self.w_gate, &self.w_down];
+        let tied = self.lm_head.as_f32().is_some_and(|head| {
+            (head.data().as_ptr(), head.size()) == (self.embedding_table.data().as_ptr(), self.embedding_table.size())
+        });
+        dense.map(|t| t.size() * mem::size_of::<f32>()).sum::<usize>()
+            + weights.into_iter().flatten().map(W::nbytes).sum::<usize>()
+            + if tied { 0 } else { self.lm_head.nbytes() }
+    }
+}
+
+// Storage for projection matrices converted from f32 as each one is loaded, so the f32 copies of a
+// non-f32 checkpoint never have to be resident at once
+pub trait QuantizeOnLoad {
+    fn quantize_on_load(t: Tensor<f32>) -> Self;
+}
+
+impl QuantizeOnLoad for Tensor<f32> {
+    fn quantize_on_load(t: Tensor<f32>) -> Self {
+        t
+    }
+}
+
+impl QuantizeOnLoad for Int8Tensor {
+    fn quantize_on_load(t: Tensor<f32>) -> Self {
+        Int8Tensor::quantize(&t)
+    }
+}
+
+impl<B: QuantBlock> QuantizeOnLoad for QuantTensor<B> {
+    fn quantize_on_load(t: Tensor<f32>) -> Self {
+        QuantTensor::quantize(&t)
+    }
+}
+
+impl<W: QuantizeOnLoad> LLamaParams<f32, W> {
+    // e.g. `LLamaParams::<f32, Int8Tensor>::from_safetensors_quantized(..)`, any float dtype is accepted
+    pub fn from_safetensors_quantized(safetensor: &SafeTensors, config: &LlamaConfigJson) -> Self {
+        let names = NameMap::detect(safetensor.names().into_iter().map(String::as_str));
+        let get_tensor = |name: &str| -> Option<Tensor<f32>> {
+            let tensor_view = safetensor.tensor(&names.map(name)).ok()?;
+            Some(read_as(name, &tensor_view))
+        };
+        Self::load(config, 0..config.num_hidden_layers, get_tensor, W::quantize_on_load, |_| {})
+    }
+
+    pub fn from_dir_quantized(model_dir: impl AsRef<Path>, config: &LlamaConfigJson) -> Self {
+        load_dir(model_dir.as_ref(), config, 0..config.num_hidden_layers, None, W::quantize_on_load, |_| {})
+    }
+}
+
+// Hugging Face name and shape of every tensor `config` implies, in load order
+pub(crate) fn expected_shapes(config: &LlamaConfigJson) -> Vec<(String, Vec<usize>)> {
+    expected_shapes_in(config, 0..config.num_hidden_layers)
+}
+
+// expected_shapes() restricted to decoder layers in `layers`
+pub(crate) fn expected_shapes_in(config: &LlamaConfigJson, layers: Range<usize>) -> Vec<(String, Vec<usize>)> {
+    let (d, di, vocab) = (config.hidden_size, config.intermediate_size, config.vocab_size);
+    let dqkv = config.head_dim();
+    let (q, kv) = (config.num_attention_heads * dqkv, config.num_key_value_heads * dqkv);
+    let mut shapes = vec![
+        ("model.embed_tokens.weight".to_string(), vec![vocab, d]),
+        ("lm_head.weight".to_string(), vec![vocab, d]),
+        ("model.norm.weight".to_string(), vec![d]),
+    ];
+    for i in layers {
+        for (suffix, shape) in [
+            ("input_layernorm.weight", vec![d]),
+            ("self_attn.q_proj.weight", vec![q, d]),
+            ("self_attn.k_proj.weight", vec![kv, d]),
+            ("self_attn.v_proj.weight", vec![kv, d]),
+            ("self_attn.o_proj.weight", vec![d, q]),
+            ("post_attention_layernorm.weight", vec![d]),
+            ("mlp.up_proj.weight", vec![di, d]),
+            ("mlp.gate_proj.weight", vec![di, d]),
+            ("mlp.down_proj.weight", vec![d, di]),
+        ] {
+            shapes.push((format!("model.layers.{i}.{suffix}"), shape));
+        }
+    }
+    shapes
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShapeMismatch {
+    pub name: String,
+    pub expected: Vec<usize>,
+    pub found: Vec<usize>,
+}
+
+// Every tensor whose shape disagrees with the config, see LLamaParams::validate
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShapeError {
+    pub mismatches: Vec<ShapeMismatch>,
+}
+
+impl fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} tensor(s) do not match the config:", self.mismatches.len())?;
+        for m in &self.mismatches {
+            write!(f, "\n  {}: expected {:?}, found {:?}", m.name, m.expected, m.found)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ShapeError {}
+
+// Serialize a tiny random checkpoint matching `config`, keeping only names accepted by `keep`
+#[cfg(test)]
+pub(crate) fn tiny_checkpoint(config: &LlamaConfigJson, keep: impl Fn(&str) -> bool) -> Vec<u8> {
+    use safetensors::tensor::{serialize, TensorView};
+    let shapes = expected_shapes(config);
+    let bytes: Vec<(String, Vec<usize>, Vec<u8>)> = shapes
+        .into_iter()
+        .filter(|(name, _)| keep(name))
+        .enumerate()
+        .map(|(seed, (name, shape))| {
+            let t = Tensor::randn(&shape, seed as u64);
+            let data = t.data().iter().flat_map(|x| x.to_le_bytes()).collect();
+            (name, shape, data)
+        })
+        .collect();
+    let views = bytes
+        .iter()
+        .map(|(name, shape, data)| (name.clone(), TensorView::new(Dtype::F32, shape.clone(), data).unwrap()));
+    serialize(views, &None).unwrap()
+}
+
+#[cfg(test)]
+pub(crate) fn tiny_config(n_layers: usize, tie_word_embeddings: bool) -> LlamaConfigJson {
+    LlamaConfigJson::builder()
+        .hidden_size(8)
+        .intermediate_size(16)
+        .max_position_embeddings(32)
+        .heads(2, 1)
+        .num_hidden_layers(n_layers)
+        .vocab_size(10)
+        .tie_word_embeddings(tie_word_embeddings)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn test_tied_and_untied_embeddings() {
+    let untied = tiny_config(3, false);
+    let bytes = tiny_checkpoint(&untied, |_| true);
+    let params = LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&bytes).unwrap(), &untied);
+    assert_eq!(params.wq.len(), 3);
+    assert_ne!(params.embedding_table.data(), params.lm_head.data());
+
+    let tied = tiny_config(1, true);
+    let bytes = tiny_checkpoint(&tied, |name| name != "model.embed_tokens.weight");
+    let params = LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&bytes).unwrap(), &tied);
+    assert_eq!(params.embedding_table.data(), params.lm_head.data());
+
+    let bytes = tiny_checkpoint(&untied, |name| name != "lm_head.weight");
+    let result = std::panic::catch_unwind(|| {
+        LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&bytes).unwrap(), &untied)
+    });
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_loads_every_layer() {
+    // each of the config's layers gets its own tensors, not copies of the first two
+    let config = tiny_config(5, false);
+    let bytes = tiny_checkpoint(&config, |_| true);
+    let safetensor = SafeTensors::deserialize(&bytes).unwrap();
+    let params = LLamaParams::<f32>::from_safetensors(&safetensor, &config);
+    let layers = [&params.wq, &params.wk, &params.wv, &params.wo, &params.w_up, &params.w_gate, &params.w_down];
+    assert!(layers.iter().all(|w| w.len() == 5) && params.rms_att_w.len() == 5 && params.rms_ffn_w.len() == 5);
+    for (i, wq) in params.wq.iter().enumerate() {
+        let name = format!("model.layers.{i}.self_attn.q_proj.weight");
+        assert_eq!(wq.data(), read_as::<f32>(&name, &safetensor.tensor(&name).unwrap()).data());
+    }
+    assert_ne!(params.w_down[3].data(), params.w_down[4].data());
+
+    // a checkpoint with fewer layers than the config claims is an error, not a shorter model
+    let bytes = tiny_checkpoint(&config, |name| !name.starts_with("model.layers.4."));
+    let result = std::panic::catch_unwind(|| {
+        LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&bytes).unwrap(), &config)
+    });
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_sharded_checkpoint() {
+    use safetensors::tensor::serialize;
+    let config = tiny_config(2, true);
+    let bytes = tiny_checkpoint(&config, |_| true);
+    let full = SafeTensors::deserialize(&bytes).unwrap();
+    // split into two shards: layer 1 in the second file, everything else in the first
+    let dir = std::env::temp_dir().join(format!("sharded_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut weight_map = serde_json::Map::new();
+    for (shard, file) in ["model-00001-of-00002.safetensors", "model-00002-of-00002.safetensors"].iter().enumerate() {
+        let views: Vec<_> = full
+            .tensors()
+            .into_iter()
+            .filter(|(name, _)| name.starts_with("model.layers.1.") == (shard == 1))
+            .collect();
+        for (name, _) in &views {
+            weight_map.insert(name.clone(), serde_json::json!(file));
+        }
+        std::fs::write(dir.join(file), serialize(views, &None).unwrap()).unwrap();
+    }
+    let index = serde_json::json!({ "metadata": {}, "weight_map": weight_map });
+    std::fs::write(dir.join("model.safetensors.index.json"), index.to_string()).unwrap();
+
+    let sharded = LLamaParams::<f32>::from_dir(&dir, &config);
+    let single = LLamaParams::<f32>::from_safetensors(&full, &config);
+    assert_eq!(sharded.wq[1].data(), single.wq[1].data());
+    assert_eq!(sharded.w_down[0].data(), single.w_down[0].data());
+    assert_eq!(sharded.lm_head.data(), single.lm_head.data());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_load_as_converts_dtypes() {
+    use safetensors::tensor::serialize;
+    let config = tiny_config(1, true);
+    let bytes = tiny_checkpoint(&config, |_| true);
+    let reference = SafeTensors::deserialize(&bytes).unwrap();
+    let expected = LLamaParams::<f32>::from_safetensors(&reference, &config);
+    // re-encode the f32 checkpoint with a different dtype per tensor
+    let encoded: Vec<(String, Dtype, Vec<usize>, Vec<u8>)> = reference
+        .tensors()
+        .into_iter()
+        .enumerate()
+        .map(|(i, (name, view))| {
+            let values = view.data().chunks(4).map(|b| f32::from_le_bytes(b.try_into().unwrap()));
+            let (dtype, data): (Dtype, Vec<u8>) = match i % 3 {
+                0 => (Dtype::F16, values.flat_map(|x| f16::from_f32(x).to_le_bytes()).collect()),
+                1 => (Dtype::BF16, values.flat_map(|x| bf16::from_f32(x).to_le_bytes()).collect()),
+                _ => (Dtype::F64, values.flat_map(|x| (x as f64).to_le_bytes()).collect()),
+            };
+            (name, dtype, view.shape().to_vec(), data)
+        })
+        .collect();
+    let views = encoded
+        .iter()
+        .map(|(name, dtype, shape, data)| (name.clone(), TensorView::new(*dtype, shape.clone(), data).unwrap()));
+    let bytes = serialize(views, &None).unwrap();
+    let mixed = SafeTensors::deserialize(&bytes).unwrap();
+
+    let params = load_as::<f32>(&mixed, &config);
+    for (a, b) in [(&params.wq[0], &expected.wq[0]), (&params.w_down[0], &expected.w_down[0]), (&params.lm_head, &expected.lm_head)] {
+        assert!(a.compare(b).max_abs_err < 2e-2);
+    }
+    let half = load_as::<f16>(&mixed, &config);
+    assert!(half.wv[0].to_f32().compare(&expected.wv[0]).max_abs_err < 2e-2);
+}
+
+#[test]
+fn test_load_progress() {
+    let config = tiny_config(2, true);
+    let dir = std::env::temp_dir().join(format!("progress_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("model.safetensors"), tiny_checkpoint(&config, |name| name != "lm_head.weight")).unwrap();
+    let mut reports = Vec::new();
+    let params = LLamaParams::<f32>::from_dir_with_progress(&dir, &config, |p| {
+        reports.push((p.name.to_string(), p.tensors_loaded, p.tensors_total, p.bytes_read))
+    });
+    std::fs::remove_dir_all(dir).unwrap();
+    assert_eq!(reports.len(), 2 * 9 + 2);
+    assert_eq!(reports[0].0, "model.embed_tokens.weight");
+    assert!(reports.iter().all(|r| r.2 == 2 * 9 + 3));
+    assert!(reports.windows(2).all(|w| w[1].1 == w[0].1 + 1 && w[1].3 > w[0].3));
+    let total: usize = [&params.embedding_table, &params.rms_out_w].iter().map(|t| t.size() * 4).sum::<usize>()
+        + params.wq.iter().chain(&params.wk).chain(&params.wv).chain(&params.wo).chain(&params.w_up)
+            .chain(&params.w_gate).chain(&params.w_down).chain(&params.rms_att_w).chain(&params.rms_ffn_w)
+            .map(|t| t.size() * 4)
+            .sum::<usize>();
+    assert_eq!(reports.last().unwrap().3, total);
+}
+
+#[test]
+fn test_validate_shapes() {
+    let config = tiny_config(1, false);
+    let bytes = tiny_checkpoint(&config, |_| true);
+    let safetensor = SafeTensors::deserialize(&bytes).unwrap();
+    let mut params = LLamaParams::<f32>::from_safetensors(&safetensor, &config);
+    assert_eq!(params.validate(&config), Ok(()));
+
+    params.wk[0] = Tensor::default(&vec![8, 8]);
+    params.w_down[0] = Tensor::default(&vec![16, 8]);
+    let err = params.validate(&config).unwrap_err();
+    assert_eq!(err.mismatches.len(), 2);
+    assert_eq!(err.mismatches[0].name, "model.layers.0.self_attn.k_proj.weight");
+    assert_eq!((err.mismatches[0].expected.clone(), err.mismatches[0].found.clone()), (vec![4, 8], vec![8, 8]));
+    assert!(err.to_string().contains("model.layers.0.mlp.down_proj.weight: expected [8, 16], found [16, 8]"));
+
+    // a checkpoint for a wider model fails at load time
+    let wide: LlamaConfigJson = serde_json::from_value(serde_json::json!({
+        "bos_token_id": 1, "eos_token_id": 2, "hidden_size": 8, "intermediate_size": 32,
+        "max_position_embeddings": 32, "num_attention_heads": 2, "num_hidden_layers": 1,
+        "num_key_value_heads": 1, "vocab_size": 10, "torch_dtype": "float32"
+    }))
+    .unwrap();
+    let result = std::panic::catch_unwind(|| LLamaParams::<f32>::from_safetensors(&safetensor, &wide));
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_non_standard_names() {
+    use safetensors::tensor::serialize;
+    let config = tiny_config(1, false);
+    let bytes = tiny_checkpoint(&config, |_| true);
+    let reference = SafeTensors::deserialize(&bytes).unwrap();
+    let expected = LLamaParams::<f32>::from_safetensors(&reference, &config);
+    let rename = |names: &NameMap| {
+        let views = reference.tensors().into_iter().map(|(name, view)| (names.map(&name), view));
+        serialize(views, &None).unwrap()
+    };
+
+    // built-in preset, picked up automatically
+    let bytes = rename(&NameMap::transformer_h());
+    let params = LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&bytes).unwrap(), &config);
+    assert_eq!(params.wq[0].data(), expected.wq[0].data());
+
+    // user mapping on top of a preset
+    let custom = NameMap::transformer_h().with("model.layers.{i}.mlp.down_proj.weight", "transformer.h.{i}.mlp.c_proj.weight");
+    let dir = std::env::temp_dir().join(format!("names_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("model.safetensors"), rename(&custom)).unwrap();
+    let params = LLamaParams::<f32>::from_dir_with_names(&dir, &config, Some(custom), |_| {});
+    std::fs::remove_dir_all(dir).unwrap();
+    assert_eq!(params.w_down[0].data(), expected.w_down[0].data());
+}
+
+#[test]
+fn test_quantize_on_load() {
+    let config = tiny_config(1, true);
+    let bytes = tiny_checkpoint(&config, |name| name != "lm_head.weight");
+    let safetensor = SafeTensors::deserialize(&bytes).unwrap();
+    let expected = LLamaParams::<f32>::from_safetensors(&safetensor, &config);
+    let params = LLamaParams::<f32, Int8Tensor>::from_safetensors_quantized(&safetensor, &config);
+    // embeddings and norms stay f32
+    assert_eq!(params.embedding_table.data(), expected.embedding_table.data());
+    assert_eq!(params.rms_att_w[0].data(), expected.rms_att_w[0].data());
+    for (q, w) in [(&params.wq[0], &expected.wq[0]), (&params.w_down[0], &expected.w_down[0]), (&params.lm_head, &expected.lm_head)] {
+        assert_eq!(q.shape(), w.shape());
+        // 8-wide rows here, the per-row scale overhead vanishes on real models
+        assert!(q.nbytes() * 2 < w.size() * 4);
+        assert!(q.dequantize().compare(w).max_abs_err < 0.05);
+    }
+}
+
+#[test]
+fn test_read_as_across_chunks() {
+    // more than one DECODE_CHUNK so chunk offsets are exercised
+    let n = DECODE_CHUNK * 2 + 7;
+    let values: Vec<f32> = (0..n).map(|i| (i % 256) as f32 / 8.).collect();
+    let bytes: Vec<u8> = values.iter().flat_map(|&x| bf16::from_f32(x).to_le_bytes()).collect();
+    let view = TensorView::new(Dtype::BF16, vec![n], &bytes).unwrap();
+    let t = read_as::<f32>("x", &view);
+    assert!(t.data() == &values[..]);
+    let h = read_as::<f16>("x", &view);
+    assert_eq!(h.data()[n - 1], f16::from_f32(values[n - 1]));
+}
+
+#[test]
+fn test_generic_dtype_loading() {
+    use safetensors::tensor::serialize;
+    let config = tiny_config(1, true);
+    let bytes = tiny_checkpoint(&config, |_| true);
+    let reference = SafeTensors::deserialize(&bytes).unwrap();
+    // store the checkpoint as f16, as most public half-precision checkpoints are
+    let encoded: Vec<(String, Vec<usize>, Vec<u8>)> = reference
+        .tensors()
+        .into_iter()
+        .map(|(name, view)| {
+            let data = view.data().chunks(4)
+                .flat_map(|b| f16::from_f32(f32::from_le_bytes(b.try_into().unwrap())).to_le_bytes())
+                .collect();
+            (name, view.shape().to_vec(), data)
+        })
+        .collect();
+    let views = encoded.iter().map(|(name, shape, data)| (name.clone(), TensorView::new(Dtype::F16, shape.clone(), data).unwrap()));
+    let dir = std::env::temp_dir().join(format!("f16_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("model.safetensors"), serialize(views, &None).unwrap()).unwrap();
+
+    let half = LLamaParams::<f16>::from_dir(&dir, &config);
+    let full = LLamaParams::<f32>::from_dir(&dir, &config);
+    let brain = LLamaParams::<bf16>::from_dir(&dir, &config);
+    std::fs::remove_dir_all(dir).unwrap();
+    // same dtype: bit-exact and mapped, 2-byte alignment always holds
+    let stored: Vec<f16> = encoded[0].2.chunks(2).map(|b| f16::from_le_bytes(b.try_into().unwrap())).collect();
+    let name = &encoded[0].0;
+    let loaded = half.named_tensors().into_iter().find(|(n, _)| n == name).unwrap().1.clone();
+    assert_eq!(loaded.data(), &stored[..]);
+    assert!(half.named_tensors().iter().all(|(_, t)| t.is_mapped()));
+    assert!(!full.wq[0].is_mapped());
+    assert!(half.wq[0].to_f32().data() == full.wq[0].data());
+    assert!(brain.wq[0].to_f32().compare(&full.wq[0]).max_abs_err < 2e-2);
+}
+
+#[test]
+fn test_layer_range() {
+    let config = tiny_config(4, true);
+    let bytes = tiny_checkpoint(&config, |name| name != "lm_head.weight");
+    let safetensor = SafeTensors::deserialize(&bytes).unwrap();
+    let full = LLamaParams::<f32>::from_safetensors(&safetensor, &config);
+    let stage = LLamaParams::<f32>::from_safetensors_layers(&safetensor, &config, 1..3);
+    assert_eq!(stage.layers, 1..3);
+    assert_eq!(stage.wq.len(), 2);
+    assert_eq!(stage.wq[0].data(), full.wq[1].data());
+    assert_eq!(stage.w_down[1].data(), full.w_down[2].data());
+    assert_eq!(stage.validate(&config), Ok(()));
+    assert!(stage.named_tensors().iter().any(|(name, _)| name == "model.layers.2.mlp.up_proj.weight"));
+    assert!(!stage.named_tensors().iter().any(|(name, _)| name.starts_with("model.layers.0.")));
+
+    // the range is checked against the config
+    let result = std::panic::catch_unwind(|| LLamaParams::<f32>::from_safetensors_layers(&safetensor, &config, 3..5));
+    assert!(result.is_err());
+}
+
+// tiny_checkpoint() with q, k and v stacked into one qkv_proj per layer, `qkv_rows` of them
+#[cfg(test)]
+fn tiny_fused_checkpoint(config: &LlamaConfigJson, qkv_rows: impl Fn(usize) -> usize) -> Vec<u8> {
+    use safetensors::tensor::serialize;
+    let bytes = tiny_checkpoint(config, |_| true);
+    let reference = SafeTensors::deserialize(&bytes).unwrap();
+    let mut encoded: Vec<(String, Vec<usize>, Vec<u8>)> = reference
+        .tensors()
+        .into_iter()
+        .filter(|(name, _)| !name.contains("self_attn.q_proj") && !name.contains("self_attn.k_proj") && !name.contains("self_attn.v_proj"))
+        .map(|(name, view)| (name, view.shape().to_vec(), view.data().to_vec()))
+        .collect();
+    for i in 0..config.num_hidden_layers {
+        let part = |p: &str| reference.tensor(&format!("model.layers.{i}.self_attn.{p}_proj.weight")).unwrap();
+        let (q, k, v) = (part("q"), part("k"), part("v"));
+        let rows = qkv_rows(q.shape()[0] + k.shape()[0] + v.shape()[0]);
+        let data = [q.data(), k.data(), v.data()].concat()[..rows * config.hidden_size * 4].to_vec();
+        encoded.push((format!("model.layers.{i}.self_attn.qkv_proj.weight"), vec![rows, config.hidden_size], data));
+    }
+    let views = encoded.iter().map(|(name, shape, data)| (name.clone(), TensorView::new(Dtype::F32, shape.clone(), data).unwrap()));
+    serialize(views, &None).unwrap()
+}
+
+#[test]
+fn test_fused_qkv() {
+    let config = tiny_config(2, true);
+    let bytes = tiny_checkpoint(&config, |_| true);
+    let expected = LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&bytes).unwrap(), &config);
+    let bytes = tiny_fused_checkpoint(&config, |rows| rows);
+    let params = LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&bytes).unwrap(), &config);
+    for i in 0..2 {
+        assert_eq!(params.wq[i].data(), expected.wq[i].data());
+        assert_eq!(params.wk[i].data(), expected.wk[i].data());
+        assert_eq!(params.wv[i].data(), expected.wv[i].data());
+    }
+    assert_eq!(params.validate(&config), Ok(()));
+
+    // progress still ends at the total, every qkv_proj stands for its three projections
+    let dir = std::env::temp_dir().join(format!("fused_progress_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("model.safetensors"), &bytes).unwrap();
+    let mut last = None;
+    LLamaParams::<f32>::from_dir_with_progress(&dir, &config, |p| last = Some((p.tensors_loaded, p.tensors_total)));
+    std::fs::remove_dir_all(dir).unwrap();
+    assert_eq!(last, Some((2 * 9 + 3, 2 * 9 + 3)));
+}
+
+#[test]
+fn test_verify_checkpoint() {
+    use safetensors::tensor::serialize;
+    let config = tiny_config(1, true);
+    let bytes = tiny_checkpoint(&config, |name| name != "lm_head.weight");
+    let good = SafeTensors::deserialize(&bytes).unwrap();
+    let report = verify(&good, &config);
+    assert!(report.is_ok(), "{report}");
+    assert_eq!(report.tensors.len(), 9 + 2);
+    assert!(report.tensors.iter().all(|t| t.norm > 0. && t.nan_count == 0));
+
+    // drop a tensor, truncate another, and poison a third
+    let mut encoded: Vec<(String, Vec<usize>, Vec<u8>)> = good
+        .tensors()
+        .into_iter()
+        .filter(|(name, _)| name != "model.norm.weight")
+        .map(|(name, view)| (name, view.shape().to_vec(), view.data().to_vec()))
+        .collect();
+    for (name, shape, data) in encoded.iter_mut() {
+        if name.ends_with("mlp.up_proj.weight") {
+            shape[0] -= 1;
+            data.truncate(data.len() - shape[1] * 4);
+        }
+        if name.ends_with("self_attn.v_proj.weight") {
+            data[..4].copy_from_slice(&f32::NAN.to_le_bytes());
+        }
+    }
+    let views = encoded.iter().map(|(name, shape, data)| (name.clone(), TensorView::new(Dtype::F32, shape.clone(), data).unwrap()));
+    let bytes = serialize(views, &None).unwrap();
+    let report = verify(&SafeTensors::deserialize(&bytes).unwrap(), &config);
+    assert!(!report.is_ok());
+    assert_eq!(report.missing, vec!["model.norm.weight".to_string()]);
+    assert_eq!(report.problems.len(), 2, "{report}");
+    assert!(report.problems[0].contains("up_proj") || report.problems[1].contains("up_proj"));
+    let v = report.tensors.iter().find(|t| t.name.ends_with("v_proj.weight")).unwrap();
+    assert_eq!(v.nan_count, 1);
+    assert!(v.norm.is_finite());
+}
+
+#[test]
+fn test_verify_fused_qkv() {
+    let config = tiny_config(2, true);
+    let bytes = tiny_fused_checkpoint(&config, |rows| rows);
+    let report = verify(&SafeTensors::deserialize(&bytes).unwrap(), &config);
+    assert!(report.is_ok(), "{report}");
+    // the fused tensor is checked once per layer, in place of q, k and v
+    assert_eq!(report.tensors.len(), 2 * 7 + 3);
+    assert!(report.tensors.iter().any(|t| t.name == "model.layers.1.self_attn.qkv_proj.weight"));
+
+    // a fused tensor missing the last v row
+    let bytes = tiny_fused_checkpoint(&config, |rows| rows - 1);
+    let report = verify(&SafeTensors::deserialize(&bytes).unwrap(), &config);
+    assert!(report.missing.is_empty(), "{report}");
+    assert_eq!(report.problems.len(), 2, "{report}");
+    assert!(report.problems[0].starts_with("model.layers.0.self_attn.qkv_proj.weight: expected shape [16, 8]"));
+}
+
+#[test]
+fn test_explicit_head_dim() {
+    let mut config = tiny_config(1, false);
+    config.head_dim = Some(6);
+    let bytes = tiny_checkpoint(&config, |_| true);
+    let params = LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&bytes).unwrap(), &config);
+    assert_eq!(params.wq[0].shape(), &vec![12, 8]);
+    assert_eq!(params.wk[0].shape(), &vec![6, 8]);
+    assert_eq!(params.wo[0].shape(), &vec![8, 12]);
+}
+
+#[test]
+fn test_random_params() {
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let params = LLamaParams::<f32>::random(&config, 7);
+    assert_eq!(params.wq.len(), 2);
+    assert_eq!(params.wk[1].shape(), &vec![32, 64]);
+    assert_eq!(params.validate(&config), Ok(()));
+    assert!(params.rms_att_w[0].data().iter().all(|&x| x == 1.));
+    // reproducible from the seed, different across seeds
+    assert_eq!(params.w_up[1].data(), LLamaParams::<f32>::random(&config, 7).w_up[1].data());
+    assert_ne!(params.w_up[1].data(), LLamaParams::<f32>::random(&config, 8).w_up[1].data());
+    let half = LLamaParams::<f16>::random(&config, 7);
+    assert_eq!(half.wo[0].shape(), &vec![64, 64]);
+
+    assert_eq!(
+        LlamaConfigJson::builder().heads(3, 1).build().unwrap_err(),
+        crate::config::ConfigError::HeadsDontDivideHidden { hidden_size: 64, num_attention_heads: 3 }
+    );
 }
- 
\ No newline at end of file
@@ -0,0 +1,519 @@
+use crate::config::LlamaConfigJson;
+use crate::names::NameMap;
+use crate::params::{expected_shapes, LLamaParams, LoadProgress};
+use crate::tensor::Tensor;
+use half::{bf16, f16};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek};
+use std::path::Path;
+use zip::ZipArchive;
+
+// Importer for `torch.save` checkpoints (the zip format used since PyTorch 1.6): `<archive>/data.pkl`
+// pickles the state dict, tensor payloads live in `<archive>/data/<storage key>`.
+// The unpickler only understands what plain tensor state dicts are made of, and never runs any code.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageDtype {
+    F32,
+    F16,
+    BF16,
+    F64,
+}
+
+impl StorageDtype {
+    fn from_class(name: &str) -> Option<Self> {
+        match name {
+            "FloatStorage" => Some(StorageDtype::F32),
+            "HalfStorage" => Some(StorageDtype::F16),
+            "BFloat16Storage" => Some(StorageDtype::BF16),
+            "DoubleStorage" => Some(StorageDtype::F64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            StorageDtype::F32 => 4,
+            StorageDtype::F16 | StorageDtype::BF16 => 2,
+            StorageDtype::F64 => 8,
+        }
+    }
+
+    fn to_f32(self, b: &[u8]) -> f32 {
+        match self {
+            StorageDtype::F32 => f32::from_le_bytes(b.try_into().unwrap()),
+            StorageDtype::F16 => f16::from_le_bytes(b.try_into().unwrap()).to_f32(),
+            StorageDtype::BF16 => bf16::from_le_bytes(b.try_into().unwrap()).to_f32(),
+            StorageDtype::F64 => f64::from_le_bytes(b.try_into().unwrap()) as f32,
+        }
+    }
+}
+
+// Where a tensor of the state dict lives, element offsets and strides are in units of the storage dtype
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorRef {
+    pub storage_key: String,
+    pub dtype: StorageDtype,
+    pub offset: usize,
+    pub shape: Vec<usize>,
+    pub strides: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    Dict(Vec<(Value, Value)>),
+    Global(String, String),
+    Storage(String, StorageDtype),
+    Tensor(TensorRef),
+    // anything else built by REDUCE, kept only so the stack stays balanced
+    Object,
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn as_usizes(v: &Value) -> io::Result<Vec<usize>> {
+    match v {
+        Value::Tuple(items) | Value::List(items) => items
+            .iter()
+            .map(|i| match i {
+                Value::Int(n) => usize::try_from(*n).map_err(|_| invalid("negative size or stride")),
+                _ => Err(invalid("expected an integer tuple")),
+            })
+            .collect(),
+        _ => Err(invalid("expected an integer tuple")),
+    }
+}
+
+// torch._utils._rebuild_tensor_v2(storage, storage_offset, size, stride, ...)
+fn rebuild_tensor(args: Value) -> io::Result<Value> {
+    let Value::Tuple(args) = args else {
+        return Err(invalid("_rebuild_tensor_v2 expects a tuple of arguments"));
+    };
+    match args.as_slice() {
+        [Value::Storage(key, dtype), Value::Int(offset), size, stride, ..] => Ok(Value::Tensor(TensorRef {
+            storage_key: key.clone(),
+            dtype: *dtype,
+            offset: usize::try_from(*offset).map_err(|_| invalid("negative storage offset"))?,
+            shape: as_usizes(size)?,
+            strides: as_usizes(stride)?,
+        })),
+        _ => Err(invalid("unexpected _rebuild_tensor_v2 arguments")),
+    }
+}
+
+// ('storage', torch.FloatStorage, '<key>', 'cpu', numel)
+fn persistent_load(pid: Value) -> io::Result<Value> {
+    match pid {
+        Value::Tuple(items) => match items.as_slice() {
+            [Value::Str(kind), Value::Global(_, class), Value::Str(key), ..] if kind == "storage" => {
+                let dtype = StorageDtype::from_class(class)
+                    .ok_or_else(|| invalid(format!("unsupported storage type {class}")))?;
+                Ok(Value::Storage(key.clone(), dtype))
+            }
+            _ => Err(invalid("unexpected persistent id")),
+        },
+        _ => Err(invalid("unexpected persistent id")),
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + n).ok_or_else(|| invalid("truncated pickle"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn string(&mut self, n: usize) -> io::Result<String> {
+        String::from_utf8(self.bytes(n)?.to_vec()).map_err(|_| invalid("invalid string in pickle"))
+    }
+
+    // newline-terminated argument of GLOBAL
+    fn line(&mut self) -> io::Result<String> {
+        let len = self.buf[self.pos..].iter().position(|&c| c == b'\n').ok_or_else(|| invalid("truncated pickle"))?;
+        let line = self.string(len)?;
+        self.pos += 1;
+        Ok(line)
+    }
+}
+
+// Minimal pickle (protocol 2-5) stack machine over the opcodes torch.save emits for state dicts
+fn unpickle(data: &[u8]) -> io::Result<Value> {
+    let mut r = Reader { buf: data, pos: 0 };
+    let mut stack: Vec<Value> = Vec::new();
+    let mut marks: Vec<usize> = Vec::new();
+    let mut memo: HashMap<u32, Value> = HashMap::new();
+    let pop = |stack: &mut Vec<Value>| stack.pop().ok_or_else(|| invalid("pickle stack underflow"));
+    let pop_mark = |stack: &mut Vec<Value>, marks: &mut Vec<usize>| -> io::Result<Vec<Value>> {
+        let mark = marks.pop().ok_or_else(|| invalid("pickle mark underflow"))?;
+        Ok(stack.split_off(mark))
+    };
+    let top = |stack: &Vec<Value>| stack.last().cloned().ok_or_else(|| invalid("empty stack on memo put"));
+
+    loop {
+        let op = r.u8()?;
+        match op {
+            0x80 => {
+                r.u8()?; // PROTO
+            }
+            0x95 => {
+                r.bytes(8)?; // FRAME
+            }
+            b'.' => return pop(&mut stack),
+            b'(' => marks.push(stack.len()),
+            b'N' => stack.push(Value::None),
+            0x88 => stack.push(Value::Bool(true)),
+            0x89 => stack.push(Value::Bool(false)),
+            b'K' => stack.push(Value::Int(r.u8()? as i64)),
+            b'M' => stack.push(Value::Int(u16::from_le_bytes(r.array()?) as i64)),
+            b'J' => stack.push(Value::Int(i32::from_le_bytes(r.array()?) as i64)),
+            0x8a => {
+                // LONG1: little-endian two's complement of n bytes
+                let n = r.u8()? as usize;
+                let bytes = r.bytes(n)?;
+                if n > 8 {
+                    return Err(invalid("LONG1 value too large"));
+                }
+                let mut buf = if bytes.last().is_some_and(|&b| b & 0x80 != 0) { [0xff; 8] } else { [0; 8] };
+                buf[..n].copy_from_slice(bytes);
+                stack.push(Value::Int(i64::from_le_bytes(buf)));
+            }
+            b'G' => stack.push(Value::Float(f64::from_be_bytes(r.array()?))),
+            b'X' => {
+                let n = u32::from_le_bytes(r.array()?) as usize;
+                stack.push(Value::Str(r.string(n)?));
+            }
+            0x8c => {
+                let n = r.u8()? as usize;
+                stack.push(Value::Str(r.string(n)?));
+            }
+            b'c' => {
+                let module = r.line()?;
+                let name = r.line()?;
+                stack.push(Value::Global(module, name));
+            }
+            0x93 => {
+                let name = pop(&mut stack)?;
+                let module = pop(&mut stack)?;
+                match (module, name) {
+                    (Value::Str(module), Value::Str(name)) => stack.push(Value::Global(module, name)),
+                    _ => return Err(invalid("STACK_GLOBAL expects two strings")),
+                }
+            }
+            b'}' => stack.push(Value::Dict(Vec::new())),
+            b']' => stack.push(Value::List(Vec::new())),
+            b')' => stack.push(Value::Tuple(Vec::new())),
+            b't' => {
+                let items = pop_mark(&mut stack, &mut marks)?;
+                stack.push(Value::Tuple(items));
+            }
+            0x85..=0x87 => {
+                let n = (op - 0x84) as usize;
+                if stack.len() < n {
+                    return Err(invalid("pickle stack underflow"));
+                }
+                let items = stack.split_off(stack.len() - n);
+                stack.push(Value::Tuple(items));
+            }
+            b'q' => {
+                let key = r.u8()? as u32;
+                memo.insert(key, top(&stack)?);
+            }
+            b'r' => {
+                let key = u32::from_le_bytes(r.array()?);
+                memo.insert(key, top(&stack)?);
+            }
+            0x94 => {
+                let key = memo.len() as u32;
+                memo.insert(key, top(&stack)?);
+            }
+            b'h' | b'j' => {
+                let key = if op == b'h' { r.u8()? as u32 } else { u32::from_le_bytes(r.array()?) };
+                stack.push(memo.get(&key).cloned().ok_or_else(|| invalid(format!("memo key {key} not found")))?);
+            }
+            b'Q' => {
+                let pid = pop(&mut stack)?;
+                stack.push(persistent_load(pid)?);
+            }
+            b'R' => {
+                let args = pop(&mut stack)?;
+                let callable = pop(&mut stack)?;
+                let value = match &callable {
+                    Value::Global(m, n) if m == "torch._utils" && n == "_rebuild_tensor_v2" => rebuild_tensor(args)?,
+                    Value::Global(m, n) if m == "collections" && n == "OrderedDict" => Value::Dict(Vec::new()),
+                    _ => Value::Object,
+                };
+                stack.push(value);
+            }
+            b'b' => {
+                pop(&mut stack)?; // BUILD: state (e.g. _metadata of the state dict) is not needed
+            }
+            b's' | b'u' => {
+                let items = if op == b's' {
+                    let value = pop(&mut stack)?;
+                    let key = pop(&mut stack)?;
+                    vec![key, value]
+                } else {
+                    pop_mark(&mut stack, &mut marks)?
+                };
+                match stack.last_mut() {
+                    Some(Value::Dict(dict)) => {
+                        let mut items = items.into_iter();
+                        while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                            dict.push((k, v));
+                        }
+                    }
+                    _ => return Err(invalid("SETITEM on a non-dict")),
+                }
+            }
+            b'a' | b'e' => {
+                let items = if op == b'a' { vec![pop(&mut stack)?] } else { pop_mark(&mut stack, &mut marks)? };
+                match stack.last_mut() {
+                    Some(Value::List(list)) => list.extend(items),
+                    _ => return Err(invalid("APPEND on a non-list")),
+                }
+            }
+            op => return Err(invalid(format!("unsupported pickle opcode 0x{op:02x}"))),
+        }
+    }
+}
+
+pub struct PytorchCheckpoint<R> {
+    archive: ZipArchive<R>,
+    prefix: String,
+    pub tensors: HashMap<String, TensorRef>,
+}
+
+impl PytorchCheckpoint<File> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+}
+
+impl<R: Read + Seek> PytorchCheckpoint<R> {
+    pub fn new(reader: R) -> io::Result<Self> {
+        let mut archive = ZipArchive::new(reader)?;
+        let pkl = archive
+            .file_names()
+            .find(|name| name.ends_with("data.pkl"))
+            .ok_or_else(|| invalid("no data.pkl in archive, legacy (non-zip) torch.save files are not supported"))?
+            .to_string();
+        let prefix = pkl.trim_end_matches("data.pkl").to_string();
+        let mut bytes = Vec::new();
+        archive.by_name(&pkl)?.read_to_end(&mut bytes)?;
+        let Value::Dict(entries) = unpickle(&bytes)? else {
+            return Err(invalid("checkpoint is not a state dict"));
+        };
+        let mut tensors = HashMap::new();
+        for (key, value) in entries {
+            if let (Value::Str(name), Value::Tensor(t)) = (key, value) {
+                tensors.insert(name, t);
+            }
+        }
+        Ok(PytorchCheckpoint { archive, prefix, tensors })
+    }
+
+    // Tensor `name` converted to f32, laid out contiguously whatever its strides in the storage
+    pub fn tensor_f32(&mut self, name: &str) -> io::Result<Option<Tensor<f32>>> {
+        let Some(t) = self.tensors.get(name).cloned() else {
+            return Ok(None);
+        };
+        let mut bytes = Vec::new();
+        self.archive.by_name(&format!("{}data/{}", self.prefix, t.storage_key))?.read_to_end(&mut bytes)?;
+        // shape, strides and offset come from the pickle, so every size is checked before it is used
+        let out_of_bounds = || invalid(format!("tensor {name} is out of bounds"));
+        if t.strides.len() != t.shape.len() {
+            let (strides, dims) = (t.strides.len(), t.shape.len());
+            return Err(invalid(format!("tensor {name} has {strides} strides for {dims} dimensions")));
+        }
+        let elem = t.dtype.size();
+        let n = t.shape.iter().try_fold(1usize, |n, &d| n.checked_mul(d)).ok_or_else(out_of_bounds)?;
+        // strides of 0 repeat elements, so only the payload size bounds what is reserved up front
+        let mut data = Vec::with_capacity(n.min(bytes.len() / elem));
+        let mut index = vec![0usize; t.shape.len()];
+        for _ in 0..n {
+            let at = index
+                .iter()
+                .zip(&t.strides)
+                .try_fold(t.offset, |at, (&i, &s)| i.checked_mul(s).and_then(|step| at.checked_add(step)));
+            let range = at.and_then(|at| Some(at.checked_mul(elem)?..at.checked_add(1)?.checked_mul(elem)?));
+            let b = range.and_then(|range| bytes.get(range)).ok_or_else(out_of_bounds)?;
+            data.push(t.dtype.to_f32(b));
+            for axis in (0..index.len()).rev() {
+                index[axis] += 1;
+                if index[axis] < t.shape[axis] {
+                    break;
+                }
+                index[axis] = 0;
+            }
+        }
+        Ok(Some(Tensor::new(data, &t.shape)))
+    }
+}
+
+impl LLamaParams<f32> {
+    pub fn from_pytorch<R: Read + Seek>(
+        checkpoint: &mut PytorchCheckpoint<R>,
+        config: &LlamaConfigJson,
+    ) -> io::Result<Self> {
+        Self::from_pytorch_with_progress(checkpoint, config, |_| {})
+    }
+
+    // from_pytorch, reporting every tensor as it is loaded. Payloads are all read up front, so a
+    // truncated or malformed archive is an error rather than a panic halfway through the layers
+    pub fn from_pytorch_with_progress<R: Read + Seek>(
+        checkpoint: &mut PytorchCheckpoint<R>,
+        config: &LlamaConfigJson,
+        on_progress: impl FnMut(&LoadProgress),
+    ) -> io::Result<Self> {
+        let names = NameMap::detect(checkpoint.tensors.keys().map(String::as_str));
+        // a fused qkv_proj stands in for missing q/k/v projections
+        let fused = (0..config.num_hidden_layers).map(|i| format!("model.layers.{i}.self_attn.qkv_proj.weight"));
+        let mut tensors = HashMap::new();
+        for name in expected_shapes(config).into_iter().map(|(name, _)| name).chain(fused) {
+            if let Some(t) = checkpoint.tensor_f32(&names.map(&name))? {
+                tensors.insert(name, t);
+            }
+        }
+        let get_tensor = |name: &str| tensors.get(name).cloned();
+        Ok(Self::load(config, 0..config.num_hidden_layers, get_tensor, |t| t, on_progress))
+    }
+}
+
+// Serialize `tensors` (name, shape, f32 data) the way torch.save writes a state dict with protocol 2
+#[cfg(test)]
+pub(crate) fn write_pytorch(tensors: &[(&str, Vec<usize>, Vec<f32>)]) -> Vec<u8> {
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    let mut pkl = vec![0x80, 2];
+    let global = |pkl: &mut Vec<u8>, module: &str, name: &str| {
+        pkl.push(b'c');
+        pkl.extend(format!("{module}\n{name}\n").as_bytes());
+    };
+    let string = |pkl: &mut Vec<u8>, s: &str| {
+        pkl.push(b'X');
+        pkl.extend((s.len() as u32).to_le_bytes());
+        pkl.extend(s.as_bytes());
+    };
+    let ints = |pkl: &mut Vec<u8>, xs: &[usize]| {
+        pkl.push(b'(');
+        for &x in xs {
+            pkl.push(b'J');
+            pkl.extend((x as i32).to_le_bytes());
+        }
+        pkl.push(b't');
+    };
+    global(&mut pkl, "collections", "OrderedDict");
+    pkl.extend([b')', b'R', b'q', 0, b'(']);
+    for (key, (name, shape, _)) in tensors.iter().enumerate() {
+        string(&mut pkl, name);
+        global(&mut pkl, "torch._utils", "_rebuild_tensor_v2");
+        pkl.push(b'(');
+        // persistent id
+        pkl.push(b'(');
+        string(&mut pkl, "storage");
+        global(&mut pkl, "torch", "FloatStorage");
+        string(&mut pkl, &key.to_string());
+        string(&mut pkl, "cpu");
+        pkl.push(b'K');
+        pkl.push(shape.iter().product::<usize>() as u8);
+        pkl.extend([b't', b'Q', b'K', 0]);
+        ints(&mut pkl, shape);
+        let mut strides = vec![1; shape.len()];
+        for i in (0..shape.len().saturating_sub(1)).rev() {
+            strides[i] = strides[i + 1] * shape[i + 1];
+        }
+        ints(&mut pkl, &strides);
+        pkl.push(0x89);
+        global(&mut pkl, "collections", "OrderedDict");
+        pkl.extend([b')', b'R', b't', b'R']);
+    }
+    pkl.extend([b'u', b'.']);
+
+    let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    zip.start_file("archive/data.pkl", options).unwrap();
+    zip.write_all(&pkl).unwrap();
+    for (key, (_, _, data)) in tensors.iter().enumerate() {
+        zip.start_file(format!("archive/data/{key}"), options).unwrap();
+        zip.write_all(&data.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>()).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+#[test]
+fn test_pytorch_state_dict() {
+    use crate::params::{tiny_checkpoint, tiny_config};
+    use safetensors::SafeTensors;
+    let config = tiny_config(1, false);
+    let bytes = tiny_checkpoint(&config, |_| true);
+    let reference = SafeTensors::deserialize(&bytes).unwrap();
+    let views = reference.tensors();
+    let tensors: Vec<(&str, Vec<usize>, Vec<f32>)> = views
+        .iter()
+        .map(|(name, view)| {
+            let data = view.data().chunks(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+            (name.as_str(), view.shape().to_vec(), data)
+        })
+        .collect();
+    let mut checkpoint = PytorchCheckpoint::new(io::Cursor::new(write_pytorch(&tensors))).unwrap();
+    assert_eq!(checkpoint.tensors.len(), tensors.len());
+    let mut loaded = 0;
+    let params = LLamaParams::<f32>::from_pytorch_with_progress(&mut checkpoint, &config, |_| loaded += 1).unwrap();
+    assert_eq!(loaded, tensors.len());
+    let expected = LLamaParams::<f32>::from_safetensors(&reference, &config);
+    assert_eq!(params.wq[0].data(), expected.wq[0].data());
+    assert_eq!(params.w_down[0].data(), expected.w_down[0].data());
+    assert_eq!(params.lm_head.data(), expected.lm_head.data());
+
+    // a transposed view is read back through its strides
+    let t = &mut checkpoint.tensors.get_mut("model.layers.0.mlp.up_proj.weight").unwrap();
+    t.shape.reverse();
+    t.strides.reverse();
+    let transposed = checkpoint.tensor_f32("model.layers.0.mlp.up_proj.weight").unwrap().unwrap();
+    assert_eq!(transposed.data(), expected.w_up[0].transpose(vec![1, 0]).contiguous().data());
+
+    // sizes from the pickle that overflow or point past the storage are errors, not panics or huge allocations
+    let storage = Value::Storage("0".to_string(), StorageDtype::F32);
+    let args = |offset: i64| {
+        Value::Tuple(vec![storage.clone(), Value::Int(offset), Value::Tuple(vec![]), Value::Tuple(vec![])])
+    };
+    assert!(rebuild_tensor(args(0)).is_ok());
+    assert!(rebuild_tensor(args(-1)).is_err());
+    let name = "model.layers.0.mlp.down_proj.weight";
+    let original = checkpoint.tensors[name].clone();
+    for broken in [
+        TensorRef { shape: vec![usize::MAX, 2], ..original.clone() },
+        TensorRef { offset: usize::MAX, ..original.clone() },
+        TensorRef { strides: vec![usize::MAX, 1], ..original.clone() },
+        TensorRef { strides: vec![1], ..original.clone() },
+    ] {
+        checkpoint.tensors.insert(name.to_string(), broken);
+        assert!(checkpoint.tensor_f32(name).is_err());
+    }
+    assert!(LLamaParams::<f32>::from_pytorch(&mut checkpoint, &config).is_err());
+    // callables other than the tensor/OrderedDict rebuilders are never invoked
+    assert_eq!(unpickle(b"\x80\x02cos\nsystem\n(X\x02\x00\x00\x00lstR.").unwrap(), Value::Object);
+}
@@ -0,0 +1,333 @@
+use crate::tensor::Tensor;
+
+// A fixed-size group of quantized weights that carries its own scale
+pub trait QuantBlock: Copy {
+    const SIZE: usize;
+
+    fn quantize(x: &[f32]) -> Self;
+    fn dequantize(&self, y: &mut [f32]);
+
+    // Dot product with SIZE f32 values, dequantizing on the fly
+    fn dot(&self, x: &[f32]) -> f32;
+}
+
+pub const QK8_0: usize = 32;
+
+// Q8_0: 32 weights as i8 with a shared f32 scale, x = scale * q
+#[derive(Clone, Copy, Debug)]
+pub struct BlockQ8_0 {
+    pub scale: f32,
+    pub qs: [i8; QK8_0],
+}
+
+impl QuantBlock for BlockQ8_0 {
+    const SIZE: usize = QK8_0;
+
+    fn quantize(x: &[f32]) -> Self {
+        assert!(x.len() == QK8_0);
+        let amax = x.iter().fold(0f32, |m, v| m.max(v.abs()));
+        let scale = amax / 127.;
+        let inv = if scale == 0. { 0. } else { 1. / scale };
+        let mut qs = [0i8; QK8_0];
+        for (q, v) in qs.iter_mut().zip(x) {
+            *q = (v * inv).round() as i8;
+        }
+        BlockQ8_0 { scale, qs }
+    }
+
+    fn dequantize(&self, y: &mut [f32]) {
+        for (y, &q) in y.iter_mut().zip(&self.qs) {
+            *y = q as f32 * self.scale;
+        }
+    }
+
+    fn dot(&self, x: &[f32]) -> f32 {
+        let sum: f32 = self.qs.iter().zip(x).map(|(&q, v)| q as f32 * v).sum();
+        sum * self.scale
+    }
+}
+
+pub const QK4_0: usize = 32;
+
+// Q4_0: 32 weights as 4-bit codes with a shared f32 scale, x = scale * (q - 8).
+// Element j sits in the low nibble of qs[j], element j + 16 in its high nibble
+#[derive(Clone, Copy, Debug)]
+pub struct BlockQ4_0 {
+    pub scale: f32,
+    pub qs: [u8; QK4_0 / 2],
+}
+
+impl QuantBlock for BlockQ4_0 {
+    const SIZE: usize = QK4_0;
+
+    fn quantize(x: &[f32]) -> Self {
+        assert!(x.len() == QK4_0);
+        // signed absmax maps to -8 so the full [-8, 7] code range is used
+        let max = x.iter().fold(0f32, |m, &v| if v.abs() > m.abs() { v } else { m });
+        let scale = max / -8.;
+        let inv = if scale == 0. { 0. } else { 1. / scale };
+        let code = |v: f32| ((v * inv + 8.5) as i32).clamp(0, 15) as u8;
+        let mut qs = [0u8; QK4_0 / 2];
+        for (j, q) in qs.iter_mut().enumerate() {
+            *q = code(x[j]) | code(x[j + QK4_0 / 2]) << 4;
+        }
+        BlockQ4_0 { scale, qs }
+    }
+
+    fn dequantize(&self, y: &mut [f32]) {
+        let (lo, hi) = y.split_at_mut(QK4_0 / 2);
+        for (j, &q) in self.qs.iter().enumerate() {
+            lo[j] = ((q & 0xf) as i32 - 8) as f32 * self.scale;
+            hi[j] = ((q >> 4) as i32 - 8) as f32 * self.scale;
+        }
+    }
+
+    fn dot(&self, x: &[f32]) -> f32 {
+        let (lo, hi) = x.split_at(QK4_0 / 2);
+        let sum: f32 = self
+            .qs
+            .iter()
+            .zip(lo.iter().zip(hi))
+            .map(|(&q, (a, b))| ((q & 0xf) as i32 - 8) as f32 * a + ((q >> 4) as i32 - 8) as f32 * b)
+            .sum();
+        sum * self.scale
+    }
+}
+
+pub const QK_K: usize = 256;
+const K_SUBBLOCK: usize = 32;
+
+// Q4_K: 256-weight super-block of 8 sub-blocks, each with its own 8-bit scale and min
+// relative to the super-block's d / dmin, x = d * scales[s] * q - dmin * mins[s].
+// Sub-block s packs element j in the low nibble of qs[16 * s + j] and element j + 16 in its high nibble
+#[derive(Clone, Copy, Debug)]
+pub struct BlockQ4K {
+    pub d: f32,
+    pub dmin: f32,
+    pub scales: [u8; QK_K / K_SUBBLOCK],
+    pub mins: [u8; QK_K / K_SUBBLOCK],
+    pub qs: [u8; QK_K / 2],
+}
+
+impl QuantBlock for BlockQ4K {
+    const SIZE: usize = QK_K;
+
+    fn quantize(x: &[f32]) -> Self {
+        assert!(x.len() == QK_K);
+        let n_sub = QK_K / K_SUBBLOCK;
+        let mut sub_scales = [0f32; QK_K / K_SUBBLOCK];
+        let mut sub_mins = [0f32; QK_K / K_SUBBLOCK];
+        for (s, sub) in x.chunks(K_SUBBLOCK).enumerate() {
+            let lo = sub.iter().fold(0f32, |m, &v| m.min(v));
+            let hi = sub.iter().fold(lo, |m, &v| m.max(v));
+            sub_scales[s] = (hi - lo) / 15.;
+            sub_mins[s] = -lo;
+        }
+        let d = sub_scales.iter().fold(0f32, |m, &v| m.max(v)) / 255.;
+        let dmin = sub_mins.iter().fold(0f32, |m, &v| m.max(v)) / 255.;
+        let mut block = BlockQ4K {
+            d,
+            dmin,
+            scales: [0; QK_K / K_SUBBLOCK],
+            mins: [0; QK_K / K_SUBBLOCK],
+            qs: [0; QK_K / 2],
+        };
+        for s in 0..n_sub {
+            block.scales[s] = if d == 0. { 0 } else { (sub_scales[s] / d).round() as u8 };
+            block.mins[s] = if dmin == 0. { 0 } else { (sub_mins[s] / dmin).round() as u8 };
+            let scale = d * block.scales[s] as f32;
+            let inv = if scale == 0. { 0. } else { 1. / scale };
+            let min = dmin * block.mins[s] as f32;
+            let code = |v: f32| (((v + min) * inv).round() as i32).clamp(0, 15) as u8;
+            let sub = &x[s * K_SUBBLOCK..][..K_SUBBLOCK];
+            for j in 0..K_SUBBLOCK / 2 {
+                block.qs[s * K_SUBBLOCK / 2 + j] = code(sub[j]) | code(sub[j + K_SUBBLOCK / 2]) << 4;
+            }
+        }
+        block
+    }
+
+    fn dequantize(&self, y: &mut [f32]) {
+        for (s, sub) in y.chunks_mut(K_SUBBLOCK).enumerate() {
+            let scale = self.d * self.scales[s] as f32;
+            let min = self.dmin * self.mins[s] as f32;
+            let (lo, hi) = sub.split_at_mut(K_SUBBLOCK / 2);
+            for (j, &q) in self.qs[s * K_SUBBLOCK / 2..][..K_SUBBLOCK / 2].iter().enumerate() {
+                lo[j] = (q & 0xf) as f32 * scale - min;
+                hi[j] = (q >> 4) as f32 * scale - min;
+            }
+        }
+    }
+
+    fn dot(&self, x: &[f32]) -> f32 {
+        let mut sum = 0.;
+        for (s, sub) in x.chunks(K_SUBBLOCK).enumerate() {
+            let (lo, hi) = sub.split_at(K_SUBBLOCK / 2);
+            let (mut qx, mut sx) = (0f32, 0f32);
+            for (j, &q) in self.qs[s * K_SUBBLOCK / 2..][..K_SUBBLOCK / 2].iter().enumerate() {
+                qx += (q & 0xf) as f32 * lo[j] + (q >> 4) as f32 * hi[j];
+                sx += lo[j] + hi[j];
+            }
+            sum += self.d * self.scales[s] as f32 * qx - self.dmin * self.mins[s] as f32 * sx;
+        }
+        sum
+    }
+}
+
+// Row-major tensor whose rows are stored as quantized blocks, the last dim must be a multiple of B::SIZE
+pub struct QuantTensor<B> {
+    blocks: Vec<B>,
+    shape: Vec<usize>,
+}
+
+impl<B: QuantBlock> QuantTensor<B> {
+    pub fn quantize(x: &Tensor<f32>) -> Self {
+        let shape = x.shape().clone();
+        let dim = shape[shape.len() - 1];
+        assert!(
+            dim.is_multiple_of(B::SIZE),
+            "last dim of {shape:?} is not a multiple of the block size {}",
+            B::SIZE
+        );
+        let dense = x.contiguous();
+        let blocks = dense.data().chunks(B::SIZE).map(B::quantize).collect();
+        QuantTensor { blocks, shape }
+    }
+
+    pub fn dequantize(&self) -> Tensor<f32> {
+        let mut data = vec![0f32; self.size()];
+        for (block, y) in self.blocks.iter().zip(data.chunks_mut(B::SIZE)) {
+            block.dequantize(y);
+        }
+        Tensor::new(data, &self.shape)
+    }
+
+    pub fn shape(&self) -> &Vec<usize> {
+        &self.shape
+    }
+
+    pub fn size(&self) -> usize {
+        self.blocks.len() * B::SIZE
+    }
+
+    pub fn blocks(&self) -> &[B] {
+        &self.blocks
+    }
+
+    // Blocks of row `i` when viewed as (rows, last dim)
+    pub fn row(&self, i: usize) -> &[B] {
+        let per_row = self.shape[self.shape.len() - 1] / B::SIZE;
+        &self.blocks[i * per_row..][..per_row]
+    }
+}
+
+// Per-row symmetric int8, row r dequantizes to scales[r] * qs[r * cols..][..cols].
+// Leading dims are flattened into rows, the last dim is the row length
+pub struct Int8Tensor {
+    qs: Vec<i8>,
+    scales: Vec<f32>,
+    shape: Vec<usize>,
+}
+
+impl Int8Tensor {
+    pub fn quantize(x: &Tensor<f32>) -> Self {
+        let shape = x.shape().clone();
+        let cols = shape[shape.len() - 1];
+        let dense = x.contiguous();
+        let mut qs = vec![0i8; dense.size()];
+        let mut scales = Vec::with_capacity(dense.size() / cols.max(1));
+        for (row, q) in dense.data().chunks(cols).zip(qs.chunks_mut(cols)) {
+            scales.push(quantize_i8(row, q));
+        }
+        Int8Tensor { qs, scales, shape }
+    }
+
+    pub fn dequantize(&self) -> Tensor<f32> {
+        let cols = self.shape[self.shape.len() - 1];
+        let mut data = vec![0f32; self.size()];
+        for (i, y) in data.chunks_mut(cols).enumerate() {
+            let (q, scale) = self.row(i);
+            for (y, &q) in y.iter_mut().zip(q) {
+                *y = q as f32 * scale;
+            }
+        }
+        Tensor::new(data, &self.shape)
+    }
+
+    pub fn shape(&self) -> &Vec<usize> {
+        &self.shape
+    }
+
+    pub fn size(&self) -> usize {
+        self.qs.len()
+    }
+
+    // Bytes held by the codes and scales
+    pub fn nbytes(&self) -> usize {
+        self.qs.len() + self.scales.len() * std::mem::size_of::<f32>()
+    }
+
+    // Codes and scale of row `i`
+    pub fn row(&self, i: usize) -> (&[i8], f32) {
+        let cols = self.shape[self.shape.len() - 1];
+        (&self.qs[i * cols..][..cols], self.scales[i])
+    }
+}
+
+// Symmetric int8 codes of `x` written to `q`, returns the scale with x ~ scale * q
+pub fn quantize_i8(x: &[f32], q: &mut [i8]) -> f32 {
+    let amax = x.iter().fold(0f32, |m, v| m.max(v.abs()));
+    let scale = amax / 127.;
+    let inv = if scale == 0. { 0. } else { 1. / scale };
+    for (q, v) in q.iter_mut().zip(x) {
+        *q = (v * inv).round() as i8;
+    }
+    scale
+}
+
+pub type Q8Tensor = QuantTensor<BlockQ8_0>;
+pub type Q4Tensor = QuantTensor<BlockQ4_0>;
+pub type Q4KTensor = QuantTensor<BlockQ4K>;
+
+#[test]
+fn test_q8_0_roundtrip() {
+    let x = Tensor::<f32>::new((0..64).map(|i| (i as f32 - 20.) / 7.).collect(), &vec![2, 32]);
+    let q = Q8Tensor::quantize(&x);
+    assert_eq!(q.blocks().len(), 2);
+    let y = q.dequantize();
+    assert!(x.data().iter().zip(y.data()).all(|(a, b)| (a - b).abs() < 0.03));
+    let zeros = Q8Tensor::quantize(&Tensor::default(&vec![1, 32]));
+    assert!(zeros.dequantize().data().iter().all(|&v| v == 0.));
+}
+
+#[test]
+fn test_q4_roundtrip() {
+    let x = Tensor::<f32>::new((0..512).map(|i| ((i * 37 % 101) as f32 - 50.) / 25.).collect(), &vec![2, 256]);
+    let max_err = |y: Tensor<f32>| x.data().iter().zip(y.data()).fold(0f32, |m, (a, b)| m.max((a - b).abs()));
+    let q4 = Q4Tensor::quantize(&x);
+    assert_eq!(q4.blocks().len(), 16);
+    assert!(max_err(q4.dequantize()) < 0.26);
+    let q4k = Q4KTensor::quantize(&x);
+    assert_eq!(q4k.blocks().len(), 2);
+    assert!(max_err(q4k.dequantize()) < 0.15);
+    let row = &x.data()[..256];
+    let exact: f32 = row.iter().map(|v| v * v).sum();
+    let deq = q4k.dequantize();
+    let approx: f32 = row.iter().zip(deq.data()).map(|(a, b)| a * b).sum();
+    assert!((q4k.row(0)[0].dot(row) - approx).abs() < 1e-2 * exact);
+}
+
+#[test]
+fn test_int8_per_row() {
+    let x = Tensor::<f32>::new(vec![1., -2., 0.5, 4., 0.01, 0.02, -0.03, 0.04, 0., 0., 0., 0.], &vec![3, 4]);
+    let q = Int8Tensor::quantize(&x);
+    assert_eq!(q.nbytes(), 12 + 3 * 4);
+    assert_eq!(q.row(0).0[3], 127);
+    // each row keeps its own precision
+    let y = q.dequantize();
+    for (r, (a, b)) in x.data().chunks(4).zip(y.data().chunks(4)).enumerate() {
+        let amax = a.iter().fold(0f32, |m, v| m.max(v.abs()));
+        assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() <= amax / 254. + 1e-7), "row {r}");
+    }
+    assert!(y.data()[8..].iter().all(|&v| v == 0.));
+}
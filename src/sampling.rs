@@ -0,0 +1,546 @@
+// Next-token selection as a chain of logits processors followed by a random draw. Each processor rewrites
+// the logits in place (scaling them, or masking tokens with -inf), so custom steps such as banning tokens
+// slot in next to the built-in ones without touching the generation loop
+use crate::tensor::Tensor;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::HashMap;
+
+pub trait LogitsProcessor: Send {
+    // Rewrite the next-token `logits` given the `tokens` so far (prompt and generated ones)
+    fn process(&mut self, logits: &mut [f32], tokens: &[u32]);
+
+    // Told the token drawn after process(), for processors that adapt as generation goes on
+    fn accept(&mut self, _token: u32) {}
+}
+
+// Adds a fixed bias to the logits of chosen tokens, f32::NEG_INFINITY bans a token outright and a large
+// bias on a few tokens (e.g. "Yes" and "No") all but forces one of them. Usually first in the chain
+pub struct LogitBias(pub HashMap<u32, f32>);
+
+impl LogitsProcessor for LogitBias {
+    fn process(&mut self, logits: &mut [f32], _tokens: &[u32]) {
+        for (&t, &bias) in &self.0 {
+            if let Some(x) = logits.get_mut(t as usize) {
+                *x += bias;
+            }
+        }
+    }
+}
+
+// Masks `token_ids` (typically the eos ids) until the context holds `min_len` tokens, prompt included
+pub struct MinLength {
+    pub min_len: usize,
+    pub token_ids: Vec<u32>,
+}
+
+impl LogitsProcessor for MinLength {
+    fn process(&mut self, logits: &mut [f32], tokens: &[u32]) {
+        if tokens.len() < self.min_len {
+            for &t in &self.token_ids {
+                if let Some(x) = logits.get_mut(t as usize) {
+                    *x = f32::NEG_INFINITY;
+                }
+            }
+        }
+    }
+}
+
+// Divides the logits by t, sharper below 1 and flatter above. t <= 0 keeps only the most likely token
+pub struct Temperature(pub f32);
+
+impl LogitsProcessor for Temperature {
+    fn process(&mut self, logits: &mut [f32], _tokens: &[u32]) {
+        if self.0 > 0. {
+            logits.iter_mut().for_each(|x| *x /= self.0);
+            return;
+        }
+        let best = argmax(logits);
+        logits.iter_mut().enumerate().filter(|&(i, _)| i != best).for_each(|(_, x)| *x = f32::NEG_INFINITY);
+    }
+}
+
+// CTRL-style penalty for tokens in the last `window` context tokens (all of them when None): positive
+// logits are divided by the penalty, negative ones multiplied, so > 1 discourages repeating them
+pub struct RepetitionPenalty {
+    pub penalty: f32,
+    pub window: Option<usize>,
+}
+
+impl RepetitionPenalty {
+    pub fn new(penalty: f32) -> Self {
+        RepetitionPenalty { penalty, window: None }
+    }
+}
+
+impl LogitsProcessor for RepetitionPenalty {
+    fn process(&mut self, logits: &mut [f32], tokens: &[u32]) {
+        for (t, _) in counts(logits.len(), recent(tokens, self.window)) {
+            let x = &mut logits[t];
+            *x = if *x > 0. { *x / self.penalty } else { *x * self.penalty };
+        }
+    }
+}
+
+// OpenAI-style penalties over the last `window` context tokens (all of them when None): each occurrence
+// of a token subtracts `frequency` from its logit, occurring at all subtracts `presence` once
+pub struct FrequencyPenalty {
+    pub frequency: f32,
+    pub presence: f32,
+    pub window: Option<usize>,
+}
+
+impl FrequencyPenalty {
+    pub fn new(frequency: f32, presence: f32) -> Self {
+        FrequencyPenalty { frequency, presence, window: None }
+    }
+}
+
+impl LogitsProcessor for FrequencyPenalty {
+    fn process(&mut self, logits: &mut [f32], tokens: &[u32]) {
+        for (t, count) in counts(logits.len(), recent(tokens, self.window)) {
+            logits[t] -= count as f32 * self.frequency + self.presence;
+        }
+    }
+}
+
+fn recent(tokens: &[u32], window: Option<usize>) -> &[u32] {
+    &tokens[tokens.len() - window.unwrap_or(tokens.len()).min(tokens.len())..]
+}
+
+// (token, occurrences) of every distinct in-vocab token, in order of first occurrence
+fn counts(vocab: usize, tokens: &[u32]) -> Vec<(usize, usize)> {
+    let mut index = HashMap::new();
+    let mut counts = Vec::new();
+    for t in tokens.iter().map(|&t| t as usize).filter(|&t| t < vocab) {
+        let i = *index.entry(t).or_insert_with(|| {
+            counts.push((t, 0));
+            counts.len() - 1
+        });
+        counts[i].1 += 1;
+    }
+    counts
+}
+
+// Keeps the k most likely tokens (ties with the k-th included), found by partial selection
+pub struct TopK(pub usize);
+
+impl LogitsProcessor for TopK {
+    fn process(&mut self, logits: &mut [f32], _tokens: &[u32]) {
+        if self.0 == 0 || self.0 >= logits.len() {
+            return;
+        }
+        let mut sorted = logits.to_vec();
+        let (_, &mut kth, _) = sorted.select_nth_unstable_by(self.0 - 1, |a, b| b.total_cmp(a));
+        logits.iter_mut().filter(|x| **x < kth).for_each(|x| *x = f32::NEG_INFINITY);
+    }
+}
+
+// Nucleus: keeps the smallest set of most likely tokens whose probabilities add up to at least p
+pub struct TopP(pub f32);
+
+impl LogitsProcessor for TopP {
+    fn process(&mut self, logits: &mut [f32], _tokens: &[u32]) {
+        if self.0 >= 1. {
+            return;
+        }
+        let probs = softmax(logits);
+        // only the tokens still in play are sorted, usually few after TopK
+        let mut order: Vec<usize> = (0..logits.len()).filter(|&i| probs[i] > 0.).collect();
+        order.sort_unstable_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+        let mut sum = 0.;
+        let kept = order.iter().take_while(|&&i| {
+            let reached = sum >= self.0;
+            sum += probs[i];
+            !reached
+        });
+        let n = kept.count().max(1);
+        order[n..].iter().for_each(|&i| logits[i] = f32::NEG_INFINITY);
+    }
+}
+
+// Locally typical sampling: keeps the tokens whose surprise -ln(p) is closest to the entropy of the
+// distribution, as many as it takes to cover probability p
+pub struct TypicalP(pub f32);
+
+impl LogitsProcessor for TypicalP {
+    fn process(&mut self, logits: &mut [f32], _tokens: &[u32]) {
+        if self.0 >= 1. {
+            return;
+        }
+        let log_probs = log_softmax(logits);
+        let in_play: Vec<usize> = (0..logits.len()).filter(|&i| log_probs[i].is_finite()).collect();
+        let entropy: f32 = in_play.iter().map(|&i| -log_probs[i].exp() * log_probs[i]).sum();
+        let mut order = in_play;
+        let distance = |i: usize| (-log_probs[i] - entropy).abs();
+        order.sort_unstable_by(|&a, &b| distance(a).total_cmp(&distance(b)));
+        let mut sum = 0.;
+        let kept = order.iter().take_while(|&&i| {
+            let reached = sum >= self.0;
+            sum += log_probs[i].exp();
+            !reached
+        });
+        let n = kept.count().max(1);
+        order[n..].iter().for_each(|&i| logits[i] = f32::NEG_INFINITY);
+    }
+}
+
+// Keeps tokens at least min_p times as likely as the most likely one, so the cutoff scales with the model's
+// confidence (wide when it is unsure) instead of a fixed probability mass like TopP
+pub struct MinP(pub f32);
+
+impl LogitsProcessor for MinP {
+    fn process(&mut self, logits: &mut [f32], _tokens: &[u32]) {
+        if self.0 <= 0. {
+            return;
+        }
+        // p / p_max = exp(x - x_max), no softmax needed
+        let threshold = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max) + self.0.ln();
+        logits.iter_mut().filter(|x| **x < threshold).for_each(|x| *x = f32::NEG_INFINITY);
+    }
+}
+
+// Mirostat 2.0: keeps tokens whose surprise -log2(p) is at most mu, and after every draw moves mu by
+// `eta` times the gap between the drawn token's surprise and the target `tau`, so the text stays near
+// tau bits per token. Goes last in a chain, it learns from the distribution it truncated
+pub struct Mirostat {
+    pub tau: f32,
+    pub eta: f32,
+    pub mu: f32,
+    probs: Vec<f32>, // distribution the last token was drawn from
+}
+
+impl Mirostat {
+    // mu starts at 2 * tau, e.g. Mirostat::new(5., 0.1)
+    pub fn new(tau: f32, eta: f32) -> Self {
+        Mirostat { tau, eta, mu: 2. * tau, probs: Vec::new() }
+    }
+}
+
+impl LogitsProcessor for Mirostat {
+    fn process(&mut self, logits: &mut [f32], _tokens: &[u32]) {
+        let probs = softmax(logits);
+        let best = argmax(&probs);
+        for (i, x) in logits.iter_mut().enumerate() {
+            if i != best && -probs[i].log2() > self.mu {
+                *x = f32::NEG_INFINITY;
+            }
+        }
+        self.probs = softmax(logits);
+    }
+
+    fn accept(&mut self, token: u32) {
+        if let Some(&p) = self.probs.get(token as usize) {
+            self.mu -= self.eta * (-p.log2() - self.tau);
+        }
+    }
+}
+
+// Runs its processors in order, then draws a token from the softmax of what is left. Draws come from
+// its own RNG, seeded from the OS unless with_seed() makes the generation reproducible
+pub struct Sampler {
+    processors: Vec<Box<dyn LogitsProcessor>>,
+    rng: StdRng,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Sampler { processors: Vec::new(), rng: StdRng::from_entropy() }
+    }
+}
+
+impl Sampler {
+    // The usual temperature -> top-k -> top-p chain with random_sample()'s conventions: temperature <= 0,
+    // top_k < 2 or top_p <= 0 mean greedy decoding
+    pub fn new(top_p: f32, top_k: u32, temperature: f32) -> Self {
+        if temperature <= 0. || top_k < 2 || top_p <= 0. {
+            return Self::greedy();
+        }
+        Sampler::default().with(Temperature(temperature)).with(TopK(top_k as usize)).with(TopP(top_p))
+    }
+
+    // Always the most likely token
+    pub fn greedy() -> Self {
+        Sampler::default().with(Temperature(0.))
+    }
+
+    // Draw from an RNG seeded with `seed`: the same model, prompt, chain and seed give the same tokens
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    // Append `processor` to the chain
+    pub fn with(mut self, processor: impl LogitsProcessor + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    // Run the chain over the (vocab,) or (1, vocab) `logits` following `tokens` and draw the next token
+    pub fn sample(&mut self, logits: &Tensor<f32>, tokens: &[u32]) -> u32 {
+        let probs = self.distribution(logits, tokens);
+        self.draw_from(&probs)
+    }
+
+    // The probabilities sample() draws from, without drawing
+    pub fn distribution(&mut self, logits: &Tensor<f32>, tokens: &[u32]) -> Vec<f32> {
+        let mut logits = logits.contiguous().data().to_vec();
+        for processor in self.processors.iter_mut() {
+            processor.process(&mut logits, tokens);
+        }
+        softmax(&logits)
+    }
+
+    // Draw a token with probabilities `probs` and tell the processors it was picked
+    pub fn draw_from(&mut self, probs: &[f32]) -> u32 {
+        let token = draw(probs, &mut self.rng);
+        for processor in self.processors.iter_mut() {
+            processor.accept(token);
+        }
+        token
+    }
+
+    // Uniform in [0, 1) from the sampler's RNG, so accept/reject decisions follow its seed too
+    pub(crate) fn uniform(&mut self) -> f32 {
+        self.rng.gen()
+    }
+}
+
+// Index drawn with the given probabilities
+fn draw(probs: &[f32], rng: &mut impl Rng) -> u32 {
+    let mut r = rng.gen::<f32>();
+    for (i, &p) in probs.iter().enumerate() {
+        if r < p {
+            return i as u32;
+        }
+        r -= p;
+    }
+    // rounding left r just above the total
+    argmax(probs) as u32
+}
+
+fn argmax(x: &[f32]) -> usize {
+    x.iter().enumerate().max_by(|(_, a), (_, b)| a.total_cmp(b)).map_or(0, |(i, _)| i)
+}
+
+// Classifier-free guidance: the log probabilities of `uncond` moved `scale` times as far as `cond`'s
+// differ from them. 1 gives `cond`'s, above 1 exaggerates whatever the conditioning changed and 0 ignores it.
+// A token masked (-inf) in either stays masked, unless that side has no weight (cond at 0, uncond at 1)
+pub fn guide(cond: &[f32], uncond: &[f32], scale: f32) -> Vec<f32> {
+    let (cond, uncond) = (log_softmax(cond), log_softmax(uncond));
+    let guided = cond.iter().zip(&uncond).map(|(&c, &u)| match (c.is_finite(), u.is_finite()) {
+        (true, true) => u + scale * (c - u),
+        (false, _) if scale == 0. => u,
+        (_, false) if scale == 1. => c,
+        _ => f32::NEG_INFINITY,
+    });
+    guided.collect()
+}
+
+// Natural log-probabilities of `logits`, -inf for masked entries
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let lse = max + logits.iter().map(|&x| (x - max).exp()).sum::<f32>().ln();
+    logits.iter().map(|&x| x - lse).collect()
+}
+
+// Indices and values of the k largest entries of `values`, largest first (ties by index), only those k get sorted
+pub fn top_k(values: &[f32], k: usize) -> Vec<(u32, f32)> {
+    let mut top: Vec<(u32, f32)> = values.iter().enumerate().map(|(i, &v)| (i as u32, v)).collect();
+    let by_value_desc = |a: &(u32, f32), b: &(u32, f32)| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0));
+    if k > 0 && k < top.len() {
+        top.select_nth_unstable_by(k - 1, by_value_desc);
+    }
+    top.truncate(k);
+    top.sort_unstable_by(by_value_desc);
+    top
+}
+
+// Probabilities of `logits`, 0 for masked (-inf) entries
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp: Vec<f32> = logits.iter().map(|&x| (x - max).exp()).collect();
+    let sum: f32 = exp.iter().sum();
+    exp.into_iter().map(|e| e / sum).collect()
+}
+
+#[test]
+fn test_logits_processors() {
+    let logits = || vec![2., -1., 0.5, 1.];
+    let run = |p: &mut dyn LogitsProcessor, tokens: &[u32]| {
+        let mut x = logits();
+        p.process(&mut x, tokens);
+        x
+    };
+    let inf = f32::NEG_INFINITY;
+    let mut bias = LogitBias(HashMap::from([(0, inf), (2, 1.5), (7, 1.)]));
+    assert_eq!(run(&mut bias, &[]), vec![inf, -1., 2., 1.]);
+    assert_eq!(run(&mut Temperature(2.), &[]), vec![1., -0.5, 0.25, 0.5]);
+    assert_eq!(run(&mut Temperature(0.), &[]), vec![2., inf, inf, inf]);
+    assert_eq!(run(&mut RepetitionPenalty::new(2.), &[0, 1, 1]), vec![1., -2., 0.5, 1.]);
+    let mut recent = RepetitionPenalty { penalty: 2., window: Some(2) };
+    assert_eq!(run(&mut recent, &[0, 1, 1]), vec![2., -2., 0.5, 1.]);
+    assert_eq!(run(&mut FrequencyPenalty::new(0.5, 0.25), &[0, 1, 1, 9]), vec![1.25, -2.25, 0.5, 1.]);
+    let mut recent = FrequencyPenalty { window: Some(1), ..FrequencyPenalty::new(0.5, 0.) };
+    assert_eq!(run(&mut recent, &[0, 1, 1]), vec![2., -1.5, 0.5, 1.]);
+    assert_eq!(run(&mut TopK(2), &[]), vec![2., inf, inf, 1.]);
+    // probabilities ~0.61, 0.03, 0.14, 0.22
+    assert_eq!(run(&mut TopP(0.7), &[]), vec![2., inf, inf, 1.]);
+    assert_eq!(run(&mut TopP(0.5), &[]), vec![2., inf, inf, inf]);
+    // entropy ~1.01 nats, surprises ~0.50, 3.50, 2.00, 1.50: token 3 is the most typical, then 0
+    assert_eq!(run(&mut TypicalP(0.2), &[]), vec![inf, inf, inf, 1.]);
+    assert_eq!(run(&mut TypicalP(0.5), &[]), vec![2., inf, inf, 1.]);
+    assert_eq!(run(&mut TypicalP(1.), &[]), logits());
+    // relative to p_max ~0.61: 0.22 / 0.61 = e^-1
+    assert_eq!(run(&mut MinP(0.3), &[]), vec![2., inf, inf, 1.]);
+    assert_eq!(run(&mut MinP(0.2), &[]), vec![2., inf, 0.5, 1.]);
+    assert_eq!(run(&mut MinP(0.), &[]), logits());
+}
+
+#[test]
+fn test_min_p() {
+    let inf = f32::NEG_INFINITY;
+    let run = |min_p: f32, mut logits: Vec<f32>| {
+        MinP(min_p).process(&mut logits, &[]);
+        logits
+    };
+    // masked tokens stay masked and leave the cutoff alone
+    assert_eq!(run(0.3, vec![2., inf, 0.5, 1.]), vec![2., inf, inf, 1.]);
+    assert_eq!(run(0.3, vec![inf, inf]), vec![inf, inf]);
+    // 1 keeps the most likely token and whatever ties with it
+    assert_eq!(run(1., vec![1., 3., 3., 2.]), vec![inf, 3., 3., inf]);
+    // the cutoff follows the distribution: ratios e^-2, e^-3, e^-4 drop out, e^-0.5, e^-0.75, e^-1 stay
+    assert_eq!(run(0.2, vec![4., 2., 1., 0.]), vec![4., inf, inf, inf]);
+    assert_eq!(run(0.2, vec![1., 0.5, 0.25, 0.]), vec![1., 0.5, 0.25, 0.]);
+
+    // in a GenerationConfig it sees the logits after temperature, so hotter sampling keeps more tokens
+    use crate::generation::GenerationConfig;
+    let logits = Tensor::new(vec![4., 2., 1., 0.], &vec![4]);
+    let kept = |temperature: f32| {
+        let config = GenerationConfig::builder().temperature(temperature).min_p(0.2).build().unwrap();
+        let probs = config.sampler(0, &[]).distribution(&logits, &[]);
+        probs.iter().filter(|&&p| p > 0.).count()
+    };
+    assert_eq!((kept(1.), kept(2.)), (1, 3));
+}
+
+#[test]
+fn test_penalty_window() {
+    let run = |p: &mut dyn LogitsProcessor, tokens: &[u32]| {
+        let mut logits = vec![2., -1., 0.5, 1.];
+        p.process(&mut logits, tokens);
+        logits
+    };
+    let logits = vec![2., -1., 0.5, 1.];
+    // 7 is past the vocab and ignored
+    let tokens = [0, 1, 1, 3, 7];
+    // an empty window penalizes nothing, one longer than the context the same as no window
+    assert_eq!(run(&mut RepetitionPenalty { penalty: 2., window: Some(0) }, &tokens), logits);
+    let all = run(&mut RepetitionPenalty::new(2.), &tokens);
+    assert_eq!(run(&mut RepetitionPenalty { penalty: 2., window: Some(100) }, &tokens), all);
+    assert_eq!(all, vec![1., -2., 0.5, 0.5]);
+    assert_eq!(run(&mut FrequencyPenalty::new(1., 1.), &[]), logits);
+    // only occurrences inside the window count: one 1 and the 3 among the last three tokens
+    let mut recent = FrequencyPenalty { window: Some(3), ..FrequencyPenalty::new(0.5, 0.25) };
+    assert_eq!(run(&mut recent, &tokens), vec![2., -1.75, 0.5, 0.25]);
+    // presence is paid once however often a token occurs
+    assert_eq!(run(&mut FrequencyPenalty::new(0., 1.), &[1, 1, 1]), vec![2., -2., 0.5, 1.]);
+
+    // GenerationConfig's penalty_window applies to both penalties: with a window of one only the
+    // last context token is penalized and greedy decoding goes back to the earlier one
+    use crate::generation::GenerationConfig;
+    let logits = Tensor::new(vec![2., -1., 0.4, 1.9], &vec![4]);
+    let greedy = |window: Option<usize>| {
+        let builder = GenerationConfig::builder().temperature(0.).repetition_penalty(2.).penalties(0.5, 0.);
+        let config = match window {
+            Some(window) => builder.penalty_window(window),
+            None => builder,
+        };
+        config.build().unwrap().sampler(2, &[]).sample(&logits, &[3, 0])
+    };
+    assert_eq!((greedy(Some(1)), greedy(None)), (3, 0));
+}
+
+#[test]
+fn test_typical_p() {
+    let inf = f32::NEG_INFINITY;
+    let run = |p: f32, mut logits: Vec<f32>| {
+        TypicalP(p).process(&mut logits, &[]);
+        logits
+    };
+    // masked tokens are out of play, the entropy and the kept set are those of the rest
+    assert_eq!(run(0.5, vec![2., inf, -1., 0.5, 1.]), vec![2., inf, inf, inf, 1.]);
+    assert_eq!(run(0.5, vec![inf, 3., inf]), vec![inf, 3., inf]);
+    // a tiny p still keeps the single most typical token, here not the most likely one
+    assert_eq!(run(1e-6, vec![2., -1., 0.5, 1.]), vec![inf, inf, inf, 1.]);
+    // in a uniform distribution every token is exactly typical and p decides how many stay
+    let kept = |p| run(p, vec![0.; 4]).iter().filter(|x| x.is_finite()).count();
+    assert_eq!((kept(0.25), kept(0.5), kept(0.9)), (1, 2, 4));
+}
+
+#[test]
+fn test_sampler() {
+    let logits = Tensor::new(vec![2., -1., 0.5, 1.], &vec![1, 4]);
+    assert!((0..50).all(|_| Sampler::greedy().sample(&logits, &[]) == 0));
+    let mut sampler = Sampler::new(0.9, 2, 1.);
+    let samples: Vec<u32> = (0..200).map(|_| sampler.sample(&logits, &[])).collect();
+    assert!(samples.iter().all(|&t| t == 0 || t == 3) && samples.contains(&3));
+
+    // a custom processor banning token 0
+    struct Ban(u32);
+    impl LogitsProcessor for Ban {
+        fn process(&mut self, logits: &mut [f32], _tokens: &[u32]) {
+            logits[self.0 as usize] = f32::NEG_INFINITY;
+        }
+    }
+    let mut sampler = Sampler::default().with(Ban(0)).with(Temperature(0.));
+    assert_eq!(sampler.sample(&logits, &[]), 3);
+
+    // the same seed draws the same tokens
+    let draws = |seed| {
+        let mut sampler = Sampler::new(1., 4, 1.).with_seed(seed);
+        (0..32).map(|_| sampler.sample(&logits, &[])).collect::<Vec<_>>()
+    };
+    assert_eq!(draws(7), draws(7));
+    assert_ne!(draws(7), draws(8));
+}
+
+#[test]
+fn test_mirostat() {
+    // probabilities 1/2, 1/4, 1/8, 1/8: surprises of 1, 2, 3 and 3 bits
+    let logits = || vec![0., -(2f32.ln()), -(4f32.ln()), -(4f32.ln())];
+    let mut mirostat = Mirostat::new(2., 1.5);
+    let mut x = logits();
+    mirostat.process(&mut x, &[]);
+    assert!(x.iter().all(|x| x.is_finite()));
+    // drawing a 3 bit token when aiming for 2 lowers mu to 4 - 1.5 * (3 - 2), the 3 bit tokens drop out
+    mirostat.accept(2);
+    assert!((mirostat.mu - 2.5).abs() < 1e-5);
+    let mut x = logits();
+    mirostat.process(&mut x, &[]);
+    assert_eq!(x.iter().map(|x| x.is_finite()).collect::<Vec<_>>(), vec![true, true, false, false]);
+    // token 0 now has probability 2/3, well below the target surprise, so mu climbs back
+    mirostat.accept(0);
+    assert!((mirostat.mu - (2.5 - 1.5 * (-(2f32 / 3.).log2() - 2.))).abs() < 1e-5);
+
+    let mut sampler = Sampler::default().with(Mirostat::new(1.5, 0.2));
+    let tensor = Tensor::new(logits(), &vec![4]);
+    (0..100).for_each(|_| assert!(sampler.sample(&tensor, &[]) < 4));
+}
+
+#[test]
+fn test_guide() {
+    let (cond, uncond) = ([1., 2., f32::NEG_INFINITY, 0.], [2., 1., f32::NEG_INFINITY, 0.]);
+    assert_eq!(guide(&cond, &uncond, 1.), log_softmax(&cond));
+    assert_eq!(guide(&cond, &uncond, 0.), log_softmax(&uncond));
+    // past 1 the token the conditioning favoured pulls further ahead, masked tokens stay masked
+    let guided = guide(&cond, &uncond, 2.);
+    assert!(guided[1] - guided[0] > 1. && guided[2] == f32::NEG_INFINITY);
+    // masked on one side only: no NaN, the side with no weight doesn't mask
+    let (cond, uncond) = ([1., f32::NEG_INFINITY, 0.], [2., 1., f32::NEG_INFINITY]);
+    assert_eq!(guide(&cond, &uncond, 0.), log_softmax(&uncond));
+    assert_eq!(guide(&cond, &uncond, 1.), log_softmax(&cond));
+    for scale in [0.5, 1.5, 3.] {
+        let guided = guide(&cond, &uncond, scale);
+        assert!(guided[0].is_finite() && guided[1] == f32::NEG_INFINITY && guided[2] == f32::NEG_INFINITY);
+    }
+}
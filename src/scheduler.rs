@@ -0,0 +1,246 @@
+// Continuous (iteration-level) batching: requests wait in a queue and join the running batch as soon as
+// there is room for them, every step() advances all running sequences by one token in a single batched
+// forward pass, and a finished sequence leaves right away, its place and KV blocks going to the next
+// request. Sequences live in one CacheManager pool, so requests sharing a prompt prefix share its blocks
+use crate::backend::{Cpu, Ops, Weight};
+use crate::generation::{Generation, GenerationConfig, InvalidGenerationConfig, StopReason, TokenLogprob};
+use crate::kvcache::{CacheManager, SeqId};
+use crate::model::Llama;
+use crate::sampling::Sampler;
+use crate::tensor::Tensor;
+use std::collections::VecDeque;
+
+pub type RequestId = u64;
+
+// A submitted request and what it generated so far, `tokens` is the prompt followed by the new tokens
+struct Request {
+    id: RequestId,
+    config: GenerationConfig,
+    sampler: Sampler,
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    logprobs: Option<Vec<TokenLogprob>>,
+}
+
+impl Request {
+    // Sample the next token from `logits`, returning why the request is done if it is
+    fn advance<O: Ops, W: Weight>(&mut self, model: &Llama<f32, O, W>, logits: &Tensor<f32>) -> Option<StopReason> {
+        if self.tokens.len() - self.prompt_len >= self.config.max_new_tokens {
+            return Some(StopReason::MaxTokens);
+        }
+        let next = self.sampler.sample(logits, &self.tokens);
+        self.tokens.push(next);
+        if let (Some(logprobs), Some(top_n)) = (&mut self.logprobs, self.config.logprobs) {
+            logprobs.push(TokenLogprob::new(next, logits.data(), top_n));
+        }
+        if let Some(reason) = model.stop_reason(&self.tokens[self.prompt_len..], &self.config.stop) {
+            return Some(reason);
+        }
+        if self.tokens.len() - self.prompt_len >= self.config.max_new_tokens {
+            return Some(StopReason::MaxTokens);
+        }
+        // the next pass would feed a position past the model's context
+        if self.tokens.len() > model.max_seq_len() {
+            return Some(StopReason::ContextFull);
+        }
+        None
+    }
+
+    fn finish(mut self, reason: StopReason) -> (RequestId, Generation) {
+        let tokens = self.tokens.split_off(self.prompt_len);
+        (self.id, Generation::finish(tokens, self.logprobs, &self.config.stop, reason))
+    }
+}
+
+pub struct Scheduler<'a, O = Cpu, W = Tensor<f32>> {
+    model: &'a Llama<f32, O, W>,
+    manager: CacheManager,
+    max_batch: usize,
+    waiting: VecDeque<Request>,
+    running: Vec<(SeqId, Request)>, // in admission order
+    next_id: RequestId,
+}
+
+impl<'a, O: Ops, W: Weight> Scheduler<'a, O, W> {
+    // Run up to `max_batch` sequences at once in `manager`'s pool, e.g. model.new_cache_manager(16, 1024)
+    pub fn new(model: &'a Llama<f32, O, W>, manager: CacheManager, max_batch: usize) -> Self {
+        assert!(max_batch > 0, "max_batch must be at least 1");
+        Scheduler { model, manager, max_batch, waiting: VecDeque::new(), running: Vec::new(), next_id: 0 }
+    }
+
+    // Queue `prompt` (just bos if empty) to be continued as `config` describes. Guidance would need a
+    // second sequence per request and is refused. A prompt longer than the model's context is accepted
+    // and finishes with ContextFull, and no tokens, on the next step()
+    pub fn submit(&mut self, prompt: &[u32], config: GenerationConfig) -> Result<RequestId, InvalidGenerationConfig> {
+        if config.guidance_scale != 1. {
+            let reason = "must be 1, the scheduler does not support guidance".to_string();
+            return Err(InvalidGenerationConfig { field: "guidance_scale", reason });
+        }
+        let tokens = if prompt.is_empty() { vec![self.model.bos_token_id()] } else { prompt.to_vec() };
+        let sampler = config.sampler(tokens.len(), self.model.eos_token_ids());
+        let id = self.next_id;
+        self.next_id += 1;
+        let logprobs = config.logprobs.map(|_| Vec::new());
+        self.waiting.push_back(Request { id, config, sampler, prompt_len: tokens.len(), tokens, logprobs });
+        Ok(id)
+    }
+
+    pub fn num_waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn num_running(&self) -> usize {
+        self.running.len()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.waiting.is_empty() && self.running.is_empty()
+    }
+
+    pub fn manager(&self) -> &CacheManager {
+        &self.manager
+    }
+
+    // Admit waiting requests, run one batched forward pass (prompts of new sequences, the last token of
+    // the others) and sample a token for every running sequence. Returns the requests that finished.
+    // When the pool runs out of blocks the most recently admitted sequences go back to the front of the
+    // queue, keeping what they generated, and are recomputed once there is room again
+    pub fn step(&mut self) -> Vec<(RequestId, Generation)> {
+        let mut finished = self.admit();
+        if self.running.is_empty() {
+            return finished;
+        }
+        let logits = loop {
+            let manager = &mut self.manager;
+            let cached: Vec<usize> = self.running.iter().map(|(seq, _)| manager.tokens(*seq).len()).collect();
+            let inputs: Vec<(SeqId, &[u32])> = self
+                .running
+                .iter()
+                .zip(&cached)
+                .map(|((seq, request), &cached)| (*seq, &request.tokens[cached..]))
+                .collect();
+            match manager.forward_batch(self.model, &inputs) {
+                Ok(logits) => break logits,
+                Err(_) if self.running.len() > 1 => {
+                    let (seq, request) = self.running.pop().unwrap();
+                    self.manager.remove(seq);
+                    self.waiting.push_front(request);
+                }
+                // alone and still too long for the whole pool
+                Err(_) => {
+                    let (seq, request) = self.running.pop().unwrap();
+                    self.manager.remove(seq);
+                    finished.push(request.finish(StopReason::ContextFull));
+                    return finished;
+                }
+            }
+        };
+        let vocab = logits.shape()[1];
+        for (i, (seq, mut request)) in std::mem::take(&mut self.running).into_iter().enumerate() {
+            match request.advance(self.model, &logits.slice(i * vocab, &vec![vocab])) {
+                Some(reason) => {
+                    self.manager.remove(seq);
+                    finished.push(request.finish(reason));
+                }
+                None => self.running.push((seq, request)),
+            }
+        }
+        finished
+    }
+
+    // step() until every submitted request finished, returning them in the order they did
+    pub fn run(&mut self) -> Vec<(RequestId, Generation)> {
+        let mut finished = Vec::new();
+        while !self.is_idle() {
+            finished.extend(self.step());
+        }
+        finished
+    }
+
+    // Move waiting requests into the batch while it has room and the pool has blocks for their tokens.
+    // A request always gets in when nothing runs, so one too long for the pool ends with ContextFull.
+    // Returns the requests whose prompt alone is past the model's context, finished without running
+    fn admit(&mut self) -> Vec<(RequestId, Generation)> {
+        let mut finished = Vec::new();
+        let block_size = self.manager.pool().block_size();
+        // every running sequence may need a new block for its next token
+        let mut free = self.manager.pool().num_free_blocks().saturating_sub(self.running.len());
+        while self.running.len() < self.max_batch {
+            let Some(request) = self.waiting.front() else {
+                break;
+            };
+            if request.tokens.len() > self.model.max_seq_len() {
+                let request = self.waiting.pop_front().unwrap();
+                finished.push(request.finish(StopReason::ContextFull));
+                continue;
+            }
+            let needed = request.tokens.len().div_ceil(block_size);
+            if needed > free && !self.running.is_empty() {
+                break;
+            }
+            free = free.saturating_sub(needed);
+            let request = self.waiting.pop_front().unwrap();
+            let (seq, _) = self.manager.create_with_prefix(&request.tokens);
+            self.running.push((seq, request));
+        }
+        finished
+    }
+}
+
+#[test]
+fn test_scheduler() {
+    use crate::config::LlamaConfigJson;
+    let config = LlamaConfigJson::builder().heads(4, 2).build().unwrap();
+    let llama = Llama::random(&config, 27);
+    let prompts: [&[u32]; 5] = [&[3, 1, 4, 1, 5, 9, 2, 6], &[5], &[3, 1, 4, 1, 5, 9, 2, 6, 5, 3], &[], &[7, 7]];
+    let greedy = |i: usize| GenerationConfig { logprobs: Some(1), ..GenerationConfig::greedy(3 + 2 * i) };
+    let expected: Vec<Generation> = prompts.iter().enumerate().map(|(i, p)| llama.generate(p, &greedy(i))).collect();
+    // a roomy pool, and one so small that sequences get preempted and recomputed
+    for max_blocks in [64, 6] {
+        let mut scheduler = Scheduler::new(&llama, llama.new_cache_manager(4, max_blocks), 3);
+        let ids: Vec<RequestId> =
+            prompts.iter().enumerate().map(|(i, p)| scheduler.submit(p, greedy(i)).unwrap()).collect();
+        assert_eq!((scheduler.num_waiting(), scheduler.num_running()), (5, 0));
+        let mut finished = scheduler.step();
+        assert!(scheduler.num_running() <= 3);
+        assert_eq!(scheduler.num_running() + scheduler.num_waiting() + finished.len(), 5);
+        finished.extend(scheduler.run());
+        assert!(scheduler.is_idle());
+        assert_eq!(finished.len(), 5);
+        for (id, generation) in finished {
+            let i = ids.iter().position(|&x| x == id).unwrap();
+            assert_eq!(generation.tokens, expected[i].tokens, "request {i} with {max_blocks} blocks");
+            assert_eq!(generation.stop, expected[i].stop);
+            let logprobs = generation.logprobs.unwrap();
+            for (a, b) in logprobs.iter().zip(expected[i].logprobs.as_ref().unwrap()) {
+                assert!((a.logprob - b.logprob).abs() < 1e-4);
+            }
+        }
+        // every block went back to the pool
+        assert_eq!(scheduler.manager().pool().num_free_blocks(), max_blocks);
+    }
+}
+
+#[test]
+fn test_scheduler_rejects() {
+    use crate::config::LlamaConfigJson;
+    let config = LlamaConfigJson::builder().heads(4, 2).max_position_embeddings(8).build().unwrap();
+    let llama = Llama::random(&config, 28);
+    let mut scheduler = Scheduler::new(&llama, llama.new_cache_manager(4, 16), 2);
+    let guided = GenerationConfig::builder().guidance(1.5, &[]).build().unwrap();
+    assert_eq!(scheduler.submit(&[1, 2], guided).unwrap_err().field, "guidance_scale");
+    assert!(scheduler.is_idle());
+
+    // a prompt past the context ends without running while the others are served normally
+    let long = scheduler.submit(&[1; 9], GenerationConfig::greedy(4)).unwrap();
+    let full = scheduler.submit(&[1; 8], GenerationConfig::greedy(4)).unwrap();
+    let short = scheduler.submit(&[1, 2], GenerationConfig::greedy(4)).unwrap();
+    let mut finished = scheduler.step();
+    assert_eq!((finished[0].0, finished[0].1.tokens.len()), (long, 0));
+    assert_eq!(finished[0].1.stop, StopReason::ContextFull);
+    finished.extend(scheduler.run());
+    let stop = |id| finished.iter().find(|(x, _)| *x == id).map(|(_, g)| (g.tokens.len(), g.stop.clone())).unwrap();
+    assert_eq!(stop(full), (1, StopReason::ContextFull));
+    assert_eq!(stop(short), (4, StopReason::MaxTokens));
+    assert_eq!(scheduler.manager().pool().num_free_blocks(), 16);
+}
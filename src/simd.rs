@@ -0,0 +1,267 @@
+// Vectorized f32 kernels. With the `simd` feature on x86_64 the AVX2/FMA paths are picked at
+// runtime and aarch64 always uses NEON, everything else falls back to scalar loops written so the
+// compiler can auto-vectorize
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+#[inline]
+fn has_avx2() -> bool {
+    is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma")
+}
+
+// sum(x * y)
+pub fn dot(x: &[f32], y: &[f32]) -> f32 {
+    assert!(x.len() == y.len());
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if has_avx2() {
+        return unsafe { avx2::dot(x, y) };
+    }
+    #[cfg(all(feature = "simd", target_arch = "aarch64"))]
+    return unsafe { neon::dot(x, y) };
+    #[allow(unreachable_code)]
+    scalar::dot(x, y)
+}
+
+// [dot(x, y0), .., dot(x, y3)], reading x once for four rows (the matmul_transb inner kernel)
+pub fn dot4(x: &[f32], ys: [&[f32]; 4]) -> [f32; 4] {
+    assert!(ys.iter().all(|y| y.len() == x.len()));
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if has_avx2() {
+        return unsafe { avx2::dot4(x, ys) };
+    }
+    #[cfg(all(feature = "simd", target_arch = "aarch64"))]
+    return unsafe { neon::dot4(x, ys) };
+    #[allow(unreachable_code)]
+    ys.map(|y| scalar::dot(x, y))
+}
+
+// sum(x * y) of int8 codes, accumulated exactly in i32
+pub fn dot_i8(x: &[i8], y: &[i8]) -> i32 {
+    assert!(x.len() == y.len());
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if has_avx2() {
+        return unsafe { avx2::dot_i8(x, y) };
+    }
+    #[cfg(all(feature = "simd", target_arch = "aarch64"))]
+    return unsafe { neon::dot_i8(x, y) };
+    #[allow(unreachable_code)]
+    scalar::dot_i8(x, y)
+}
+
+// y += a * x
+pub fn axpy(a: f32, x: &[f32], y: &mut [f32]) {
+    assert!(x.len() == y.len());
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if has_avx2() {
+        return unsafe { avx2::axpy(a, x, y) };
+    }
+    scalar::axpy(a, x, y)
+}
+
+// x *= a
+pub fn scale(a: f32, x: &mut [f32]) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if has_avx2() {
+        return unsafe { avx2::scale(a, x) };
+    }
+    scalar::scale(a, x)
+}
+
+mod scalar {
+    const LANES: usize = 8;
+
+    pub fn dot(x: &[f32], y: &[f32]) -> f32 {
+        let mut acc = [0f32; LANES];
+        let (xc, yc) = (x.chunks_exact(LANES), y.chunks_exact(LANES));
+        let tail: f32 = xc.remainder().iter().zip(yc.remainder()).map(|(a, b)| a * b).sum();
+        for (xs, ys) in xc.zip(yc) {
+            for i in 0..LANES {
+                acc[i] += xs[i] * ys[i];
+            }
+        }
+        acc.iter().sum::<f32>() + tail
+    }
+
+    pub fn dot_i8(x: &[i8], y: &[i8]) -> i32 {
+        x.iter().zip(y).map(|(&a, &b)| a as i32 * b as i32).sum()
+    }
+
+    pub fn axpy(a: f32, x: &[f32], y: &mut [f32]) {
+        for (y, x) in y.iter_mut().zip(x) {
+            *y += a * x;
+        }
+    }
+
+    pub fn scale(a: f32, x: &mut [f32]) {
+        for x in x {
+            *x *= a;
+        }
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod avx2 {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot(x: &[f32], y: &[f32]) -> f32 {
+        let n = x.len() / 16 * 16;
+        let (mut acc0, mut acc1) = (_mm256_setzero_ps(), _mm256_setzero_ps());
+        for i in (0..n).step_by(16) {
+            let (xp, yp) = (x.as_ptr().add(i), y.as_ptr().add(i));
+            acc0 = _mm256_fmadd_ps(_mm256_loadu_ps(xp), _mm256_loadu_ps(yp), acc0);
+            acc1 = _mm256_fmadd_ps(_mm256_loadu_ps(xp.add(8)), _mm256_loadu_ps(yp.add(8)), acc1);
+        }
+        hsum(_mm256_add_ps(acc0, acc1)) + super::scalar::dot(&x[n..], &y[n..])
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot4(x: &[f32], ys: [&[f32]; 4]) -> [f32; 4] {
+        let n = x.len() / 8 * 8;
+        let mut acc = [_mm256_setzero_ps(); 4];
+        for i in (0..n).step_by(8) {
+            let xv = _mm256_loadu_ps(x.as_ptr().add(i));
+            for (acc, y) in acc.iter_mut().zip(ys) {
+                *acc = _mm256_fmadd_ps(xv, _mm256_loadu_ps(y.as_ptr().add(i)), *acc);
+            }
+        }
+        let mut out = [0f32; 4];
+        for (j, acc) in acc.into_iter().enumerate() {
+            out[j] = hsum(acc) + super::scalar::dot(&x[n..], &ys[j][n..]);
+        }
+        out
+    }
+
+    // i8 lanes widened to i16, madd sums adjacent products into i32
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot_i8(x: &[i8], y: &[i8]) -> i32 {
+        let n = x.len() / 16 * 16;
+        let mut acc = _mm256_setzero_si256();
+        for i in (0..n).step_by(16) {
+            let xv = _mm256_cvtepi8_epi16(_mm_loadu_si128(x.as_ptr().add(i) as *const __m128i));
+            let yv = _mm256_cvtepi8_epi16(_mm_loadu_si128(y.as_ptr().add(i) as *const __m128i));
+            acc = _mm256_add_epi32(acc, _mm256_madd_epi16(xv, yv));
+        }
+        let sum4 = _mm_add_epi32(_mm256_castsi256_si128(acc), _mm256_extracti128_si256(acc, 1));
+        let sum2 = _mm_hadd_epi32(sum4, sum4);
+        _mm_cvtsi128_si32(_mm_hadd_epi32(sum2, sum2)) + super::scalar::dot_i8(&x[n..], &y[n..])
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn hsum(acc: __m256) -> f32 {
+        let sum4 = _mm_add_ps(_mm256_castps256_ps128(acc), _mm256_extractf128_ps(acc, 1));
+        let sum2 = _mm_add_ps(sum4, _mm_movehl_ps(sum4, sum4));
+        _mm_cvtss_f32(_mm_add_ss(sum2, _mm_shuffle_ps(sum2, sum2, 1)))
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn axpy(a: f32, x: &[f32], y: &mut [f32]) {
+        let n = x.len() / 8 * 8;
+        let av = _mm256_set1_ps(a);
+        for i in (0..n).step_by(8) {
+            let yp = y.as_mut_ptr().add(i);
+            _mm256_storeu_ps(yp, _mm256_fmadd_ps(av, _mm256_loadu_ps(x.as_ptr().add(i)), _mm256_loadu_ps(yp)));
+        }
+        super::scalar::axpy(a, &x[n..], &mut y[n..]);
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn scale(a: f32, x: &mut [f32]) {
+        let n = x.len() / 8 * 8;
+        let av = _mm256_set1_ps(a);
+        for i in (0..n).step_by(8) {
+            let xp = x.as_mut_ptr().add(i);
+            _mm256_storeu_ps(xp, _mm256_mul_ps(av, _mm256_loadu_ps(xp)));
+        }
+        super::scalar::scale(a, &mut x[n..]);
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "aarch64"))]
+mod neon {
+    use std::arch::aarch64::*;
+
+    pub unsafe fn dot(x: &[f32], y: &[f32]) -> f32 {
+        let n = x.len() / 8 * 8;
+        let (mut acc0, mut acc1) = (vdupq_n_f32(0.), vdupq_n_f32(0.));
+        for i in (0..n).step_by(8) {
+            let (xp, yp) = (x.as_ptr().add(i), y.as_ptr().add(i));
+            acc0 = vfmaq_f32(acc0, vld1q_f32(xp), vld1q_f32(yp));
+            acc1 = vfmaq_f32(acc1, vld1q_f32(xp.add(4)), vld1q_f32(yp.add(4)));
+        }
+        vaddvq_f32(vaddq_f32(acc0, acc1)) + super::scalar::dot(&x[n..], &y[n..])
+    }
+
+    pub unsafe fn dot4(x: &[f32], ys: [&[f32]; 4]) -> [f32; 4] {
+        let n = x.len() / 4 * 4;
+        let mut acc = [vdupq_n_f32(0.); 4];
+        for i in (0..n).step_by(4) {
+            let xv = vld1q_f32(x.as_ptr().add(i));
+            for (acc, y) in acc.iter_mut().zip(ys) {
+                *acc = vfmaq_f32(*acc, xv, vld1q_f32(y.as_ptr().add(i)));
+            }
+        }
+        let mut out = [0f32; 4];
+        for (j, acc) in acc.into_iter().enumerate() {
+            out[j] = vaddvq_f32(acc) + super::scalar::dot(&x[n..], &ys[j][n..]);
+        }
+        out
+    }
+
+    pub unsafe fn dot_i8(x: &[i8], y: &[i8]) -> i32 {
+        let n = x.len() / 16 * 16;
+        let mut acc = vdupq_n_s32(0);
+        for i in (0..n).step_by(16) {
+            let (xv, yv) = (vld1q_s8(x.as_ptr().add(i)), vld1q_s8(y.as_ptr().add(i)));
+            acc = vpadalq_s16(acc, vmull_s8(vget_low_s8(xv), vget_low_s8(yv)));
+            acc = vpadalq_s16(acc, vmull_high_s8(xv, yv));
+        }
+        vaddvq_s32(acc) + super::scalar::dot_i8(&x[n..], &y[n..])
+    }
+}
+
+#[test]
+fn test_simd_kernels() {
+    let x: Vec<f32> = (0..37).map(|i| i as f32 * 0.5 - 3.).collect();
+    let y: Vec<f32> = (0..37).map(|i| (i % 5) as f32).collect();
+    let expected: f32 = x.iter().zip(&y).map(|(a, b)| a * b).sum();
+    assert!((dot(&x, &y) - expected).abs() < 1e-3);
+    assert!((scalar::dot(&x, &y) - expected).abs() < 1e-3);
+    let mut z = y.clone();
+    axpy(2., &x, &mut z);
+    assert!(z.iter().zip(x.iter().zip(&y)).all(|(z, (a, b))| (z - (2. * a + b)).abs() < 1e-5));
+    scale(0.5, &mut z);
+    assert!((z[36] - (x[36] + y[36] / 2.)).abs() < 1e-5);
+
+    let rows: Vec<Vec<f32>> = (0..4).map(|r| x.iter().map(|v| v * r as f32 - 1.).collect()).collect();
+    let dots = dot4(&y, [&rows[0], &rows[1], &rows[2], &rows[3]]);
+    for (r, row) in rows.iter().enumerate() {
+        assert!((dots[r] - scalar::dot(&y, row)).abs() < 1e-3);
+    }
+
+    let xq: Vec<i8> = (0..37).map(|i| if i % 3 == 0 { -127 } else { (i * 7 % 255 - 127) as i8 }).collect();
+    let yq: Vec<i8> = (0..37).map(|i| if i % 3 == 0 { -127 } else { (i * 11 % 255 - 127) as i8 }).collect();
+    assert_eq!(dot_i8(&xq, &yq), scalar::dot_i8(&xq, &yq));
+}
+
+#[test]
+fn test_simd_every_length() {
+    // lengths around the 4-, 8- and 16-lane loops, so every vector path and its scalar tail runs
+    for len in 0..=50 {
+        let x: Vec<f32> = (0..len).map(|i| (i as f32 * 0.37).sin()).collect();
+        let ys: Vec<Vec<f32>> = (0..4).map(|r| (0..len).map(|i| ((i + r) as f32 * 0.11).cos()).collect()).collect();
+        let close = |a: f32, b: f32| (a - b).abs() <= 1e-4 * (1. + b.abs());
+        assert!(close(dot(&x, &ys[0]), scalar::dot(&x, &ys[0])), "dot {len}");
+        let dots = dot4(&x, [&ys[0], &ys[1], &ys[2], &ys[3]]);
+        assert!(dots.iter().zip(&ys).all(|(&d, y)| close(d, scalar::dot(&x, y))), "dot4 {len}");
+        let (mut z, mut expected) = (ys[1].clone(), ys[1].clone());
+        axpy(-1.5, &x, &mut z);
+        scalar::axpy(-1.5, &x, &mut expected);
+        assert!(z.iter().zip(&expected).all(|(&a, &b)| close(a, b)), "axpy {len}");
+        scale(3., &mut z);
+        scalar::scale(3., &mut expected);
+        assert!(z.iter().zip(&expected).all(|(&a, &b)| close(a, b)), "scale {len}");
+        let xq: Vec<i8> = (0..len).map(|i| (i * 37 % 255) as u8 as i8).collect();
+        let yq: Vec<i8> = (0..len).map(|i| (i * 101 % 255) as u8 as i8).collect();
+        assert_eq!(dot_i8(&xq, &yq), scalar::dot_i8(&xq, &yq), "dot_i8 {len}");
+    }
+}
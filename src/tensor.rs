@@ -1,15 +1,73 @@
-use std::{slice, sync::Arc, vec};
+use std::{fmt, marker::PhantomData, mem, ops::{Deref, Range}, slice, sync::Arc, vec};
+use memmap2::Mmap;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use std::ops::{Mul, Add};
-use num::Zero;
+use half::{bf16, f16, slice::HalfFloatSliceExt};
  
 pub struct Tensor<T> {
-    data: Arc<Box<[T]>>,
+    data: Arc<Storage<T>>,
     shape: Vec<usize>,
+    strides: Vec<usize>,
     offset: usize,
     length: usize,
 }
  
+// Byte alignment of buffers from Tensor::new_aligned, Tensor::default and TensorPool
+pub const ALIGNMENT: usize = 64;
+
+// Backing memory of a tensor, either an owned buffer or a read-only region of a mapped file.
+// Owned buffers may carry leading padding so that element `start` is aligned
+enum Storage<T> {
+    Owned { buf: Box<[T]>, start: usize, len: usize },
+    Mapped { mmap: Arc<Mmap>, start: usize, len: usize, _elem: PhantomData<T> },
+}
+
+impl<T: Copy + Default> Storage<T> {
+    fn owned(data: Vec<T>) -> Self {
+        let len = data.len();
+        Storage::Owned { buf: data.into_boxed_slice(), start: 0, len }
+    }
+
+    // Zero-initialized buffer of `len` elements starting on an ALIGNMENT-byte boundary
+    // (best effort for element types whose size doesn't divide ALIGNMENT)
+    fn aligned(len: usize) -> Self {
+        let size = mem::size_of::<T>().max(1);
+        let pad = ALIGNMENT / size;
+        let buf = vec![T::default(); len + pad].into_boxed_slice();
+        let addr = buf.as_ptr() as usize;
+        let start = (0..=pad).find(|k| (addr + k * size).is_multiple_of(ALIGNMENT)).unwrap_or(0);
+        Storage::Owned { buf, start, len }
+    }
+}
+
+impl<T> Storage<T> {
+    fn as_mut_slice(&mut self) -> Option<&mut [T]> {
+        match self {
+            Storage::Owned { buf, start, len } => Some(&mut buf[*start..][..*len]),
+            Storage::Mapped { .. } => None,
+        }
+    }
+}
+
+impl<T> Deref for Storage<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Storage::Owned { buf, start, len } => &buf[*start..][..*len],
+            // alignment and bounds were checked when the mapped storage was created
+            Storage::Mapped { mmap, start, len, .. } => unsafe {
+                slice::from_raw_parts(mmap.as_ptr().add(*start) as *const T, *len)
+            },
+        }
+    }
+}
+
 // 多维张量转置子函数
+// shapes are passed around as &Vec throughout the tensor API
+#[allow(clippy::ptr_arg)]
 fn compute_strides(shape: &Vec<usize>) -> Vec<usize> {
     let mut strides = vec![0; shape.len()];
     let mut stride = 1;
@@ -20,7 +78,7 @@ fn compute_strides(shape: &Vec<usize>) -> Vec<usize> {
     strides
 }
  
-fn compute_index(index: usize, strides: &Vec<usize>) -> Vec<usize> {
+fn compute_index(index: usize, strides: &[usize]) -> Vec<usize> {
     let mut indexs = vec![0; strides.len()];
     let mut remainder = index;
     for i in 0..strides.len() {
@@ -30,7 +88,7 @@ fn compute_index(index: usize, strides: &Vec<usize>) -> Vec<usize> {
     indexs
 }
  
-fn compute_flat_index(indexs: Vec<usize>, strides: &Vec<usize>) -> usize{
+fn compute_flat_index(indexs: Vec<usize>, strides: &[usize]) -> usize{
     let mut flat_index: usize = 0;
     for i in 0..indexs.len(){
         flat_index += indexs[i] * strides[i];
@@ -39,107 +97,998 @@ fn compute_flat_index(indexs: Vec<usize>, strides: &Vec<usize>) -> usize{
 }
  
  
+impl<T> Clone for Tensor<T> {
+    fn clone(&self) -> Self {
+        Tensor {
+            data: self.data.clone(),
+            shape: self.shape.clone(),
+            strides: self.strides.clone(),
+            offset: self.offset,
+            length: self.length,
+        }
+    }
+}
+
+// Recoverable shape and indexing failures, returned by the try_* variants of tensor methods
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TensorError {
+    // data length or element count does not match the requested shape
+    SizeMismatch { shape: Vec<usize>, expected: usize, actual: usize },
+    OutOfBounds { shape: Vec<usize>, axis: Option<usize>, start: usize, end: usize },
+    InvalidAxis { shape: Vec<usize>, axis: usize },
+    InvalidPermutation { shape: Vec<usize>, perm: Vec<usize> },
+    NotContiguous { op: &'static str },
+}
+
+impl fmt::Display for TensorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TensorError::SizeMismatch { shape, expected, actual } => {
+                write!(f, "shape {shape:?} needs {expected} elements but got {actual}")
+            }
+            TensorError::OutOfBounds { shape, axis: Some(axis), start, end } => {
+                write!(f, "range {start}..{end} out of bounds for axis {axis} of {shape:?}")
+            }
+            TensorError::OutOfBounds { shape, axis: None, start, end } => {
+                write!(f, "range {start}..{end} out of bounds for tensor of {shape:?}")
+            }
+            TensorError::InvalidAxis { shape, axis } => {
+                write!(f, "axis {axis} out of range for {shape:?}")
+            }
+            TensorError::InvalidPermutation { shape, perm } => {
+                write!(f, "invalid permutation {perm:?} for tensor of {shape:?}")
+            }
+            TensorError::NotContiguous { op } => {
+                write!(f, "{op}() on a strided view, call contiguous() first")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TensorError {}
+
+pub type TensorResult<T> = Result<T, TensorError>;
+
 impl<T: Copy + Clone + Default> Tensor<T> {
     pub fn new(data: Vec<T>, shape: &Vec<usize>) -> Self {
+        Self::try_new(data, shape).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_new(data: Vec<T>, shape: &Vec<usize>) -> TensorResult<Self> {
         let length = data.len();
-        Tensor {
-            data: Arc::new(data.into_boxed_slice().try_into().unwrap()),
+        let expected: usize = shape.iter().product();
+        if expected != length {
+            return Err(TensorError::SizeMismatch { shape: shape.clone(), expected, actual: length });
+        }
+        Ok(Tensor {
+            data: Arc::new(Storage::owned(data)),
             shape: shape.clone(),
+            strides: compute_strides(shape),
             offset: 0,
-            length: length,
-        }
+            length,
+        })
     }
  
+    // Zero-copy tensor over `shape.product()` elements of `mmap` starting at byte `start`.
+    // Returns None if that region is out of bounds or not aligned for T.
+    // Safety: the bytes must be valid little-endian values of T and the file must not change while mapped
+    pub(crate) unsafe fn from_mmap(mmap: &Arc<Mmap>, start: usize, shape: &Vec<usize>) -> Option<Self> {
+        let length: usize = shape.iter().product();
+        let end = start.checked_add(length.checked_mul(mem::size_of::<T>())?)?;
+        let aligned = (mmap.as_ptr() as usize + start).is_multiple_of(mem::align_of::<T>());
+        if end > mmap.len() || !aligned || cfg!(target_endian = "big") {
+            return None;
+        }
+        Some(Tensor {
+            data: Arc::new(Storage::Mapped { mmap: mmap.clone(), start, len: length, _elem: PhantomData }),
+            shape: shape.clone(),
+            strides: compute_strides(shape),
+            offset: 0,
+            length,
+        })
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(*self.data, Storage::Mapped { .. })
+    }
+
+    // Like new(), but copies `data` into an ALIGNMENT-byte aligned buffer for SIMD loads
+    pub fn new_aligned(data: Vec<T>, shape: &Vec<usize>) -> Self {
+        let mut t = Self::default(shape);
+        let expected = t.length;
+        match t.try_data_mut() {
+            Some(dst) if dst.len() == data.len() => dst.copy_from_slice(&data),
+            _ => panic!("{}", TensorError::SizeMismatch { shape: shape.clone(), expected, actual: data.len() }),
+        }
+        t
+    }
+
+    // Zero-filled tensor, its buffer is ALIGNMENT-byte aligned
     pub fn default(shape: &Vec<usize>) -> Self {
         let length = shape.iter().product();
-        let data = vec![T::default(); length];
-        Self::new(data, shape)
+        Tensor {
+            data: Arc::new(Storage::aligned(length)),
+            shape: shape.clone(),
+            strides: compute_strides(shape),
+            offset: 0,
+            length,
+        }
     }
  
     pub fn data(&self) -> &[T] {
+        assert!(self.is_contiguous(), "data() on a strided view, call contiguous() first");
         &self.data[self.offset..][..self.length]
     }
+
+    // Underlying storage starting at this view's offset, to be indexed with `strides()`
+    pub fn strided_data(&self) -> &[T] {
+        &self.data[self.offset..]
+    }
  
-    pub unsafe fn data_mut(&mut self) -> &mut [T] {
-        let ptr = self.data.as_ptr().add(self.offset) as *mut T;
-        slice::from_raw_parts_mut(ptr, self.length)
+    // Mutable access to this tensor's elements, None if the storage is shared with another
+    // tensor, read-only mapped, or this is a strided view
+    pub fn try_data_mut(&mut self) -> Option<&mut [T]> {
+        if !self.is_contiguous() {
+            return None;
+        }
+        match Arc::get_mut(&mut self.data) {
+            Some(data) => Some(&mut data.as_mut_slice()?[self.offset..][..self.length]),
+            None => None,
+        }
+    }
+
+    // Copy-on-write mutable access: shared, mapped or strided tensors first get a private
+    // compact copy, so writes are never visible through other tensors
+    pub fn make_mut(&mut self) -> &mut [T] {
+        if self.try_data_mut().is_none() {
+            let owned = self.strided_data_iter().collect::<Vec<_>>();
+            *self = Tensor::new(owned, &self.shape);
+        }
+        self.try_data_mut().unwrap()
+    }
+
+    // Elements in row-major order, following strides
+    fn strided_data_iter(&self) -> impl Iterator<Item = T> + '_ {
+        let src = self.strided_data();
+        let dense_strides = compute_strides(&self.shape);
+        (0..self.length).map(move |i| src[compute_flat_index(compute_index(i, &dense_strides), &self.strides)])
     }
  
     pub fn shape(&self) -> &Vec<usize> {
         &self.shape
     }
  
+    pub fn strides(&self) -> &Vec<usize> {
+        &self.strides
+    }
+ 
     pub fn size(&self) -> usize {
         self.length
     }
- 
-    pub fn clone(&self) -> Self {
-        Tensor {
-            data: self.data.clone(),
-            shape: self.shape.clone(),
-            offset: self.offset,
-            length: self.length,
-        }
+
+    // True if the elements are laid out densely in row-major order, so data() can be used directly
+    pub fn is_contiguous(&self) -> bool {
+        let expected = compute_strides(&self.shape);
+        (0..self.shape.len()).all(|i| self.shape[i] == 1 || self.strides[i] == expected[i])
     }
  
     // Reinterpret the tensor as a new shape while preserving total size.
     pub fn reshape(&mut self, new_shape: &Vec<usize>) -> &mut Self {
+        if let Err(e) = self.try_reshape(new_shape) {
+            panic!("{e}");
+        }
+        self
+    }
+
+    pub fn try_reshape(&mut self, new_shape: &Vec<usize>) -> TensorResult<&mut Self> {
         let new_length: usize = new_shape.iter().product();
         if new_length != self.length {
-            let old_shape = self.shape.clone();
-            panic!("New shape {new_shape:?} does not match tensor of {old_shape:?}");
+            return Err(TensorError::SizeMismatch {
+                shape: new_shape.clone(),
+                expected: new_length,
+                actual: self.length,
+            });
+        }
+        if !self.is_contiguous() {
+            return Err(TensorError::NotContiguous { op: "reshape" });
         }
         self.shape = new_shape.clone();
-        self
+        self.strides = compute_strides(new_shape);
+        Ok(self)
     }
  
     pub fn slice(&self, start: usize, shape: &Vec<usize>) -> Self {
+        self.try_slice(start, shape).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_slice(&self, start: usize, shape: &Vec<usize>) -> TensorResult<Self> {
         let new_length: usize = shape.iter().product();
-        assert!(self.offset + start + new_length <= self.length);
-        Tensor {
+        if start + new_length > self.length {
+            return Err(TensorError::OutOfBounds {
+                shape: self.shape.clone(),
+                axis: None,
+                start,
+                end: start + new_length,
+            });
+        }
+        if !self.is_contiguous() {
+            return Err(TensorError::NotContiguous { op: "slice" });
+        }
+        Ok(Tensor {
             data: self.data.clone(),
             shape: shape.clone(),
+            strides: compute_strides(shape),
             offset: self.offset + start,
             length: new_length,
-        }
+        })
     }
  
-    // 多维张量转置
-    pub fn transpose(&self, perm: Vec<usize>) -> Self{
-        let shape_len = self.shape.len();
-        let data = self.data();
- 
-        let mut new_shape = vec![0; shape_len];
- 
-        for i in 0..shape_len {
-            new_shape[i] = self.shape[perm[i]];
+    // 多维张量转置: returns a view over the same storage with permuted strides
+    pub fn transpose(&self, perm: Vec<usize>) -> Self {
+        self.permute(&perm)
+    }
+
+    pub fn permute(&self, perm: &[usize]) -> Self {
+        self.try_permute(perm).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_permute(&self, perm: &[usize]) -> TensorResult<Self> {
+        let ndim = self.shape.len();
+        let invalid = || TensorError::InvalidPermutation { shape: self.shape.clone(), perm: perm.to_vec() };
+        if perm.len() != ndim {
+            return Err(invalid());
         }
- 
-        let old_strides = compute_strides(&self.shape());
- 
-        let new_strides = compute_strides(&new_shape);
- 
-        // 创建T数组
-        let mut new_data = vec![T::default(); self.data.len()];
- 
-        for i in 0..self.size(){
-            let old_index = compute_index(i, &old_strides);
- 
-            let mut new_index = vec![0; old_index.len()];
-            
-            for j in 0..old_index.len() {
-                new_index[perm[j]] = old_index[j];
+        let mut seen = vec![false; ndim];
+        for &p in perm {
+            if p >= ndim || seen[p] {
+                return Err(invalid());
             }
- 
-            let new_flat_index = compute_flat_index(new_index, &new_strides);
- 
-            new_data[new_flat_index] = data[i];
+            seen[p] = true;
+        }
+        Ok(Tensor {
+            data: self.data.clone(),
+            shape: perm.iter().map(|&p| self.shape[p]).collect(),
+            strides: perm.iter().map(|&p| self.strides[p]).collect(),
+            offset: self.offset,
+            length: self.length,
+        })
+    }
+
+    // Materialize a compact row-major copy, only copies if the tensor is a strided view
+    pub fn contiguous(&self) -> Self {
+        if self.is_contiguous() {
+            return self.clone();
         }
-        Tensor::new(new_data, &new_shape)
+        Tensor::new(self.strided_data_iter().collect(), &self.shape)
     }
- 
+
+    // View this tensor as `shape` following NumPy broadcasting rules, broadcast dims get stride 0
+    pub fn broadcast_to(&self, shape: &[usize]) -> Self {
+        self.try_broadcast_to(shape)
+            .unwrap_or_else(|| panic!("cannot broadcast {:?} to {shape:?}", self.shape))
+    }
+
+    pub fn try_broadcast_to(&self, shape: &[usize]) -> Option<Self> {
+        let ndim = shape.len();
+        if ndim < self.shape.len() {
+            return None;
+        }
+        let lead = ndim - self.shape.len();
+        let mut strides = vec![0; ndim];
+        for i in lead..ndim {
+            let (dim, stride) = (self.shape[i - lead], self.strides[i - lead]);
+            if dim == shape[i] {
+                strides[i] = stride;
+            } else if dim != 1 {
+                return None;
+            }
+        }
+        Some(Tensor {
+            data: self.data.clone(),
+            shape: shape.to_vec(),
+            strides,
+            offset: self.offset,
+            length: shape.iter().product(),
+        })
+    }
+
+    // Elementwise binary op over the broadcast shape of both operands
+    pub fn zip_with(&self, other: &Self, f: impl Fn(T, T) -> T) -> Self {
+        let shape = broadcast_shape(&self.shape, &other.shape).unwrap_or_else(|| {
+            panic!("shapes {:?} and {:?} are not broadcastable", self.shape, other.shape)
+        });
+        let (a, b) = (self.broadcast_to(&shape), other.broadcast_to(&shape));
+        if a.is_contiguous() && b.is_contiguous() {
+            let data = a.data().iter().zip(b.data()).map(|(&x, &y)| f(x, y)).collect();
+            return Tensor::new(data, &shape);
+        }
+        let dense_strides = compute_strides(&shape);
+        let (a_data, b_data) = (a.strided_data(), b.strided_data());
+        let data = (0..a.length)
+            .map(|i| {
+                let index = compute_index(i, &dense_strides);
+                let x = a_data[compute_flat_index(index.clone(), &a.strides)];
+                let y = b_data[compute_flat_index(index, &b.strides)];
+                f(x, y)
+            })
+            .collect();
+        Tensor::new(data, &shape)
+    }
+
+    // Join tensors along an existing axis, all other dims must match
+    pub fn concat(tensors: &[&Self], axis: usize) -> Self {
+        assert!(!tensors.is_empty(), "concat() needs at least one tensor");
+        let first = tensors[0].shape();
+        assert!(axis < first.len(), "axis {axis} out of range for {first:?}");
+        for t in tensors {
+            let shape = t.shape();
+            assert!(
+                shape.len() == first.len()
+                    && (0..first.len()).all(|i| i == axis || shape[i] == first[i]),
+                "cannot concat {shape:?} with {first:?} along axis {axis}"
+            );
+        }
+        let mut shape = first.clone();
+        shape[axis] = tensors.iter().map(|t| t.shape()[axis]).sum();
+
+        let dense: Vec<Self> = tensors.iter().map(|t| t.contiguous()).collect();
+        // each tensor contributes one block of shape[axis..] per index of the leading dims, products
+        // rather than size() / outer so a 0 anywhere in the shape just gives no blocks
+        let outer: usize = first[..axis].iter().product();
+        let chunks: Vec<usize> = dense.iter().map(|t| t.shape()[axis..].iter().product()).collect();
+        let mut data = Vec::with_capacity(shape.iter().product());
+        for o in 0..outer {
+            for (t, &chunk) in dense.iter().zip(&chunks) {
+                data.extend_from_slice(&t.data()[o * chunk..][..chunk]);
+            }
+        }
+        Tensor::new(data, &shape)
+    }
+
+    // Join same-shaped tensors along a new axis inserted at `axis`
+    pub fn stack(tensors: &[&Self], axis: usize) -> Self {
+        assert!(!tensors.is_empty(), "stack() needs at least one tensor");
+        let first = tensors[0].shape();
+        assert!(axis <= first.len(), "axis {axis} out of range for {first:?}");
+        let mut unsqueezed = first.clone();
+        unsqueezed.insert(axis, 1);
+        let views: Vec<Self> = tensors
+            .iter()
+            .map(|t| {
+                assert!(t.shape() == first, "cannot stack {:?} with {first:?}", t.shape());
+                let mut t = t.contiguous();
+                t.reshape(&unsqueezed);
+                t
+            })
+            .collect();
+        Tensor::concat(&views.iter().collect::<Vec<_>>(), axis)
+    }
+
+    // View of `len` entries along `axis` starting at `start`, shares storage with self
+    pub fn narrow(&self, axis: usize, start: usize, len: usize) -> Self {
+        self.try_narrow(axis, start, len).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_narrow(&self, axis: usize, start: usize, len: usize) -> TensorResult<Self> {
+        if axis >= self.shape.len() {
+            return Err(TensorError::InvalidAxis { shape: self.shape.clone(), axis });
+        }
+        if start + len > self.shape[axis] {
+            return Err(TensorError::OutOfBounds {
+                shape: self.shape.clone(),
+                axis: Some(axis),
+                start,
+                end: start + len,
+            });
+        }
+        let mut shape = self.shape.clone();
+        shape[axis] = len;
+        Ok(Tensor {
+            data: self.data.clone(),
+            length: shape.iter().product(),
+            shape,
+            strides: self.strides.clone(),
+            offset: if len == 0 { self.offset } else { self.offset + start * self.strides[axis] },
+        })
+    }
+
+    // Strided view of the given range along each leading dim, missing trailing dims are kept whole
+    pub fn slice_dims(&self, ranges: &[Range<usize>]) -> Self {
+        assert!(
+            ranges.len() <= self.shape.len(),
+            "{} ranges given for tensor of {:?}",
+            ranges.len(),
+            self.shape
+        );
+        ranges.iter().enumerate().fold(self.clone(), |view, (axis, r)| {
+            assert!(r.start <= r.end, "invalid range {r:?} for axis {axis}");
+            view.narrow(axis, r.start, r.end - r.start)
+        })
+    }
+
+    // View of every `step`-th entry along `axis` starting from the first, e.g. every other row
+    pub fn step_by(&self, axis: usize, step: usize) -> Self {
+        assert!(axis < self.shape.len(), "axis {axis} out of range for {:?}", self.shape);
+        assert!(step > 0, "step_by() needs a positive step");
+        let mut shape = self.shape.clone();
+        shape[axis] = self.shape[axis].div_ceil(step);
+        let mut strides = self.strides.clone();
+        strides[axis] *= step;
+        Tensor {
+            data: self.data.clone(),
+            length: shape.iter().product(),
+            shape,
+            strides,
+            offset: self.offset,
+        }
+    }
+
+    // Extend `axis` by `before` and `after` entries filled with `value`
+    pub fn pad(&self, axis: usize, before: usize, after: usize, value: T) -> Self {
+        assert!(axis < self.shape.len(), "axis {axis} out of range for {:?}", self.shape);
+        let filler = |n: usize| {
+            let mut shape = self.shape.clone();
+            shape[axis] = n;
+            Tensor::new(vec![value; shape.iter().product()], &shape)
+        };
+        let (head, tail) = (filler(before), filler(after));
+        Tensor::concat(&[&head, self, &tail], axis)
+    }
+
+    // Right-pad sequences along axis 0 to the longest one and stack them into (batch, max_len, ..).
+    // The mask is (batch, max_len) and marks real (non-padding) positions
+    pub fn pad_batch(seqs: &[&Self], value: T) -> (Self, Tensor<bool>) {
+        assert!(!seqs.is_empty(), "pad_batch() needs at least one sequence");
+        let max_len = seqs.iter().map(|t| t.shape()[0]).max().unwrap();
+        let padded: Vec<Self> = seqs.iter().map(|t| t.pad(0, 0, max_len - t.shape()[0], value)).collect();
+        let mask = seqs
+            .iter()
+            .flat_map(|t| (0..max_len).map(move |i| i < t.shape()[0]))
+            .collect();
+        (
+            Tensor::stack(&padded.iter().collect::<Vec<_>>(), 0),
+            Tensor::new(mask, &vec![seqs.len(), max_len]),
+        )
+    }
+
+    // Pick entries along `axis` by index (e.g. embedding rows by token id), result owns its data
+    pub fn index_select(&self, axis: usize, indices: &[u32]) -> Self {
+        assert!(axis < self.shape.len(), "axis {axis} out of range for {:?}", self.shape);
+        let dim = self.shape[axis];
+        if let Some(&i) = indices.iter().find(|&&i| i as usize >= dim) {
+            panic!("index {i} out of bounds for axis {axis} of {:?}", self.shape);
+        }
+        let mut shape = self.shape.clone();
+        shape[axis] = indices.len();
+        if axis == 0 && self.is_contiguous() {
+            let row = self.length / dim.max(1);
+            let src = self.data();
+            let mut data = Vec::with_capacity(indices.len() * row);
+            for &i in indices {
+                data.extend_from_slice(&src[i as usize * row..][..row]);
+            }
+            return Tensor::new(data, &shape);
+        }
+        if indices.is_empty() {
+            return Tensor::default(&shape);
+        }
+        let picked: Vec<Self> = indices.iter().map(|&i| self.narrow(axis, i as usize, 1)).collect();
+        Tensor::concat(&picked.iter().collect::<Vec<_>>(), axis)
+    }
+
+    // Fold entries along `axis` with `f`, the axis is dropped or kept as size 1
+    pub fn reduce_axis(&self, axis: usize, keep_dim: bool, f: impl Fn(T, T) -> T) -> Self {
+        assert!(axis < self.shape.len(), "axis {axis} out of range for {:?}", self.shape);
+        let dim = self.shape[axis];
+        assert!(dim > 0, "cannot reduce over empty axis {axis} of {:?}", self.shape);
+        let outer: usize = self.shape[..axis].iter().product();
+        let inner: usize = self.shape[axis + 1..].iter().product();
+        let dense = self.contiguous();
+        let src = dense.data();
+        let mut data = Vec::with_capacity(outer * inner);
+        for o in 0..outer {
+            let base = o * dim * inner;
+            for i in 0..inner {
+                let acc = (1..dim).fold(src[base + i], |acc, d| f(acc, src[base + d * inner + i]));
+                data.push(acc);
+            }
+        }
+        let mut shape = self.shape.clone();
+        if keep_dim {
+            shape[axis] = 1;
+        } else {
+            shape.remove(axis);
+        }
+        Tensor::new(data, &shape)
+    }
+
+    // View of entry `i` along the leading axis, with that axis dropped
+    pub fn index_axis0(&self, i: usize) -> Self {
+        assert!(!self.shape.is_empty(), "index_axis0() on a scalar tensor");
+        assert!(i < self.shape[0], "index {i} out of bounds for {:?}", self.shape);
+        let shape = self.shape[1..].to_vec();
+        Tensor {
+            data: self.data.clone(),
+            length: shape.iter().product(),
+            shape,
+            strides: self.strides[1..].to_vec(),
+            offset: self.offset + i * self.strides[0],
+        }
+    }
+
+    // Views of each entry along the leading axis, e.g. each sequence of a (batch, seq, hidden) tensor
+    pub fn iter_axis0(&self) -> Axis0Iter<T> {
+        assert!(!self.shape.is_empty(), "iter_axis0() on a scalar tensor");
+        Axis0Iter { tensor: self.clone(), front: 0, back: self.shape[0] }
+    }
+
+    // Views of each last-dim row, all leading dims are flattened (copies if self is strided)
+    pub fn rows(&self) -> Axis0Iter<T> {
+        assert!(!self.shape.is_empty(), "rows() on a scalar tensor");
+        let dim = self.shape[self.shape.len() - 1];
+        let mut dense = self.contiguous();
+        dense.reshape(&vec![self.length / dim.max(1), dim]);
+        dense.iter_axis0()
+    }
+
+    // Split along `axis` into views of the given sizes, which must cover the whole axis
+    pub fn split(&self, axis: usize, sizes: &[usize]) -> Vec<Self> {
+        assert!(axis < self.shape.len(), "axis {axis} out of range for {:?}", self.shape);
+        assert!(
+            sizes.iter().sum::<usize>() == self.shape[axis],
+            "split sizes {sizes:?} do not add up to axis {axis} of {:?}",
+            self.shape
+        );
+        let mut start = 0;
+        sizes
+            .iter()
+            .map(|&len| {
+                let view = self.narrow(axis, start, len);
+                start += len;
+                view
+            })
+            .collect()
+    }
+
+    // Split along `axis` into `n` views, the last one is smaller if the axis doesn't divide evenly
+    pub fn chunk(&self, axis: usize, n: usize) -> Vec<Self> {
+        assert!(n > 0, "chunk() needs at least one chunk");
+        assert!(axis < self.shape.len(), "axis {axis} out of range for {:?}", self.shape);
+        let dim = self.shape[axis];
+        let step = dim.div_ceil(n);
+        let sizes: Vec<usize> = (0..dim).step_by(step.max(1)).map(|s| step.min(dim - s)).collect();
+        self.split(axis, &sizes)
+    }
+
+}
+
+impl<T: Copy + Clone + Default> Tensor<T> {
+    // Apply `f(x, y)` in place over self with `other` broadcast to self's shape
+    pub fn zip_with_(&mut self, other: &Self, f: impl Fn(T, T) -> T) -> &mut Self {
+        let other = other
+            .try_broadcast_to(&self.shape)
+            .unwrap_or_else(|| panic!("cannot broadcast {:?} to {:?}", other.shape, self.shape));
+        if other.is_contiguous() {
+            for (x, &y) in self.make_mut().iter_mut().zip(other.data()) {
+                *x = f(*x, y);
+            }
+        } else {
+            for (x, y) in self.make_mut().iter_mut().zip(other.strided_data_iter()) {
+                *x = f(*x, y);
+            }
+        }
+        self
+    }
+
+    // Overwrite self with `other`, which must broadcast to self's shape
+    pub fn copy_from(&mut self, other: &Self) -> &mut Self {
+        self.zip_with_(other, |_, y| y)
+    }
+}
+
+impl<T: Copy + Default + Add<Output = T> + Mul<Output = T>> Tensor<T> {
+    pub fn add_(&mut self, other: &Self) -> &mut Self {
+        self.zip_with_(other, |x, y| x + y)
+    }
+
+    pub fn mul_(&mut self, other: &Self) -> &mut Self {
+        self.zip_with_(other, |x, y| x * y)
+    }
+
+    // self[.., indices[i], ..] += src[.., i, ..] along `axis`, repeated indices accumulate
+    pub fn index_add_(&mut self, axis: usize, indices: &[u32], src: &Self) -> &mut Self {
+        assert!(axis < self.shape.len(), "axis {axis} out of range for {:?}", self.shape);
+        let mut expected = self.shape.clone();
+        expected[axis] = indices.len();
+        assert!(src.shape == expected, "index_add_() source {:?} should be {expected:?}", src.shape);
+        let dim = self.shape[axis];
+        if let Some(&i) = indices.iter().find(|&&i| i as usize >= dim) {
+            panic!("index {i} out of bounds for axis {axis} of {:?}", self.shape);
+        }
+        let outer: usize = self.shape[..axis].iter().product();
+        let inner: usize = self.shape[axis + 1..].iter().product();
+        let src = src.contiguous();
+        let src = src.data();
+        let dst = self.make_mut();
+        for o in 0..outer {
+            for (i, &idx) in indices.iter().enumerate() {
+                let to = &mut dst[(o * dim + idx as usize) * inner..][..inner];
+                let from = &src[(o * indices.len() + i) * inner..][..inner];
+                for (x, &y) in to.iter_mut().zip(from) {
+                    *x = *x + y;
+                }
+            }
+        }
+        self
+    }
+
+    // For every position p of `src`: self[p with p[axis] = indices[p]] += src[p],
+    // `indices` has src's shape and repeated targets accumulate
+    pub fn scatter_add_(&mut self, axis: usize, indices: &Tensor<u32>, src: &Self) -> &mut Self {
+        let ndim = self.shape.len();
+        assert!(axis < ndim, "axis {axis} out of range for {:?}", self.shape);
+        assert!(
+            indices.shape == src.shape && src.shape.len() == ndim,
+            "scatter_add_() needs indices {:?} and source {:?} of the same rank as {:?}",
+            indices.shape,
+            src.shape,
+            self.shape
+        );
+        assert!(
+            (0..ndim).all(|d| d == axis || src.shape[d] <= self.shape[d]),
+            "scatter_add_() source {:?} does not fit into {:?}",
+            src.shape,
+            self.shape
+        );
+        let (indices, src) = (indices.contiguous(), src.contiguous());
+        let src_strides = compute_strides(&src.shape);
+        let dst_strides = compute_strides(&self.shape);
+        let dim = self.shape[axis];
+        let dst = self.make_mut();
+        for (i, (&idx, &y)) in indices.data().iter().zip(src.data()).enumerate() {
+            assert!((idx as usize) < dim, "index {idx} out of bounds for axis {axis} of size {dim}");
+            let mut pos = compute_index(i, &src_strides);
+            pos[axis] = idx as usize;
+            let flat = compute_flat_index(pos, &dst_strides);
+            dst[flat] = dst[flat] + y;
+        }
+        self
+    }
+
+    pub fn scatter_add(&self, axis: usize, indices: &Tensor<u32>, src: &Self) -> Self {
+        let mut out = self.contiguous();
+        out.scatter_add_(axis, indices, src);
+        out
+    }
+
+    pub fn scale_(&mut self, factor: T) -> &mut Self {
+        for x in self.make_mut() {
+            *x = *x * factor;
+        }
+        self
+    }
+}
+
+impl<T: Copy + Default + Add<Output = T> + Mul<Output = T>> Tensor<T> {
+    // Broadcasting elementwise sum
+    pub fn add(&self, other: &Self) -> Self {
+        self.zip_with(other, |x, y| x + y)
+    }
+
+    // Broadcasting elementwise product
+    pub fn mul(&self, other: &Self) -> Self {
+        self.zip_with(other, |x, y| x * y)
+    }
+
+    pub fn sum_axis(&self, axis: usize, keep_dim: bool) -> Self {
+        self.reduce_axis(axis, keep_dim, |x, y| x + y)
+    }
+}
+
+impl<T: Copy + Default + PartialOrd> Tensor<T> {
+    pub fn max_axis(&self, axis: usize, keep_dim: bool) -> Self {
+        self.reduce_axis(axis, keep_dim, |x, y| if y > x { y } else { x })
+    }
+
+    pub fn min_axis(&self, axis: usize, keep_dim: bool) -> Self {
+        self.reduce_axis(axis, keep_dim, |x, y| if y < x { y } else { x })
+    }
+}
+
+impl Tensor<f32> {
+    pub fn mean_axis(&self, axis: usize, keep_dim: bool) -> Self {
+        let n = self.shape[axis] as f32;
+        let sum = self.sum_axis(axis, keep_dim);
+        let data = sum.data().iter().map(|x| x / n).collect();
+        Tensor::new(data, sum.shape())
+    }
+
+    // Index and value of the largest entry, the tensor is treated as a flat vector
+    pub fn argmax(&self) -> (u32, f32) {
+        let dense = self.contiguous();
+        let (i, &v) = dense
+            .data()
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .expect("argmax() on an empty tensor");
+        (i as u32, v)
+    }
+
+    // The k largest entries in descending order, partial selection before sorting only those k
+    pub fn top_k(&self, k: usize) -> (Vec<u32>, Vec<f32>) {
+        let dense = self.contiguous();
+        let mut pairs: Vec<(u32, f32)> =
+            dense.data().iter().enumerate().map(|(i, &v)| (i as u32, v)).collect();
+        let k = k.min(pairs.len());
+        let by_value_desc = |a: &(u32, f32), b: &(u32, f32)| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0));
+        if k < pairs.len() && k > 0 {
+            pairs.select_nth_unstable_by(k - 1, by_value_desc);
+        }
+        pairs.truncate(k);
+        pairs.sort_unstable_by(by_value_desc);
+        pairs.into_iter().unzip()
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl<T: Copy + Clone + Default + Send + Sync> Tensor<T> {
+    // Parallel iterator over the leading-axis views, see iter_axis0()
+    pub fn par_iter_axis0(&self) -> impl IndexedParallelIterator<Item = Self> + '_ {
+        assert!(!self.shape.is_empty(), "par_iter_axis0() on a scalar tensor");
+        (0..self.shape[0]).into_par_iter().map(|i| self.index_axis0(i))
+    }
+
+    // Mutable slices of each leading-axis entry processed in parallel (copies first if shared, see make_mut())
+    pub fn par_chunks_axis0_mut(&mut self) -> impl IndexedParallelIterator<Item = &mut [T]> + '_ {
+        assert!(!self.shape.is_empty(), "par_chunks_axis0_mut() on a scalar tensor");
+        let chunk = (self.length / self.shape[0].max(1)).max(1);
+        self.make_mut().par_chunks_mut(chunk)
+    }
+}
+
+pub struct Axis0Iter<T> {
+    tensor: Tensor<T>,
+    front: usize,
+    back: usize,
+}
+
+impl<T: Copy + Clone + Default> Iterator for Axis0Iter<T> {
+    type Item = Tensor<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        self.front += 1;
+        Some(self.tensor.index_axis0(self.front - 1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.back - self.front;
+        (n, Some(n))
+    }
+}
+
+impl<T: Copy + Clone + Default> DoubleEndedIterator for Axis0Iter<T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        self.back -= 1;
+        Some(self.tensor.index_axis0(self.back))
+    }
+}
+
+impl<T: Copy + Clone + Default> ExactSizeIterator for Axis0Iter<T> {}
+
+// Reusable scratch buffers for activations. Tensors handed out by take() are given back
+// with recycle() once the caller is done with them
+pub struct TensorPool<T> {
+    free: Vec<Storage<T>>,
+}
+
+impl<T: Copy + Clone + Default> TensorPool<T> {
+    pub fn new() -> Self {
+        TensorPool { free: Vec::new() }
+    }
+
+    // A zeroed tensor of `shape`, reusing the smallest free buffer that is large enough
+    pub fn take(&mut self, shape: &Vec<usize>) -> Tensor<T> {
+        let length: usize = shape.iter().product();
+        let best = (0..self.free.len())
+            .filter(|&i| self.free[i].len() >= length)
+            .min_by_key(|&i| self.free[i].len());
+        let data = match best {
+            Some(i) => {
+                let mut data = self.free.swap_remove(i);
+                data.as_mut_slice().unwrap()[..length].fill(T::default());
+                data
+            }
+            None => Storage::aligned(length),
+        };
+        Tensor {
+            data: Arc::new(data),
+            shape: shape.clone(),
+            strides: compute_strides(shape),
+            offset: 0,
+            length,
+        }
+    }
+
+    // Hand a tensor's buffer back to the pool, ignored if other views of it are still alive
+    pub fn recycle(&mut self, tensor: Tensor<T>) {
+        if let Ok(data @ Storage::Owned { .. }) = Arc::try_unwrap(tensor.data) {
+            self.free.push(data);
+        }
+    }
+
+    // Number of elements held in free buffers
+    pub fn capacity(&self) -> usize {
+        self.free.iter().map(|data| data.len()).sum()
+    }
+}
+
+impl<T: Copy + Clone + Default> Default for TensorPool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Result shape of broadcasting `a` against `b`, None if they are incompatible
+pub fn broadcast_shape(a: &[usize], b: &[usize]) -> Option<Vec<usize>> {
+    let ndim = a.len().max(b.len());
+    let mut shape = vec![0; ndim];
+    for i in 0..ndim {
+        let x = if i < ndim - a.len() { 1 } else { a[i - (ndim - a.len())] };
+        let y = if i < ndim - b.len() { 1 } else { b[i - (ndim - b.len())] };
+        shape[i] = match (x, y) {
+            (x, y) if x == y => x,
+            (1, y) => y,
+            (x, 1) => x,
+            _ => return None,
+        };
+    }
+    Some(shape)
 }
  
+impl Tensor<f32> {
+    // Standard normal samples (Box-Muller), identical for the same seed
+    pub fn randn(shape: &Vec<usize>, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let length: usize = shape.iter().product();
+        let mut data = Vec::with_capacity(length + 1);
+        while data.len() < length {
+            let u1 = 1. - rng.gen::<f32>(); // (0, 1], keeps ln() finite
+            let u2 = rng.gen::<f32>();
+            let r = (-2. * u1.ln()).sqrt();
+            let (sin, cos) = (2. * std::f32::consts::PI * u2).sin_cos();
+            data.extend([r * cos, r * sin]);
+        }
+        data.truncate(length);
+        Tensor::new(data, shape)
+    }
+
+    // Uniform samples in [lo, hi), identical for the same seed
+    pub fn uniform(shape: &Vec<usize>, lo: f32, hi: f32, seed: u64) -> Self {
+        assert!(lo < hi, "uniform() needs lo < hi, got {lo}..{hi}");
+        let mut rng = StdRng::seed_from_u64(seed);
+        let length: usize = shape.iter().product();
+        Tensor::new((0..length).map(|_| rng.gen_range(lo..hi)).collect(), shape)
+    }
+
+}
+
+// Floating-point element types tensors can be cast between, conversions go through f32
+pub trait Float: Copy + Clone + Default + Send + Sync {
+    fn to_f32(self) -> f32;
+    fn from_f32(x: f32) -> Self;
+
+    // Bulk conversions, overridden where a vectorized slice routine exists
+    fn slice_to_f32(src: &[Self], dst: &mut [f32]) {
+        for (d, &x) in dst.iter_mut().zip(src) {
+            *d = x.to_f32();
+        }
+    }
+
+    fn slice_from_f32(src: &[f32], dst: &mut [Self]) {
+        for (d, &x) in dst.iter_mut().zip(src) {
+            *d = Self::from_f32(x);
+        }
+    }
+}
+
+impl Float for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(x: f32) -> Self {
+        x
+    }
+
+    fn slice_to_f32(src: &[Self], dst: &mut [f32]) {
+        dst.copy_from_slice(src);
+    }
+
+    fn slice_from_f32(src: &[f32], dst: &mut [Self]) {
+        dst.copy_from_slice(src);
+    }
+}
+
+impl Float for f64 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(x: f32) -> Self {
+        x as f64
+    }
+}
+
+impl Float for f16 {
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+
+    fn from_f32(x: f32) -> Self {
+        f16::from_f32(x)
+    }
+
+    fn slice_to_f32(src: &[Self], dst: &mut [f32]) {
+        src.convert_to_f32_slice(dst);
+    }
+
+    fn slice_from_f32(src: &[f32], dst: &mut [Self]) {
+        dst.convert_from_f32_slice(src);
+    }
+}
+
+impl Float for bf16 {
+    fn to_f32(self) -> f32 {
+        bf16::to_f32(self)
+    }
+
+    fn from_f32(x: f32) -> Self {
+        bf16::from_f32(x)
+    }
+
+    fn slice_to_f32(src: &[Self], dst: &mut [f32]) {
+        src.convert_to_f32_slice(dst);
+    }
+
+    fn slice_from_f32(src: &[f32], dst: &mut [Self]) {
+        dst.convert_from_f32_slice(src);
+    }
+}
+
+impl<T: Float> Tensor<T> {
+    // Convert every element to U, strided views are compacted on the way
+    pub fn cast<U: Float>(&self) -> Tensor<U> {
+        let dense = self.contiguous();
+        let mut f32s = vec![0f32; self.length];
+        T::slice_to_f32(dense.data(), &mut f32s);
+        let mut out = Tensor::<U>::default(&self.shape);
+        U::slice_from_f32(&f32s, out.make_mut());
+        out
+    }
+
+    pub fn to_f32(&self) -> Tensor<f32> {
+        let dense = self.contiguous();
+        let mut out = Tensor::<f32>::default(&self.shape);
+        T::slice_to_f32(dense.data(), out.make_mut());
+        out
+    }
+
+    pub fn to_f16(&self) -> Tensor<f16> {
+        self.cast()
+    }
+
+    pub fn to_bf16(&self) -> Tensor<bf16> {
+        self.cast()
+    }
+}
+
 // Some helper functions for testing and debugging
 impl Tensor<f32> {
     #[allow(unused)]
@@ -147,24 +1096,554 @@ impl Tensor<f32> {
         if self.shape() != other.shape() {
             return false;
         }
-        let a = self.data();
-        let b = other.data();
+        let (a, b) = (self.contiguous(), other.contiguous());
+        let (a, b) = (a.data(), b.data());
         
-        return a.iter().zip(b).all(|(x, y)| float_eq(x, y, rel));
+        a.iter().zip(b).all(|(x, y)| float_eq(x, y, rel))
     }
     #[allow(unused)]
     pub fn print(&self){
-        println!("shpae: {:?}, offset: {}, length: {}", self.shape, self.offset, self.length);
-        let dim = self.shape()[self.shape().len() - 1];
-        let batch = self.length / dim;
-        for i in 0..batch {
-            let start = i * dim;
-            println!("{:?}", &self.data()[start..][..dim]);
+        println!("{self:?}");
+    }
+
+    // Elementwise error statistics of self against a reference tensor of the same shape
+    #[allow(unused)]
+    pub fn compare(&self, reference: &Self) -> Comparison {
+        assert!(
+            self.shape() == reference.shape(),
+            "cannot compare {:?} with {:?}",
+            self.shape(),
+            reference.shape()
+        );
+        let (a, b) = (self.contiguous(), reference.contiguous());
+        let mut report = Comparison {
+            max_abs_err: 0.,
+            max_rel_err: 0.,
+            worst_index: vec![0; self.shape.len()],
+            nan_count: 0,
+            inf_count: 0,
+        };
+        let mut worst = 0;
+        for (i, (&x, &y)) in a.data().iter().zip(b.data()).enumerate() {
+            report.nan_count += x.is_nan() as usize + y.is_nan() as usize;
+            report.inf_count += x.is_infinite() as usize + y.is_infinite() as usize;
+            if !x.is_finite() || !y.is_finite() {
+                continue;
+            }
+            let abs = (x - y).abs();
+            let rel = if y == 0. { if x == 0. { 0. } else { f32::INFINITY } } else { abs / y.abs() };
+            report.max_rel_err = report.max_rel_err.max(rel);
+            if abs > report.max_abs_err {
+                report.max_abs_err = abs;
+                worst = i;
+            }
+        }
+        if self.length > 0 {
+            report.worst_index = compute_index(worst, &compute_strides(&self.shape));
         }
+        report
+    }
+}
+
+// Result of Tensor::compare, counts include non-finite values from both tensors
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+    pub max_abs_err: f32,
+    pub max_rel_err: f32,
+    pub worst_index: Vec<usize>, // position of the largest absolute error
+    pub nan_count: usize,
+    pub inf_count: usize,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "max abs err {:e} at {:?}, max rel err {:e}, {} NaN, {} Inf",
+            self.max_abs_err, self.worst_index, self.max_rel_err, self.nan_count, self.inf_count
+        )
+    }
+}
+
+// Truncation settings for printing, dims longer than 2 * edge_items are summarized
+// once the tensor has more than `threshold` elements
+#[derive(Clone, Copy, Debug)]
+pub struct PrintOptions {
+    pub edge_items: usize,
+    pub threshold: usize,
+    pub precision: Option<usize>,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        PrintOptions { edge_items: 3, threshold: 1000, precision: None }
+    }
+}
+
+pub struct TensorDisplay<'a, T> {
+    tensor: &'a Tensor<T>,
+    options: PrintOptions,
+}
+
+impl<T: Copy + Clone + Default> Tensor<T> {
+    pub fn display_with(&self, options: PrintOptions) -> TensorDisplay<'_, T> {
+        TensorDisplay { tensor: self, options }
+    }
+}
+
+impl<T: Copy + fmt::Display> TensorDisplay<'_, T> {
+    fn write_dim(&self, f: &mut fmt::Formatter, dim: usize, base: usize, indent: usize) -> fmt::Result {
+        let t = self.tensor;
+        let data = &t.data[t.offset..];
+        if t.shape.is_empty() {
+            return self.write_elem(f, data[base]);
+        }
+        let n = t.shape[dim];
+        let edge = self.options.edge_items;
+        let summarize = t.length > self.options.threshold && n > 2 * edge;
+        let last = dim + 1 == t.shape.len();
+        write!(f, "[")?;
+        let mut first = true;
+        for i in 0..n {
+            if summarize && i >= edge && i < n - edge {
+                if i == edge {
+                    write!(f, "{}...", if last { ", " } else { ",\n" })?;
+                    if !last {
+                        write!(f, "{:indent$}", "", indent = indent + dim + 1)?;
+                    }
+                }
+                continue;
+            }
+            if !first {
+                if last {
+                    write!(f, ", ")?;
+                } else {
+                    write!(f, ",\n{:indent$}", "", indent = indent + dim + 1)?;
+                }
+            }
+            first = false;
+            let pos = base + i * t.strides[dim];
+            if last {
+                self.write_elem(f, data[pos])?;
+            } else {
+                self.write_dim(f, dim + 1, pos, indent)?;
+            }
+        }
+        write!(f, "]")
+    }
+
+    fn write_elem(&self, f: &mut fmt::Formatter, x: T) -> fmt::Result {
+        match self.options.precision {
+            Some(p) => write!(f, "{x:.p$}"),
+            None => write!(f, "{x}"),
+        }
+    }
+}
+
+impl<T: Copy + fmt::Display> fmt::Display for TensorDisplay<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tensor(")?;
+        self.write_dim(f, 0, 0, "tensor(".len())?;
+        write!(f, ", shape={:?})", self.tensor.shape)
+    }
+}
+
+impl<T: Copy + Clone + Default + fmt::Display> fmt::Display for Tensor<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let options = PrintOptions { precision: f.precision(), ..Default::default() };
+        write!(f, "{}", self.display_with(options))
+    }
+}
+
+impl<T: Copy + Clone + Default + fmt::Display> fmt::Debug for Tensor<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Tensor {{ shape: {:?}, strides: {:?}, offset: {}, data: ",
+            self.shape, self.strides, self.offset
+        )?;
+        self.display_with(PrintOptions::default()).write_dim(f, 0, 0, 0)?;
+        write!(f, " }}")
     }
 }
  
 #[inline]
 pub fn float_eq(x: &f32, y: &f32, rel: f32) -> bool {
     (x - y).abs() <= rel * (x.abs() + y.abs()) / 2.0
-}
\ No newline at end of file
+}
+#[test]
+fn test_transpose_view() {
+    let t = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let v = t.transpose(vec![1, 0]);
+    assert_eq!(v.shape(), &vec![3, 2]);
+    assert_eq!(v.strides(), &vec![1, 3]);
+    assert!(Arc::ptr_eq(&t.data, &v.data));
+    assert!(!v.is_contiguous());
+    let c = v.contiguous();
+    assert_eq!(c.data(), &[1., 4., 2., 5., 3., 6.]);
+    assert!(c.close_to(&v, 1e-6));
+}
+
+#[test]
+fn test_broadcast_add_mul() {
+    let x = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let bias = Tensor::<f32>::new(vec![10., 20., 30.], &vec![3]);
+    let col = Tensor::<f32>::new(vec![2., 3.], &vec![2, 1]);
+    assert!(x.add(&bias).close_to(
+        &Tensor::<f32>::new(vec![11., 22., 33., 14., 25., 36.], &vec![2, 3]),
+        1e-6
+    ));
+    assert!(x.mul(&col).close_to(
+        &Tensor::<f32>::new(vec![2., 4., 6., 12., 15., 18.], &vec![2, 3]),
+        1e-6
+    ));
+    assert_eq!(broadcast_shape(&[4, 1, 3], &[2, 1]), Some(vec![4, 2, 3]));
+    assert_eq!(broadcast_shape(&[2, 3], &[2]), None);
+}
+
+#[test]
+fn test_concat_stack() {
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let b = Tensor::<f32>::new(vec![5., 6.], &vec![1, 2]);
+    let c = Tensor::<f32>::new(vec![7., 8.], &vec![2, 1]);
+    assert_eq!(Tensor::concat(&[&a, &b], 0).data(), &[1., 2., 3., 4., 5., 6.]);
+    let ac = Tensor::concat(&[&a, &c], 1);
+    assert_eq!(ac.shape(), &vec![2, 3]);
+    assert_eq!(ac.data(), &[1., 2., 7., 3., 4., 8.]);
+    let s = Tensor::stack(&[&a, &a.transpose(vec![1, 0])], 1);
+    assert_eq!(s.shape(), &vec![2, 2, 2]);
+    assert_eq!(s.data(), &[1., 2., 1., 3., 3., 4., 2., 4.]);
+    // empty leading or concatenated dims
+    let empty = Tensor::concat(&[&Tensor::<f32>::default(&vec![0, 2]), &Tensor::default(&vec![0, 3])], 1);
+    assert_eq!((empty.shape(), empty.size()), (&vec![0, 5], 0));
+    let rows = Tensor::concat(&[&Tensor::<f32>::default(&vec![2, 0]), &Tensor::default(&vec![2, 1])], 1);
+    assert_eq!(rows.shape(), &vec![2, 1]);
+}
+
+#[test]
+fn test_split_chunk() {
+    let qkv = Tensor::<f32>::new((0..12).map(|x| x as f32).collect(), &vec![2, 6]);
+    let parts = qkv.split(1, &[2, 2, 2]);
+    assert_eq!(parts.len(), 3);
+    assert_eq!(parts[1].shape(), &vec![2, 2]);
+    assert!(Arc::ptr_eq(&qkv.data, &parts[1].data));
+    assert_eq!(parts[1].contiguous().data(), &[2., 3., 8., 9.]);
+    let rows = qkv.chunk(0, 2);
+    assert_eq!(rows[1].data(), &[6., 7., 8., 9., 10., 11.]);
+    let uneven = qkv.chunk(1, 4);
+    assert_eq!(uneven.iter().map(|t| t.shape()[1]).collect::<Vec<_>>(), vec![2, 2, 2]);
+}
+
+#[test]
+fn test_slice_dims() {
+    let x = Tensor::<f32>::new((0..24).map(|x| x as f32).collect(), &vec![4, 6]);
+    // axes without a range are kept whole
+    let leading = 1..3;
+    let rows = x.slice_dims(&[leading]);
+    assert_eq!(rows.shape(), &vec![2, 6]);
+    assert_eq!(rows.data(), &x.data()[6..18]);
+    let block = x.slice_dims(&[1..3, 2..5]);
+    assert_eq!(block.shape(), &vec![2, 3]);
+    assert_eq!(block.strides(), &vec![6, 1]);
+    assert_eq!(block.contiguous().data(), &[8., 9., 10., 14., 15., 16.]);
+    let first = 0..1;
+    let col = x.transpose(vec![1, 0]).slice_dims(&[first]);
+    assert_eq!(col.contiguous().data(), &[0., 6., 12., 18.]);
+}
+
+#[test]
+fn test_index_select() {
+    let table = Tensor::<f32>::new((0..12).map(|x| x as f32).collect(), &vec![4, 3]);
+    let rows = table.index_select(0, &[3, 0, 3]);
+    assert_eq!(rows.shape(), &vec![3, 3]);
+    assert_eq!(rows.data(), &[9., 10., 11., 0., 1., 2., 9., 10., 11.]);
+    let cols = table.index_select(1, &[2, 0]);
+    assert_eq!(cols.data(), &[2., 0., 5., 3., 8., 6., 11., 9.]);
+    let strided = table.transpose(vec![1, 0]).index_select(0, &[1]);
+    assert_eq!(strided.data(), &[1., 4., 7., 10.]);
+}
+
+#[test]
+fn test_axis_reductions() {
+    let x = Tensor::<f32>::new(vec![1., 5., 3., 4., 2., 6.], &vec![2, 3]);
+    assert_eq!(x.sum_axis(0, false).data(), &[5., 7., 9.]);
+    let s = x.sum_axis(1, true);
+    assert_eq!(s.shape(), &vec![2, 1]);
+    assert_eq!(s.data(), &[9., 12.]);
+    assert_eq!(x.mean_axis(1, false).data(), &[3., 4.]);
+    assert_eq!(x.max_axis(1, false).data(), &[5., 6.]);
+    assert_eq!(x.min_axis(0, false).data(), &[1., 2., 3.]);
+    assert_eq!(x.transpose(vec![1, 0]).max_axis(0, false).data(), &[5., 6.]);
+}
+
+#[test]
+fn test_argmax_top_k() {
+    let x = Tensor::<f32>::new(vec![0.1, 0.7, -2., 0.7, 3., 0.], &vec![1, 6]);
+    assert_eq!(x.argmax(), (4, 3.));
+    let (idx, val) = x.top_k(3);
+    assert_eq!(idx, vec![4, 1, 3]);
+    assert_eq!(val, vec![3., 0.7, 0.7]);
+    assert_eq!(x.top_k(10).0.len(), 6);
+    assert!(x.top_k(0).0.is_empty());
+}
+
+#[test]
+fn test_display() {
+    let x = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    assert_eq!(format!("{x}"), "tensor([[1, 2, 3],\n        [4, 5, 6]], shape=[2, 3])");
+    let first_row = x.transpose(vec![1, 0]).narrow(0, 0, 1);
+    assert_eq!(format!("{first_row:.1}"), "tensor([[1.0, 4.0]], shape=[1, 2])");
+    let big = Tensor::<u32>::new((0..2000).collect(), &vec![2, 1000]);
+    let options = PrintOptions { edge_items: 2, ..Default::default() };
+    assert_eq!(
+        big.display_with(options).to_string(),
+        "tensor([[0, 1, ..., 998, 999],\n        [1000, 1001, ..., 1998, 1999]], shape=[2, 1000])"
+    );
+    assert!(format!("{big:?}").starts_with("Tensor { shape: [2, 1000], strides: [1000, 1], offset: 0"));
+}
+
+#[test]
+fn test_iter_axis0() {
+    let x = Tensor::<f32>::new((0..12).map(|x| x as f32).collect(), &vec![2, 2, 3]);
+    let seqs: Vec<_> = x.iter_axis0().collect();
+    assert_eq!(seqs.len(), 2);
+    assert_eq!(seqs[1].shape(), &vec![2, 3]);
+    assert_eq!(seqs[1].data(), &[6., 7., 8., 9., 10., 11.]);
+    let mut rows = x.rows();
+    assert_eq!(rows.len(), 4);
+    assert_eq!(rows.next_back().unwrap().data(), &[9., 10., 11.]);
+    let cols: Vec<_> = x.index_axis0(0).transpose(vec![1, 0]).iter_axis0().collect();
+    assert_eq!(cols[2].contiguous().data(), &[2., 5.]);
+}
+
+#[test]
+fn test_f16_roundtrip() {
+    let x = Tensor::<f32>::new(vec![1., -0.5, 3.25, 65504.], &vec![2, 2]);
+    let h = x.transpose(vec![1, 0]).to_f16();
+    assert_eq!(h.data()[1], f16::from_f32(3.25));
+    assert!(h.to_f32().close_to(&x.transpose(vec![1, 0]), 1e-6));
+}
+
+#[test]
+fn test_bf16_roundtrip() {
+    let x = Tensor::<f32>::new(vec![1., -0.5, 3.25, 1e30], &vec![4]);
+    let h = x.to_bf16();
+    assert_eq!(h.data()[2], bf16::from_f32(3.25));
+    assert!(h.to_f32().close_to(&x, 1e-2));
+}
+
+#[test]
+fn test_mmap_storage() {
+    use std::io::Write;
+    let path = std::env::temp_dir().join(format!("tensor_mmap_{}.bin", std::process::id()));
+    let values = [1f32, 2., 3., 4., 5., 6.];
+    let mut file = std::fs::File::create(&path).unwrap();
+    file.write_all(&[0u8; 4]).unwrap();
+    file.write_all(&values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>()).unwrap();
+    drop(file);
+    let mmap = Arc::new(unsafe { Mmap::map(&std::fs::File::open(&path).unwrap()).unwrap() });
+    let t = unsafe { Tensor::<f32>::from_mmap(&mmap, 4, &vec![2, 3]) }.unwrap();
+    assert!(t.is_mapped());
+    assert_eq!(t.data(), &values);
+    assert_eq!(t.transpose(vec![1, 0]).contiguous().data(), &[1., 4., 2., 5., 3., 6.]);
+    assert!(unsafe { Tensor::<f32>::from_mmap(&mmap, 2, &vec![2]) }.is_none());
+    assert!(unsafe { Tensor::<f32>::from_mmap(&mmap, 4, &vec![7]) }.is_none());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_tensor_pool() {
+    let mut pool = TensorPool::<f32>::new();
+    let mut a = pool.take(&vec![2, 3]);
+    a.make_mut().fill(1.);
+    let a_ptr = a.data().as_ptr();
+    let b = pool.take(&vec![4]);
+    let keep = b.clone();
+    pool.recycle(a);
+    pool.recycle(b);
+    // the 6-element buffer is reused and cleared, the one still referenced by `keep` is not
+    assert_eq!(pool.capacity(), 6);
+    let c = pool.take(&vec![5]);
+    assert_eq!(c.data().as_ptr(), a_ptr);
+    assert_eq!(c.data(), &[0.; 5]);
+    assert_eq!(pool.capacity(), 0);
+    drop(keep);
+}
+
+#[test]
+fn test_make_mut_copy_on_write() {
+    let mut a = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    assert!(a.try_data_mut().is_some());
+    let view = a.clone();
+    assert!(a.try_data_mut().is_none());
+    a.make_mut()[0] = 9.;
+    assert_eq!(a.data(), &[9., 2., 3., 4.]);
+    assert_eq!(view.data(), &[1., 2., 3., 4.]);
+    let mut t = view.transpose(vec![1, 0]);
+    t.make_mut()[1] = 0.;
+    assert_eq!(t.data(), &[1., 0., 2., 4.]);
+    assert_eq!(view.data(), &[1., 2., 3., 4.]);
+}
+
+#[test]
+fn test_try_errors() {
+    assert_eq!(
+        Tensor::<f32>::try_new(vec![1., 2., 3.], &vec![2, 2]).err(),
+        Some(TensorError::SizeMismatch { shape: vec![2, 2], expected: 4, actual: 3 })
+    );
+    let mut x = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    assert!(x.try_reshape(&vec![4, 2]).is_err());
+    assert_eq!(x.try_reshape(&vec![3, 2]).unwrap().shape(), &vec![3, 2]);
+    assert!(matches!(x.try_slice(4, &vec![3]), Err(TensorError::OutOfBounds { .. })));
+    let mut t = x.transpose(vec![1, 0]);
+    assert_eq!(t.try_reshape(&vec![6]).err(), Some(TensorError::NotContiguous { op: "reshape" }));
+    assert!(x.try_permute(&[0, 0]).is_err());
+    assert_eq!(
+        x.try_narrow(1, 1, 2).unwrap_err().to_string(),
+        "range 1..3 out of bounds for axis 1 of [3, 2]"
+    );
+}
+
+#[test]
+fn test_inplace_ops() {
+    let mut x = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let ptr = x.data().as_ptr();
+    x.add_(&Tensor::new(vec![10., 20., 30.], &vec![3]));
+    assert_eq!(x.data(), &[11., 22., 33., 14., 25., 36.]);
+    x.mul_(&Tensor::new(vec![1., 0.], &vec![2, 1])).scale_(2.);
+    assert_eq!(x.data(), &[22., 44., 66., 0., 0., 0.]);
+    assert_eq!(x.data().as_ptr(), ptr);
+    let src = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![3, 2]);
+    x.copy_from(&src.transpose(vec![1, 0]));
+    assert_eq!(x.data(), &[1., 3., 5., 2., 4., 6.]);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_par_axis0() {
+    let mut x = Tensor::<f32>::new((0..6).map(|x| x as f32).collect(), &vec![3, 2]);
+    let sums: Vec<f32> = x.par_iter_axis0().map(|row| row.data().iter().sum()).collect();
+    assert_eq!(sums, vec![1., 5., 9.]);
+    x.par_chunks_axis0_mut().enumerate().for_each(|(i, row)| row.fill(i as f32));
+    assert_eq!(x.data(), &[0., 0., 1., 1., 2., 2.]);
+}
+
+#[test]
+fn test_aligned_allocation() {
+    let is_aligned = |t: &Tensor<f32>| (t.data().as_ptr() as usize).is_multiple_of(ALIGNMENT);
+    for n in [1, 3, 17, 1000] {
+        assert!(is_aligned(&Tensor::default(&vec![n])));
+    }
+    let t = Tensor::<f32>::new_aligned(vec![1., 2., 3.], &vec![3]);
+    assert!(is_aligned(&t));
+    assert_eq!(t.data(), &[1., 2., 3.]);
+    let mut pool = TensorPool::<f32>::new();
+    assert!(is_aligned(&pool.take(&vec![5, 7])));
+}
+
+#[test]
+fn test_strided_row_views() {
+    // (seq, n_heads * head_dim) buffer with 3 tokens and 2 heads of size 2
+    let x = Tensor::<f32>::new((0..12).map(|x| x as f32).collect(), &vec![3, 4]);
+    let head1 = x.slice_dims(&[0..3, 2..4]);
+    assert_eq!(head1.strides(), &vec![4, 1]);
+    assert!(!head1.is_contiguous());
+    assert_eq!(head1.contiguous().data(), &[2., 3., 6., 7., 10., 11.]);
+    let even = x.step_by(0, 2);
+    assert_eq!(even.shape(), &vec![2, 4]);
+    assert_eq!(even.strides(), &vec![8, 1]);
+    assert_eq!(even.contiguous().data(), &[0., 1., 2., 3., 8., 9., 10., 11.]);
+    let odd_cols = x.narrow(1, 1, 3).step_by(1, 2);
+    assert_eq!(odd_cols.contiguous().data(), &[1., 3., 5., 7., 9., 11.]);
+    assert!(even.add(&head1.step_by(0, 2).contiguous().slice_dims(&[0..2, 0..1])).close_to(
+        &Tensor::new(vec![2., 3., 4., 5., 18., 19., 20., 21.], &vec![2, 4]),
+        1e-6
+    ));
+}
+
+#[test]
+fn test_is_contiguous() {
+    let x = Tensor::<f32>::new((0..6).map(|x| x as f32).collect(), &vec![2, 3]);
+    assert!(x.is_contiguous());
+    assert!(x.narrow(0, 1, 1).is_contiguous());
+    assert!(!x.narrow(1, 1, 2).is_contiguous());
+    assert!(!x.transpose(vec![1, 0]).is_contiguous());
+    assert!(x.narrow(0, 1, 1).transpose(vec![1, 0]).is_contiguous());
+    assert!(!x.narrow(1, 1, 1).transpose(vec![1, 0]).is_contiguous());
+    let t = x.transpose(vec![1, 0]);
+    let c = t.contiguous();
+    assert!(c.is_contiguous());
+    let again = c.contiguous();
+    assert!(Arc::ptr_eq(&c.data, &again.data));
+}
+
+#[test]
+fn test_pad() {
+    let x = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let p = x.pad(1, 1, 2, 0.);
+    assert_eq!(p.shape(), &vec![2, 5]);
+    assert_eq!(p.data(), &[0., 1., 2., 0., 0., 0., 3., 4., 0., 0.]);
+    assert_eq!(x.pad(0, 0, 0, 9.).data(), x.data());
+    let a = Tensor::<u32>::new(vec![5, 6, 7], &vec![3]);
+    let b = Tensor::<u32>::new(vec![8], &vec![1]);
+    let (batch, mask) = Tensor::pad_batch(&[&a, &b], 0);
+    assert_eq!(batch.shape(), &vec![2, 3]);
+    assert_eq!(batch.data(), &[5, 6, 7, 8, 0, 0]);
+    assert_eq!(mask.data(), &[true, true, true, true, false, false]);
+}
+
+#[test]
+fn test_compare() {
+    let a = Tensor::<f32>::new(vec![1., 2., 3., f32::NAN, 5., 6.], &vec![2, 3]);
+    let b = Tensor::<f32>::new(vec![1., 2.5, 3., 4., 5., f32::INFINITY], &vec![2, 3]);
+    let report = a.compare(&b);
+    assert_eq!(report.max_abs_err, 0.5);
+    assert_eq!(report.max_rel_err, 0.2);
+    assert_eq!(report.worst_index, vec![0, 1]);
+    assert_eq!((report.nan_count, report.inf_count), (1, 1));
+    assert_eq!(report.to_string(), "max abs err 5e-1 at [0, 1], max rel err 2e-1, 1 NaN, 1 Inf");
+}
+
+#[test]
+fn test_random_constructors() {
+    let a = Tensor::randn(&vec![64, 64], 42);
+    assert_eq!(a.data(), Tensor::randn(&vec![64, 64], 42).data());
+    assert_ne!(a.data(), Tensor::randn(&vec![64, 64], 43).data());
+    let mean = a.data().iter().sum::<f32>() / a.size() as f32;
+    let var = a.data().iter().map(|x| (x - mean).powi(2)).sum::<f32>() / a.size() as f32;
+    assert!(mean.abs() < 0.05 && (var - 1.).abs() < 0.1);
+    assert_eq!(Tensor::randn(&vec![3], 0).size(), 3);
+    let u = Tensor::uniform(&vec![1000], -0.5, 2., 7);
+    assert!(u.data().iter().all(|&x| (-0.5..2.).contains(&x)));
+    assert_eq!(u.data(), Tensor::uniform(&vec![1000], -0.5, 2., 7).data());
+}
+
+#[test]
+fn test_cast() {
+    let x = Tensor::<f32>::new(vec![0.5, -1.25, 3., 1e-3], &vec![2, 2]);
+    let h: Tensor<f16> = x.cast();
+    let b = h.cast::<bf16>();
+    assert_eq!(b.data()[1], bf16::from_f32(-1.25));
+    let d = x.transpose(vec![1, 0]).cast::<f64>();
+    assert_eq!(d.data(), &[0.5, 3., -1.25, 1e-3f32 as f64]);
+    assert!(d.to_f32().close_to(&x.transpose(vec![1, 0]), 1e-6));
+    assert_eq!(x.to_f32().data(), x.data());
+}
+
+#[test]
+fn test_scatter_index_add() {
+    let mut x = Tensor::<f32>::default(&vec![3, 2]);
+    x.index_add_(0, &[2, 0, 2], &Tensor::new(vec![1., 2., 3., 4., 5., 6.], &vec![3, 2]));
+    assert_eq!(x.data(), &[3., 4., 0., 0., 6., 8.]);
+    let logits = Tensor::<f32>::new(vec![0., 0., 0., 0., 0., 0., 0., 0.], &vec![2, 4]);
+    let idx = Tensor::<u32>::new(vec![3, 3, 0, 1], &vec![2, 2]);
+    let src = Tensor::<f32>::new(vec![1., 2., 5., 7.], &vec![2, 2]);
+    let y = logits.scatter_add(1, &idx, &src);
+    assert_eq!(y.data(), &[0., 0., 0., 3., 5., 7., 0., 0.]);
+    assert_eq!(logits.data(), &[0.; 8]);
+    let col = Tensor::<u32>::new(vec![1, 0], &vec![1, 2]);
+    let mut z = Tensor::<f32>::default(&vec![2, 2]);
+    z.scatter_add_(0, &col, &Tensor::new(vec![1., 2.], &vec![1, 2]));
+    assert_eq!(z.data(), &[0., 2., 1., 0.]);
+}
+